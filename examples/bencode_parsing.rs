@@ -6,9 +6,7 @@
 use torro::bencode;
 
 fn main() {
-    let input_data = "li64e22:a bencode example herei0ee"
-        .as_bytes()
-        .to_vec(); // our inputted data as Vec<u8>
+    let input_data = "li64e22:a bencode example herei0ee".as_bytes().to_vec(); // our inputted data as Vec<u8>
 
     match bencode::parse(input_data) {
         Ok(resulting_bencode) => println!(
@@ -8,6 +8,7 @@
 use crate::error::BencodeError;
 use std::collections::BTreeMap;
 use std::iter::Enumerate;
+use std::ops::Range;
 
 /// Control char num for detecting int starts, equates to `i`
 const INT_START: u8 = 105;
@@ -110,15 +111,17 @@ fn read_until(
 /// referenced back
 ///
 /// If you want to decode a whole `i3432e` block, see [decode_int] instead
-fn decode_num(byte_ind: usize, bytes: Vec<u8>) -> Result<u32, BencodeError> {
-    if bytes.len() == 0 {
+fn decode_num(byte_ind: usize, bytes: Vec<u8>) -> Result<i64, BencodeError> {
+    if bytes.is_empty() {
         return Err(BencodeError::NoIntGiven(byte_ind));
     } else if bytes[0] == 48 && bytes.len() > 1 {
         return Err(BencodeError::LeadingZeros(byte_ind));
+    } else if !bytes.iter().all(|byte| byte.is_ascii_digit()) {
+        return Err(BencodeError::InvalidInt(byte_ind)); // `parse` allows a leading `+`
     }
 
     match std::str::from_utf8(&bytes) {
-        Ok(numstr) => match numstr.parse::<u32>() {
+        Ok(numstr) => match numstr.parse::<i64>() {
             Ok(num) => Ok(num),
             Err(_) => Err(BencodeError::InvalidInt(byte_ind)),
         },
@@ -135,7 +138,7 @@ fn decode_int(
 
     let mut is_negative = false;
 
-    if got_bytes.len() == 0 {
+    if got_bytes.is_empty() {
         // this is in decode_num but need to safeguard here too
         return Err(BencodeError::NoIntGiven(byte_ind));
     } else if got_bytes[0] == 45 {
@@ -152,9 +155,9 @@ fn decode_int(
             return Err(BencodeError::NegativeZero(byte_ind));
         }
 
-        Ok(-(decode_num(byte_ind, got_bytes)? as i64))
+        Ok(-decode_num(byte_ind, got_bytes)?)
     } else {
-        Ok(decode_num(byte_ind, got_bytes)? as i64)
+        decode_num(byte_ind, got_bytes)
     }
 }
 
@@ -190,9 +193,14 @@ fn decode_bytestring(
     let mut len_utf8 = vec![cur_byte.1];
     len_utf8.append(&mut read_until(STR_SEP, bytes_iter)?);
 
-    let string_len = decode_num(cur_byte.0, len_utf8)?;
+    let string_len = decode_num(cur_byte.0, len_utf8)? as usize;
+    let bytestring: Vec<u8> = bytes_iter.take(string_len).map(|x| x.1).collect();
 
-    Ok(bytes_iter.take(string_len as usize).map(|x| x.1).collect())
+    if bytestring.len() != string_len {
+        Err(BencodeError::UnexpectedEOF)
+    } else {
+        Ok(bytestring)
+    }
 }
 
 /// Decodes a dictionary (json-like object or equivilant to a `BTreeMap<Vec<u8>, Bencode>`)
@@ -207,7 +215,7 @@ fn decode_dict(
     loop {
         match bytes_iter.next() {
             Some(cur_byte) => {
-                if key_buf != None && val_buf != None {
+                if key_buf.is_some() && val_buf.is_some() {
                     btree_out.insert(key_buf.take().unwrap(), val_buf.take().unwrap());
                 }
 
@@ -216,9 +224,9 @@ fn decode_dict(
                     break;
                 }
 
-                if key_buf == None {
+                if key_buf.is_none() {
                     key_buf = Some(decode_bytestring(cur_byte, bytes_iter)?);
-                } else if val_buf == None {
+                } else if val_buf.is_none() {
                     val_buf = Some(get_next(Some(cur_byte), bytes_iter)?);
                 }
             }
@@ -239,9 +247,10 @@ fn get_next(
             INT_START => Ok(Bencode::Int(decode_int(byte_ind, bytes_iter)?)),
             LIST_START => Ok(Bencode::List(decode_list(bytes_iter)?)),
             DICT_START => Ok(Bencode::Dict(decode_dict(bytes_iter)?)),
-            48 | 49 | 50 | 51 | 52 | 53 | 54 | 55 | 56 | 57 => Ok(Bencode::ByteString(
-                decode_bytestring(cur_byte.unwrap(), bytes_iter)?,
-            )),
+            48..=57 => Ok(Bencode::ByteString(decode_bytestring(
+                cur_byte.unwrap(),
+                bytes_iter,
+            )?)),
             _ => Err(BencodeError::UnexpectedByte(cur_byte.unwrap())),
         },
         None => Err(BencodeError::UnexpectedEOF),
@@ -267,11 +276,17 @@ fn get_next(
 /// }
 /// ```
 pub fn parse(data: Vec<u8>) -> Result<Bencode, BencodeError> {
-    if data.len() == 0 {
+    if data.is_empty() {
         return Err(BencodeError::EmptyFile);
     }
 
-    let mut bytes_iter = data.into_iter().enumerate();
+    parse_iter(data.into_iter())
+}
+
+/// Parses a single toplevel [Bencode] from any byte iterator, used as the shared
+/// core of [parse] and [parse_slice] so that slices don't have to be copied
+fn parse_iter(bytes: impl Iterator<Item = u8>) -> Result<Bencode, BencodeError> {
+    let mut bytes_iter = bytes.enumerate();
 
     match get_next(bytes_iter.next(), &mut bytes_iter) {
         Ok(bencode_out) => {
@@ -285,7 +300,8 @@ pub fn parse(data: Vec<u8>) -> Result<Bencode, BencodeError> {
     }
 }
 
-/// Alias to [parse] which allows a [u8] [slice](std::slice), e.g. &[[u8]]
+/// Alias to [parse] which allows a [u8] [slice](std::slice), e.g. &[[u8]],
+/// without copying it into a new [Vec] first
 ///
 /// # Examples
 ///
@@ -300,7 +316,142 @@ pub fn parse(data: Vec<u8>) -> Result<Bencode, BencodeError> {
 /// }
 /// ```
 pub fn parse_slice(data: &[u8]) -> Result<Bencode, BencodeError> {
-    parse(data.to_vec())
+    if data.is_empty() {
+        return Err(BencodeError::EmptyFile);
+    }
+
+    parse_iter(data.iter().copied())
+}
+
+/// Encodes a given [Bencode] into `buf`, see [encode] for the public
+/// interface of this function
+fn encode_into(bencode: &Bencode, buf: &mut Vec<u8>) {
+    match bencode {
+        Bencode::Dict(dict) => {
+            buf.push(DICT_START);
+
+            for (key, value) in dict {
+                encode_bytestring_into(key, buf);
+                encode_into(value, buf);
+            }
+
+            buf.push(END);
+        }
+        Bencode::List(list) => {
+            buf.push(LIST_START);
+
+            for value in list {
+                encode_into(value, buf);
+            }
+
+            buf.push(END);
+        }
+        Bencode::ByteString(bytestring) => encode_bytestring_into(bytestring, buf),
+        Bencode::Int(int) => {
+            buf.push(INT_START);
+            buf.extend_from_slice(int.to_string().as_bytes());
+            buf.push(END);
+        }
+    }
+}
+
+/// Encodes a bytestring into `buf` as `<length>:<contents>`, used for both
+/// [Bencode::ByteString] and [Bencode::Dict] keys
+fn encode_bytestring_into(bytestring: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(bytestring.len().to_string().as_bytes());
+    buf.push(STR_SEP);
+    buf.extend_from_slice(bytestring);
+}
+
+/// Encodes given [Bencode] into canonical bencode bytes, the opposite of [parse]
+///
+/// [Bencode::Dict] keys are always written in lexographical order as the
+/// spec requires, so encoding the result of [parse] may not give the exact
+/// bytes originally parsed if they where not canonical
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::bencode;
+///
+/// fn main() {
+///     let data = "d5:hello6:there!e".as_bytes();
+///     let got_bencode = bencode::parse_slice(data).unwrap();
+///
+///     assert_eq!(bencode::encode(&got_bencode), data);
+/// }
+/// ```
+pub fn encode(bencode: &Bencode) -> Vec<u8> {
+    let mut buf = vec![];
+    encode_into(bencode, &mut buf);
+
+    buf
+}
+
+/// Finds the length in bytes of the first bencode value at the start of `data`
+/// without decoding it, allowing values to be pulled out of the source bytes
+/// as-is (e.g. for info-hashing the raw `info` dictionary)
+pub(crate) fn value_len(data: &[u8]) -> Result<usize, BencodeError> {
+    match data.first() {
+        Some(&INT_START) => match data.iter().position(|byte| *byte == END) {
+            Some(end_ind) => Ok(end_ind + 1),
+            None => Err(BencodeError::UnexpectedEOF),
+        },
+        Some(&LIST_START) | Some(&DICT_START) => {
+            let mut ind = 1;
+
+            loop {
+                match data.get(ind) {
+                    Some(&END) => return Ok(ind + 1),
+                    Some(_) => ind += value_len(&data[ind..])?,
+                    None => return Err(BencodeError::UnexpectedEOF),
+                }
+            }
+        }
+        Some(48..=57) => {
+            let sep_ind = data
+                .iter()
+                .position(|byte| *byte == STR_SEP)
+                .ok_or(BencodeError::UnexpectedEOF)?;
+            let string_len = decode_num(0, data[..sep_ind].to_vec())? as usize;
+
+            if data.len() < sep_ind + 1 + string_len {
+                Err(BencodeError::UnexpectedEOF)
+            } else {
+                Ok(sep_ind + 1 + string_len)
+            }
+        }
+        Some(byte) => Err(BencodeError::UnexpectedByte((0, *byte))),
+        None => Err(BencodeError::UnexpectedEOF),
+    }
+}
+
+/// Finds the byte range of the value for `key` inside of the top-level
+/// dictionary of `data`, returning [None] if `data` is not a dictionary or the
+/// key isn't present. If a key is given twice, the last one is used to match
+/// [parse]
+pub(crate) fn dict_value_range(data: &[u8], key: &[u8]) -> Option<Range<usize>> {
+    if data.first() != Some(&DICT_START) {
+        return None;
+    }
+
+    let mut found = None;
+    let mut ind = 1;
+
+    while data.get(ind) != Some(&END) {
+        let key_len = value_len(data.get(ind..)?).ok()?;
+        let key_range = ind..ind + key_len;
+        let value_start = key_range.end;
+        let value_end = value_start + value_len(data.get(value_start..)?).ok()?;
+
+        if parse_slice(&data[key_range]).ok() == Some(Bencode::ByteString(key.to_vec())) {
+            found = Some(value_start..value_end);
+        }
+
+        ind = value_end;
+    }
+
+    found
 }
 
 #[cfg(test)]
@@ -340,6 +491,24 @@ mod tests {
         );
     }
 
+    /// Tests that integers larger than 32 bits (e.g. lengths of files over 4GiB)
+    /// are parsed and that a leading `+` or truncated bytestring is an error
+    #[test]
+    fn large_ints_truncated() {
+        assert_eq!(
+            parse("i5368709120e".as_bytes().to_vec()),
+            Ok(Bencode::Int(5368709120))
+        );
+        assert_eq!(
+            parse("i+5e".as_bytes().to_vec()),
+            Err(BencodeError::InvalidInt(0))
+        );
+        assert_eq!(
+            parse("5:abc".as_bytes().to_vec()),
+            Err(BencodeError::UnexpectedEOF)
+        );
+    }
+
     /// Tests [parse] makes a proper [Bencode::ByteString] (from [decode_bytestring])
     #[test]
     fn bytestring() {
@@ -484,4 +653,40 @@ mod tests {
             Err(BencodeError::UnexpectedEOF)
         ); // 15 starts, 14 ends
     }
+
+    /// Tests that [encode] gives back the same canonical bencode which was parsed
+    #[test]
+    fn encode_roundtrip() {
+        let inputs = vec![
+            "i0e",
+            "i-1000e",
+            "0:",
+            "5:hello",
+            "le",
+            "li64e4:teste",
+            "de",
+            "d5:first5:value4:listli-1000e11:lastelementee",
+        ];
+
+        for input in inputs {
+            assert_eq!(
+                encode(&parse(input.as_bytes().to_vec()).unwrap()),
+                input.as_bytes()
+            );
+        }
+    }
+
+    /// Tests that [dict_value_range] finds raw values inside of a dictionary
+    #[test]
+    fn dict_value_ranges() {
+        let data = "d1:ai1e4:infod1:xli1ei2eee1:z0:e".as_bytes();
+
+        assert_eq!(
+            dict_value_range(data, b"info").map(|range| &data[range]),
+            Some("d1:xli1ei2eee".as_bytes())
+        );
+        assert_eq!(dict_value_range(data, b"missing"), None);
+        assert_eq!(dict_value_range(b"li0ee", b"info"), None);
+        assert_eq!(value_len(b"4:abc"), Err(BencodeError::UnexpectedEOF));
+    }
 }
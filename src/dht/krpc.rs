@@ -0,0 +1,668 @@
+//! KRPC, the bencoded query/response protocol DHT nodes talk to each other
+//! with over UDP
+
+use crate::bencode::{self, Bencode};
+use crate::error::DhtError;
+use crate::tracker_server::encode_compact_peers;
+use crate::tracker_udp::parse_compact_peers;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+
+/// Length of an IPv4 compact node info, a node id followed by a compact peer
+pub const COMPACT_NODE_LEN: usize = 26;
+
+/// Length of an IPv6 compact node info, from
+/// [BEP0032](https://www.bittorrent.org/beps/bep_0032.html)
+pub const COMPACT_NODE6_LEN: usize = 38;
+
+/// KRPC error code for generic errors
+pub const ERROR_GENERIC: i64 = 201;
+
+/// KRPC error code for server errors
+pub const ERROR_SERVER: i64 = 202;
+
+/// KRPC error code for malformed packets, invalid arguments or bad tokens
+pub const ERROR_PROTOCOL: i64 = 203;
+
+/// KRPC error code for queries with an unknown method
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// The id and address of a DHT node, as given in compact node infos
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct NodeInfo {
+    /// Id of the node
+    pub id: [u8; 20],
+
+    /// Address the node's UDP socket is at
+    pub addr: SocketAddr,
+}
+
+/// Encodes the IPv6 (if `ipv6`) or IPv4 nodes from `nodes` into compact node
+/// infos, leaving out nodes of the other family
+pub(crate) fn encode_compact_nodes(nodes: &[NodeInfo], ipv6: bool) -> Vec<u8> {
+    let mut buf = vec![];
+
+    for node in nodes.iter().filter(|node| node.addr.is_ipv6() == ipv6) {
+        buf.extend_from_slice(&node.id);
+        buf.extend(encode_compact_peers(&[node.addr], ipv6));
+    }
+
+    buf
+}
+
+/// Parses compact node infos from `buf`, as IPv6 nodes if `ipv6`, giving
+/// [DhtError::BadMessage] with `key` if there are trailing bytes
+pub(crate) fn parse_compact_nodes(
+    buf: &[u8],
+    ipv6: bool,
+    key: &'static str,
+) -> Result<Vec<NodeInfo>, DhtError> {
+    let node_len = if ipv6 {
+        COMPACT_NODE6_LEN
+    } else {
+        COMPACT_NODE_LEN
+    };
+
+    if !buf.len().is_multiple_of(node_len) {
+        return Err(DhtError::BadMessage(key));
+    }
+
+    Ok(buf
+        .chunks_exact(node_len)
+        .map(|node| NodeInfo {
+            id: node[..20].try_into().unwrap(),
+            addr: parse_compact_peers(&node[20..], ipv6)[0],
+        })
+        .collect())
+}
+
+/// A query sent to a DHT node, with the arguments of each method
+#[derive(Debug, PartialEq, Clone)]
+pub enum Query {
+    /// Checks if a node is up, answered with just it's id
+    Ping,
+
+    /// Asks for the nodes closest to `target` the queried node knows
+    FindNode {
+        /// Id of the node being looked for
+        target: [u8; 20],
+    },
+
+    /// Asks for peers of a torrent, answered with peers if the queried node
+    /// has any or the closest nodes otherwise, along with a token needed to
+    /// announce
+    GetPeers {
+        /// Info-hash of the torrent
+        info_hash: [u8; 20],
+    },
+
+    /// Tells the queried node we are a peer of a torrent
+    AnnouncePeer {
+        /// Info-hash of the torrent
+        info_hash: [u8; 20],
+
+        /// Port we accept peer connections on
+        port: u16,
+
+        /// If the port of the query's source address should be used in place
+        /// of `port`, for peers behind a NAT
+        implied_port: bool,
+
+        /// Token given by the queried node in an earlier `get_peers` response
+        token: Vec<u8>,
+    },
+
+    /// Asks for a random sample of the info-hashes the queried node keeps
+    /// peers for, from [BEP0051](https://www.bittorrent.org/beps/bep_0051.html),
+    /// answered along with the closest nodes to `target` so crawlers can move
+    /// on to other nodes
+    SampleInfohashes {
+        /// Id nodes close to are given back
+        target: [u8; 20],
+    },
+
+    /// A query with a method torro doesn't know, which should be answered
+    /// with an [ERROR_METHOD_UNKNOWN] error
+    Unknown(Vec<u8>),
+}
+
+impl Query {
+    /// Method name of this query, as given in the `q` key
+    pub fn method(&self) -> &[u8] {
+        match self {
+            Query::Ping => b"ping",
+            Query::FindNode { .. } => b"find_node",
+            Query::GetPeers { .. } => b"get_peers",
+            Query::AnnouncePeer { .. } => b"announce_peer",
+            Query::SampleInfohashes { .. } => b"sample_infohashes",
+            Query::Unknown(method) => method,
+        }
+    }
+}
+
+/// A response to any [Query], with every key a response may have as queries
+/// are told apart by their transaction id rather than by their response
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Response {
+    /// Id of the responding node
+    pub id: [u8; 20],
+
+    /// Nodes close to the target, from both the `nodes` and `nodes6` keys
+    pub nodes: Vec<NodeInfo>,
+
+    /// Peers of a torrent given to `get_peers`
+    pub values: Vec<SocketAddr>,
+
+    /// Token to give back when announcing, given to `get_peers`
+    pub token: Option<Vec<u8>>,
+
+    /// Seconds to wait before sampling the node again, given to
+    /// `sample_infohashes`
+    pub interval: Option<u32>,
+
+    /// Amount of info-hashes the node keeps in total, given to
+    /// `sample_infohashes`
+    pub num: Option<u64>,
+
+    /// Sample of the info-hashes the node keeps, given to `sample_infohashes`
+    pub samples: Vec<[u8; 20]>,
+}
+
+/// What a [KrpcMessage] is, being the `y` key and it's contents
+#[derive(Debug, PartialEq, Clone)]
+pub enum KrpcBody {
+    /// A query from the node with given id
+    Query {
+        /// Id of the querying node
+        id: [u8; 20],
+
+        /// The query and it's arguments
+        query: Query,
+    },
+
+    /// A response to an earlier query
+    Response(Response),
+
+    /// An error in response to an earlier query
+    Error {
+        /// Error code, such as [ERROR_PROTOCOL]
+        code: i64,
+
+        /// Human-readable error message
+        message: String,
+    },
+}
+
+/// A single KRPC message, sent as one UDP packet
+///
+/// # BitTorrent Description
+///
+/// *Taken from [BEP0005](https://www.bittorrent.org/beps/bep_0005.html)*
+///
+/// ```none
+/// A KRPC message is a single dictionary with three keys common to every
+/// message and additional keys depending on the type of message. Every
+/// message has a key "t" with a string value representing a transaction ID.
+/// [...] Every message also has a key "y" with a single character value
+/// describing the type of message. The value of the "y" key is one of "q" for
+/// query, "r" for response, or "e" for error.
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::dht::{KrpcBody, KrpcMessage, Query};
+///
+/// fn main() {
+///     let ping = KrpcMessage::query(b"aa".to_vec(), *b"abcdefghij0123456789", Query::Ping);
+///     let encoded = ping.encode();
+///
+///     assert_eq!(encoded, b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe");
+///     assert_eq!(KrpcMessage::decode(&encoded), Ok(ping));
+/// }
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct KrpcMessage {
+    /// Transaction id, chosen by the querying node and echoed back in the
+    /// response
+    pub transaction: Vec<u8>,
+
+    /// What the message is
+    pub body: KrpcBody,
+
+    /// Address the message was sent to as seen by the sender, which nodes put
+    /// in responses so the querying node may learn it's external address from
+    /// [BEP0042](https://www.bittorrent.org/beps/bep_0042.html)
+    pub ip: Option<SocketAddr>,
+}
+
+impl KrpcMessage {
+    /// Creates a query message from the node with given `id`
+    pub fn query(transaction: Vec<u8>, id: [u8; 20], query: Query) -> Self {
+        Self {
+            transaction,
+            body: KrpcBody::Query { id, query },
+            ip: None,
+        }
+    }
+
+    /// Creates a response message
+    pub fn response(transaction: Vec<u8>, response: Response) -> Self {
+        Self {
+            transaction,
+            body: KrpcBody::Response(response),
+            ip: None,
+        }
+    }
+
+    /// Creates an error message with given `code` and `message`
+    pub fn error(transaction: Vec<u8>, code: i64, message: &str) -> Self {
+        Self {
+            transaction,
+            body: KrpcBody::Error {
+                code,
+                message: message.to_string(),
+            },
+            ip: None,
+        }
+    }
+
+    /// Sets the address the message is sent to, see [KrpcMessage::ip]
+    pub fn ip(mut self, addr: SocketAddr) -> Self {
+        self.ip = Some(addr);
+        self
+    }
+
+    /// Encodes this message into the payload of a UDP packet
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        let bytes = |bytes: &[u8]| Bencode::ByteString(bytes.to_vec());
+
+        dict.insert(b"t".to_vec(), bytes(&self.transaction));
+
+        if let Some(addr) = self.ip {
+            dict.insert(
+                b"ip".to_vec(),
+                Bencode::ByteString(encode_compact_peers(&[addr], addr.is_ipv6())),
+            );
+        }
+
+        match &self.body {
+            KrpcBody::Query { id, query } => {
+                let mut args = BTreeMap::new();
+                args.insert(b"id".to_vec(), bytes(id));
+
+                match query {
+                    Query::Ping | Query::Unknown(_) => (),
+                    Query::FindNode { target } | Query::SampleInfohashes { target } => {
+                        args.insert(b"target".to_vec(), bytes(target));
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.insert(b"info_hash".to_vec(), bytes(info_hash));
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.insert(b"info_hash".to_vec(), bytes(info_hash));
+                        args.insert(b"port".to_vec(), Bencode::Int(*port as i64));
+                        args.insert(b"token".to_vec(), bytes(token));
+
+                        if *implied_port {
+                            args.insert(b"implied_port".to_vec(), Bencode::Int(1));
+                        }
+                    }
+                }
+
+                dict.insert(b"y".to_vec(), bytes(b"q"));
+                dict.insert(b"q".to_vec(), bytes(query.method()));
+                dict.insert(b"a".to_vec(), Bencode::Dict(args));
+            }
+            KrpcBody::Response(response) => {
+                let mut values = BTreeMap::new();
+                values.insert(b"id".to_vec(), bytes(&response.id));
+
+                for (key, ipv6) in [(&b"nodes"[..], false), (b"nodes6", true)] {
+                    let nodes = encode_compact_nodes(&response.nodes, ipv6);
+
+                    if !nodes.is_empty() {
+                        values.insert(key.to_vec(), Bencode::ByteString(nodes));
+                    }
+                }
+
+                if !response.values.is_empty() {
+                    let peers = response
+                        .values
+                        .iter()
+                        .map(|peer| {
+                            Bencode::ByteString(encode_compact_peers(&[*peer], peer.is_ipv6()))
+                        })
+                        .collect();
+
+                    values.insert(b"values".to_vec(), Bencode::List(peers));
+                }
+
+                if let Some(token) = &response.token {
+                    values.insert(b"token".to_vec(), bytes(token));
+                }
+
+                if let Some(interval) = response.interval {
+                    values.insert(b"interval".to_vec(), Bencode::Int(interval as i64));
+                }
+
+                if let Some(num) = response.num {
+                    values.insert(b"num".to_vec(), Bencode::Int(num as i64));
+                }
+
+                if response.interval.is_some() || !response.samples.is_empty() {
+                    values.insert(b"samples".to_vec(), bytes(&response.samples.concat()));
+                }
+
+                dict.insert(b"y".to_vec(), bytes(b"r"));
+                dict.insert(b"r".to_vec(), Bencode::Dict(values));
+            }
+            KrpcBody::Error { code, message } => {
+                dict.insert(b"y".to_vec(), bytes(b"e"));
+                dict.insert(
+                    b"e".to_vec(),
+                    Bencode::List(vec![Bencode::Int(*code), bytes(message.as_bytes())]),
+                );
+            }
+        }
+
+        bencode::encode(&Bencode::Dict(dict))
+    }
+
+    /// Decodes a message from the payload of a UDP packet, giving
+    /// [DhtError::BadMessage] if a key needed is missing or has the wrong type
+    pub fn decode(packet: &[u8]) -> Result<Self, DhtError> {
+        let mut dict = match bencode::parse_slice(packet).map(|bencode| bencode.dict()) {
+            Ok(Some(dict)) => dict,
+            _ => return Err(DhtError::BadMessage("")),
+        };
+        let transaction = take_bytes(&mut dict, "t")?;
+        let ip = match take_bytes(&mut dict, "ip") {
+            Ok(ip) if ip.len() == 6 || ip.len() == 18 => {
+                parse_compact_peers(&ip, ip.len() == 18).pop()
+            }
+            _ => None,
+        };
+        let body = match take_bytes(&mut dict, "y")?.as_slice() {
+            b"q" => {
+                let method = take_bytes(&mut dict, "q")?;
+                let mut args = take_dict(&mut dict, "a")?;
+                let id = take_id(&mut args, "id")?;
+                let query = match method.as_slice() {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode {
+                        target: take_id(&mut args, "target")?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        info_hash: take_id(&mut args, "info_hash")?,
+                    },
+                    b"sample_infohashes" => Query::SampleInfohashes {
+                        target: take_id(&mut args, "target")?,
+                    },
+                    b"announce_peer" => Query::AnnouncePeer {
+                        info_hash: take_id(&mut args, "info_hash")?,
+                        port: take_int(&mut args, "port")?
+                            .try_into()
+                            .map_err(|_| DhtError::BadMessage("port"))?,
+                        implied_port: take_int(&mut args, "implied_port").unwrap_or(0) != 0,
+                        token: take_bytes(&mut args, "token")?,
+                    },
+                    _ => Query::Unknown(method),
+                };
+
+                KrpcBody::Query { id, query }
+            }
+            b"r" => {
+                let mut values = take_dict(&mut dict, "r")?;
+                let mut nodes = match take_bytes(&mut values, "nodes") {
+                    Ok(nodes) => parse_compact_nodes(&nodes, false, "nodes")?,
+                    Err(_) => vec![],
+                };
+
+                if let Ok(nodes6) = take_bytes(&mut values, "nodes6") {
+                    nodes.extend(parse_compact_nodes(&nodes6, true, "nodes6")?);
+                }
+
+                let peers = values
+                    .remove(&b"values"[..])
+                    .and_then(|peers| peers.list())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|peer| match peer.bytestring() {
+                        Some(peer) if peer.len() == 6 => Some(parse_compact_peers(&peer, false)),
+                        Some(peer) if peer.len() == 18 => Some(parse_compact_peers(&peer, true)),
+                        _ => None,
+                    })
+                    .flatten()
+                    .collect();
+
+                let samples = match take_bytes(&mut values, "samples") {
+                    Ok(samples) if samples.len().is_multiple_of(20) => samples
+                        .chunks_exact(20)
+                        .map(|sample| sample.try_into().unwrap())
+                        .collect(),
+                    Ok(_) => return Err(DhtError::BadMessage("samples")),
+                    Err(_) => vec![],
+                };
+
+                KrpcBody::Response(Response {
+                    id: take_id(&mut values, "id")?,
+                    nodes,
+                    values: peers,
+                    token: take_bytes(&mut values, "token").ok(),
+                    interval: take_int(&mut values, "interval")
+                        .ok()
+                        .and_then(|interval| interval.try_into().ok()),
+                    num: take_int(&mut values, "num")
+                        .ok()
+                        .and_then(|num| num.try_into().ok()),
+                    samples,
+                })
+            }
+            b"e" => match dict
+                .remove(&b"e"[..])
+                .and_then(|error| error.list())
+                .as_deref()
+            {
+                Some([Bencode::Int(code), Bencode::ByteString(message), ..]) => KrpcBody::Error {
+                    code: *code,
+                    message: String::from_utf8_lossy(message).into_owned(),
+                },
+                _ => return Err(DhtError::BadMessage("e")),
+            },
+            _ => return Err(DhtError::BadMessage("y")),
+        };
+
+        Ok(Self {
+            transaction,
+            body,
+            ip,
+        })
+    }
+}
+
+/// Takes the bytestring at `key` out of `dict`
+fn take_bytes(
+    dict: &mut BTreeMap<Vec<u8>, Bencode>,
+    key: &'static str,
+) -> Result<Vec<u8>, DhtError> {
+    dict.remove(key.as_bytes())
+        .and_then(|value| value.bytestring())
+        .ok_or(DhtError::BadMessage(key))
+}
+
+/// Takes the dictionary at `key` out of `dict`
+fn take_dict(
+    dict: &mut BTreeMap<Vec<u8>, Bencode>,
+    key: &'static str,
+) -> Result<BTreeMap<Vec<u8>, Bencode>, DhtError> {
+    dict.remove(key.as_bytes())
+        .and_then(|value| value.dict())
+        .ok_or(DhtError::BadMessage(key))
+}
+
+/// Takes the integer at `key` out of `dict`
+fn take_int(dict: &mut BTreeMap<Vec<u8>, Bencode>, key: &'static str) -> Result<i64, DhtError> {
+    dict.remove(key.as_bytes())
+        .and_then(|value| value.int())
+        .ok_or(DhtError::BadMessage(key))
+}
+
+/// Takes the 20-byte id or info-hash at `key` out of `dict`
+fn take_id(dict: &mut BTreeMap<Vec<u8>, Bencode>, key: &'static str) -> Result<[u8; 20], DhtError> {
+    take_bytes(dict, key)?
+        .try_into()
+        .map_err(|_| DhtError::BadMessage(key))
+}
+
+/// Gets the raw bytes of an IP address, as put in compact peers
+pub(crate) fn ip_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests decoding and encoding the example messages given in BEP0005
+    #[test]
+    fn krpc_bep5_examples() {
+        let id = *b"abcdefghij0123456789";
+        let examples: Vec<(&[u8], KrpcMessage)> = vec![
+            (
+                b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe",
+                KrpcMessage::query(
+                    b"aa".to_vec(),
+                    id,
+                    Query::FindNode {
+                        target: *b"mnopqrstuvwxyz123456",
+                    },
+                ),
+            ),
+            (
+                b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe",
+                KrpcMessage::query(
+                    b"aa".to_vec(),
+                    id,
+                    Query::AnnouncePeer {
+                        info_hash: *b"mnopqrstuvwxyz123456",
+                        port: 6881,
+                        implied_port: true,
+                        token: b"aoeusnth".to_vec(),
+                    },
+                ),
+            ),
+            (
+                b"d1:rd2:id20:mnopqrstuvwxyz1234565:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re",
+                KrpcMessage::response(
+                    b"aa".to_vec(),
+                    Response {
+                        id: *b"mnopqrstuvwxyz123456",
+                        nodes: vec![],
+                        values: vec!["97.120.106.101:11893".parse().unwrap(), "105.100.104.116:28269".parse().unwrap()],
+                        token: Some(b"aoeusnth".to_vec()),
+                        ..Response::default()
+                    },
+                ),
+            ),
+            (
+                b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee",
+                KrpcMessage::error(b"aa".to_vec(), ERROR_GENERIC, "A Generic Error Ocurred"),
+            ),
+        ];
+
+        for (encoded, message) in examples {
+            assert_eq!(KrpcMessage::decode(encoded).as_ref(), Ok(&message));
+            assert_eq!(message.encode(), encoded);
+        }
+    }
+
+    /// Tests that `sample_infohashes` queries and responses roundtrip along
+    /// with the external `ip` key
+    #[test]
+    fn krpc_samples_and_ip() {
+        let query = KrpcMessage::query(
+            b"aa".to_vec(),
+            [1; 20],
+            Query::SampleInfohashes { target: [2; 20] },
+        );
+        assert_eq!(KrpcMessage::decode(&query.encode()), Ok(query));
+
+        let response = KrpcMessage::response(
+            b"aa".to_vec(),
+            Response {
+                id: [1; 20],
+                interval: Some(60),
+                num: Some(3),
+                samples: vec![[3; 20], [4; 20]],
+                ..Response::default()
+            },
+        )
+        .ip("203.0.113.7:6881".parse().unwrap());
+        let encoded = response.encode();
+
+        assert!(encoded.starts_with(b"d2:ip6:\xcb\x00\x71\x07\x1a\xe1"));
+        assert_eq!(KrpcMessage::decode(&encoded), Ok(response));
+        assert_eq!(
+            KrpcMessage::decode(b"d1:rd2:id20:mnopqrstuvwxyz1234567:samples3:abce1:t2:aa1:y1:re"),
+            Err(DhtError::BadMessage("samples"))
+        );
+    }
+
+    /// Tests that compact nodes of both families roundtrip and that bad
+    /// messages are refused rather than panicking
+    #[test]
+    fn krpc_nodes_and_errors() {
+        let response = KrpcMessage::response(
+            vec![0, 1],
+            Response {
+                id: [1; 20],
+                nodes: vec![
+                    NodeInfo {
+                        id: [2; 20],
+                        addr: "10.0.0.1:6881".parse().unwrap(),
+                    },
+                    NodeInfo {
+                        id: [3; 20],
+                        addr: "[2001:db8::1]:6881".parse().unwrap(),
+                    },
+                ],
+                ..Response::default()
+            },
+        );
+        assert_eq!(KrpcMessage::decode(&response.encode()), Ok(response));
+
+        assert_eq!(
+            KrpcMessage::decode(b"d1:rd2:id20:mnopqrstuvwxyz1234565:nodes3:abce1:t2:aa1:y1:re"),
+            Err(DhtError::BadMessage("nodes"))
+        );
+        assert_eq!(
+            KrpcMessage::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe"),
+            Err(DhtError::BadMessage("id"))
+        );
+        assert_eq!(
+            KrpcMessage::decode(b"d1:t2:aa1:y1:xe"),
+            Err(DhtError::BadMessage("y"))
+        );
+        assert_eq!(KrpcMessage::decode(b"i5e"), Err(DhtError::BadMessage("")));
+        assert_eq!(
+            KrpcMessage::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe")
+                .unwrap()
+                .body,
+            KrpcBody::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::Unknown(b"vote".to_vec())
+            }
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! Finding peers without trackers over the Mainline DHT from
+//! [BEP0005](https://www.bittorrent.org/beps/bep_0005.html)
+//!
+//! See [Dht] for running a node, which keeps known nodes in a [RoutingTable]
+//! and talks to others with [KrpcMessage]s. Node ids are hardened following
+//! [BEP0042](https://www.bittorrent.org/beps/bep_0042.html) (see
+//! [secure_id]) and info-hashes can be sampled from other nodes with a
+//! [Crawler] following [BEP0051](https://www.bittorrent.org/beps/bep_0051.html)
+
+mod krpc;
+mod node;
+mod routing;
+mod security;
+
+pub use krpc::*;
+pub use node::*;
+pub use routing::*;
+pub use security::*;
+
+use crate::sha1::sha1;
+use crate::utils::randish_128;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counter mixed into [random_id] so ids made at the same time still differ
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Makes a new random 160-bit id, such as a node id or a lookup target
+///
+/// # Usage notice
+///
+/// Like [randish_128], this is not cryptographically secure
+pub(crate) fn random_id() -> [u8; 20] {
+    let mut data = randish_128().to_be_bytes().to_vec();
+    data.extend_from_slice(&ID_COUNTER.fetch_add(1, Ordering::SeqCst).to_be_bytes());
+
+    sha1(&data)
+}
@@ -0,0 +1,1106 @@
+//! A running DHT node, answering queries from others on a background thread
+//! and looking up nodes and peers for the application
+
+use super::krpc::{
+    ip_octets, KrpcBody, KrpcMessage, NodeInfo, Query, Response, ERROR_METHOD_UNKNOWN,
+    ERROR_PROTOCOL,
+};
+use super::random_id;
+use super::routing::{distance, RoutingTable, K};
+use super::security::{is_secure_id, secure_id};
+use crate::error::DhtError;
+use crate::sha1::sha1;
+use crate::tracker::shuffle;
+use crate::utils::random_secret;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a queried node is given to respond by default
+pub const DHT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Amount of nodes queried at once during a lookup
+const ALPHA: usize = 3;
+
+/// How often the secret tokens are made from changes, tokens from before the
+/// last change also being accepted
+const TOKEN_ROTATE: Duration = Duration::from_secs(5 * 60);
+
+/// How long an announced peer is kept without announcing again
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Most peers given in one `get_peers` response, keeping it inside of a
+/// single UDP packet
+const MAX_VALUES: usize = 50;
+
+/// Most peers kept for a single info-hash
+const MAX_PEERS_PER_HASH: usize = 1000;
+
+/// Most info-hashes peers are kept for
+const MAX_HASHES: usize = 10_000;
+
+/// Most info-hashes given in one `sample_infohashes` response
+const MAX_SAMPLES: usize = 20;
+
+/// Interval given in `sample_infohashes` responses. Samples are picked again
+/// for every query, so crawlers are only asked to wait a short while
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most external address votes kept, newer votes pushing out the oldest
+const MAX_VOTES: usize = 64;
+
+/// Amount of nodes which have to agree on our external address before it's
+/// believed
+const MIN_VOTES: usize = 3;
+
+/// How often the background thread checks if the node was dropped
+const READER_POLL: Duration = Duration::from_millis(500);
+
+/// Resolves the `(host, port)` pairs of [Torrent::nodes](crate::Torrent::nodes)
+/// into addresses to give to [Dht::bootstrap], skipping any which can't be
+/// resolved
+pub fn resolve_nodes(nodes: &[(String, u16)]) -> Vec<SocketAddr> {
+    nodes
+        .iter()
+        .filter_map(|(host, port)| (host.as_str(), *port).to_socket_addrs().ok())
+        .flatten()
+        .collect()
+}
+
+/// Makes the token given to the node at `addr` with `secret`, being a
+/// truncated SHA-1 hash of it's IP address and the secret
+fn make_token(secret: u128, addr: &SocketAddr) -> Vec<u8> {
+    let mut data = ip_octets(addr.ip());
+    data.extend_from_slice(&secret.to_be_bytes());
+
+    sha1(&data)[..8].to_vec()
+}
+
+/// Mutable state shared between the node's background thread and lookups
+#[derive(Debug)]
+struct DhtState {
+    /// Known nodes
+    table: RoutingTable,
+
+    /// Announced peers by info-hash and then IP address, with their port and
+    /// when they last announced. Only one port is kept per IP address so a
+    /// single host can't fill an info-hash's peers
+    peers: HashMap<[u8; 20], HashMap<IpAddr, (u16, Instant)>>,
+
+    /// Secret tokens are currently made from
+    secret: u128,
+
+    /// Secret tokens where made from before the last change
+    previous_secret: u128,
+
+    /// When the secret last changed
+    rotated_at: Instant,
+
+    /// Our external address as told by the nodes which responded to us, the
+    /// oldest vote first
+    votes: VecDeque<(SocketAddr, IpAddr)>,
+}
+
+impl DhtState {
+    /// Changes the secret if it's due at `now`
+    fn rotate_secret(&mut self, now: Instant) {
+        if now.duration_since(self.rotated_at) >= TOKEN_ROTATE {
+            self.previous_secret = self.secret;
+            self.secret = random_secret();
+            self.rotated_at = now;
+        }
+    }
+
+    /// Counts the external address `ip` the node at `voter` says we have,
+    /// moving to a [secure](is_secure_id) id for it once enough nodes agree
+    fn vote(&mut self, voter: SocketAddr, ip: IpAddr) {
+        self.votes.retain(|(other, _)| *other != voter);
+        self.votes.push_back((voter, ip));
+
+        if self.votes.len() > MAX_VOTES {
+            self.votes.pop_front();
+        }
+
+        if let Some(ip) = self.external_ip() {
+            self.secure_for(ip);
+        }
+    }
+
+    /// Gets the external address most nodes agree on, if at least
+    /// [MIN_VOTES] do
+    fn external_ip(&self) -> Option<IpAddr> {
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+
+        for (_, ip) in self.votes.iter() {
+            *counts.entry(*ip).or_default() += 1;
+        }
+
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count >= MIN_VOTES)
+            .max_by_key(|(_, count)| *count)
+            .map(|(ip, _)| ip)
+    }
+
+    /// Changes our id to a new one if it isn't secure for the external
+    /// address `ip`, keeping the nodes of the routing table
+    fn secure_for(&mut self, ip: IpAddr) {
+        if !is_secure_id(&self.table.id(), ip) {
+            self.table = self.table.with_id(secure_id(ip));
+        }
+    }
+
+    /// Picks up to [MAX_SAMPLES] random info-hashes we keep peers for
+    fn sample_infohashes(&self) -> Vec<[u8; 20]> {
+        let mut samples: Vec<[u8; 20]> = self.peers.keys().copied().collect();
+
+        shuffle(&mut samples);
+        samples.truncate(MAX_SAMPLES);
+        samples
+    }
+
+    /// If `token` was given to the node at `addr` recently enough
+    fn valid_token(&self, token: &[u8], addr: &SocketAddr) -> bool {
+        token == make_token(self.secret, addr).as_slice()
+            || token == make_token(self.previous_secret, addr).as_slice()
+    }
+
+    /// Gets up to [MAX_VALUES] peers for `info_hash` which haven't expired at
+    /// `now`
+    fn peers_for(&mut self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddr> {
+        let peers = match self.peers.get_mut(info_hash) {
+            Some(peers) => peers,
+            None => return vec![],
+        };
+
+        peers.retain(|_, (_, announced)| now.duration_since(*announced) < PEER_TTL);
+
+        let found = peers
+            .iter()
+            .take(MAX_VALUES)
+            .map(|(ip, (port, _))| SocketAddr::new(*ip, *port))
+            .collect();
+
+        if peers.is_empty() {
+            self.peers.remove(info_hash);
+        }
+
+        found
+    }
+
+    /// Stores `peer` as announced for `info_hash` at `now`, unless too many
+    /// peers or info-hashes are already kept. A peer announcing again from the
+    /// same IP address replaces it's old port
+    fn store_peer(&mut self, info_hash: [u8; 20], peer: SocketAddr, now: Instant) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_HASHES {
+            return;
+        }
+
+        let peers = self.peers.entry(info_hash).or_default();
+
+        if peers.len() < MAX_PEERS_PER_HASH || peers.contains_key(&peer.ip()) {
+            peers.insert(peer.ip(), (peer.port(), now));
+        }
+    }
+}
+
+/// Everything shared between a [Dht] and it's background thread
+#[derive(Debug)]
+struct Shared {
+    /// Socket the node sends and recieves on
+    socket: UdpSocket,
+
+    /// Routing table, peers and tokens
+    state: Mutex<DhtState>,
+
+    /// Queries waiting on a response by transaction id, with the address the
+    /// response should come from
+    pending: Mutex<HashMap<u16, (SocketAddr, Sender<KrpcMessage>)>>,
+}
+
+impl Shared {
+    /// Sends `message` to `addr`
+    fn send(&self, message: &KrpcMessage, addr: SocketAddr) -> Result<(), DhtError> {
+        self.socket
+            .send_to(&message.encode(), addr)
+            .map(|_| ())
+            .map_err(|err| DhtError::SocketIo(err.kind()))
+    }
+
+    /// Handles a `packet` recieved `from` another node, answering queries and
+    /// handing responses to the lookup waiting on them
+    fn handle_packet(&self, packet: &[u8], from: SocketAddr) {
+        let message = match KrpcMessage::decode(packet) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let now = Instant::now();
+
+        match message.body {
+            KrpcBody::Query { id, query } => {
+                let reply = self
+                    .answer(message.transaction, id, query, from, now)
+                    .ip(from);
+                self.send(&reply, from).ok();
+            }
+            KrpcBody::Response(_) | KrpcBody::Error { .. } => {
+                let transaction = match <[u8; 2]>::try_from(message.transaction.as_slice()) {
+                    Ok(transaction) => u16::from_be_bytes(transaction),
+                    Err(_) => return,
+                };
+                let pending = self.pending.lock().unwrap();
+
+                if let Some((addr, sender)) = pending.get(&transaction) {
+                    if *addr == from {
+                        if let KrpcBody::Response(response) = &message.body {
+                            let mut state = self.state.lock().unwrap();
+                            let node = NodeInfo {
+                                id: response.id,
+                                addr: from,
+                            };
+
+                            state.table.insert(node, now);
+
+                            if let Some(ip) = message.ip {
+                                state.vote(from, ip.ip());
+                            }
+                        }
+
+                        sender.send(message).ok();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes the reply to a `query` from the node with given `id` at `from`
+    fn answer(
+        &self,
+        transaction: Vec<u8>,
+        id: [u8; 20],
+        query: Query,
+        from: SocketAddr,
+        now: Instant,
+    ) -> KrpcMessage {
+        let mut state = self.state.lock().unwrap();
+        let mut response = Response {
+            id: state.table.id(),
+            ..Response::default()
+        };
+
+        state.rotate_secret(now);
+        state.table.insert(NodeInfo { id, addr: from }, now);
+
+        match query {
+            Query::Ping => (),
+            Query::FindNode { target } => response.nodes = state.table.closest(&target, K),
+            Query::GetPeers { info_hash } => {
+                response.values = state.peers_for(&info_hash, now);
+                response.token = Some(make_token(state.secret, &from));
+
+                if response.values.is_empty() {
+                    response.nodes = state.table.closest(&info_hash, K);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !state.valid_token(&token, &from) {
+                    return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Bad token");
+                }
+
+                let port = if implied_port { from.port() } else { port };
+                state.store_peer(info_hash, SocketAddr::new(from.ip(), port), now);
+            }
+            Query::SampleInfohashes { target } => {
+                response.nodes = state.table.closest(&target, K);
+                response.samples = state.sample_infohashes();
+                response.interval = Some(SAMPLE_INTERVAL.as_secs() as u32);
+                response.num = Some(state.peers.len() as u64);
+            }
+            Query::Unknown(_) => {
+                return KrpcMessage::error(transaction, ERROR_METHOD_UNKNOWN, "Method Unknown")
+            }
+        }
+
+        KrpcMessage::response(transaction, response)
+    }
+}
+
+/// A query which was sent and may be waited on, unregistering itself once
+/// dropped
+struct PendingQuery<'a> {
+    /// State the query is registered in
+    shared: &'a Shared,
+
+    /// Node the query was sent to
+    addr: SocketAddr,
+
+    /// Transaction id of the query
+    transaction: u16,
+
+    /// Recieves the response to the query
+    reciever: Receiver<KrpcMessage>,
+}
+
+impl PendingQuery<'_> {
+    /// Waits up to `timeout` for the response, giving [DhtError::Timeout] if
+    /// there is none or [DhtError::KrpcError] if the node answered with one
+    fn wait(&self, timeout: Duration) -> Result<Response, DhtError> {
+        match self.reciever.recv_timeout(timeout) {
+            Ok(KrpcMessage {
+                body: KrpcBody::Response(response),
+                ..
+            }) => Ok(response),
+            Ok(KrpcMessage {
+                body: KrpcBody::Error { code, message },
+                ..
+            }) => Err(DhtError::KrpcError((code, message))),
+            _ => Err(DhtError::Timeout),
+        }
+    }
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        self.shared
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.transaction);
+    }
+}
+
+/// A query which may have been sent, with the id of the queried node if it
+/// came from the routing table
+type SentQuery<'a> = (Option<[u8; 20]>, Result<PendingQuery<'a>, DhtError>);
+
+/// The results of a lookup
+#[derive(Debug, Default)]
+struct Lookup {
+    /// Closest nodes which responded, closest first, with the tokens they gave
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+
+    /// Peers given by the nodes queried
+    peers: Vec<SocketAddr>,
+}
+
+/// A Mainline DHT node from
+/// [BEP0005](https://www.bittorrent.org/beps/bep_0005.html), finding peers for
+/// torrents without trackers
+///
+/// Queries from other nodes are answered on a background thread for as long
+/// as this is kept around, stopping once dropped. Lookups block the calling
+/// thread and may be made from several threads at once, as the node can be
+/// shared behind an [Arc]
+///
+/// A new node knows nobody, so has to be bootstrapped with [Dht::bootstrap]
+/// from the [Torrent::nodes](crate::Torrent::nodes) of a trackerless torrent
+/// (see [resolve_nodes]) or any other known nodes. Saving the routing table
+/// with [Dht::save] and starting from it with [Dht::with_table] next time
+/// avoids needing to bootstrap again
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// BitTorrent uses a "distributed sloppy hash table" (DHT) for storing peer
+/// contact information for "trackerless" torrents. In effect, each peer
+/// becomes a tracker. The protocol is based on Kademila and is implemented
+/// over UDP.
+/// ```
+///
+/// # Examples
+///
+/// ```no_run
+/// use torro::dht::Dht;
+/// use std::net::ToSocketAddrs;
+///
+/// fn main() {
+///     let dht = Dht::bind("0.0.0.0:6881").unwrap();
+///     let router: Vec<_> = "router.bittorrent.com:6881".to_socket_addrs().unwrap().collect();
+///
+///     dht.bootstrap(&router).unwrap();
+///
+///     let peers = dht.announce([0; 20], Some(6881)).unwrap();
+///     println!("Found {} peers", peers.len());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Dht {
+    /// State shared with the background thread, which stops once it's gone
+    shared: Arc<Shared>,
+
+    /// Address the node is bound to
+    local_addr: SocketAddr,
+
+    /// How long queried nodes are given to respond
+    timeout: Duration,
+}
+
+impl Dht {
+    /// Starts a node with a new random id on a socket bound to `bind_addr`,
+    /// which is [secure](is_secure_id) for the address bound to if it's a
+    /// public one
+    pub fn bind(bind_addr: &str) -> Result<Self, DhtError> {
+        let dht = Self::with_table(bind_addr, RoutingTable::new(random_id()))?;
+
+        if !dht.local_addr.ip().is_unspecified() {
+            dht.shared
+                .state
+                .lock()
+                .unwrap()
+                .secure_for(dht.local_addr.ip());
+        }
+
+        Ok(dht)
+    }
+
+    /// Starts a node from an existing routing `table`, such as one saved with
+    /// [Dht::save], keeping it's id until nodes tell us of an external address
+    /// it isn't secure for
+    pub fn with_table(bind_addr: &str, table: RoutingTable) -> Result<Self, DhtError> {
+        let socket =
+            UdpSocket::bind(bind_addr).map_err(|_| DhtError::SocketBind(bind_addr.to_string()))?;
+        let io_err = |err: std::io::Error| DhtError::SocketIo(err.kind());
+        let local_addr = socket.local_addr().map_err(io_err)?;
+        let reader = socket.try_clone().map_err(io_err)?;
+        reader.set_read_timeout(Some(READER_POLL)).map_err(io_err)?;
+
+        let secret = random_secret();
+        let shared = Arc::new(Shared {
+            socket,
+            state: Mutex::new(DhtState {
+                table,
+                peers: HashMap::new(),
+                secret,
+                previous_secret: secret,
+                rotated_at: Instant::now(),
+                votes: VecDeque::new(),
+            }),
+            pending: Mutex::new(HashMap::new()),
+        });
+
+        spawn_reader(reader, Arc::downgrade(&shared));
+
+        Ok(Self {
+            shared,
+            local_addr,
+            timeout: DHT_TIMEOUT,
+        })
+    }
+
+    /// Sets how long queried nodes are given to respond, [DHT_TIMEOUT] by
+    /// default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets if nodes without a [secure](is_secure_id) id are kept out of the
+    /// routing table, see [RoutingTable::enforce_secure]
+    pub fn enforce_secure(self, enforce: bool) -> Self {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .set_enforce_secure(enforce);
+        self
+    }
+
+    /// Our own node id, which changes to a [secure](is_secure_id) one once
+    /// enough nodes agree on an external address the current id isn't secure
+    /// for
+    pub fn id(&self) -> [u8; 20] {
+        self.shared.state.lock().unwrap().table.id()
+    }
+
+    /// Gets our external address as told by the nodes which responded to us,
+    /// once enough of them agree
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.shared.state.lock().unwrap().external_ip()
+    }
+
+    /// Gets the address the node is bound to, useful when bound to port `0`
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Gets a copy of the routing table as it is now
+    pub fn routing_table(&self) -> RoutingTable {
+        self.shared.state.lock().unwrap().table.clone()
+    }
+
+    /// Saves the routing table to the file at `path`, to be loaded with
+    /// [RoutingTable::load] and given to [Dht::with_table] next time
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), DhtError> {
+        self.routing_table().save(path)
+    }
+
+    /// Sends `query` to `addr`, giving the query to wait on
+    fn send_query(&self, addr: SocketAddr, query: Query) -> Result<PendingQuery<'_>, DhtError> {
+        let (sender, reciever) = mpsc::channel();
+        let mut pending = self.shared.pending.lock().unwrap();
+
+        // random so responses can't be forged without seeing the query
+        let transaction = loop {
+            let transaction = random_secret() as u16;
+
+            if !pending.contains_key(&transaction) {
+                break transaction;
+            }
+        };
+
+        pending.insert(transaction, (addr, sender));
+        drop(pending);
+
+        let pending = PendingQuery {
+            shared: &self.shared,
+            addr,
+            transaction,
+            reciever,
+        };
+        let message = KrpcMessage::query(transaction.to_be_bytes().to_vec(), self.id(), query);
+        self.shared.send(&message, addr)?;
+
+        Ok(pending)
+    }
+
+    /// Waits on every query in `pending`, giving each result in the same
+    /// order. Nodes in the routing table which time out are marked as failed
+    fn wait_all(
+        &self,
+        pending: Vec<SentQuery<'_>>,
+    ) -> Vec<Result<(SocketAddr, Response), DhtError>> {
+        let deadline = Instant::now() + self.timeout;
+
+        pending
+            .into_iter()
+            .map(|(id, pending)| {
+                let pending = pending?;
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let result = pending.wait(timeout);
+
+                if let (Err(DhtError::Timeout), Some(id)) = (&result, id) {
+                    self.shared.state.lock().unwrap().table.failed(&id);
+                }
+
+                result.map(|response| (pending.addr, response))
+            })
+            .collect()
+    }
+
+    /// Pings the node at `addr`, giving it's id. Nodes which answer are added
+    /// to the routing table
+    pub fn ping(&self, addr: SocketAddr) -> Result<[u8; 20], DhtError> {
+        let results = self.wait_all(vec![(None, self.send_query(addr, Query::Ping))]);
+
+        results
+            .into_iter()
+            .next()
+            .unwrap()
+            .map(|(_, response)| response.id)
+    }
+
+    /// Fills the routing table starting from the nodes at `addrs`, then looks
+    /// up our own id so nodes close to us know about us. Gives the amount of
+    /// nodes in the routing table afterwards, or [DhtError::NoNodes] if nobody
+    /// answered
+    pub fn bootstrap(&self, addrs: &[SocketAddr]) -> Result<usize, DhtError> {
+        let target = self.id();
+        let pending = addrs
+            .iter()
+            .map(|addr| (None, self.send_query(*addr, Query::FindNode { target })))
+            .collect();
+        let mut found = vec![];
+
+        for (_, response) in self.wait_all(pending).into_iter().flatten() {
+            found.extend(response.nodes);
+        }
+
+        self.lookup(target, false, found)?;
+
+        match self.routing_table().len() {
+            0 => Err(DhtError::NoNodes),
+            len => Ok(len),
+        }
+    }
+
+    /// Looks up the [K] nodes closest to `target` which answered us
+    pub fn find_node(&self, target: [u8; 20]) -> Result<Vec<NodeInfo>, DhtError> {
+        let lookup = self.lookup(target, false, vec![])?;
+
+        Ok(lookup.closest.into_iter().map(|(node, _)| node).collect())
+    }
+
+    /// Looks up peers of the torrent with given `info_hash`
+    pub fn get_peers(&self, info_hash: [u8; 20]) -> Result<Vec<SocketAddr>, DhtError> {
+        Ok(self.lookup(info_hash, true, vec![])?.peers)
+    }
+
+    /// Looks up peers of the torrent with given `info_hash` and announces us
+    /// as a peer to the closest nodes, listening on `port` or on the port the
+    /// nodes see our queries come from if [None]. Gives the peers found on the
+    /// way
+    pub fn announce(
+        &self,
+        info_hash: [u8; 20],
+        port: Option<u16>,
+    ) -> Result<Vec<SocketAddr>, DhtError> {
+        let lookup = self.lookup(info_hash, true, vec![])?;
+        let pending = lookup
+            .closest
+            .into_iter()
+            .filter_map(|(node, token)| Some((node, token?)))
+            .map(|(node, token)| {
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port: port.unwrap_or(0),
+                    implied_port: port.is_none(),
+                    token,
+                };
+
+                (Some(node.id), self.send_query(node.addr, query))
+            })
+            .collect();
+
+        self.wait_all(pending);
+        Ok(lookup.peers)
+    }
+
+    /// Asks the node at `addr` for a sample of the info-hashes it keeps peers
+    /// for along with the nodes closest to `target`, see [Crawler] for
+    /// sampling the whole DHT
+    pub fn sample_infohashes(
+        &self,
+        addr: SocketAddr,
+        target: [u8; 20],
+    ) -> Result<Response, DhtError> {
+        let query = Query::SampleInfohashes { target };
+        let results = self.wait_all(vec![(None, self.send_query(addr, query))]);
+
+        results
+            .into_iter()
+            .next()
+            .unwrap()
+            .map(|(_, response)| response)
+    }
+
+    /// Refreshes every bucket of the routing table which hasn't changed in a
+    /// while by looking up a random id inside of it, which should be called
+    /// every few minutes to keep the table healthy
+    pub fn refresh(&self) {
+        let targets = self
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .refresh_targets(Instant::now());
+
+        for target in targets {
+            self.lookup(target, false, vec![]).ok();
+        }
+    }
+
+    /// Iteratively looks up `target`, querying the closest nodes known (and
+    /// any given in `extra`) with `get_peers` if `peers` or `find_node`
+    /// otherwise, then the closer nodes they give, until the [K] closest nodes
+    /// found have all been queried
+    fn lookup(
+        &self,
+        target: [u8; 20],
+        peers: bool,
+        extra: Vec<NodeInfo>,
+    ) -> Result<Lookup, DhtError> {
+        let mut candidates = self.shared.state.lock().unwrap().table.closest(&target, K);
+        candidates.extend(extra);
+
+        if candidates.is_empty() {
+            return Err(DhtError::NoNodes);
+        }
+
+        let query = if peers {
+            Query::GetPeers { info_hash: target }
+        } else {
+            Query::FindNode { target }
+        };
+        let own_id = self.id();
+        let mut queried = HashSet::new();
+        let mut lookup = Lookup::default();
+
+        loop {
+            candidates.sort_by_key(|node| distance(&node.id, &target));
+            candidates.dedup_by_key(|node| node.addr);
+
+            let batch: Vec<NodeInfo> = candidates
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+
+            if batch.is_empty() {
+                break;
+            }
+
+            let pending = batch
+                .iter()
+                .map(|node| {
+                    queried.insert(node.addr);
+                    (Some(node.id), self.send_query(node.addr, query.clone()))
+                })
+                .collect();
+
+            for (node, result) in batch.iter().zip(self.wait_all(pending)) {
+                let response = match result {
+                    Ok((_, response)) => response,
+                    Err(_) => {
+                        candidates.retain(|other| other.addr != node.addr);
+                        continue;
+                    }
+                };
+
+                for peer in response.values {
+                    if !lookup.peers.contains(&peer) {
+                        lookup.peers.push(peer);
+                    }
+                }
+
+                candidates.extend(
+                    response
+                        .nodes
+                        .into_iter()
+                        .filter(|found| found.id != own_id && !queried.contains(&found.addr)),
+                );
+                lookup.closest.push((
+                    NodeInfo {
+                        id: response.id,
+                        addr: node.addr,
+                    },
+                    response.token,
+                ));
+            }
+        }
+
+        lookup
+            .closest
+            .sort_by_key(|(node, _)| distance(&node.id, &target));
+        lookup.closest.truncate(K);
+
+        Ok(lookup)
+    }
+}
+
+/// Walks the DHT collecting info-hashes with the `sample_infohashes` query from
+/// [BEP0051](https://www.bittorrent.org/beps/bep_0051.html), for indexing
+/// what's being shared
+///
+/// Crawling starts from the routing table of a bootstrapped [Dht] and moves on
+/// to the nodes each sampled node gives back. Nodes which don't support the
+/// query are asked with `find_node` instead so the walk can carry on past them,
+/// and nodes holding more info-hashes than they gave are sampled again once
+/// the interval they asked for is up
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// DHT indexing is already common practice and incurs high traffic on the DHT
+/// [...] This extension aims to provide an efficient alternative to the
+/// get_peers-based passive indexing.
+/// ```
+///
+/// # Examples
+///
+/// ```no_run
+/// use torro::dht::{Crawler, Dht};
+/// use std::net::ToSocketAddrs;
+///
+/// fn main() {
+///     let dht = Dht::bind("0.0.0.0:6881").unwrap();
+///     let router: Vec<_> = "router.bittorrent.com:6881".to_socket_addrs().unwrap().collect();
+///     dht.bootstrap(&router).unwrap();
+///
+///     let mut crawler = Crawler::new(&dht);
+///     crawler.crawl(500).unwrap();
+///
+///     println!("Found {} info-hashes", crawler.info_hashes().len());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Crawler<'a> {
+    /// Node queries are sent from
+    dht: &'a Dht,
+
+    /// Nodes waiting to be sampled
+    queue: VecDeque<NodeInfo>,
+
+    /// Addresses of every node ever queued
+    known: HashSet<SocketAddr>,
+
+    /// Nodes to sample again, with when they may be
+    revisit: Vec<(Instant, NodeInfo)>,
+
+    /// Every info-hash found
+    info_hashes: HashSet<[u8; 20]>,
+}
+
+impl<'a> Crawler<'a> {
+    /// Creates a crawler sending queries from `dht`
+    pub fn new(dht: &'a Dht) -> Self {
+        Self {
+            dht,
+            queue: VecDeque::new(),
+            known: HashSet::new(),
+            revisit: vec![],
+            info_hashes: HashSet::new(),
+        }
+    }
+
+    /// Gets every info-hash found so far
+    pub fn info_hashes(&self) -> &HashSet<[u8; 20]> {
+        &self.info_hashes
+    }
+
+    /// Gets the amount of nodes found so far
+    pub fn nodes_found(&self) -> usize {
+        self.known.len()
+    }
+
+    /// Sends up to `max_queries` more queries, giving the amount of new
+    /// info-hashes found or [DhtError::NoNodes] if the [Dht] knows no nodes to
+    /// start from. This may be called again to carry on crawling, which gives
+    /// `0` once every node found has been sampled and none are due again
+    pub fn crawl(&mut self, max_queries: usize) -> Result<usize, DhtError> {
+        if self.known.is_empty() {
+            for node in self.dht.routing_table().nodes() {
+                self.enqueue(node);
+            }
+
+            if self.known.is_empty() {
+                return Err(DhtError::NoNodes);
+            }
+        }
+
+        let now = Instant::now();
+        let (due, waiting) = self.revisit.drain(..).partition(|(at, _)| *at <= now);
+        self.revisit = waiting;
+        self.queue
+            .extend(due.into_iter().map(|(_, node): (Instant, NodeInfo)| node));
+
+        let found = self.info_hashes.len();
+        let mut queries = 0;
+
+        while queries < max_queries && !self.queue.is_empty() {
+            let count = ALPHA.min(max_queries - queries).min(self.queue.len());
+            let batch: Vec<NodeInfo> = self.queue.drain(..count).collect();
+            let pending = batch
+                .iter()
+                .map(|node| {
+                    let query = Query::SampleInfohashes {
+                        target: random_id(),
+                    };
+
+                    (Some(node.id), self.dht.send_query(node.addr, query))
+                })
+                .collect();
+            let mut unsupported = vec![];
+
+            queries += batch.len();
+
+            for (node, result) in batch.into_iter().zip(self.dht.wait_all(pending)) {
+                match result {
+                    Ok((_, response)) => self.sampled(node, response),
+                    Err(DhtError::KrpcError(_)) => unsupported.push(node),
+                    Err(_) => (),
+                }
+            }
+
+            let count = unsupported.len().min(max_queries - queries);
+            let pending = unsupported
+                .iter()
+                .take(count)
+                .map(|node| {
+                    let query = Query::FindNode {
+                        target: random_id(),
+                    };
+
+                    (Some(node.id), self.dht.send_query(node.addr, query))
+                })
+                .collect();
+
+            queries += count;
+
+            for (_, response) in self.dht.wait_all(pending).into_iter().flatten() {
+                for found in response.nodes {
+                    self.enqueue(found);
+                }
+            }
+        }
+
+        Ok(self.info_hashes.len() - found)
+    }
+
+    /// Queues `node` to be sampled if it hasn't been already
+    fn enqueue(&mut self, node: NodeInfo) {
+        if node.addr != self.dht.local_addr && self.known.insert(node.addr) {
+            self.queue.push_back(node);
+        }
+    }
+
+    /// Takes in the `response` to sampling `node`
+    fn sampled(&mut self, node: NodeInfo, response: Response) {
+        let given = response.samples.len() as u64;
+
+        self.info_hashes.extend(response.samples);
+
+        for found in response.nodes {
+            self.enqueue(found);
+        }
+
+        if let (Some(interval), Some(num)) = (response.interval, response.num) {
+            if num > given {
+                let at = Instant::now() + Duration::from_secs(interval as u64);
+                self.revisit.push((at, node));
+            }
+        }
+    }
+}
+
+/// Spawns the background thread answering queries on `socket`, which stops
+/// once `shared` has been dropped
+fn spawn_reader(socket: UdpSocket, shared: Weak<Shared>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+
+        loop {
+            let recieved = socket.recv_from(&mut buf);
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => break,
+            };
+
+            if let Ok((len, from)) = recieved {
+                shared.handle_packet(&buf[..len], from);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that tokens are tied to the IP address and are accepted for one
+    /// rotation after they change
+    #[test]
+    fn dht_tokens() {
+        let start = Instant::now();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut state = DhtState {
+            table: RoutingTable::new([0; 20]),
+            peers: HashMap::new(),
+            secret: 1,
+            previous_secret: 1,
+            rotated_at: start,
+            votes: VecDeque::new(),
+        };
+        let token = make_token(state.secret, &addr);
+
+        assert_eq!(token.len(), 8);
+        assert!(state.valid_token(&token, &"10.0.0.1:1".parse().unwrap()));
+        assert!(!state.valid_token(&token, &"10.0.0.2:6881".parse().unwrap()));
+
+        state.rotate_secret(start + TOKEN_ROTATE);
+        assert!(state.valid_token(&token, &addr));
+
+        state.rotate_secret(start + TOKEN_ROTATE * 2);
+        assert!(!state.valid_token(&token, &addr));
+        assert_ne!(state.secret, state.previous_secret);
+    }
+
+    /// Tests that our id only moves to a secure one once enough nodes agree on
+    /// our external address, keeping the routing table
+    #[test]
+    fn dht_external_ip_votes() {
+        let start = Instant::now();
+        let mut state = DhtState {
+            table: RoutingTable::new([0; 20]),
+            peers: HashMap::new(),
+            secret: 1,
+            previous_secret: 1,
+            rotated_at: start,
+            votes: VecDeque::new(),
+        };
+        let external: IpAddr = "203.0.113.7".parse().unwrap();
+        let voter = |ind: u8| SocketAddr::from(([10, 0, 0, ind], 6881));
+
+        state.table.insert(
+            NodeInfo {
+                id: [0xff; 20],
+                addr: voter(9),
+            },
+            start,
+        );
+
+        state.vote(voter(1), external);
+        state.vote(voter(1), external);
+        state.vote(voter(2), external);
+        state.vote(voter(3), "198.51.100.1".parse().unwrap());
+        assert_eq!(state.external_ip(), None);
+        assert_eq!(state.table.id(), [0; 20]);
+
+        state.vote(voter(4), external);
+        assert_eq!(state.external_ip(), Some(external));
+        assert!(is_secure_id(&state.table.id(), external));
+        assert!(state.table.contains(&[0xff; 20]));
+
+        let id = state.table.id();
+        state.vote(voter(5), external);
+        assert_eq!(state.table.id(), id);
+    }
+
+    /// Tests that announced peers expire, are capped per info-hash and are
+    /// only kept once per IP address
+    #[test]
+    fn dht_peer_store() {
+        let start = Instant::now();
+        let mut state = DhtState {
+            table: RoutingTable::new([0; 20]),
+            peers: HashMap::new(),
+            secret: 1,
+            previous_secret: 1,
+            rotated_at: start,
+            votes: VecDeque::new(),
+        };
+
+        for port in 0..10 {
+            state.store_peer([2; 20], SocketAddr::from(([10, 0, 0, 1], port)), start);
+        }
+
+        assert_eq!(
+            state.peers_for(&[2; 20], start),
+            vec![SocketAddr::from(([10, 0, 0, 1], 9))]
+        );
+
+        for host in 0..(MAX_PEERS_PER_HASH + 10) as u16 {
+            let [high, low] = host.to_be_bytes();
+            state.store_peer([1; 20], SocketAddr::from(([10, 0, high, low], 6881)), start);
+        }
+
+        assert_eq!(state.peers[&[1; 20]].len(), MAX_PEERS_PER_HASH);
+        assert_eq!(state.peers_for(&[1; 20], start).len(), MAX_VALUES);
+        assert!(state.peers_for(&[1; 20], start + PEER_TTL).is_empty());
+        assert!(state.peers_for(&[2; 20], start + PEER_TTL).is_empty());
+        assert!(state.peers.is_empty());
+    }
+}
@@ -0,0 +1,560 @@
+//! The Kademlia routing table of k-buckets, keeping the nodes a DHT node knows
+//! about
+
+use super::krpc::{encode_compact_nodes, parse_compact_nodes, NodeInfo};
+use super::random_id;
+use super::security::is_secure_id;
+use crate::bencode::{self, Bencode};
+use crate::error::DhtError;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Amount of nodes kept in each bucket, also the amount of closest nodes
+/// given to `find_node` and `get_peers` queries
+pub const K: usize = 8;
+
+/// How long a bucket may go without changing before it should be refreshed
+/// with a lookup, see [RoutingTable::refresh_targets]
+pub const BUCKET_REFRESH: Duration = Duration::from_secs(15 * 60);
+
+/// Amount of queries in a row a node may fail to answer before it's counted as
+/// bad and may be replaced
+const MAX_FAILURES: u8 = 3;
+
+/// Amount of buckets, one for every bit of distance a node may be from us
+const NUM_BUCKETS: usize = 160;
+
+/// Gets the XOR distance between two ids
+pub fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0; 20];
+
+    for ind in 0..20 {
+        out[ind] = a[ind] ^ b[ind];
+    }
+
+    out
+}
+
+/// A node in a bucket
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Id and address of the node
+    info: NodeInfo,
+
+    /// Amount of queries in a row the node failed to answer
+    failures: u8,
+
+    /// If the node's id is [secure](is_secure_id) for it's address
+    secure: bool,
+}
+
+/// A single k-bucket of nodes sharing the same amount of leading bits with our
+/// id
+#[derive(Debug, Clone)]
+struct Bucket {
+    /// Nodes in the bucket, the least recently seen first
+    entries: Vec<Entry>,
+
+    /// When a node was last added to or seen in this bucket
+    last_changed: Instant,
+}
+
+/// The nodes a DHT node knows about, kept in k-buckets of [K] nodes by their
+/// distance from our own id
+///
+/// torro keeps a bucket for every bit of distance rather than splitting
+/// buckets as they fill, which holds the same nodes as a fully split table.
+/// Nodes which fail to answer several queries in a row are counted as bad and
+/// are replaced by new nodes once their bucket is full
+///
+/// Nodes with ids which aren't [secure](is_secure_id) for their address are
+/// also replaced by secure nodes once their bucket is full, or aren't added
+/// at all if [RoutingTable::enforce_secure] is set
+///
+/// # BitTorrent Description
+///
+/// *Taken from [BEP0005](https://www.bittorrent.org/beps/bep_0005.html)*
+///
+/// ```none
+/// Every node maintains a routing table of known good nodes. The nodes in the
+/// routing table are used as starting points for queries in the DHT. [...]
+///
+/// Each bucket can only hold K nodes, currently eight, before becoming "full."
+/// When a bucket is full of known good nodes, no more nodes may be added
+/// unless our own node ID falls within the range of the bucket. [...] When the
+/// bucket is full of good nodes, the new node is simply discarded. If any
+/// nodes in the bucket are known to have become bad, then one is replaced by
+/// the new node.
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Instant;
+/// use torro::dht::{NodeInfo, RoutingTable};
+///
+/// fn main() {
+///     let mut table = RoutingTable::new([0; 20]);
+///     table.insert(NodeInfo { id: [1; 20], addr: "10.0.0.1:6881".parse().unwrap() }, Instant::now());
+///     table.insert(NodeInfo { id: [0xff; 20], addr: "10.0.0.2:6881".parse().unwrap() }, Instant::now());
+///
+///     assert_eq!(table.closest(&[2; 20], 1)[0].id, [1; 20]);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    /// Our own id
+    id: [u8; 20],
+
+    /// Buckets by amount of leading bits shared with our id
+    buckets: Vec<Bucket>,
+
+    /// If nodes without a secure id are refused
+    enforce_secure: bool,
+}
+
+impl RoutingTable {
+    /// Creates an empty table for a node with given `id`
+    pub fn new(id: [u8; 20]) -> Self {
+        let now = Instant::now();
+
+        Self {
+            id,
+            buckets: vec![
+                Bucket {
+                    entries: vec![],
+                    last_changed: now,
+                };
+                NUM_BUCKETS
+            ],
+            enforce_secure: false,
+        }
+    }
+
+    /// Sets if nodes with ids which aren't [secure](is_secure_id) for their
+    /// address are refused rather than only being replaced first, removing any
+    /// already in the table. This is off by default as many nodes still don't
+    /// use secure ids
+    pub fn enforce_secure(mut self, enforce: bool) -> Self {
+        self.set_enforce_secure(enforce);
+        self
+    }
+
+    /// Sets [RoutingTable::enforce_secure] on an existing table
+    pub(crate) fn set_enforce_secure(&mut self, enforce: bool) {
+        self.enforce_secure = enforce;
+
+        if enforce {
+            for bucket in self.buckets.iter_mut() {
+                bucket.entries.retain(|entry| entry.secure);
+            }
+        }
+    }
+
+    /// Makes a table with a new `id` holding the same nodes, for when our own
+    /// id changes
+    pub(crate) fn with_id(&self, id: [u8; 20]) -> Self {
+        let mut table = Self::new(id).enforce_secure(self.enforce_secure);
+        let now = Instant::now();
+
+        for node in self.nodes() {
+            table.insert(node, now);
+        }
+
+        table
+    }
+
+    /// Our own id
+    pub fn id(&self) -> [u8; 20] {
+        self.id
+    }
+
+    /// Amount of nodes in the table
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.entries.len()).sum()
+    }
+
+    /// If the table has no nodes
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the bucket index for `id`, being the amount of leading bits it
+    /// shares with our own id, or [None] for our own id
+    fn bucket_index(&self, id: &[u8; 20]) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let ind = distance.iter().position(|byte| *byte != 0)?;
+
+        Some(ind * 8 + distance[ind].leading_zeros() as usize)
+    }
+
+    /// Adds `node` seen at `now`, or marks it as seen if it's already known.
+    /// Gives if the node is in the table afterwards, which it won't be if it's
+    /// bucket is full of good nodes
+    ///
+    /// Once a bucket is full, bad nodes are replaced first and nodes without a
+    /// secure id after, but only by a node with a secure id
+    ///
+    /// A known id at a new address is only moved if the old address has gone
+    /// bad, so nodes can't be hijacked by others claiming their id
+    pub fn insert(&mut self, node: NodeInfo, now: Instant) -> bool {
+        let ind = match self.bucket_index(&node.id) {
+            Some(ind) => ind,
+            None => return false,
+        };
+        let secure = is_secure_id(&node.id, node.addr.ip());
+
+        if self.enforce_secure && !secure {
+            return false;
+        }
+
+        let bucket = &mut self.buckets[ind];
+
+        if let Some(pos) = bucket
+            .entries
+            .iter()
+            .position(|entry| entry.info.id == node.id)
+        {
+            if bucket.entries[pos].info.addr != node.addr
+                && bucket.entries[pos].failures < MAX_FAILURES
+            {
+                return false;
+            }
+
+            let mut entry = bucket.entries.remove(pos);
+            entry.info = node;
+            entry.failures = 0;
+            entry.secure = secure;
+            bucket.entries.push(entry);
+            bucket.last_changed = now;
+            return true;
+        }
+
+        if bucket.entries.len() >= K {
+            let bad = bucket
+                .entries
+                .iter()
+                .position(|entry| entry.failures >= MAX_FAILURES);
+            let insecure = || bucket.entries.iter().position(|entry| !entry.secure);
+
+            match bad.or_else(|| if secure { insecure() } else { None }) {
+                Some(bad) => {
+                    bucket.entries.remove(bad);
+                }
+                None => return false,
+            }
+        }
+
+        bucket.entries.push(Entry {
+            info: node,
+            failures: 0,
+            secure,
+        });
+        bucket.last_changed = now;
+        true
+    }
+
+    /// Records that the node with given `id` failed to answer a query
+    pub fn failed(&mut self, id: &[u8; 20]) {
+        if let Some(ind) = self.bucket_index(id) {
+            for entry in self.buckets[ind].entries.iter_mut() {
+                if entry.info.id == *id {
+                    entry.failures = entry.failures.saturating_add(1);
+                }
+            }
+        }
+    }
+
+    /// Removes the node with given `id`
+    pub fn remove(&mut self, id: &[u8; 20]) {
+        if let Some(ind) = self.bucket_index(id) {
+            self.buckets[ind]
+                .entries
+                .retain(|entry| entry.info.id != *id);
+        }
+    }
+
+    /// If the node with given `id` is in the table
+    pub fn contains(&self, id: &[u8; 20]) -> bool {
+        self.bucket_index(id).is_some_and(|ind| {
+            self.buckets[ind]
+                .entries
+                .iter()
+                .any(|entry| entry.info.id == *id)
+        })
+    }
+
+    /// Gets up to `count` nodes closest to `target`, closest first, leaving
+    /// out bad nodes
+    pub fn closest(&self, target: &[u8; 20], count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .map(|entry| entry.info)
+            .collect();
+
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Gets every node in the table
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter().map(|entry| entry.info))
+            .collect()
+    }
+
+    /// Gets a random id to look up for every bucket which hasn't changed in
+    /// [BUCKET_REFRESH] at `now`, so each gets refreshed. Buckets further in
+    /// than the closest node we know of are left out, as they can't be filled
+    pub fn refresh_targets(&self, now: Instant) -> Vec<[u8; 20]> {
+        let deepest = match self
+            .buckets
+            .iter()
+            .rposition(|bucket| !bucket.entries.is_empty())
+        {
+            Some(deepest) => deepest,
+            None => return vec![],
+        };
+
+        (0..=deepest)
+            .filter(|ind| now.duration_since(self.buckets[*ind].last_changed) >= BUCKET_REFRESH)
+            .map(|ind| {
+                // keep the first `ind` bits of our id, flip the next one and
+                // randomise the rest
+                let mut target = random_id();
+
+                for bit in 0..=ind {
+                    let mask = 0x80 >> (bit % 8);
+                    let ours = self.id[bit / 8] & mask;
+                    let wanted = if bit == ind { ours ^ mask } else { ours };
+
+                    target[bit / 8] = (target[bit / 8] & !mask) | wanted;
+                }
+
+                target
+            })
+            .collect()
+    }
+
+    /// Encodes our id and every node into a bencoded dictionary, to be saved
+    /// and given back to [RoutingTable::decode] on the next start
+    pub fn encode(&self) -> Vec<u8> {
+        let nodes = self.nodes();
+        let mut dict = BTreeMap::new();
+
+        dict.insert(b"id".to_vec(), Bencode::ByteString(self.id.to_vec()));
+        dict.insert(
+            b"nodes".to_vec(),
+            Bencode::ByteString(encode_compact_nodes(&nodes, false)),
+        );
+        dict.insert(
+            b"nodes6".to_vec(),
+            Bencode::ByteString(encode_compact_nodes(&nodes, true)),
+        );
+
+        bencode::encode(&Bencode::Dict(dict))
+    }
+
+    /// Decodes a table saved with [RoutingTable::encode], giving
+    /// [DhtError::BadTable] if it's malformed. The nodes are counted as seen
+    /// now, but won't be used for long if they are gone
+    pub fn decode(data: &[u8]) -> Result<Self, DhtError> {
+        let mut dict = match bencode::parse_slice(data).map(|bencode| bencode.dict()) {
+            Ok(Some(dict)) => dict,
+            _ => return Err(DhtError::BadTable),
+        };
+        let mut bytes = |key: &[u8]| {
+            dict.remove(key)
+                .and_then(|value| value.bytestring())
+                .ok_or(DhtError::BadTable)
+        };
+        let id: [u8; 20] = bytes(b"id")?.try_into().map_err(|_| DhtError::BadTable)?;
+        let mut nodes = parse_compact_nodes(&bytes(b"nodes")?, false, "nodes")
+            .map_err(|_| DhtError::BadTable)?;
+        nodes.extend(
+            parse_compact_nodes(&bytes(b"nodes6")?, true, "nodes6")
+                .map_err(|_| DhtError::BadTable)?,
+        );
+
+        let mut table = Self::new(id);
+        let now = Instant::now();
+
+        for node in nodes {
+            table.insert(node, now);
+        }
+
+        Ok(table)
+    }
+
+    /// Saves this table to the file at `path`, see [RoutingTable::encode]
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), DhtError> {
+        std::fs::write(path, self.encode()).map_err(|err| DhtError::TableIo(err.kind()))
+    }
+
+    /// Loads a table saved to the file at `path` with [RoutingTable::save]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, DhtError> {
+        let data = std::fs::read(path).map_err(|err| DhtError::TableIo(err.kind()))?;
+
+        Self::decode(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::secure_id;
+    use std::net::SocketAddr;
+
+    /// Creates a node with an id starting with `first` and the rest `rest`
+    fn node(first: u8, rest: u8) -> NodeInfo {
+        let mut id = [rest; 20];
+        id[0] = first;
+
+        NodeInfo {
+            id,
+            addr: SocketAddr::from(([10, 0, first, rest], 6881)),
+        }
+    }
+
+    /// Tests that full buckets drop new nodes until a node goes bad, and that
+    /// known ids can't be moved to a new address while good
+    #[test]
+    fn routing_bucket_full() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+
+        for rest in 0..K as u8 {
+            assert!(table.insert(node(0x80, rest), now));
+        }
+
+        assert!(!table.insert(node(0x80, 100), now));
+        assert!(table.insert(node(0x40, 1), now)); // another bucket
+        assert!(!table.insert(
+            NodeInfo {
+                id: [0; 20],
+                addr: node(1, 1).addr
+            },
+            now
+        ));
+        assert_eq!(table.len(), K + 1);
+
+        let moved = NodeInfo {
+            id: node(0x80, 0).id,
+            addr: "10.9.9.9:1".parse().unwrap(),
+        };
+        assert!(!table.insert(moved, now));
+
+        for _ in 0..MAX_FAILURES {
+            table.failed(&node(0x80, 0).id);
+        }
+
+        assert!(table.insert(node(0x80, 100), now));
+        assert!(!table.contains(&node(0x80, 0).id));
+        assert_eq!(table.len(), K + 1);
+    }
+
+    /// Tests that [RoutingTable::closest] orders by XOR distance and that
+    /// refresh targets land in the bucket they are for
+    #[test]
+    fn routing_closest_and_refresh() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+
+        for first in [0x01, 0x02, 0x10, 0x80, 0xff] {
+            table.insert(node(first, 0), now);
+        }
+
+        let closest: Vec<u8> = table
+            .closest(&node(0x11, 0).id, 3)
+            .iter()
+            .map(|node| node.id[0])
+            .collect();
+        assert_eq!(closest, vec![0x10, 0x01, 0x02]);
+
+        let later = Instant::now() + BUCKET_REFRESH;
+        let targets = table.refresh_targets(later);
+        assert_eq!(targets.len(), 8); // buckets 0 to 7
+
+        for (ind, target) in targets.iter().enumerate() {
+            assert_eq!(table.bucket_index(target), Some(ind));
+        }
+
+        assert!(table.refresh_targets(now).is_empty());
+    }
+
+    /// Tests that secure nodes replace insecure ones in full buckets, and that
+    /// insecure nodes are refused once enforced
+    #[test]
+    fn routing_secure_ids() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+        let public = |ind: u8| SocketAddr::from(([203, 0, 113, ind], 6881));
+        let insecure = |ind: u8| {
+            let mut id = [ind; 20];
+            id[0] = 0x80;
+
+            NodeInfo {
+                id,
+                addr: public(ind),
+            }
+        };
+
+        for ind in 0..K as u8 {
+            assert!(table.insert(insecure(ind), now));
+        }
+
+        assert!(!table.insert(insecure(50), now));
+
+        let secure = (0..)
+            .map(|_| secure_id(public(100).ip()))
+            .find(|id| id[0] & 0x80 != 0)
+            .unwrap();
+        assert!(table.insert(
+            NodeInfo {
+                id: secure,
+                addr: public(100)
+            },
+            now
+        ));
+        assert!(!table.contains(&insecure(0).id));
+        assert_eq!(table.len(), K);
+
+        let table = table.enforce_secure(true);
+        assert_eq!(
+            table.nodes(),
+            vec![NodeInfo {
+                id: secure,
+                addr: public(100)
+            }]
+        );
+        assert!(table.clone().insert(node(0x10, 0), now)); // local addresses are exempt
+    }
+
+    /// Tests that a table roundtrips through [RoutingTable::encode]
+    #[test]
+    fn routing_encode_roundtrip() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([7; 20]);
+        table.insert(node(1, 2), now);
+        table.insert(
+            NodeInfo {
+                id: [9; 20],
+                addr: "[2001:db8::9]:6881".parse().unwrap(),
+            },
+            now,
+        );
+
+        let decoded = RoutingTable::decode(&table.encode()).unwrap();
+
+        assert_eq!(decoded.id(), [7; 20]);
+        assert_eq!(decoded.closest(&[0; 20], 10), table.closest(&[0; 20], 10));
+        assert_eq!(RoutingTable::decode(b"de").err(), Some(DhtError::BadTable));
+    }
+}
@@ -0,0 +1,179 @@
+//! Node id hardening from
+//! [BEP0042](https://www.bittorrent.org/beps/bep_0042.html), tying node ids
+//! to the IP address of the node so ids near a target can't be picked freely
+
+use super::random_id;
+use std::convert::TryInto;
+use std::net::IpAddr;
+
+/// Mask applied to IPv4 addresses before hashing
+const MASK_V4: u32 = 0x030f_3fff;
+
+/// Mask applied to the first 8 bytes of IPv6 addresses before hashing
+const MASK_V6: u64 = 0x0103_070f_1f3f_7fff;
+
+/// Calculates the CRC32-C (Castagnoli) checksum of `data`
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Gets the checksum the first 21 bits of an id for `ip` with the random
+/// number `r` in it's last byte must match
+fn id_crc(ip: IpAddr, r: u8) -> u32 {
+    let r = (r & 0x07) as u64;
+
+    match ip {
+        IpAddr::V4(ip) => {
+            let masked = (u32::from(ip) & MASK_V4) | ((r as u32) << 29);
+            crc32c(&masked.to_be_bytes())
+        }
+        IpAddr::V6(ip) => {
+            let first = u64::from_be_bytes(ip.octets()[..8].try_into().unwrap());
+            crc32c(&((first & MASK_V6) | (r << 61)).to_be_bytes())
+        }
+    }
+}
+
+/// If `ip` is a local address, which is exempt from needing a secure id as
+/// such nodes can't know their external address
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// The following IP address blocks are exempt from this check:
+///
+///     10.0.0.0/8
+///     172.16.0.0/12
+///     192.168.0.0/16
+///     169.254.0.0/16
+///     127.0.0.0/8
+/// ```
+///
+/// torro also exempts loopback, link-local and unique local IPv6 addresses
+pub fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || first & 0xffc0 == 0xfe80 || first & 0xfe00 == 0xfc00
+        }
+    }
+}
+
+/// Makes the secure id for `ip` from the random bytes of `seed`, keeping it's
+/// last byte as the random number `r` which has to be sent along
+fn secure_id_from(ip: IpAddr, mut seed: [u8; 20]) -> [u8; 20] {
+    let crc = id_crc(ip, seed[19]);
+
+    seed[0] = (crc >> 24) as u8;
+    seed[1] = (crc >> 16) as u8;
+    seed[2] = ((crc >> 8) as u8 & 0xf8) | (seed[2] & 0x07);
+    seed
+}
+
+/// Makes a new random node id which is secure for a node with the external
+/// address `ip`
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// The first 21 bits of the node ID used in the DHT will be derived from the
+/// external IP address of the node. The last byte of the node ID is a random
+/// number, r. [...] The remaining bits of the node ID are random.
+/// ```
+pub fn secure_id(ip: IpAddr) -> [u8; 20] {
+    secure_id_from(ip, random_id())
+}
+
+/// If `id` is allowed for a node at `ip`, being either secure for it or `ip`
+/// being [exempt](is_exempt)
+pub fn is_secure_id(id: &[u8; 20], ip: IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+
+    let crc = id_crc(ip, id[19]);
+
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests against the example ids given in BEP0042, with their first three
+    /// bytes and `r`
+    #[test]
+    fn security_bep42_examples() {
+        let examples: [(&str, [u8; 3], u8); 5] = [
+            ("124.31.75.21", [0x5f, 0xbf, 0xbf], 0x01),
+            ("21.75.31.124", [0x5a, 0x3c, 0xe9], 0x56),
+            ("65.23.51.170", [0xa5, 0xd4, 0x32], 0x16),
+            ("84.124.73.14", [0x1b, 0x03, 0x21], 0x41),
+            ("43.213.53.83", [0xe5, 0x6f, 0x6c], 0x5a),
+        ];
+
+        for (ip, start, r) in examples.iter() {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut id = [0; 20];
+            id[..3].copy_from_slice(start);
+            id[19] = *r;
+
+            assert!(is_secure_id(&id, ip));
+
+            id[1] ^= 0x01;
+            assert!(!is_secure_id(&id, ip));
+
+            let mut seed = [0xaa; 20];
+            seed[2] = start[2];
+            seed[19] = *r;
+            assert_eq!(&secure_id_from(ip, seed)[..3], start);
+        }
+    }
+
+    /// Tests that generated ids are secure for their own address only, and
+    /// that local addresses are exempt
+    #[test]
+    fn security_ids() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let ip6: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert!(is_secure_id(&secure_id(ip), ip));
+        assert!(is_secure_id(&secure_id(ip6), ip6));
+        assert!(!is_secure_id(
+            &secure_id(ip),
+            "198.51.100.1".parse().unwrap()
+        ));
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        for local in [
+            "10.1.2.3",
+            "172.20.0.1",
+            "192.168.1.1",
+            "169.254.0.1",
+            "127.0.0.1",
+            "::1",
+            "fd00::1",
+        ] {
+            assert!(is_secure_id(&[0; 20], local.parse().unwrap()));
+        }
+
+        assert!(!is_exempt("172.32.0.1".parse().unwrap()));
+    }
+}
@@ -19,15 +19,29 @@ pub enum TorroError {
     /// [Torrent::from_file](crate::Torrent::from_file))
     TorrentCreationError(TorrentCreationError),
 
-    /// An error relating to the [crate::tracker_udp] module (which is used inside
-    /// of [Torrent::download](crate::Torrent::download))
+    /// An error relating to the [crate::tracker], [crate::tracker_udp] or
+    /// [crate::tracker_http] modules (which are used inside of
+    /// [Torrent::download](crate::Torrent::download))
     TrackerError(TrackerError),
 
+    /// An error relating to the [crate::peer] module, from talking to other
+    /// peers over the peer wire protocol
+    PeerError(PeerError),
+
+    /// An error relating to the [crate::dht] module, from finding peers over
+    /// the Mainline DHT
+    DhtError(DhtError),
+
     /// When an attemped file read failed, typically happens with
     /// [Torrent::from_file](crate::Torrent::from_file). See
     /// [TorroError::BadFileWrite] for errors related to file writes
     BadFileRead(PathBuf),
 
+    /// When reading from a [Read](std::io::Read) source failed, typically
+    /// happens with [Torrent::from_reader](crate::Torrent::from_reader). The
+    /// kind of IO error which occured is given
+    BadRead(std::io::ErrorKind),
+
     /// A bad file write occured, typically happens when trying to safe a result
     /// of a download without corrent write permissions. See
     /// [TorroError::BadFileRead] for errors related to file reads
@@ -122,6 +136,10 @@ pub enum TorrentCreationError {
     /// should be an integer (e.g. [Bencode::Int](crate::bencode::Bencode::Int))
     LengthWrongType,
 
+    /// When a/the given `length` key was negative or too large, or the lengths
+    /// of all files added up to more than a [u64] can hold
+    BadLength,
+
     /// When the given `announce` key was given the wrong type. The
     /// `announce` key should be a dictionary (e.g.
     /// [Bencode::Dict](crate::bencode::Bencode::Dict))
@@ -195,15 +213,72 @@ impl From<TorrentCreationError> for TorroError {
 }
 
 /// Error enum used inside of [Torrent::download](crate::Torrent::download)
-/// which extends from the [crate::tracker_udp] module (where it originates).
+/// which extends from the [crate::tracker], [crate::tracker_udp] and
+/// [crate::tracker_http] modules (where it originates).
 /// This type of error happens when torro could not properly connect to a tracker
 /// to maintain infomation
 #[derive(Debug, PartialEq, Clone)]
 pub enum TrackerError {
-    /// An error occured relating to creating a udp socket to connect to the
-    /// tracker. The address used to try to connect is provided as a [String],
-    /// typically the [crate::tracker_udp::TORRO_BIND_ADDR] constant
-    SocketBind(&'static str)
+    /// An error occured relating to binding a socket to connect to the tracker
+    /// with (or to serve as one). The address used to try to bind is provided
+    /// as a [String], typically the [crate::tracker_udp::TORRO_BIND_ADDR]
+    /// constant
+    SocketBind(String),
+
+    /// Sending to or recieving from the tracker failed after the socket was
+    /// bound, with the kind of IO error which occured given
+    SocketIo(std::io::ErrorKind),
+
+    /// The tracker address given could not be resolved into a socket address.
+    /// The address which was tried is given
+    AddressResolution(String),
+
+    /// The tracker didn't respond to a request after all retries where used up
+    Timeout,
+
+    /// The tracker only responded with packets for a different transaction id
+    /// than the one requested before all retries where used up
+    WrongTransactionId,
+
+    /// The tracker responded with a packet too short (or otherwise malformed)
+    /// to be parsed, the length of which is given
+    ShortPacket(usize),
+
+    /// The tracker responded with an error (action `3` for UDP trackers or a
+    /// `failure reason` for HTTP trackers), the human-readable message of which
+    /// is given
+    TrackerMessage(String),
+
+    /// The tracker URL given uses a scheme which isn't supported, such as
+    /// `https` as torro has no TLS implementation. The scheme is given
+    UnsupportedScheme(String),
+
+    /// The tracker URL given could not be understood, the URL is given
+    InvalidUrl(String),
+
+    /// An HTTP tracker responded with a non-`200` status code and no
+    /// `failure reason`, the status code is given
+    HttpStatus(u16),
+
+    /// An HTTP tracker's response wasn't valid HTTP or it's body wasn't a
+    /// bencoded dictionary
+    MalformedHttp,
+
+    /// An HTTP tracker's response body wasn't valid bencode
+    BadBencode(BencodeError),
+
+    /// The tracker's announce URL doesn't follow the scrape convention, so it
+    /// can't be scraped
+    ScrapeUnsupported,
+
+    /// No tracker could be announced to as every tracker is being backed off
+    /// from after failing (or there are no trackers), see
+    /// [AnnounceManager](crate::tracker::AnnounceManager)
+    NoTrackersAvailable,
+
+    /// An HTTP tracker's response was missing a required key (or it had the
+    /// wrong type), the key is given
+    MissingKey(&'static str),
 }
 
 impl From<TrackerError> for TorroError {
@@ -211,3 +286,129 @@ impl From<TrackerError> for TorroError {
         TorroError::TrackerError(error)
     }
 }
+
+/// Error enum for errors from the [crate::peer] module (where it originates).
+/// This type of error happens when a peer sends something which doesn't follow
+/// the peer wire protocol or the connection to it fails
+#[derive(Debug, PartialEq, Clone)]
+pub enum PeerError {
+    /// Sending to or recieving from the peer failed, with the kind of IO error
+    /// which occured given. A peer closing the connection part-way through a
+    /// message gives [std::io::ErrorKind::UnexpectedEof]
+    Io(std::io::ErrorKind),
+
+    /// The peer's handshake didn't start with the `BitTorrent protocol` string
+    BadHandshake,
+
+    /// The peer's handshake was for a different info-hash than the one wanted
+    InfoHashMismatch,
+
+    /// The length prefix of a message was larger than
+    /// [MAX_MESSAGE_LEN](crate::peer::MAX_MESSAGE_LEN), which is given
+    MessageTooLarge(u32),
+
+    /// A message was recieved with an id torro doesn't know, the id is given
+    UnknownMessage(u8),
+
+    /// A message's length didn't match what it's id requires. The first item
+    /// in tuple represents the message id and second represents the length
+    /// given (excluding the id)
+    BadMessageLength((u8, usize)),
+
+    /// A piece index past the end of the torrent was given, which is given
+    BadPieceIndex(u32),
+
+    /// A bitfield was sent or recieved after other messages, when it's only
+    /// allowed directly after the handshake
+    LateBitfield,
+
+    /// A bitfield had the wrong length for the torrent or had spare bits set
+    BadBitfield,
+
+    /// A block was requested while the side asked was choking the one asking
+    RequestWhileChoked,
+
+    /// A block request asked for zero bytes or more than
+    /// [MAX_REQUEST_LEN](crate::peer::MAX_REQUEST_LEN), the length asked for
+    /// is given
+    BadRequestLength(u32),
+
+    /// A peer sent more unanswered requests than
+    /// [MAX_PEER_REQUESTS](crate::peer::MAX_PEER_REQUESTS) without the fast
+    /// extension, so they couldn't be rejected
+    TooManyRequests,
+
+    /// A fast extension message was sent or recieved on a connection where
+    /// both sides didn't set the fast bit in their handshakes
+    FastNotNegotiated,
+
+    /// A peer rejected a request which we never sent it
+    UnrequestedReject,
+
+    /// An extended message was sent or recieved on a connection where both
+    /// sides didn't set the extension protocol bit in their handshakes
+    ExtendedNotNegotiated,
+
+    /// An extended handshake wasn't a bencoded dictionary
+    BadExtendedHandshake,
+
+    /// An extended message was recieved with an id we never gave any
+    /// extension, the id is given
+    UnknownExtension(u8),
+
+    /// A message was to be sent for an extension the peer doesn't support,
+    /// the name of the extension is given
+    ExtensionNotSupported(String),
+
+    /// A peer exchange message wasn't a dictionary, had compact peers of the
+    /// wrong length or listed too many peers
+    BadPex,
+}
+
+impl From<PeerError> for TorroError {
+    fn from(error: PeerError) -> Self {
+        TorroError::PeerError(error)
+    }
+}
+
+/// Error enum for errors from the [crate::dht] module (where it originates),
+/// from running a DHT node and querying others
+#[derive(Debug, PartialEq, Clone)]
+pub enum DhtError {
+    /// Binding the node's UDP socket failed, the address used to try to bind
+    /// is given
+    SocketBind(String),
+
+    /// Sending or recieving on the node's socket failed, with the kind of IO
+    /// error which occured given
+    SocketIo(std::io::ErrorKind),
+
+    /// A queried node didn't respond in time
+    Timeout,
+
+    /// A KRPC message wasn't a bencoded dictionary or was missing a key (or it
+    /// had the wrong type), the key is given
+    BadMessage(&'static str),
+
+    /// A queried node responded with a KRPC error, the error code and
+    /// human-readable message of which are given
+    KrpcError((i64, String)),
+
+    /// There are no nodes to query, as the routing table is empty and no
+    /// nodes to bootstrap from responded
+    NoNodes,
+
+    /// Reading or writing a saved routing table failed, with the kind of IO
+    /// error which occured given
+    TableIo(std::io::ErrorKind),
+
+    /// A saved routing table wasn't in the format
+    /// [RoutingTable::encode](crate::dht::RoutingTable::encode) gives
+    BadTable,
+}
+
+impl From<DhtError> for TorroError {
+    fn from(error: DhtError) -> Self {
+        TorroError::DhtError(error)
+    }
+}
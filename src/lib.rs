@@ -3,10 +3,10 @@
 //! ## Objectives
 //!
 //! - Easy-to-use library interface that assumes by default with customisability
-//! if needed
+//!   if needed
 //! - Extremely low dependency count (none ideally)
 //! - High amount of documentation, no data structures/functions implemented
-//! without a line of docstring
+//!   without a line of docstring
 //! - Correctness with the BitTorrent protocols
 //!
 //! ## Development/Production Status
@@ -16,11 +16,17 @@
 //! supported, see the torro [roadmap](https://github.com/Owez/torro/issues/20)
 //! for future plans.
 
+mod sha1;
 mod utils;
 
 pub mod bencode;
+pub mod dht;
 pub mod error;
+pub mod peer;
 pub mod torrent;
+pub mod tracker;
+pub mod tracker_http;
+pub mod tracker_server;
 pub mod tracker_udp;
 
 pub use torrent::*;
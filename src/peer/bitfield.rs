@@ -0,0 +1,141 @@
+//! Bitfield of which pieces a peer (or torro itself) has
+
+use crate::error::PeerError;
+
+/// Which pieces of a torrent a peer has, stored as in the `bitfield` message
+/// with the highest bit of the first byte being the first piece
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::peer::Bitfield;
+///
+/// fn main() {
+///     let mut bitfield = Bitfield::new(10);
+///     bitfield.set(0);
+///     bitfield.set(9);
+///
+///     assert!(bitfield.get(9));
+///     assert_eq!(bitfield.count(), 2);
+///     assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+/// }
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Bitfield {
+    /// Bits as sent on the wire, with spare bits at the end always cleared
+    bits: Vec<u8>,
+
+    /// Amount of pieces
+    len: u32,
+}
+
+impl Bitfield {
+    /// Creates a new bitfield for `len` pieces with none set
+    pub fn new(len: u32) -> Self {
+        Self {
+            bits: vec![0; (len as usize).div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Creates a new bitfield for `len` pieces with all set, such as for a
+    /// `have all` message
+    pub fn full(len: u32) -> Self {
+        let mut bitfield = Self::new(len);
+
+        for index in 0..len {
+            bitfield.set(index);
+        }
+
+        bitfield
+    }
+
+    /// Creates a bitfield for `len` pieces from the bytes of a `bitfield`
+    /// message, giving [PeerError::BadBitfield] if there is the wrong amount of
+    /// bytes or any spare bits at the end are set
+    pub fn from_bytes(bytes: &[u8], len: u32) -> Result<Self, PeerError> {
+        let mut bitfield = Self::new(len);
+
+        if bytes.len() != bitfield.bits.len() {
+            return Err(PeerError::BadBitfield);
+        }
+
+        bitfield.bits.copy_from_slice(bytes);
+
+        if (len..bitfield.bits.len() as u32 * 8).any(|index| bitfield.bit(index)) {
+            return Err(PeerError::BadBitfield);
+        }
+
+        Ok(bitfield)
+    }
+
+    /// Gets the bit for `index` without checking it is under [Bitfield::len]
+    fn bit(&self, index: u32) -> bool {
+        self.bits[index as usize / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Amount of pieces this bitfield is for
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// If this bitfield is for no pieces at all
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// If the piece at `index` is set, giving `false` for indexes past the end
+    pub fn get(&self, index: u32) -> bool {
+        index < self.len && self.bit(index)
+    }
+
+    /// Sets the piece at `index`, doing nothing for indexes past the end
+    pub fn set(&mut self, index: u32) {
+        if index < self.len {
+            self.bits[index as usize / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Amount of pieces set
+    pub fn count(&self) -> u32 {
+        self.bits.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    /// If every piece is set
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Gets the bytes of this bitfield as sent in a `bitfield` message
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// Iterates over the indexes of every set piece
+    pub fn iter_set(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len).filter(move |index| self.bit(*index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that [Bitfield::from_bytes] checks the length and spare bits
+    #[test]
+    fn bitfield_from_bytes() {
+        let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b1000_0000], 9).unwrap();
+
+        assert_eq!(bitfield.iter_set().collect::<Vec<u32>>(), vec![0, 2, 8]);
+        assert!(!bitfield.get(9));
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff], 9),
+            Err(PeerError::BadBitfield)
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0xff, 0b0100_0000], 9),
+            Err(PeerError::BadBitfield)
+        );
+        assert!(Bitfield::from_bytes(&[0xff, 0xff], 16).unwrap().is_full());
+    }
+}
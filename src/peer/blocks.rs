@@ -0,0 +1,658 @@
+//! Block-level bookkeeping of pieces being downloaded: splitting pieces into
+//! block requests, pipelining them per peer, assembling recieved blocks and
+//! checking finished pieces
+
+use super::{Bitfield, BlockRequest, PiecePicker};
+use crate::sha1::sha1;
+use crate::Torrent;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Length of the blocks pieces are requested in, 16 KiB as nearly all clients
+/// use
+pub const BLOCK_LEN: u32 = 16384;
+
+/// How long a request may go unanswered before it's given to another peer
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Fewest requests kept outstanding with a peer
+const MIN_QUEUE: usize = 2;
+
+/// Most requests kept outstanding with a peer
+const MAX_QUEUE: usize = 250;
+
+/// How many seconds of a peer's measured throughput are kept requested, so the
+/// peer never waits on us for more requests
+const QUEUE_SECS: f64 = 3.0;
+
+/// Shortest window throughput is measured over
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// State of a single block of a [PartialPiece]
+#[derive(Debug, PartialEq, Clone)]
+enum BlockState {
+    /// Not requested from anyone
+    Needed,
+
+    /// Requested from the given peers, with when each request was sent. More
+    /// than one peer is only ever given in endgame mode
+    Requested(Vec<(SocketAddr, Instant)>),
+
+    /// Recieved and copied into the piece's buffer
+    Recieved,
+}
+
+/// A piece which has been started but not finished
+#[derive(Debug, Clone)]
+struct PartialPiece {
+    /// Buffer the piece's blocks are assembled into
+    buf: Vec<u8>,
+
+    /// State of each block
+    blocks: Vec<BlockState>,
+
+    /// Peers which sent blocks of this piece, blamed if it fails it's hash
+    /// check
+    contributors: Vec<SocketAddr>,
+}
+
+/// What happened to a piece once it's last block was recieved, see
+/// [PieceDownloads::block_recieved]
+#[derive(Debug, PartialEq, Clone)]
+pub enum PieceResult {
+    /// The piece matched it's hash, with it's index and data given
+    Verified(u32, Vec<u8>),
+
+    /// The piece didn't match it's hash and will be downloaded again, with it's
+    /// index and every peer which sent data for it given
+    Failed(u32, Vec<SocketAddr>),
+}
+
+/// The outcome of a recieved block, see [PieceDownloads::block_recieved]
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct BlockOutcome {
+    /// Duplicate requests for the same block with other peers, from endgame
+    /// mode, which should now be cancelled
+    pub cancels: Vec<(SocketAddr, BlockRequest)>,
+
+    /// What happened to the piece, if this was it's last block
+    pub piece: Option<PieceResult>,
+}
+
+/// Keeps track of every block of the pieces being downloaded, working with a
+/// [PiecePicker] to decide what to request from each peer
+///
+/// Pieces are split into [BLOCK_LEN] requests (with the last block of the last
+/// piece possibly shorter), which are assembled back into the piece as they
+/// arrive. Finished pieces are checked against [Torrent::pieces] and any that
+/// fail are downloaded again, with the peers which sent data for them reported
+/// so they can be dealt with. Requests which go unanswered for
+/// [REQUEST_TIMEOUT] are given back out with [PieceDownloads::timed_out]
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// When data is being transferred, downloaders should keep several piece
+/// requests queued up at once in order to get good TCP performance (this is
+/// called 'pipelining'.) On the other side, requests which can't be written out
+/// to the TCP buffer immediately should be queued up in memory rather than kept
+/// in an application-level network buffer, so they can all be thrown out when a
+/// choke happens.
+/// ```
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::PathBuf;
+/// use torro::peer::{Bitfield, PieceDownloads, PiecePicker, RequestPipeline};
+/// use torro::Torrent;
+///
+/// fn main() {
+///     let torrent = Torrent::from_file(PathBuf::from("my.torrent")).unwrap();
+///     let mut picker = PiecePicker::from_torrent(&torrent);
+///     let mut downloads = PieceDownloads::new(&torrent);
+///     let pipeline = RequestPipeline::new();
+///
+///     let peer = "127.0.0.1:6881".parse().unwrap();
+///     let peer_has = Bitfield::new(torrent.pieces.len() as u32); // from the peer
+///
+///     for req in downloads.next_requests(&mut picker, peer, &peer_has, pipeline.queue_len()) {
+///         println!("Requesting {:?}", req);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PieceDownloads {
+    /// Length of every piece but the last
+    piece_length: u64,
+
+    /// Length of all pieces together
+    total_length: u64,
+
+    /// Expected SHA-1 hash of each piece
+    hashes: Vec<Vec<u8>>,
+
+    /// Pieces which have been started but not finished
+    pieces: HashMap<u32, PartialPiece>,
+
+    /// How long a request may go unanswered
+    timeout: Duration,
+}
+
+impl PieceDownloads {
+    /// Creates block bookkeeping for the `torrent`'s pieces
+    pub fn new(torrent: &Torrent) -> Self {
+        Self {
+            piece_length: torrent.piece_length as u64,
+            // only edited torrents can overflow, whose pieces then bound it
+            total_length: torrent.file_structure.total_length().unwrap_or(u64::MAX),
+            hashes: torrent.pieces.clone(),
+            pieces: HashMap::new(),
+            timeout: REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a request may go unanswered before it's given out again,
+    /// [REQUEST_TIMEOUT] by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Length of the piece at `index`, which is [Torrent::piece_length] for
+    /// every piece but the last
+    pub fn piece_len(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length;
+
+        self.piece_length
+            .min(self.total_length.saturating_sub(start)) as u32
+    }
+
+    /// Gets the request for block `block_ind` of the piece at `index`
+    fn block_request(&self, index: u32, block_ind: usize) -> BlockRequest {
+        let begin = block_ind as u32 * BLOCK_LEN;
+
+        BlockRequest {
+            index,
+            begin,
+            length: BLOCK_LEN.min(self.piece_len(index) - begin),
+        }
+    }
+
+    /// Gets the requests to send to `peer` (which has the pieces in
+    /// `peer_has`) to bring it up to `max` outstanding requests, as given by
+    /// [RequestPipeline::queue_len]
+    ///
+    /// New pieces are picked with `picker` as needed. In endgame mode blocks
+    /// already requested from other peers are requested again, skipping pieces
+    /// which every block has already been requested from `peer` for
+    pub fn next_requests(
+        &mut self,
+        picker: &mut PiecePicker,
+        peer: SocketAddr,
+        peer_has: &Bitfield,
+        max: usize,
+    ) -> Vec<BlockRequest> {
+        let mut outstanding = self.outstanding(peer);
+        let mut reqs = vec![];
+        let mut exhausted = vec![];
+        let now = Instant::now();
+
+        while outstanding < max {
+            let index = match picker.pick_excluding(peer_has, &exhausted) {
+                Some(index) => index,
+                None => break,
+            };
+            let endgame = picker.in_endgame();
+            let block_count = self.piece_len(index).div_ceil(BLOCK_LEN) as usize;
+            let piece = self.pieces.entry(index).or_insert_with(|| PartialPiece {
+                buf: vec![],
+                blocks: vec![BlockState::Needed; block_count],
+                contributors: vec![],
+            });
+            let mut picked = vec![];
+
+            for (block_ind, block) in piece.blocks.iter_mut().enumerate() {
+                if outstanding + picked.len() >= max {
+                    break;
+                }
+
+                match block {
+                    BlockState::Needed => *block = BlockState::Requested(vec![(peer, now)]),
+                    BlockState::Requested(peers)
+                        if endgame && !peers.iter().any(|(other, _)| *other == peer) =>
+                    {
+                        peers.push((peer, now))
+                    }
+                    _ => continue,
+                }
+
+                picked.push(block_ind);
+            }
+
+            if !piece.blocks.contains(&BlockState::Needed) {
+                picker.fully_requested(index);
+            }
+
+            if picked.is_empty() {
+                exhausted.push(index); // nothing left in this piece for this peer
+                continue;
+            }
+
+            outstanding += picked.len();
+            reqs.extend(
+                picked
+                    .into_iter()
+                    .map(|block_ind| self.block_request(index, block_ind)),
+            );
+        }
+
+        reqs
+    }
+
+    /// Counts the requests outstanding with `peer`
+    fn outstanding(&self, peer: SocketAddr) -> usize {
+        self.pieces
+            .values()
+            .flat_map(|piece| piece.blocks.iter())
+            .filter(|block| match block {
+                BlockState::Requested(peers) => peers.iter().any(|(other, _)| *other == peer),
+                _ => false,
+            })
+            .count()
+    }
+
+    /// Handles a `block` recieved from `peer` for the piece at `index`, copying
+    /// it into the piece and checking the piece once it's complete
+    ///
+    /// Blocks which weren't requested or are already recieved are ignored,
+    /// giving an empty [BlockOutcome]
+    pub fn block_recieved(
+        &mut self,
+        picker: &mut PiecePicker,
+        peer: SocketAddr,
+        index: u32,
+        begin: u32,
+        block: &[u8],
+    ) -> BlockOutcome {
+        let mut outcome = BlockOutcome::default();
+        let block_ind = (begin / BLOCK_LEN) as usize;
+        let expected = match self.pieces.get(&index) {
+            Some(piece) if begin.is_multiple_of(BLOCK_LEN) && block_ind < piece.blocks.len() => {
+                self.block_request(index, block_ind)
+            }
+            _ => return outcome,
+        };
+
+        if block.len() != expected.length as usize {
+            return outcome;
+        }
+
+        let piece_len = self.piece_len(index) as usize;
+        let piece = self.pieces.get_mut(&index).unwrap();
+        let requesters = match &piece.blocks[block_ind] {
+            BlockState::Recieved => return outcome,
+            BlockState::Needed => vec![],
+            BlockState::Requested(peers) => peers.iter().map(|(other, _)| *other).collect(),
+        };
+
+        if piece.buf.is_empty() {
+            piece.buf = vec![0; piece_len];
+        }
+
+        piece.buf[begin as usize..begin as usize + block.len()].copy_from_slice(block);
+        piece.blocks[block_ind] = BlockState::Recieved;
+
+        if !piece.contributors.contains(&peer) {
+            piece.contributors.push(peer);
+        }
+
+        outcome.cancels = requesters
+            .into_iter()
+            .filter(|other| *other != peer)
+            .map(|other| (other, expected))
+            .collect();
+
+        if piece
+            .blocks
+            .iter()
+            .all(|block| *block == BlockState::Recieved)
+        {
+            let piece = self.pieces.remove(&index).unwrap();
+            let matches = self.hashes.get(index as usize).map(|hash| hash.as_slice())
+                == Some(&sha1(&piece.buf)[..]);
+
+            outcome.piece = Some(if matches {
+                picker.mark_have(index);
+                PieceResult::Verified(index, piece.buf)
+            } else {
+                picker.mark_failed(index);
+                PieceResult::Failed(index, piece.contributors)
+            });
+        }
+
+        outcome
+    }
+
+    /// Forgets every request outstanding with `peer`, such as after it choked
+    /// us or disconnected, so they can be given to other peers
+    pub fn peer_dropped(&mut self, picker: &mut PiecePicker, peer: SocketAddr) {
+        self.drop_requests(picker, |other, _| other == peer);
+    }
+
+    /// Forgets every request which has gone unanswered for longer than the
+    /// timeout, giving them so they can be cancelled. The blocks are given out
+    /// again by [PieceDownloads::next_requests]
+    pub fn timed_out(&mut self, picker: &mut PiecePicker) -> Vec<(SocketAddr, BlockRequest)> {
+        let now = Instant::now();
+        let timeout = self.timeout;
+
+        self.drop_requests(picker, |_, sent| now.duration_since(sent) >= timeout)
+    }
+
+    /// Removes every request matching `should_drop` (given the peer and when
+    /// it was sent), giving them back. Blocks left with no requests are needed
+    /// again and their pieces reopened in the `picker`
+    fn drop_requests(
+        &mut self,
+        picker: &mut PiecePicker,
+        should_drop: impl Fn(SocketAddr, Instant) -> bool,
+    ) -> Vec<(SocketAddr, BlockRequest)> {
+        let mut dropped = vec![];
+
+        for (index, piece) in self.pieces.iter_mut() {
+            let mut reopened = false;
+
+            for (block_ind, block) in piece.blocks.iter_mut().enumerate() {
+                let peers = match block {
+                    BlockState::Requested(peers) => peers,
+                    _ => continue,
+                };
+
+                peers.retain(|(peer, sent)| {
+                    if should_drop(*peer, *sent) {
+                        dropped.push((*peer, (*index, block_ind)));
+                        false
+                    } else {
+                        true
+                    }
+                });
+
+                if peers.is_empty() {
+                    *block = BlockState::Needed;
+                    reopened = true;
+                }
+            }
+
+            if reopened {
+                picker.requests_dropped(*index);
+            }
+        }
+
+        dropped
+            .into_iter()
+            .map(|(peer, (index, block_ind))| (peer, self.block_request(index, block_ind)))
+            .collect()
+    }
+}
+
+/// Measures a single peer's download throughput to decide how many requests
+/// to keep outstanding with it
+///
+/// Enough requests are kept to cover a few seconds of the peer's throughput,
+/// so a fast peer is never left waiting for us to ask for more while a slow
+/// peer isn't given blocks it will take ages to send
+#[derive(Debug, Clone)]
+pub struct RequestPipeline {
+    /// Smoothed throughput in bytes per second
+    rate: f64,
+
+    /// Start of the current measuring window
+    window_start: Instant,
+
+    /// Bytes recieved in the current measuring window
+    window_bytes: u64,
+}
+
+impl RequestPipeline {
+    /// Creates a pipeline for a peer with no throughput measured yet
+    pub fn new() -> Self {
+        Self {
+            rate: 0.0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Records `bytes` of block data recieved from the peer
+    pub fn recieved(&mut self, bytes: usize) {
+        self.recieved_at(bytes, Instant::now())
+    }
+
+    /// Records `bytes` recieved at `now`, closing the measuring window if it
+    /// has gone on long enough
+    fn recieved_at(&mut self, bytes: usize, now: Instant) {
+        self.window_bytes += bytes as u64;
+
+        let elapsed = now.duration_since(self.window_start);
+
+        if elapsed >= RATE_WINDOW {
+            let window_rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+
+            self.rate = if self.rate == 0.0 {
+                window_rate
+            } else {
+                (self.rate + window_rate) / 2.0
+            };
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+
+    /// Measured throughput in bytes per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// How many requests to keep outstanding with the peer
+    pub fn queue_len(&self) -> usize {
+        let blocks = (self.rate * QUEUE_SECS / BLOCK_LEN as f64).ceil() as usize;
+
+        blocks.clamp(MIN_QUEUE, MAX_QUEUE)
+    }
+}
+
+impl Default for RequestPipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::make_torrent;
+
+    /// Creates a torrent of `data` split into pieces of `piece_length`, with the
+    /// hash of piece `bad` (if any) broken
+    fn torrent_for(data: &[u8], piece_length: usize, bad: Option<usize>) -> Torrent {
+        let pieces: Vec<Vec<u8>> = data
+            .chunks(piece_length)
+            .enumerate()
+            .map(|(ind, piece)| match bad {
+                Some(bad) if bad == ind => vec![0; 20],
+                _ => sha1(piece).to_vec(),
+            })
+            .collect();
+
+        make_torrent(
+            "test",
+            piece_length,
+            &pieces.concat(),
+            &[(data.len(), "file")],
+        )
+    }
+
+    /// Gets the data of `req` from `data` with pieces of `piece_length`
+    fn block_of(data: &[u8], piece_length: usize, req: &BlockRequest) -> Vec<u8> {
+        let start = req.index as usize * piece_length + req.begin as usize;
+
+        data[start..start + req.length as usize].to_vec()
+    }
+
+    /// Tests that pieces are split into 16 KiB blocks (with a shorter last
+    /// block), assembled back together and checked, blaming the senders of a
+    /// bad piece
+    #[test]
+    fn downloads_assemble_and_check() {
+        let piece_length = BLOCK_LEN as usize * 2;
+        let data: Vec<u8> = (0..piece_length * 2 + 100).map(|ind| ind as u8).collect();
+        let torrent = torrent_for(&data, piece_length, Some(1));
+        let mut picker = PiecePicker::from_torrent(&torrent);
+        let mut downloads = PieceDownloads::new(&torrent);
+        let (first, second): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+        let mut everything = Bitfield::new(3);
+        (0..3).for_each(|index| everything.set(index));
+
+        assert_eq!(downloads.piece_len(2), 100);
+
+        let reqs = [
+            (
+                first,
+                downloads.next_requests(&mut picker, first, &everything, 3),
+            ),
+            (
+                second,
+                downloads.next_requests(&mut picker, second, &everything, 2),
+            ),
+        ];
+
+        assert_eq!(reqs[0].1.len() + reqs[1].1.len(), 5);
+        assert_eq!(
+            downloads.next_requests(&mut picker, first, &everything, 3),
+            vec![]
+        );
+        assert!(reqs.iter().any(|(_, reqs)| reqs.contains(&BlockRequest {
+            index: 2,
+            begin: 0,
+            length: 100
+        })));
+
+        let mut results = vec![];
+
+        for (peer, req) in reqs
+            .iter()
+            .flat_map(|(peer, reqs)| reqs.iter().map(move |req| (*peer, req)))
+        {
+            let outcome = downloads.block_recieved(
+                &mut picker,
+                peer,
+                req.index,
+                req.begin,
+                &block_of(&data, piece_length, req),
+            );
+
+            assert!(outcome.cancels.is_empty());
+            results.extend(outcome.piece);
+        }
+
+        assert_eq!(results.len(), 3);
+        assert!(results.contains(&PieceResult::Verified(0, data[..piece_length].to_vec())));
+        assert!(results.iter().any(|result| match result {
+            PieceResult::Failed(1, peers) => !peers.is_empty(),
+            _ => false,
+        }));
+        assert!(picker.has(2));
+        assert!(!picker.has(1));
+        assert_eq!(
+            downloads
+                .block_recieved(&mut picker, first, 0, 0, &[0; 16384])
+                .piece,
+            None
+        );
+    }
+
+    /// Tests that timed out requests and requests of a dropped peer are given
+    /// out again, and that endgame duplicates are cancelled once recieved
+    #[test]
+    fn downloads_rerequest_and_endgame() {
+        let data = vec![7; BLOCK_LEN as usize * 2];
+        let torrent = torrent_for(&data, data.len(), None);
+        let mut picker = PiecePicker::from_torrent(&torrent);
+        let mut downloads = PieceDownloads::new(&torrent).timeout(Duration::from_secs(0));
+        let (first, second): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+        let mut everything = Bitfield::new(1);
+        everything.set(0);
+
+        let reqs = downloads.next_requests(&mut picker, first, &everything, 2);
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(
+            downloads.timed_out(&mut picker),
+            reqs.iter()
+                .map(|req| (first, *req))
+                .collect::<Vec<(SocketAddr, BlockRequest)>>()
+        );
+
+        let mut downloads = PieceDownloads::new(&torrent);
+        let mut picker = PiecePicker::from_torrent(&torrent);
+
+        downloads.next_requests(&mut picker, first, &everything, 1);
+        downloads.peer_dropped(&mut picker, first);
+        assert_eq!(
+            downloads.next_requests(&mut picker, second, &everything, 1)[0].begin,
+            0
+        );
+
+        // both blocks now requested from someone, so endgame gives duplicates
+        downloads.next_requests(&mut picker, first, &everything, 1);
+        let dupes = downloads.next_requests(&mut picker, first, &everything, 4);
+        assert!(picker.in_endgame());
+        assert_eq!(dupes.len(), 1);
+
+        let outcome = downloads.block_recieved(&mut picker, first, 0, 0, &data[..16384]);
+        assert_eq!(outcome.cancels, vec![(second, dupes[0])]);
+    }
+
+    /// Tests that endgame skips pieces already duplicated to a peer rather
+    /// than stopping at them
+    #[test]
+    fn downloads_endgame_skips_duplicated() {
+        let data = vec![7; BLOCK_LEN as usize * 2];
+        let torrent = torrent_for(&data, BLOCK_LEN as usize, None);
+        let mut picker = PiecePicker::from_torrent(&torrent);
+        let mut downloads = PieceDownloads::new(&torrent);
+        let (first, second): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+        let mut everything = Bitfield::new(2);
+        (0..2).for_each(|index| everything.set(index));
+
+        let ours = downloads.next_requests(&mut picker, first, &everything, 1)[0];
+        let theirs = downloads.next_requests(&mut picker, second, &everything, 1)[0];
+        picker.peer_have(theirs.index); // so our own piece is picked first
+
+        assert_eq!(
+            downloads.next_requests(&mut picker, first, &everything, 4),
+            vec![theirs]
+        );
+        assert!(picker.in_endgame());
+        assert_ne!(ours.index, theirs.index);
+    }
+
+    /// Tests that the pipeline's queue grows with measured throughput
+    #[test]
+    fn pipeline_queue_len() {
+        let mut pipeline = RequestPipeline::new();
+        let start = pipeline.window_start;
+
+        assert_eq!(pipeline.queue_len(), MIN_QUEUE);
+
+        pipeline.recieved_at(BLOCK_LEN as usize * 10, start + Duration::from_secs(1));
+        assert_eq!(pipeline.queue_len(), 30);
+
+        pipeline.recieved_at(usize::MAX / 2, start + Duration::from_secs(2));
+        assert_eq!(pipeline.queue_len(), MAX_QUEUE);
+    }
+}
@@ -0,0 +1,292 @@
+//! Deciding which peers to upload to, with the standard tit-for-tat choker as
+//! the default [Choker]
+
+use crate::utils::Xorshift;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How often [TitForTat] re-decides which peers are unchoked
+pub const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often [TitForTat] moves it's optimistic unchoke to another peer
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Amount of peers [TitForTat] uploads to at once by default, including the
+/// optimistic unchoke
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// How long a peer counts as new, making it three times as likely to be picked
+/// as the optimistic unchoke
+const NEW_PEER_AGE: Duration = Duration::from_secs(3 * 30);
+
+/// A connected peer as seen by a [Choker]
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChokePeer {
+    /// Address of the peer, used to tell peers apart
+    pub addr: SocketAddr,
+
+    /// Rate we are downloading from the peer at, in bytes per second
+    pub download_rate: f64,
+
+    /// Rate we are uploading to the peer at, in bytes per second
+    pub upload_rate: f64,
+
+    /// If the peer is interested in pieces we have
+    pub interested: bool,
+
+    /// When the peer connected
+    pub connected_at: Instant,
+}
+
+/// A strategy deciding which peers to upload to, so choking algorithms other
+/// than [TitForTat] can be swapped in
+///
+/// The download engine calls [Choker::unchoke] every [Choker::interval] with
+/// every connected peer, unchoking the peers given back and choking the rest
+pub trait Choker {
+    /// Decides which of the connected `peers` to unchoke at `now`, with
+    /// `seeding` given if we have the whole torrent
+    fn unchoke(&mut self, peers: &[ChokePeer], seeding: bool, now: Instant) -> Vec<SocketAddr>;
+
+    /// How often [Choker::unchoke] should be called
+    fn interval(&self) -> Duration {
+        UNCHOKE_INTERVAL
+    }
+}
+
+/// The standard BitTorrent choker, reciprocating with the peers which give us
+/// the most and optimistically trying out others
+///
+/// All but one of the upload slots go to the interested peers we download
+/// from fastest (or upload to fastest when seeding, so the swarm gets the
+/// most out of us). The last slot is an optimistic unchoke, moved to a random
+/// other interested peer every [OPTIMISTIC_INTERVAL], with newly connected
+/// peers three times as likely to be picked as they have nothing to offer yet
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// The currently deployed choking algorithm avoids fibrillation by only
+/// changing who's choked once every ten seconds. It does reciprocation and
+/// number of uploads capping by unchoking the four peers which it has the best
+/// download rates from and are interested. [...]
+///
+/// The one exception is that the downloader's optimistic unchoke, which is
+/// rotated once every thirty seconds, is unchoked regardless of its download
+/// rate. [...] In order to give them a decent chance of getting a complete
+/// piece to upload, new connections are three times as likely to start as the
+/// current optimistic unchoke as anywhere else in the rotation.
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Instant;
+/// use torro::peer::{ChokePeer, Choker, TitForTat};
+///
+/// fn main() {
+///     let mut choker = TitForTat::new().slots(2);
+///     let peers: Vec<ChokePeer> = (1..=3)
+///         .map(|ind| ChokePeer {
+///             addr: format!("10.0.0.{}:6881", ind).parse().unwrap(),
+///             download_rate: ind as f64 * 1000.0,
+///             upload_rate: 0.0,
+///             interested: true,
+///             connected_at: Instant::now(),
+///         })
+///         .collect();
+///
+///     let unchoked = choker.unchoke(&peers, false, Instant::now());
+///
+///     assert_eq!(unchoked.len(), 2);
+///     assert!(unchoked.contains(&peers[2].addr)); // fastest peer
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TitForTat {
+    /// Amount of peers to unchoke, including the optimistic unchoke
+    slots: usize,
+
+    /// Current optimistic unchoke and when it was picked
+    optimistic: Option<(SocketAddr, Instant)>,
+
+    /// Random numbers used to pick optimistic unchokes
+    rng: Xorshift,
+}
+
+impl TitForTat {
+    /// Creates a choker with [DEFAULT_UPLOAD_SLOTS] upload slots
+    pub fn new() -> Self {
+        Self {
+            slots: DEFAULT_UPLOAD_SLOTS,
+            optimistic: None,
+            rng: Xorshift::new(),
+        }
+    }
+
+    /// Sets the amount of peers to upload to at once, including the optimistic
+    /// unchoke
+    pub fn slots(mut self, slots: usize) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// Picks a new optimistic unchoke from `candidates`, weighting new peers
+    fn pick_optimistic(&mut self, candidates: &[&ChokePeer], now: Instant) -> Option<SocketAddr> {
+        let weight = |peer: &ChokePeer| {
+            if now.duration_since(peer.connected_at) < NEW_PEER_AGE {
+                3
+            } else {
+                1
+            }
+        };
+        let total: u64 = candidates.iter().map(|peer| weight(peer)).sum();
+
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = self.rng.next_u64() % total;
+
+        for peer in candidates {
+            if point < weight(peer) {
+                return Some(peer.addr);
+            }
+
+            point -= weight(peer);
+        }
+
+        None
+    }
+}
+
+impl Default for TitForTat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Choker for TitForTat {
+    fn unchoke(&mut self, peers: &[ChokePeer], seeding: bool, now: Instant) -> Vec<SocketAddr> {
+        let rate = |peer: &ChokePeer| {
+            if seeding {
+                peer.upload_rate
+            } else {
+                peer.download_rate
+            }
+        };
+        let mut interested: Vec<&ChokePeer> = peers.iter().filter(|peer| peer.interested).collect();
+
+        interested.sort_by(|a, b| rate(b).total_cmp(&rate(a)));
+
+        let regular = self.slots.saturating_sub(1).min(interested.len());
+        let mut unchoked: Vec<SocketAddr> =
+            interested[..regular].iter().map(|peer| peer.addr).collect();
+        let candidates = &interested[regular..];
+
+        if self.slots == 0 {
+            return unchoked;
+        }
+
+        let keep = match self.optimistic {
+            Some((addr, since)) => {
+                now.duration_since(since) < OPTIMISTIC_INTERVAL
+                    && candidates.iter().any(|peer| peer.addr == addr)
+            }
+            None => false,
+        };
+
+        if !keep {
+            self.optimistic = self
+                .pick_optimistic(candidates, now)
+                .map(|addr| (addr, now));
+        }
+
+        unchoked.extend(self.optimistic.map(|(addr, _)| addr));
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an interested peer `ind` with given rates, connected at `since`
+    fn peer(ind: u8, download_rate: f64, upload_rate: f64, since: Instant) -> ChokePeer {
+        ChokePeer {
+            addr: SocketAddr::from(([10, 0, 0, ind], 6881)),
+            download_rate,
+            upload_rate,
+            interested: true,
+            connected_at: since,
+        }
+    }
+
+    /// Tests that the fastest interested peers are unchoked, by download rate
+    /// while downloading and upload rate while seeding
+    #[test]
+    fn tit_for_tat_fastest() {
+        let now = Instant::now();
+        let mut peers: Vec<ChokePeer> = (1..=5)
+            .map(|ind| peer(ind, ind as f64, 10.0 - ind as f64, now))
+            .collect();
+        peers[4].interested = false;
+
+        let mut choker = TitForTat::new().slots(3);
+        let unchoked = choker.unchoke(&peers, false, now);
+
+        assert_eq!(unchoked.len(), 3);
+        assert_eq!(&unchoked[..2], &[peers[3].addr, peers[2].addr]);
+        assert!(!unchoked.contains(&peers[4].addr));
+
+        let unchoked = TitForTat::new().slots(3).unchoke(&peers, true, now);
+        assert_eq!(&unchoked[..2], &[peers[0].addr, peers[1].addr]);
+
+        assert!(TitForTat::new()
+            .slots(0)
+            .unchoke(&peers, false, now)
+            .is_empty());
+    }
+
+    /// Tests that the optimistic unchoke only moves every 30 seconds and
+    /// prefers newly connected peers
+    #[test]
+    fn tit_for_tat_optimistic() {
+        // in the future so old peers can be made without underflowing on
+        // machines which just started
+        let start = Instant::now() + NEW_PEER_AGE * 2;
+        let old = start - NEW_PEER_AGE * 2;
+        let peers = vec![
+            peer(1, 100.0, 0.0, old),
+            peer(2, 0.0, 0.0, old),
+            peer(3, 0.0, 0.0, old),
+            peer(4, 0.0, 0.0, start),
+        ];
+        let mut choker: Box<dyn Choker> = Box::new(TitForTat::new().slots(2));
+        let first = choker.unchoke(&peers, false, start);
+
+        assert_eq!(first[0], peers[0].addr);
+        assert_eq!(
+            choker.unchoke(&peers, false, start + Duration::from_secs(20)),
+            first
+        );
+
+        let mut counts = [0; 4];
+        let mut choker = TitForTat::new().slots(2);
+
+        for round in 0..600 {
+            let now = start + OPTIMISTIC_INTERVAL * round;
+            let mut peers = peers.clone();
+            peers[3].connected_at = now;
+
+            let optimistic = choker.unchoke(&peers, false, now)[1];
+            counts[peers
+                .iter()
+                .position(|peer| peer.addr == optimistic)
+                .unwrap()] += 1;
+        }
+
+        assert_eq!(counts[0], 0);
+        assert!(counts[3] > counts[1] && counts[3] > counts[2]);
+    }
+}
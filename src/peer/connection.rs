@@ -0,0 +1,1313 @@
+//! Per-peer connection state, tracking choking, interest, the peer's pieces and
+//! outstanding requests on top of the wire codec, following the rules of the
+//! fast extension when both sides support it
+
+use super::{Bitfield, ExtendedEvent, ExtendedHandshake, ExtensionRegistry, Handshake, Message};
+use crate::error::PeerError;
+use std::io::{Read, Write};
+
+/// Largest block length which may be requested, as BEP0003 says connections
+/// asking for more than 2^17 bytes are closed
+pub const MAX_REQUEST_LEN: u32 = 1 << 17;
+
+/// Most unanswered requests kept from a single peer, matching the `reqq`
+/// commonly advertised in extended handshakes. Requests past this are rejected
+/// with the fast extension and refused with [PeerError::TooManyRequests]
+/// otherwise
+pub const MAX_PEER_REQUESTS: usize = 250;
+
+/// A request for a single block of a piece, as sent in `request` and `cancel`
+/// messages
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct BlockRequest {
+    /// Index of the piece
+    pub index: u32,
+
+    /// Offset of the block inside of the piece
+    pub begin: u32,
+
+    /// Length of the block
+    pub length: u32,
+}
+
+/// Something a peer did which the download engine may want to act on, given
+/// by [PeerConnection::recv]
+#[derive(Debug, PartialEq, Clone)]
+pub enum PeerEvent {
+    /// The peer choked us, discarding the given requests of ours which were
+    /// still outstanding so they may be requested again elsewhere
+    Choked(Vec<BlockRequest>),
+
+    /// The peer unchoked us, so requests may be sent
+    Unchoked,
+
+    /// The peer wants pieces we have
+    Interested,
+
+    /// The peer no longer wants pieces we have
+    NotInterested,
+
+    /// The peer finished the piece at given index, which is now set in
+    /// [PeerConnection::peer_bitfield]
+    Have(u32),
+
+    /// The peer sent it's bitfield (or a `have all` or `have none` with the
+    /// fast extension), which is now in [PeerConnection::peer_bitfield]
+    Bitfield,
+
+    /// The peer asked for a block, which is kept in
+    /// [PeerConnection::peer_requests] until answered or cancelled. Repeated
+    /// requests give this event again but are only kept once
+    Request(BlockRequest),
+
+    /// The peer sent a block we requested
+    Block {
+        /// Index of the piece
+        index: u32,
+
+        /// Offset of the block inside of the piece
+        begin: u32,
+
+        /// Data of the block
+        block: Vec<u8>,
+    },
+
+    /// The peer cancelled an earlier request
+    Cancel(BlockRequest),
+
+    /// The peer's DHT node listens on given port
+    Port(u16),
+
+    /// The peer won't answer the given request of ours, so it may be requested
+    /// again elsewhere
+    Rejected(BlockRequest),
+
+    /// The peer suggests we download the piece at given index, typically as
+    /// it has it cached
+    Suggest(u32),
+
+    /// The peer allows us to request the piece at given index even while it's
+    /// choking us, which is now in [PeerConnection::peer_allowed_fast]
+    AllowedFast(u32),
+
+    /// The peer sent an extended message, which was routed through
+    /// [PeerConnection::extensions]
+    Extended(ExtendedEvent),
+}
+
+/// A connection to a single peer after the [Handshake], keeping track of the
+/// state both sides are in and refusing anything the peer wire protocol doesn't
+/// allow
+///
+/// Both sides start off choking and not interested. Any [Read] and [Write]
+/// stream may be used, typically a [TcpStream](std::net::TcpStream)
+///
+/// If both handshakes set the fast extension bit (see [Handshake::fast]), the
+/// connection follows [BEP0006](https://www.bittorrent.org/beps/bep_0006.html):
+/// requests are always answered with a block or a `reject request`, so a choke
+/// no longer drops our outstanding requests and requests we won't serve are
+/// rejected rather than ignored
+///
+/// Likewise if both set the extension protocol bit (see [Handshake::extended]),
+/// extended messages are routed through the connection's
+/// [ExtensionRegistry], with replies from extensions sent back straight away
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// Connections contain two bits of state on either end: choked or not, and
+/// interested or not. Choking is a notification that no data will be sent
+/// until unchoking happens. [...]
+///
+/// Data transfer takes place whenever one side is interested and the other
+/// side is not choking.
+/// ```
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::net::TcpStream;
+/// use torro::peer::{BlockRequest, Handshake, PeerConnection, PeerEvent};
+///
+/// fn main() {
+///     let stream = TcpStream::connect("127.0.0.1:6881").unwrap();
+///     let ours = Handshake::new([0; 20], *b"-TO0000-000000000000");
+///     let (mut conn, _theirs) = PeerConnection::handshake(stream, ours, 64).unwrap();
+///
+///     conn.interested().unwrap();
+///
+///     loop {
+///         match conn.recv().unwrap() {
+///             Some(PeerEvent::Unchoked) => conn
+///                 .request(BlockRequest { index: 0, begin: 0, length: 16384 })
+///                 .unwrap(),
+///             Some(PeerEvent::Block { block, .. }) => println!("Got {} bytes", block.len()),
+///             _ => (),
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PeerConnection<S> {
+    /// Stream to the peer
+    stream: S,
+
+    /// If we are choking the peer
+    am_choking: bool,
+
+    /// If we are interested in the peer
+    am_interested: bool,
+
+    /// If the peer is choking us
+    peer_choking: bool,
+
+    /// If the peer is interested in us
+    peer_interested: bool,
+
+    /// Pieces the peer has
+    peer_bitfield: Bitfield,
+
+    /// Our requests which the peer hasn't answered yet
+    requests: Vec<BlockRequest>,
+
+    /// Our cancelled requests which the peer still has to answer with a block
+    /// or a `reject request`, only kept with the fast extension
+    cancelled: Vec<BlockRequest>,
+
+    /// The peer's requests which we haven't answered yet
+    peer_requests: Vec<BlockRequest>,
+
+    /// If any message other than a keep-alive has been recieved, after which a
+    /// bitfield isn't allowed
+    recieved_any: bool,
+
+    /// If any message other than a keep-alive has been sent, after which a
+    /// bitfield isn't allowed
+    sent_any: bool,
+
+    /// Reused buffer for recieved messages
+    read_buf: Vec<u8>,
+
+    /// Reused buffer for sent messages
+    write_buf: Vec<u8>,
+
+    /// If the fast extension is used on this connection
+    fast: bool,
+
+    /// Pieces we allow the peer to request while we are choking it
+    allowed_fast: Vec<u32>,
+
+    /// Pieces the peer allows us to request while it's choking us
+    peer_allowed_fast: Vec<u32>,
+
+    /// If the extension protocol is used on this connection
+    extended: bool,
+
+    /// Extensions used on this connection
+    extensions: ExtensionRegistry,
+}
+
+impl<S: Read + Write> PeerConnection<S> {
+    /// Creates a connection over `stream` which has already been handshaked,
+    /// for a torrent with `num_pieces` pieces
+    pub fn new(stream: S, num_pieces: u32) -> Self {
+        Self {
+            stream,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer_bitfield: Bitfield::new(num_pieces),
+            requests: vec![],
+            cancelled: vec![],
+            peer_requests: vec![],
+            recieved_any: false,
+            sent_any: false,
+            read_buf: vec![],
+            write_buf: vec![],
+            fast: false,
+            allowed_fast: vec![],
+            peer_allowed_fast: vec![],
+            extended: false,
+            extensions: ExtensionRegistry::new(),
+        }
+    }
+
+    /// Uses the fast extension on this connection, which should only be done
+    /// if both handshakes had the fast bit set
+    pub fn fast(mut self) -> Self {
+        self.fast = true;
+        self
+    }
+
+    /// Uses the extension protocol on this connection, which should only be
+    /// done if both handshakes had the extension protocol bit set
+    pub fn extended(mut self) -> Self {
+        self.extended = true;
+        self
+    }
+
+    /// Sends `ours` over `stream` and reads the peer's handshake back, checking
+    /// it is for the same info-hash before creating a connection with
+    /// [PeerConnection::new]. The peer's handshake is also given
+    ///
+    /// The fast extension and extension protocol are each used if both `ours`
+    /// and the peer's handshake support them
+    pub fn handshake(
+        mut stream: S,
+        ours: Handshake,
+        num_pieces: u32,
+    ) -> Result<(Self, Handshake), PeerError> {
+        ours.write_to(&mut stream)?;
+
+        let theirs = Handshake::read_from(&mut stream)?;
+        theirs.verify(ours.info_hash)?;
+
+        let mut conn = Self::new(stream, num_pieces);
+        conn.fast = ours.supports_fast() && theirs.supports_fast();
+        conn.extended = ours.supports_extended() && theirs.supports_extended();
+
+        Ok((conn, theirs))
+    }
+
+    /// If we are choking the peer
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    /// If we are interested in the peer
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    /// If the peer is choking us
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    /// If the peer is interested in us
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    /// Pieces the peer has, from it's bitfield and `have` messages
+    pub fn peer_bitfield(&self) -> &Bitfield {
+        &self.peer_bitfield
+    }
+
+    /// Our requests which the peer hasn't answered yet
+    pub fn requests(&self) -> &[BlockRequest] {
+        &self.requests
+    }
+
+    /// The peer's requests which we haven't answered yet
+    pub fn peer_requests(&self) -> &[BlockRequest] {
+        &self.peer_requests
+    }
+
+    /// If the fast extension is used on this connection
+    pub fn is_fast(&self) -> bool {
+        self.fast
+    }
+
+    /// Pieces the peer allows us to request while it's choking us, from it's
+    /// `allowed fast` messages
+    pub fn peer_allowed_fast(&self) -> &[u32] {
+        &self.peer_allowed_fast
+    }
+
+    /// If the extension protocol is used on this connection
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    /// Extensions used on this connection
+    pub fn extensions(&self) -> &ExtensionRegistry {
+        &self.extensions
+    }
+
+    /// Extensions used on this connection, mutably so they can be registered
+    pub fn extensions_mut(&mut self) -> &mut ExtensionRegistry {
+        &mut self.extensions
+    }
+
+    /// Gets a reference to the underlying stream
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    /// Gets the underlying stream back, ending the connection
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Checks that piece `index` exists in this torrent
+    fn check_index(&self, index: u32) -> Result<(), PeerError> {
+        if index < self.peer_bitfield.len() {
+            Ok(())
+        } else {
+            Err(PeerError::BadPieceIndex(index))
+        }
+    }
+
+    /// Checks that `req` is for an existing piece and asks for an allowed
+    /// amount of bytes
+    fn check_request(&self, req: &BlockRequest) -> Result<(), PeerError> {
+        self.check_index(req.index)?;
+
+        if req.length == 0 || req.length > MAX_REQUEST_LEN {
+            return Err(PeerError::BadRequestLength(req.length));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the fast extension is used on this connection
+    fn check_fast(&self) -> Result<(), PeerError> {
+        if self.fast {
+            Ok(())
+        } else {
+            Err(PeerError::FastNotNegotiated)
+        }
+    }
+
+    /// Checks that the extension protocol is used on this connection
+    fn check_extended(&self) -> Result<(), PeerError> {
+        if self.extended {
+            Ok(())
+        } else {
+            Err(PeerError::ExtendedNotNegotiated)
+        }
+    }
+
+    /// Sends a `reject request` for `req` to the peer
+    fn send_reject(&mut self, req: BlockRequest) -> Result<(), PeerError> {
+        self.send(Message::RejectRequest {
+            index: req.index,
+            begin: req.begin,
+            length: req.length,
+        })
+    }
+
+    /// Sends `message` to the peer
+    fn send(&mut self, message: Message) -> Result<(), PeerError> {
+        if message != Message::KeepAlive {
+            self.sent_any = true;
+        }
+
+        message.write_to(&mut self.stream, &mut self.write_buf)
+    }
+
+    /// Reads the next message from the peer and updates the connection with
+    /// it, see [PeerConnection::handle]
+    pub fn recv(&mut self) -> Result<Option<PeerEvent>, PeerError> {
+        let message = Message::read_from(&mut self.stream, &mut self.read_buf)?;
+
+        self.handle(message)
+    }
+
+    /// Updates the connection with a `message` recieved from the peer, giving
+    /// the [PeerEvent] it caused or a [PeerError] if the message broke the
+    /// protocol, after which the connection should be closed
+    ///
+    /// Keep-alives and blocks which weren't requested (such as ones which
+    /// crossed paths with a `cancel`) give no event, nor do rejects answering
+    /// one of our cancels with the fast extension. With the fast extension,
+    /// requests we won't serve as we are choking the peer are rejected straight
+    /// away and give no event either, as do cancels, which are answered with a
+    /// `reject request`. The same goes for requests past
+    /// [MAX_PEER_REQUESTS], which are a [PeerError::TooManyRequests] without
+    /// the fast extension
+    pub fn handle(&mut self, message: Message) -> Result<Option<PeerEvent>, PeerError> {
+        if message == Message::KeepAlive {
+            return Ok(None);
+        }
+
+        let first = !self.recieved_any;
+        self.recieved_any = true;
+
+        match message {
+            Message::SuggestPiece(_)
+            | Message::HaveAll
+            | Message::HaveNone
+            | Message::RejectRequest { .. }
+            | Message::AllowedFast(_) => self.check_fast()?,
+            Message::Extended { .. } => self.check_extended()?,
+            _ => (),
+        }
+
+        Ok(Some(match message {
+            Message::KeepAlive => unreachable!(),
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone if !first => {
+                return Err(PeerError::LateBitfield)
+            }
+            Message::Bitfield(bytes) => {
+                self.peer_bitfield = Bitfield::from_bytes(&bytes, self.peer_bitfield.len())?;
+                PeerEvent::Bitfield
+            }
+            Message::HaveAll => {
+                self.peer_bitfield = Bitfield::full(self.peer_bitfield.len());
+                PeerEvent::Bitfield
+            }
+            Message::HaveNone => PeerEvent::Bitfield,
+            Message::Choke if self.fast => {
+                self.peer_choking = true;
+                PeerEvent::Choked(vec![])
+            }
+            Message::Choke => {
+                self.peer_choking = true;
+                PeerEvent::Choked(std::mem::take(&mut self.requests))
+            }
+            Message::Unchoke => {
+                self.peer_choking = false;
+                PeerEvent::Unchoked
+            }
+            Message::Interested => {
+                self.peer_interested = true;
+                PeerEvent::Interested
+            }
+            Message::NotInterested => {
+                self.peer_interested = false;
+                PeerEvent::NotInterested
+            }
+            Message::Have(index) => {
+                self.check_index(index)?;
+                self.peer_bitfield.set(index);
+                PeerEvent::Have(index)
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let req = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+
+                self.check_request(&req)?;
+
+                if self.am_choking && !self.allowed_fast.contains(&req.index) {
+                    if !self.fast {
+                        return Err(PeerError::RequestWhileChoked);
+                    }
+
+                    self.send_reject(req)?;
+                    return Ok(None);
+                }
+
+                if !self.peer_requests.contains(&req) {
+                    if self.peer_requests.len() >= MAX_PEER_REQUESTS {
+                        if !self.fast {
+                            return Err(PeerError::TooManyRequests);
+                        }
+
+                        self.send_reject(req)?;
+                        return Ok(None);
+                    }
+
+                    self.peer_requests.push(req);
+                }
+
+                PeerEvent::Request(req)
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                self.check_index(index)?;
+
+                let req = BlockRequest {
+                    index,
+                    begin,
+                    length: block.len() as u32,
+                };
+
+                match self.requests.iter().position(|other| *other == req) {
+                    Some(ind) => self.requests.remove(ind),
+                    None => {
+                        self.cancelled.retain(|other| *other != req);
+                        return Ok(None);
+                    }
+                };
+
+                PeerEvent::Block {
+                    index,
+                    begin,
+                    block,
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let req = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+
+                self.check_index(index)?;
+
+                let pending = self.peer_requests.contains(&req);
+                self.peer_requests.retain(|other| *other != req);
+
+                if self.fast && pending {
+                    self.send_reject(req)?;
+                }
+
+                PeerEvent::Cancel(req)
+            }
+            Message::Port(port) => PeerEvent::Port(port),
+            Message::SuggestPiece(index) => {
+                self.check_index(index)?;
+                PeerEvent::Suggest(index)
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                let req = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+
+                if let Some(ind) = self.cancelled.iter().position(|other| *other == req) {
+                    self.cancelled.remove(ind);
+                    return Ok(None);
+                }
+
+                match self.requests.iter().position(|other| *other == req) {
+                    Some(ind) => self.requests.remove(ind),
+                    None => return Err(PeerError::UnrequestedReject),
+                };
+
+                PeerEvent::Rejected(req)
+            }
+            Message::AllowedFast(index) => {
+                self.check_index(index)?;
+
+                if !self.peer_allowed_fast.contains(&index) {
+                    self.peer_allowed_fast.push(index);
+                }
+
+                PeerEvent::AllowedFast(index)
+            }
+            Message::Extended { id, payload } => {
+                let (event, reply) = self.extensions.handle(id, payload)?;
+
+                if let Some(reply) = reply {
+                    self.send(reply)?;
+                }
+
+                PeerEvent::Extended(event)
+            }
+        }))
+    }
+
+    /// Chokes the peer, discarding any of it's requests we haven't answered
+    ///
+    /// With the fast extension, requests for pieces in our allowed fast set
+    /// are kept and the rest are rejected
+    pub fn choke(&mut self) -> Result<(), PeerError> {
+        if self.am_choking {
+            return Ok(());
+        }
+
+        self.am_choking = true;
+        self.send(Message::Choke)?;
+
+        if !self.fast {
+            self.peer_requests.clear();
+            return Ok(());
+        }
+
+        let allowed_fast = &self.allowed_fast;
+        let (kept, rejected): (Vec<BlockRequest>, Vec<BlockRequest>) =
+            std::mem::take(&mut self.peer_requests)
+                .into_iter()
+                .partition(|req| allowed_fast.contains(&req.index));
+        self.peer_requests = kept;
+
+        for req in rejected {
+            self.send_reject(req)?;
+        }
+
+        Ok(())
+    }
+
+    /// Unchokes the peer, allowing it to send requests
+    pub fn unchoke(&mut self) -> Result<(), PeerError> {
+        if !self.am_choking {
+            return Ok(());
+        }
+
+        self.am_choking = false;
+        self.send(Message::Unchoke)
+    }
+
+    /// Tells the peer we want pieces it has
+    pub fn interested(&mut self) -> Result<(), PeerError> {
+        if self.am_interested {
+            return Ok(());
+        }
+
+        self.am_interested = true;
+        self.send(Message::Interested)
+    }
+
+    /// Tells the peer we no longer want pieces it has
+    pub fn not_interested(&mut self) -> Result<(), PeerError> {
+        if !self.am_interested {
+            return Ok(());
+        }
+
+        self.am_interested = false;
+        self.send(Message::NotInterested)
+    }
+
+    /// Sends our `bitfield`, which is only allowed before any other message
+    pub fn bitfield(&mut self, bitfield: &Bitfield) -> Result<(), PeerError> {
+        if self.sent_any {
+            return Err(PeerError::LateBitfield);
+        } else if bitfield.len() != self.peer_bitfield.len() {
+            return Err(PeerError::BadBitfield);
+        }
+
+        self.send(Message::Bitfield(bitfield.as_bytes().to_vec()))
+    }
+
+    /// Tells the peer we have every piece in place of a bitfield, which needs
+    /// the fast extension
+    pub fn have_all(&mut self) -> Result<(), PeerError> {
+        self.check_fast()?;
+
+        if self.sent_any {
+            return Err(PeerError::LateBitfield);
+        }
+
+        self.send(Message::HaveAll)
+    }
+
+    /// Tells the peer we have no pieces in place of a bitfield, which needs the
+    /// fast extension
+    pub fn have_none(&mut self) -> Result<(), PeerError> {
+        self.check_fast()?;
+
+        if self.sent_any {
+            return Err(PeerError::LateBitfield);
+        }
+
+        self.send(Message::HaveNone)
+    }
+
+    /// Tells the peer we finished the piece at `index`
+    pub fn have(&mut self, index: u32) -> Result<(), PeerError> {
+        self.check_index(index)?;
+        self.send(Message::Have(index))
+    }
+
+    /// Asks the peer for a block, which is only allowed while the peer isn't
+    /// choking us or the piece is in [PeerConnection::peer_allowed_fast].
+    /// Requests which are already outstanding aren't sent again
+    pub fn request(&mut self, req: BlockRequest) -> Result<(), PeerError> {
+        if self.peer_choking && !self.peer_allowed_fast.contains(&req.index) {
+            return Err(PeerError::RequestWhileChoked);
+        }
+
+        self.check_request(&req)?;
+
+        if self.requests.contains(&req) {
+            return Ok(());
+        }
+
+        self.requests.push(req);
+        self.send(Message::Request {
+            index: req.index,
+            begin: req.begin,
+            length: req.length,
+        })
+    }
+
+    /// Cancels an outstanding request, doing nothing if it isn't outstanding
+    ///
+    /// With the fast extension the peer still answers a cancel with the block
+    /// or a `reject request`, which are accepted without giving an event
+    pub fn cancel(&mut self, req: BlockRequest) -> Result<(), PeerError> {
+        let ind = match self.requests.iter().position(|other| *other == req) {
+            Some(ind) => ind,
+            None => return Ok(()),
+        };
+
+        self.requests.remove(ind);
+
+        if self.fast {
+            self.cancelled.push(req);
+        }
+
+        self.send(Message::Cancel {
+            index: req.index,
+            begin: req.begin,
+            length: req.length,
+        })
+    }
+
+    /// Sends a `block` of a piece to the peer, answering it's request for it
+    pub fn piece(&mut self, index: u32, begin: u32, block: Vec<u8>) -> Result<(), PeerError> {
+        let req = BlockRequest {
+            index,
+            begin,
+            length: block.len() as u32,
+        };
+
+        self.peer_requests.retain(|other| *other != req);
+        self.send(Message::Piece {
+            index,
+            begin,
+            block,
+        })
+    }
+
+    /// Tells the peer we won't answer it's request `req`, which needs the fast
+    /// extension
+    pub fn reject(&mut self, req: BlockRequest) -> Result<(), PeerError> {
+        self.check_fast()?;
+        self.peer_requests.retain(|other| *other != req);
+        self.send_reject(req)
+    }
+
+    /// Suggests the peer downloads the piece at `index`, which needs the fast
+    /// extension
+    pub fn suggest(&mut self, index: u32) -> Result<(), PeerError> {
+        self.check_fast()?;
+        self.check_index(index)?;
+        self.send(Message::SuggestPiece(index))
+    }
+
+    /// Allows the peer to request the piece at `index` even while we are
+    /// choking it, which needs the fast extension. The pieces to allow are
+    /// typically from [allowed_fast_set](super::allowed_fast_set)
+    pub fn allowed_fast(&mut self, index: u32) -> Result<(), PeerError> {
+        self.check_fast()?;
+        self.check_index(index)?;
+
+        if !self.allowed_fast.contains(&index) {
+            self.allowed_fast.push(index);
+        }
+
+        self.send(Message::AllowedFast(index))
+    }
+
+    /// Sends our extended `handshake`, typically made with
+    /// [ExtensionRegistry::handshake] from [PeerConnection::extensions]
+    pub fn extended_handshake(&mut self, handshake: &ExtendedHandshake) -> Result<(), PeerError> {
+        self.check_extended()?;
+        self.send(Message::Extended {
+            id: 0,
+            payload: handshake.encode(),
+        })
+    }
+
+    /// Sends `payload` to the peer for the extension called `name`, giving
+    /// [PeerError::ExtensionNotSupported] if the peer doesn't support it
+    pub fn extension_message(&mut self, name: &str, payload: Vec<u8>) -> Result<(), PeerError> {
+        self.check_extended()?;
+
+        let message = self.extensions.message(name, payload)?;
+        self.send(message)
+    }
+
+    /// Tells the peer which port our DHT node listens on
+    pub fn port(&mut self, port: u16) -> Result<(), PeerError> {
+        self.send(Message::Port(port))
+    }
+
+    /// Sends a keep-alive so the peer doesn't time the connection out
+    pub fn keep_alive(&mut self) -> Result<(), PeerError> {
+        self.send(Message::KeepAlive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// An in-memory stream which reads from scripted `incoming` bytes and
+    /// keeps everything written in `outgoing`
+    #[derive(Debug, Default)]
+    struct MemPipe {
+        incoming: Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+    }
+
+    impl MemPipe {
+        /// Creates a pipe which will read the given `messages`
+        fn with_messages(messages: &[Message]) -> Self {
+            let mut incoming = vec![];
+
+            for message in messages {
+                message.encode(&mut incoming);
+            }
+
+            Self {
+                incoming: Cursor::new(incoming),
+                outgoing: vec![],
+            }
+        }
+    }
+
+    impl Read for MemPipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for MemPipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.outgoing.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Tests a typical download exchange: bitfield, unchoke, request, block and
+    /// then a choke dropping outstanding requests
+    #[test]
+    fn connection_download_flow() {
+        let req = |begin| BlockRequest {
+            index: 1,
+            begin,
+            length: 4,
+        };
+        let pipe = MemPipe::with_messages(&[
+            Message::Bitfield(vec![0b0110_0000]),
+            Message::KeepAlive,
+            Message::Unchoke,
+            Message::Piece {
+                index: 1,
+                begin: 0,
+                block: vec![1, 2, 3, 4],
+            },
+            Message::Piece {
+                index: 1,
+                begin: 8,
+                block: vec![1, 2, 3, 4],
+            },
+            Message::Have(0),
+            Message::Choke,
+        ]);
+        let mut conn = PeerConnection::new(pipe, 3);
+
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::Bitfield)));
+        assert!(conn.peer_bitfield().get(1));
+        assert_eq!(conn.request(req(0)), Err(PeerError::RequestWhileChoked));
+        assert_eq!(conn.recv(), Ok(None));
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::Unchoked)));
+
+        conn.interested().unwrap();
+        conn.request(req(0)).unwrap();
+        conn.request(req(4)).unwrap();
+        conn.request(req(4)).unwrap();
+        assert_eq!(conn.requests(), &[req(0), req(4)]);
+
+        assert_eq!(
+            conn.recv(),
+            Ok(Some(PeerEvent::Block {
+                index: 1,
+                begin: 0,
+                block: vec![1, 2, 3, 4]
+            }))
+        );
+        assert_eq!(conn.recv(), Ok(None)); // never requested
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::Have(0))));
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::Choked(vec![req(4)]))));
+        assert!(conn.requests().is_empty());
+        assert!(conn.peer_choking());
+
+        let mut expected = vec![];
+        Message::Interested.encode(&mut expected);
+        Message::Request {
+            index: 1,
+            begin: 0,
+            length: 4,
+        }
+        .encode(&mut expected);
+        Message::Request {
+            index: 1,
+            begin: 4,
+            length: 4,
+        }
+        .encode(&mut expected);
+        assert_eq!(conn.into_inner().outgoing, expected);
+    }
+
+    /// Tests that requests while choked, bad indexes and late or malformed
+    /// bitfields are refused
+    #[test]
+    fn connection_violations() {
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 16384,
+        };
+        let mut conn = PeerConnection::new(MemPipe::default(), 10);
+
+        assert_eq!(
+            conn.handle(request.clone()),
+            Err(PeerError::RequestWhileChoked)
+        );
+        assert_eq!(
+            conn.handle(Message::Bitfield(vec![0xff, 0])),
+            Err(PeerError::LateBitfield)
+        );
+        assert_eq!(
+            conn.handle(Message::Have(10)),
+            Err(PeerError::BadPieceIndex(10))
+        );
+
+        conn.unchoke().unwrap();
+        assert_eq!(
+            conn.handle(request.clone()),
+            Ok(Some(PeerEvent::Request(BlockRequest {
+                index: 0,
+                begin: 0,
+                length: 16384
+            })))
+        );
+        assert_eq!(conn.peer_requests().len(), 1);
+        assert_eq!(
+            conn.handle(Message::Request {
+                index: 0,
+                begin: 0,
+                length: MAX_REQUEST_LEN + 1
+            }),
+            Err(PeerError::BadRequestLength(MAX_REQUEST_LEN + 1))
+        );
+
+        conn.choke().unwrap();
+        assert!(conn.peer_requests().is_empty());
+        assert_eq!(
+            conn.bitfield(&Bitfield::new(10)),
+            Err(PeerError::LateBitfield)
+        );
+
+        let mut conn = PeerConnection::new(MemPipe::default(), 10);
+        assert_eq!(
+            conn.handle(Message::Bitfield(vec![0xff, 0xff])),
+            Err(PeerError::BadBitfield)
+        );
+    }
+
+    /// Tests the fast extension: have all, allowed fast requests while choked,
+    /// rejects in place of dropped requests and refusing fast messages when
+    /// the extension wasn't negotiated
+    #[test]
+    fn connection_fast() {
+        let req = |index| BlockRequest {
+            index,
+            begin: 0,
+            length: 4,
+        };
+        let pipe = MemPipe::with_messages(&[
+            Message::HaveAll,
+            Message::AllowedFast(2),
+            Message::SuggestPiece(1),
+            Message::Choke,
+            Message::RejectRequest {
+                index: 2,
+                begin: 0,
+                length: 4,
+            },
+            Message::RejectRequest {
+                index: 2,
+                begin: 0,
+                length: 4,
+            },
+        ]);
+        let mut conn = PeerConnection::new(pipe, 3).fast();
+
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::Bitfield)));
+        assert!(conn.peer_bitfield().is_full());
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::AllowedFast(2))));
+        assert_eq!(conn.request(req(1)), Err(PeerError::RequestWhileChoked));
+        conn.request(req(2)).unwrap();
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::Suggest(1))));
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::Choked(vec![]))));
+        assert_eq!(conn.requests(), &[req(2)]);
+        assert_eq!(conn.recv(), Ok(Some(PeerEvent::Rejected(req(2)))));
+        assert_eq!(conn.recv(), Err(PeerError::UnrequestedReject));
+
+        let reject = |index| Message::RejectRequest {
+            index,
+            begin: 0,
+            length: 4,
+        };
+        let mut conn = PeerConnection::new(MemPipe::default(), 3).fast();
+        conn.handle(Message::Unchoke).unwrap();
+        conn.request(req(0)).unwrap();
+        conn.request(req(1)).unwrap();
+        conn.cancel(req(0)).unwrap();
+        conn.cancel(req(1)).unwrap();
+        assert!(conn.requests().is_empty());
+        assert_eq!(conn.handle(reject(0)), Ok(None));
+        assert_eq!(
+            conn.handle(Message::Piece {
+                index: 1,
+                begin: 0,
+                block: vec![0; 4]
+            }),
+            Ok(None)
+        );
+        assert_eq!(conn.handle(reject(0)), Err(PeerError::UnrequestedReject));
+        assert_eq!(conn.handle(reject(1)), Err(PeerError::UnrequestedReject));
+
+        let mut conn = PeerConnection::new(MemPipe::default(), 3).fast();
+        conn.have_none().unwrap();
+        conn.allowed_fast(0).unwrap();
+        assert_eq!(
+            conn.handle(Message::HaveNone),
+            Ok(Some(PeerEvent::Bitfield))
+        );
+        assert_eq!(conn.handle(Message::HaveAll), Err(PeerError::LateBitfield));
+        assert_eq!(
+            conn.handle(Message::Request {
+                index: 1,
+                begin: 0,
+                length: 4
+            }),
+            Ok(None)
+        );
+        assert_eq!(
+            conn.handle(Message::Request {
+                index: 0,
+                begin: 0,
+                length: 4
+            }),
+            Ok(Some(PeerEvent::Request(req(0))))
+        );
+
+        conn.unchoke().unwrap();
+        conn.handle(Message::Request {
+            index: 1,
+            begin: 0,
+            length: 4,
+        })
+        .unwrap();
+        conn.choke().unwrap();
+        assert_eq!(conn.peer_requests(), &[req(0)]);
+
+        let mut expected = vec![];
+        for message in [
+            Message::HaveNone,
+            Message::AllowedFast(0),
+            Message::RejectRequest {
+                index: 1,
+                begin: 0,
+                length: 4,
+            },
+            Message::Unchoke,
+            Message::Choke,
+            Message::RejectRequest {
+                index: 1,
+                begin: 0,
+                length: 4,
+            },
+        ] {
+            message.encode(&mut expected);
+        }
+        assert_eq!(conn.into_inner().outgoing, expected);
+
+        let mut conn = PeerConnection::new(MemPipe::default(), 3);
+        assert_eq!(
+            conn.handle(Message::HaveAll),
+            Err(PeerError::FastNotNegotiated)
+        );
+        assert_eq!(conn.have_none(), Err(PeerError::FastNotNegotiated));
+    }
+
+    /// Tests that requests past [MAX_PEER_REQUESTS] are rejected with the fast
+    /// extension and refused without it, while repeats of kept ones aren't
+    #[test]
+    fn connection_request_cap() {
+        let request = |begin| Message::Request {
+            index: 0,
+            begin,
+            length: 4,
+        };
+
+        for fast in [false, true] {
+            let mut conn = PeerConnection::new(MemPipe::default(), 1);
+            conn.fast = fast;
+            conn.unchoke().unwrap();
+
+            for begin in 0..MAX_PEER_REQUESTS as u32 {
+                assert!(conn.handle(request(begin)).unwrap().is_some());
+            }
+
+            assert!(conn.handle(request(0)).unwrap().is_some());
+            assert_eq!(conn.peer_requests().len(), MAX_PEER_REQUESTS);
+
+            let over = conn.handle(request(MAX_PEER_REQUESTS as u32));
+            assert_eq!(conn.peer_requests().len(), MAX_PEER_REQUESTS);
+
+            if fast {
+                assert_eq!(over, Ok(None));
+
+                let mut expected = vec![];
+                Message::Unchoke.encode(&mut expected);
+                Message::RejectRequest {
+                    index: 0,
+                    begin: MAX_PEER_REQUESTS as u32,
+                    length: 4,
+                }
+                .encode(&mut expected);
+                assert_eq!(conn.into_inner().outgoing, expected);
+            } else {
+                assert_eq!(over, Err(PeerError::TooManyRequests));
+            }
+        }
+    }
+
+    /// Replies to every extended message with it's payload reversed
+    struct Reverse;
+
+    impl crate::peer::Extension for Reverse {
+        fn name(&self) -> &str {
+            "reverse"
+        }
+
+        fn handle(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>, PeerError> {
+            Ok(Some(payload.iter().rev().copied().collect()))
+        }
+    }
+
+    /// Tests that extended messages are routed through the registry with
+    /// replies sent back, and refused when the extension protocol isn't used
+    #[test]
+    fn connection_extended() {
+        let pipe = MemPipe::with_messages(&[
+            Message::Extended {
+                id: 0,
+                payload: b"d1:md7:reversei3eee".to_vec(),
+            },
+            Message::Extended {
+                id: 1,
+                payload: vec![1, 2],
+            },
+            Message::Extended {
+                id: 7,
+                payload: vec![],
+            },
+        ]);
+        let mut conn = PeerConnection::new(pipe, 1).extended();
+        conn.extensions_mut().register(Reverse);
+
+        let ours = conn.extensions().handshake();
+        conn.extended_handshake(&ours).unwrap();
+        assert_eq!(
+            conn.extension_message("reverse", vec![]),
+            Err(PeerError::ExtensionNotSupported("reverse".to_string()))
+        );
+        assert_eq!(
+            conn.recv(),
+            Ok(Some(PeerEvent::Extended(ExtendedEvent::Handshake)))
+        );
+        assert_eq!(
+            conn.recv(),
+            Ok(Some(PeerEvent::Extended(ExtendedEvent::Message {
+                name: "reverse".to_string(),
+                payload: vec![1, 2]
+            })))
+        );
+        assert_eq!(conn.recv(), Err(PeerError::UnknownExtension(7)));
+        conn.extension_message("reverse", vec![5]).unwrap();
+
+        let mut expected = vec![];
+        for message in [
+            Message::Extended {
+                id: 0,
+                payload: b"d1:md7:reversei1eee".to_vec(),
+            },
+            Message::Extended {
+                id: 3,
+                payload: vec![2, 1],
+            },
+            Message::Extended {
+                id: 3,
+                payload: vec![5],
+            },
+        ] {
+            message.encode(&mut expected);
+        }
+        assert_eq!(conn.into_inner().outgoing, expected);
+
+        let mut conn = PeerConnection::new(MemPipe::default(), 1);
+        assert_eq!(
+            conn.handle(Message::Extended {
+                id: 0,
+                payload: b"de".to_vec()
+            }),
+            Err(PeerError::ExtendedNotNegotiated)
+        );
+    }
+
+    /// Tests that [PeerConnection::handshake] enables the fast extension only
+    /// when both sides support it
+    #[test]
+    fn connection_handshake_fast() {
+        let mut incoming = vec![];
+        Handshake::new([1; 20], [3; 20])
+            .fast()
+            .encode(&mut incoming);
+
+        let pipe = MemPipe {
+            incoming: Cursor::new(incoming.clone()),
+            outgoing: vec![],
+        };
+        let ours = Handshake::new([1; 20], [2; 20]);
+        let (conn, _) = PeerConnection::handshake(pipe, ours.fast(), 1).unwrap();
+        assert!(conn.is_fast());
+
+        let pipe = MemPipe {
+            incoming: Cursor::new(incoming),
+            outgoing: vec![],
+        };
+        let (conn, _) = PeerConnection::handshake(pipe, ours, 1).unwrap();
+        assert!(!conn.is_fast());
+    }
+
+    /// Tests that [PeerConnection::handshake] checks the peer's info-hash
+    #[test]
+    fn connection_handshake() {
+        let ours = Handshake::new([1; 20], [2; 20]);
+        let mut incoming = vec![];
+        Handshake::new([1; 20], [3; 20]).encode(&mut incoming);
+
+        let pipe = MemPipe {
+            incoming: Cursor::new(incoming.clone()),
+            outgoing: vec![],
+        };
+        let (conn, theirs) = PeerConnection::handshake(pipe, ours, 1).unwrap();
+
+        assert_eq!(theirs.peer_id, [3; 20]);
+        assert_eq!(conn.stream().outgoing.len(), 68);
+
+        incoming[30] = 9;
+        let pipe = MemPipe {
+            incoming: Cursor::new(incoming),
+            outgoing: vec![],
+        };
+        assert_eq!(
+            PeerConnection::handshake(pipe, ours, 1).err(),
+            Some(PeerError::InfoHashMismatch)
+        );
+    }
+}
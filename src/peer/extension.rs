@@ -0,0 +1,547 @@
+//! The [BEP0010](https://www.bittorrent.org/beps/bep_0010.html) extension
+//! protocol, with a registry which applications add their own extensions to
+
+use super::Message;
+use crate::bencode::{self, Bencode};
+use crate::error::PeerError;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Extended message id of the [ExtendedHandshake], every other id being one
+/// the reciever gave an extension in it's own handshake
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The extended handshake, sent as the first extended message after both
+/// sides set the extension protocol bit in their [Handshake](super::Handshake)s
+///
+/// Every field but [ExtendedHandshake::extensions] is optional, as are any
+/// fields a peer sends which torro doesn't know about. Handshakes may also be
+/// sent again later on, in which case only the extensions given are updated
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// The payload of the handshake message is a bencoded dictionary. All items in
+/// the dictionary are optional. Any unknown names should be ignored by the
+/// client. All parts of the dictionary are case sensitive.
+///
+/// m       Dictionary of supported extension messages which maps names of
+///         extensions to an extended message ID for each extension message.
+///         [...] the ID 0 is used to signal that the extension is disabled.
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::peer::ExtendedHandshake;
+///
+/// fn main() {
+///     let mut handshake = ExtendedHandshake::default();
+///     handshake.extensions.insert("ut_pex".to_string(), 1);
+///     handshake.port = Some(6881);
+///
+///     let payload = handshake.encode();
+///
+///     assert_eq!(payload, b"d1:md6:ut_pexi1ee1:pi6881ee");
+///     assert_eq!(ExtendedHandshake::decode(&payload), Ok(handshake));
+/// }
+/// ```
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ExtendedHandshake {
+    /// Names of supported extensions mapped to the extended message id the
+    /// sender wants to recieve them with, an id of `0` disabling it (`m`)
+    pub extensions: BTreeMap<String, u8>,
+
+    /// Name and version of the sending client (`v`)
+    pub version: Option<String>,
+
+    /// Local TCP port the sender listens on (`p`)
+    pub port: Option<u16>,
+
+    /// Amount of outstanding requests the sender accepts before dropping
+    /// any more (`reqq`)
+    pub reqq: Option<u32>,
+
+    /// Address the sender sees the reciever at (`yourip`)
+    pub yourip: Option<IpAddr>,
+
+    /// Size of the info dictionary in bytes, from
+    /// [BEP0009](https://www.bittorrent.org/beps/bep_0009.html) (`metadata_size`)
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    /// Encodes this handshake into the payload of an extended message
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Bencode::Int(*id as i64)))
+            .collect();
+
+        dict.insert(b"m".to_vec(), Bencode::Dict(extensions));
+
+        if let Some(version) = &self.version {
+            dict.insert(
+                b"v".to_vec(),
+                Bencode::ByteString(version.as_bytes().to_vec()),
+            );
+        }
+
+        if let Some(port) = self.port {
+            dict.insert(b"p".to_vec(), Bencode::Int(port as i64));
+        }
+
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq".to_vec(), Bencode::Int(reqq as i64));
+        }
+
+        if let Some(yourip) = self.yourip {
+            let bytes = match yourip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+
+            dict.insert(b"yourip".to_vec(), Bencode::ByteString(bytes));
+        }
+
+        if let Some(metadata_size) = self.metadata_size {
+            dict.insert(
+                b"metadata_size".to_vec(),
+                Bencode::Int(metadata_size as i64),
+            );
+        }
+
+        bencode::encode(&Bencode::Dict(dict))
+    }
+
+    /// Decodes a handshake from the `payload` of an extended message, giving
+    /// [PeerError::BadExtendedHandshake] if it isn't a bencoded dictionary
+    ///
+    /// Fields with the wrong type or out of range are ignored rather than
+    /// failing the whole handshake, as are extensions with names which aren't
+    /// valid UTF-8
+    pub fn decode(payload: &[u8]) -> Result<Self, PeerError> {
+        let mut dict = match bencode::parse_slice(payload).map(|bencode| bencode.dict()) {
+            Ok(Some(dict)) => dict,
+            _ => return Err(PeerError::BadExtendedHandshake),
+        };
+        let mut int = |key: &[u8]| dict.remove(key).and_then(|value| value.int());
+        let port = int(b"p").and_then(|port| port.try_into().ok());
+        let reqq = int(b"reqq").and_then(|reqq| reqq.try_into().ok());
+        let metadata_size = int(b"metadata_size").and_then(|size| size.try_into().ok());
+        let mut extensions = BTreeMap::new();
+
+        for (name, id) in dict
+            .remove(&b"m"[..])
+            .and_then(|m| m.dict())
+            .unwrap_or_default()
+        {
+            let id = id.int().and_then(|id| id.try_into().ok());
+
+            if let (Ok(name), Some(id)) = (String::from_utf8(name), id) {
+                extensions.insert(name, id);
+            }
+        }
+
+        let version = dict
+            .remove(&b"v"[..])
+            .and_then(|version| version.bytestring())
+            .map(|version| String::from_utf8_lossy(&version).into_owned());
+        let yourip = dict
+            .remove(&b"yourip"[..])
+            .and_then(|ip| ip.bytestring())
+            .and_then(|ip| match ip.len() {
+                4 => ip
+                    .try_into()
+                    .ok()
+                    .map(|octets: [u8; 4]| IpAddr::from(octets)),
+                16 => ip
+                    .try_into()
+                    .ok()
+                    .map(|octets: [u8; 16]| IpAddr::from(octets)),
+                _ => None,
+            });
+
+        Ok(Self {
+            extensions,
+            version,
+            port,
+            reqq,
+            yourip,
+            metadata_size,
+        })
+    }
+}
+
+/// An extension to the peer wire protocol, such as `ut_pex`, which is added to
+/// an [ExtensionRegistry] so it's messages get routed to it
+///
+/// Extensions are kept per-connection, so any state they hold is about a
+/// single peer. They can be got back from the registry with
+/// [ExtensionRegistry::get] to read what they gathered
+pub trait Extension: Any {
+    /// Name of the extension, as given in the `m` dictionary of the
+    /// [ExtendedHandshake] (e.g. `ut_pex`)
+    fn name(&self) -> &str;
+
+    /// Adds anything this extension needs to our handshake, such as
+    /// [ExtendedHandshake::metadata_size] for `ut_metadata`
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called with every handshake the peer sends, after the registry has
+    /// updated which extensions the peer supports
+    fn peer_handshake(&mut self, _handshake: &ExtendedHandshake) {}
+
+    /// Handles the `payload` of a message the peer sent for this extension,
+    /// optionally giving the payload of a reply to send back. Errors close the
+    /// connection, so should only be given for peers breaking the extension
+    fn handle(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>, PeerError>;
+}
+
+/// Something which happened on the extension protocol, given by
+/// [ExtensionRegistry::handle]
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExtendedEvent {
+    /// The peer sent it's handshake, which is now in
+    /// [ExtensionRegistry::peer_handshake]
+    Handshake,
+
+    /// The peer sent a message which was handled by the extension with given
+    /// name, with the original payload also given
+    Message {
+        /// Name of the extension which handled the message
+        name: String,
+
+        /// Payload of the message
+        payload: Vec<u8>,
+    },
+}
+
+/// The extensions used on one connection, routing extended messages between
+/// them and the peer
+///
+/// Extensions we support are given ids by the order they where registered
+/// in, starting from `1`, which the peer uses when sending us messages. The
+/// peer gives it's own ids in it's [ExtendedHandshake], which are used for
+/// messages we send
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::error::PeerError;
+/// use torro::peer::{ExtendedEvent, Extension, ExtensionRegistry, Message};
+///
+/// /// Replies to every message with the same payload
+/// struct Echo;
+///
+/// impl Extension for Echo {
+///     fn name(&self) -> &str {
+///         "echo"
+///     }
+///
+///     fn handle(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>, PeerError> {
+///         Ok(Some(payload.to_vec()))
+///     }
+/// }
+///
+/// fn main() {
+///     let mut registry = ExtensionRegistry::new();
+///     assert_eq!(registry.register(Echo), 1);
+///
+///     // the peer wants echo messages with id 5
+///     registry.handle(0, b"d1:md4:echoi5eee".to_vec()).unwrap();
+///
+///     let (event, reply) = registry.handle(1, b"hi".to_vec()).unwrap();
+///
+///     assert_eq!(event, ExtendedEvent::Message { name: "echo".to_string(), payload: b"hi".to_vec() });
+///     assert_eq!(reply, Some(Message::Extended { id: 5, payload: b"hi".to_vec() }));
+/// }
+/// ```
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    /// Registered extensions, where the extension at index `i` has id `i + 1`
+    extensions: Vec<Box<dyn Extension>>,
+
+    /// Ids the peer gave extensions in it's handshakes
+    peer_ids: BTreeMap<String, u8>,
+
+    /// Latest handshake recieved from the peer
+    peer_handshake: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    /// Creates a registry without any extensions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `extension`, giving the id the peer should send it's messages
+    /// with. Registering an extension with the same name as an existing one
+    /// replaces it, keeping the same id
+    ///
+    /// # Panics
+    ///
+    /// If over 255 extensions are registered, as ids are single bytes
+    pub fn register(&mut self, extension: impl Extension) -> u8 {
+        if let Some(id) = self.id(extension.name()) {
+            self.extensions[id as usize - 1] = Box::new(extension);
+            return id;
+        }
+
+        assert!(
+            self.extensions.len() < 255,
+            "Too many extensions registered"
+        );
+
+        self.extensions.push(Box::new(extension));
+        self.extensions.len() as u8
+    }
+
+    /// Gets the id the peer should send messages for the extension called
+    /// `name` with, if it's registered
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .position(|extension| extension.name() == name)
+            .map(|ind| ind as u8 + 1)
+    }
+
+    /// Gets the id the peer gave the extension called `name`, if it supports
+    /// it
+    pub fn peer_id(&self, name: &str) -> Option<u8> {
+        self.peer_ids.get(name).copied()
+    }
+
+    /// If the peer supports the extension called `name`
+    pub fn peer_supports(&self, name: &str) -> bool {
+        self.peer_ids.contains_key(name)
+    }
+
+    /// Latest handshake recieved from the peer, if any
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    /// Gets the registered extension of type `T`
+    pub fn get<T: Extension>(&self) -> Option<&T> {
+        self.extensions
+            .iter()
+            .find_map(|extension| (extension.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    /// Gets the registered extension of type `T` mutably
+    pub fn get_mut<T: Extension>(&mut self) -> Option<&mut T> {
+        self.extensions
+            .iter_mut()
+            .find_map(|extension| (extension.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Creates our handshake, listing every registered extension and letting
+    /// each add what it needs. Other fields such as
+    /// [ExtendedHandshake::version] may be set before sending it
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake::default();
+
+        for (ind, extension) in self.extensions.iter().enumerate() {
+            handshake
+                .extensions
+                .insert(extension.name().to_string(), ind as u8 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+
+        handshake
+    }
+
+    /// Creates a message for the extension called `name` with given
+    /// `payload`, using the id the peer gave it. Gives
+    /// [PeerError::ExtensionNotSupported] if the peer doesn't support it
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Result<Message, PeerError> {
+        match self.peer_id(name) {
+            Some(id) => Ok(Message::Extended { id, payload }),
+            None => Err(PeerError::ExtensionNotSupported(name.to_string())),
+        }
+    }
+
+    /// Routes an extended message with given `id` and `payload` from the
+    /// peer, giving what happened and any reply which should be sent back
+    ///
+    /// Handshakes update which extensions the peer supports and every other id
+    /// is handed to the extension it belongs to, giving
+    /// [PeerError::UnknownExtension] if there is no such extension. Replies
+    /// for extensions the peer doesn't support are dropped
+    pub fn handle(
+        &mut self,
+        id: u8,
+        payload: Vec<u8>,
+    ) -> Result<(ExtendedEvent, Option<Message>), PeerError> {
+        if id == EXTENDED_HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::decode(&payload)?;
+
+            for (name, peer_id) in handshake.extensions.iter() {
+                if *peer_id == 0 {
+                    self.peer_ids.remove(name);
+                } else {
+                    self.peer_ids.insert(name.clone(), *peer_id);
+                }
+            }
+
+            for extension in self.extensions.iter_mut() {
+                extension.peer_handshake(&handshake);
+            }
+
+            self.peer_handshake = Some(handshake);
+            return Ok((ExtendedEvent::Handshake, None));
+        }
+
+        let extension = match self.extensions.get_mut(id as usize - 1) {
+            Some(extension) => extension,
+            None => return Err(PeerError::UnknownExtension(id)),
+        };
+        let name = extension.name().to_string();
+        let reply = extension.handle(&payload)?;
+        let reply = reply.and_then(|reply| self.message(&name, reply).ok());
+
+        Ok((ExtendedEvent::Message { name, payload }, reply))
+    }
+}
+
+impl fmt::Debug for ExtensionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self
+            .extensions
+            .iter()
+            .map(|extension| extension.name())
+            .collect();
+
+        f.debug_struct("ExtensionRegistry")
+            .field("extensions", &names)
+            .field("peer_ids", &self.peer_ids)
+            .field("peer_handshake", &self.peer_handshake)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the messages it gets and replies with the count
+    #[derive(Debug, Default)]
+    struct Counter {
+        count: u8,
+        peer_version: Option<String>,
+    }
+
+    impl Extension for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+            handshake.metadata_size = Some(1234);
+        }
+
+        fn peer_handshake(&mut self, handshake: &ExtendedHandshake) {
+            self.peer_version = handshake.version.clone();
+        }
+
+        fn handle(&mut self, _payload: &[u8]) -> Result<Option<Vec<u8>>, PeerError> {
+            self.count += 1;
+            Ok(Some(vec![self.count]))
+        }
+    }
+
+    /// Tests decoding the example handshake given in BEP0010 and that every
+    /// field encodes and decodes back
+    #[test]
+    fn extended_handshake_bep10_example() {
+        let example = b"d1:md11:LT_metadatai1e6:\xb5T_PEXi2ee1:pi6881e1:v13:\xc2\xb5Torrent 1.2e";
+        let handshake = ExtendedHandshake::decode(example).unwrap();
+
+        assert_eq!(handshake.extensions.get("LT_metadata"), Some(&1));
+        assert_eq!(handshake.extensions.len(), 1); // latin-1 name isn't utf-8
+        assert_eq!(handshake.port, Some(6881));
+        assert_eq!(handshake.version.as_deref(), Some("µTorrent 1.2"));
+        assert_eq!(handshake.reqq, None);
+
+        let handshake = ExtendedHandshake {
+            extensions: handshake.extensions,
+            version: Some("torro".to_string()),
+            port: Some(6881),
+            reqq: Some(250),
+            yourip: Some("2001:db8::1".parse().unwrap()),
+            metadata_size: Some(31235),
+        };
+        assert_eq!(
+            ExtendedHandshake::decode(&handshake.encode()),
+            Ok(handshake)
+        );
+
+        let odd =
+            ExtendedHandshake::decode(b"d1:md1:ai-1e1:bi7ee1:pi99999e6:yourip3:abce").unwrap();
+        assert_eq!(odd.extensions.len(), 1);
+        assert_eq!(odd.port, None);
+        assert_eq!(odd.yourip, None);
+        assert_eq!(
+            ExtendedHandshake::decode(b"li1ee"),
+            Err(PeerError::BadExtendedHandshake)
+        );
+    }
+
+    /// Tests that the registry routes messages both ways and follows the
+    /// peer enabling and disabling extensions
+    #[test]
+    fn registry_routing() {
+        let mut registry = ExtensionRegistry::new();
+        assert_eq!(registry.register(Counter::default()), 1);
+
+        let ours = registry.handshake();
+        assert_eq!(ours.extensions.get("counter"), Some(&1));
+        assert_eq!(ours.metadata_size, Some(1234));
+
+        assert_eq!(
+            registry.message("counter", vec![]),
+            Err(PeerError::ExtensionNotSupported("counter".to_string()))
+        );
+        assert_eq!(
+            registry.handle(1, vec![]).unwrap().1,
+            None // peer hasn't said it supports counter yet
+        );
+
+        registry
+            .handle(0, b"d1:md7:counteri9ee1:v3:abce".to_vec())
+            .unwrap();
+        assert!(registry.peer_supports("counter"));
+        assert_eq!(
+            registry.handle(1, vec![0]),
+            Ok((
+                ExtendedEvent::Message {
+                    name: "counter".to_string(),
+                    payload: vec![0]
+                },
+                Some(Message::Extended {
+                    id: 9,
+                    payload: vec![2]
+                })
+            ))
+        );
+        assert_eq!(
+            registry.handle(2, vec![]),
+            Err(PeerError::UnknownExtension(2))
+        );
+
+        let counter = registry.get::<Counter>().unwrap();
+        assert_eq!(counter.count, 2);
+        assert_eq!(counter.peer_version.as_deref(), Some("abc"));
+
+        registry.handle(0, b"d1:md7:counteri0eee".to_vec()).unwrap();
+        assert!(!registry.peer_supports("counter"));
+        assert_eq!(registry.register(Counter::default()), 1);
+        assert_eq!(registry.get_mut::<Counter>().unwrap().count, 0);
+    }
+}
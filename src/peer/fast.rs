@@ -0,0 +1,93 @@
+//! Parts of the [BEP0006](https://www.bittorrent.org/beps/bep_0006.html) fast
+//! extension which aren't messages, namely the allowed fast set
+
+use crate::sha1::sha1;
+use std::convert::TryInto;
+use std::net::Ipv4Addr;
+
+/// Amount of pieces typically given in an allowed fast set
+pub const ALLOWED_FAST_COUNT: u32 = 10;
+
+/// Computes the `k` pieces a peer at `ip` may request from us while choked
+/// for the torrent with given `info_hash` and `num_pieces` pieces, so the set
+/// is the same every time the peer connects and can't be grown by a peer
+/// reconnecting from addresses close to it
+///
+/// Less than `k` pieces are given if the torrent doesn't have that many
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// x = 0xFFFFFF00 & ip (1)
+/// x.append(infohash) (2)
+/// while |a| < k:
+///   x = SHA1(x) (3)
+///   for i in [0:5] and |a| < k: (4)
+///     j = i*4 (5)
+///     y = x[j:j+4] (6)
+///     y %= sz (7)
+///     if y not in a: (8)
+///       add y to a (9)
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::peer::allowed_fast_set;
+///
+/// fn main() {
+///     let set = allowed_fast_set("80.4.4.200".parse().unwrap(), [0xaa; 20], 1313, 7);
+///
+///     assert_eq!(set, vec![1059, 431, 808, 1217, 287, 376, 1188]);
+/// }
+/// ```
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: [u8; 20], num_pieces: u32, k: u32) -> Vec<u32> {
+    let k = k.min(num_pieces) as usize;
+    let mut set = Vec::with_capacity(k);
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(&info_hash);
+
+    while set.len() < k {
+        x = sha1(&x).to_vec();
+
+        for chunk in x.chunks(4) {
+            if set.len() >= k {
+                break;
+            }
+
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % num_pieces;
+
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests [allowed_fast_set] against the test vectors given in BEP0006
+    #[test]
+    fn allowed_fast_bep6_vectors() {
+        let ip = "80.4.4.200".parse().unwrap();
+
+        assert_eq!(
+            allowed_fast_set(ip, [0xaa; 20], 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, [0xaa; 20], 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        assert_eq!(
+            allowed_fast_set("80.4.4.1".parse().unwrap(), [0xaa; 20], 1313, 9),
+            allowed_fast_set(ip, [0xaa; 20], 1313, 9)
+        );
+        assert_eq!(allowed_fast_set(ip, [0xaa; 20], 3, 10).len(), 3);
+        assert!(allowed_fast_set(ip, [0xaa; 20], 0, 10).is_empty());
+    }
+}
@@ -0,0 +1,33 @@
+//! Talking to other peers over the
+//! [BEP0003](https://www.bittorrent.org/beps/bep_0003.html) peer wire protocol,
+//! including the [BEP0006](https://www.bittorrent.org/beps/bep_0006.html) fast
+//! extension and [BEP0010](https://www.bittorrent.org/beps/bep_0010.html)
+//! extension protocol
+//!
+//! See [Handshake] for opening a connection and [Message] for everything sent
+//! after it, or [PeerConnection] for a connection which keeps track of the
+//! state of both sides. Which pieces to ask peers for is decided by a
+//! [PiecePicker], with [PieceDownloads] splitting them into block requests and
+//! checking them once downloaded. Which peers to upload to is decided by a
+//! [Choker]. Extensions to the protocol are added to a connection's
+//! [ExtensionRegistry], such as [Pex] for peer exchange
+
+mod bitfield;
+mod blocks;
+mod choker;
+mod connection;
+mod extension;
+mod fast;
+mod pex;
+mod picker;
+mod wire;
+
+pub use bitfield::*;
+pub use blocks::*;
+pub use choker::*;
+pub use connection::*;
+pub use extension::*;
+pub use fast::*;
+pub use pex::*;
+pub use picker::*;
+pub use wire::*;
@@ -0,0 +1,447 @@
+//! Peer exchange (`ut_pex`) from
+//! [BEP0011](https://www.bittorrent.org/beps/bep_0011.html), learning about
+//! more peers from the ones already connected
+
+use super::Extension;
+use crate::bencode::{self, Bencode};
+use crate::error::PeerError;
+use crate::torrent::Torrent;
+use crate::tracker_server::encode_compact_peers;
+use crate::tracker_udp::parse_compact_peers;
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How often peer exchange messages are sent to each peer at most
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most added (and separately, dropped) peers given in a single message
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Flag of a [PexPeer] which prefers encrypted connections
+pub const PEX_ENCRYPTION: u8 = 0x01;
+
+/// Flag of a [PexPeer] which is a seed (or only uploads)
+pub const PEX_SEED: u8 = 0x02;
+
+/// Flag of a [PexPeer] which supports uTP
+pub const PEX_UTP: u8 = 0x04;
+
+/// Flag of a [PexPeer] which supports the `ut_holepunch` extension
+pub const PEX_HOLEPUNCH: u8 = 0x08;
+
+/// Flag of a [PexPeer] which the sender could connect to, so is reachable
+pub const PEX_REACHABLE: u8 = 0x10;
+
+/// Shortest time between two messages from a peer before the later one is
+/// ignored, a bit under [PEX_INTERVAL] to allow for timers not lining up
+const MIN_PEX_GAP: Duration = Duration::from_secs(45);
+
+/// Most recieved peers kept waiting for [Pex::take_peers]
+const MAX_QUEUED_PEERS: usize = 500;
+
+/// A peer given in a peer exchange message
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct PexPeer {
+    /// Address the peer listens on
+    pub addr: SocketAddr,
+
+    /// Flags known about the peer, such as [PEX_SEED]
+    pub flags: u8,
+}
+
+/// A single peer exchange message, listing peers the sender connected to and
+/// disconnected from since it's last message
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// The PEX message payload is a bencoded dictionary with the following keys:
+///
+/// 'added': array of peers added, in compact format
+/// 'added.f': array of flags for added peers, one byte per peer
+/// 'added6': ipv6 version of 'added'
+/// 'added6.f': ipv6 version of 'added.f'
+/// 'dropped': array of peers dropped, in compact format
+/// 'dropped6': ipv6 version of 'dropped'
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::peer::{PexMessage, PexPeer, PEX_SEED};
+///
+/// fn main() {
+///     let message = PexMessage {
+///         added: vec![PexPeer { addr: "10.0.0.1:6881".parse().unwrap(), flags: PEX_SEED }],
+///         dropped: vec!["[2001:db8::1]:6881".parse().unwrap()],
+///     };
+///
+///     assert_eq!(PexMessage::decode(&message.encode()), Ok(message));
+/// }
+/// ```
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PexMessage {
+    /// Peers the sender connected to
+    pub added: Vec<PexPeer>,
+
+    /// Peers the sender disconnected from
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    /// Encodes this message into the payload of a `ut_pex` extended message
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+
+        for (key, ipv6) in [("added", false), ("added6", true)] {
+            let peers: Vec<&PexPeer> = self
+                .added
+                .iter()
+                .filter(|peer| peer.addr.is_ipv6() == ipv6)
+                .collect();
+            let addrs: Vec<SocketAddr> = peers.iter().map(|peer| peer.addr).collect();
+            let flags = peers.iter().map(|peer| peer.flags).collect();
+
+            dict.insert(
+                key.as_bytes().to_vec(),
+                Bencode::ByteString(encode_compact_peers(&addrs, ipv6)),
+            );
+            dict.insert(
+                format!("{}.f", key).into_bytes(),
+                Bencode::ByteString(flags),
+            );
+        }
+
+        dict.insert(
+            b"dropped".to_vec(),
+            Bencode::ByteString(encode_compact_peers(&self.dropped, false)),
+        );
+        dict.insert(
+            b"dropped6".to_vec(),
+            Bencode::ByteString(encode_compact_peers(&self.dropped, true)),
+        );
+
+        bencode::encode(&Bencode::Dict(dict))
+    }
+
+    /// Decodes a message from the `payload` of a `ut_pex` extended message,
+    /// giving [PeerError::BadPex] if it isn't a dictionary, has compact peers
+    /// of the wrong length or lists over [MAX_PEX_PEERS] added or dropped peers
+    ///
+    /// Flags are left cleared if they don't line up with the added peers
+    pub fn decode(payload: &[u8]) -> Result<Self, PeerError> {
+        let dict = match bencode::parse_slice(payload).map(|bencode| bencode.dict()) {
+            Ok(Some(dict)) => dict,
+            _ => return Err(PeerError::BadPex),
+        };
+        let bytes = |key: &[u8]| match dict.get(key) {
+            Some(Bencode::ByteString(bytes)) => bytes.as_slice(),
+            _ => &[],
+        };
+        let compact = |key: &[u8], ipv6: bool| {
+            let buf = bytes(key);
+
+            if buf.len().is_multiple_of(if ipv6 { 18 } else { 6 }) {
+                Ok(parse_compact_peers(buf, ipv6))
+            } else {
+                Err(PeerError::BadPex)
+            }
+        };
+        let mut message = Self::default();
+
+        for (key, flags_key, ipv6) in [
+            (&b"added"[..], &b"added.f"[..], false),
+            (b"added6", b"added6.f", true),
+        ] {
+            let addrs = compact(key, ipv6)?;
+            let flags = bytes(flags_key);
+            let flags_valid = flags.len() == addrs.len();
+
+            message
+                .added
+                .extend(addrs.into_iter().enumerate().map(|(ind, addr)| PexPeer {
+                    addr,
+                    flags: if flags_valid { flags[ind] } else { 0 },
+                }));
+        }
+
+        message.dropped = compact(b"dropped", false)?;
+        message.dropped.extend(compact(b"dropped6", true)?);
+
+        if message.added.len() > MAX_PEX_PEERS || message.dropped.len() > MAX_PEX_PEERS {
+            return Err(PeerError::BadPex);
+        }
+
+        Ok(message)
+    }
+}
+
+/// If `addr` could be a real peer, leaving out port 0 and unspecified,
+/// multicast and broadcast addresses
+fn usable_addr(addr: &SocketAddr) -> bool {
+    let ip = addr.ip();
+    let broadcast = match ip {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        IpAddr::V6(_) => false,
+    };
+
+    addr.port() != 0 && !ip.is_unspecified() && !ip.is_multicast() && !broadcast
+}
+
+/// The `ut_pex` [Extension] for a single connection, working out which peers
+/// to tell the peer about and gathering the peers it tells us about
+///
+/// Register one in each connection's [ExtensionRegistry](super::ExtensionRegistry)
+/// using [Pex::for_torrent], which refuses private torrents as they should only
+/// get peers from their trackers. Every so often give [Pex::update] every peer
+/// we are connected to and send the payload it gives with
+/// [PeerConnection::extension_message](super::PeerConnection::extension_message),
+/// then collect new peers with [Pex::take_peers]
+///
+/// Messages from the peer coming sooner than allowed are ignored and only
+/// so many peers are kept waiting to be taken, so a peer can't flood us
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Instant;
+/// use torro::peer::{Pex, PexPeer};
+///
+/// fn main() {
+///     let mut ours = Pex::new();
+///     let mut theirs = Pex::new();
+///     let connected = [PexPeer { addr: "10.0.0.1:6881".parse().unwrap(), flags: 0 }];
+///
+///     let payload = ours.update(&connected, Instant::now()).unwrap();
+///     theirs.recieve(&payload, Instant::now()).unwrap();
+///
+///     assert_eq!(theirs.take_peers(), connected.to_vec());
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Pex {
+    /// Peers we told the peer we are connected to
+    sent: HashSet<SocketAddr>,
+
+    /// When we last sent the peer a message
+    last_sent: Option<Instant>,
+
+    /// When we last accepted a message from the peer
+    last_recieved: Option<Instant>,
+
+    /// Peers the peer told us about which haven't been taken yet
+    peers: Vec<PexPeer>,
+}
+
+impl Pex {
+    /// Name of the extension in the extended handshake
+    pub const NAME: &'static str = "ut_pex";
+
+    /// Creates peer exchange state for a new connection
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates peer exchange state for a connection downloading `torrent`,
+    /// giving [None] if it's private as peer exchange must not be used
+    pub fn for_torrent(torrent: &Torrent) -> Option<Self> {
+        if torrent.private {
+            None
+        } else {
+            Some(Self::new())
+        }
+    }
+
+    /// Works out the message to send given every peer we are `connected` to
+    /// (besides this one) at `now`, giving the payload to send or [None] if
+    /// it's too soon since the last message or nothing changed
+    ///
+    /// At most [MAX_PEX_PEERS] are added and dropped per message, with the
+    /// rest left for the next one
+    pub fn update(&mut self, connected: &[PexPeer], now: Instant) -> Option<Vec<u8>> {
+        if let Some(last_sent) = self.last_sent {
+            if now.duration_since(last_sent) < PEX_INTERVAL {
+                return None;
+            }
+        }
+
+        let current: HashSet<SocketAddr> = connected.iter().map(|peer| peer.addr).collect();
+        let message = PexMessage {
+            added: connected
+                .iter()
+                .filter(|peer| !self.sent.contains(&peer.addr))
+                .take(MAX_PEX_PEERS)
+                .copied()
+                .collect(),
+            dropped: self
+                .sent
+                .iter()
+                .filter(|addr| !current.contains(addr))
+                .take(MAX_PEX_PEERS)
+                .copied()
+                .collect(),
+        };
+
+        if message.added.is_empty() && message.dropped.is_empty() {
+            return None;
+        }
+
+        for peer in message.added.iter() {
+            self.sent.insert(peer.addr);
+        }
+
+        for addr in message.dropped.iter() {
+            self.sent.remove(addr);
+        }
+
+        self.last_sent = Some(now);
+        Some(message.encode())
+    }
+
+    /// Handles a message `payload` recieved from the peer at `now`, keeping
+    /// the usable peers it added until [Pex::take_peers]. Dropped peers are
+    /// forgotten if they haven't been taken yet
+    pub fn recieve(&mut self, payload: &[u8], now: Instant) -> Result<(), PeerError> {
+        let message = PexMessage::decode(payload)?;
+
+        if let Some(last_recieved) = self.last_recieved {
+            if now.duration_since(last_recieved) < MIN_PEX_GAP {
+                return Ok(());
+            }
+        }
+
+        self.last_recieved = Some(now);
+        self.peers
+            .retain(|peer| !message.dropped.contains(&peer.addr));
+
+        for peer in message.added {
+            if self.peers.len() >= MAX_QUEUED_PEERS {
+                break;
+            }
+
+            if usable_addr(&peer.addr) && !self.peers.iter().any(|other| other.addr == peer.addr) {
+                self.peers.push(peer);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes the peers the peer told us about since this was last called
+    pub fn take_peers(&mut self) -> Vec<PexPeer> {
+        std::mem::take(&mut self.peers)
+    }
+}
+
+impl Extension for Pex {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn handle(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>, PeerError> {
+        self.recieve(payload, Instant::now())?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a peer at `10.0.0.<ind>:6881` with given `flags`
+    fn peer(ind: u8, flags: u8) -> PexPeer {
+        PexPeer {
+            addr: SocketAddr::from(([10, 0, 0, ind], 6881)),
+            flags,
+        }
+    }
+
+    /// Tests the layout of an encoded message and that bad ones are refused
+    #[test]
+    fn pex_message_layout() {
+        let message = PexMessage {
+            added: vec![peer(1, PEX_SEED | PEX_UTP)],
+            dropped: vec![],
+        };
+
+        assert_eq!(
+            message.encode(),
+            b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x066:added60:8:added6.f0:7:dropped0:8:dropped60:e"
+        );
+        assert_eq!(
+            PexMessage::decode(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e"),
+            Ok(PexMessage {
+                added: vec![peer(1, 0)],
+                dropped: vec![]
+            })
+        );
+        assert_eq!(
+            PexMessage::decode(b"d5:added5:\x0a\x00\x00\x01\x1ae"),
+            Err(PeerError::BadPex)
+        );
+        assert_eq!(PexMessage::decode(b"le"), Err(PeerError::BadPex));
+
+        let flood = PexMessage {
+            added: (0..=MAX_PEX_PEERS as u8).map(|ind| peer(ind, 0)).collect(),
+            dropped: vec![],
+        };
+        assert_eq!(PexMessage::decode(&flood.encode()), Err(PeerError::BadPex));
+    }
+
+    /// Tests that [Pex::update] sends deltas at most once a minute and that
+    /// [Pex::recieve] rate-limits and filters what it's given
+    #[test]
+    fn pex_deltas_and_limits() {
+        let start = Instant::now();
+        let mut ours = Pex::new();
+        let mut theirs = Pex::new();
+
+        let payload = ours
+            .update(&[peer(1, 0), peer(2, PEX_SEED)], start)
+            .unwrap();
+        theirs.recieve(&payload, start).unwrap();
+        assert_eq!(theirs.take_peers(), vec![peer(1, 0), peer(2, PEX_SEED)]);
+
+        assert_eq!(ours.update(&[peer(2, PEX_SEED), peer(3, 0)], start), None);
+        assert_eq!(
+            ours.update(&[peer(1, 0), peer(2, PEX_SEED)], start + PEX_INTERVAL),
+            None
+        );
+
+        let later = start + PEX_INTERVAL * 2;
+        let payload = ours
+            .update(&[peer(2, PEX_SEED), peer(3, 0)], later)
+            .unwrap();
+        assert_eq!(
+            PexMessage::decode(&payload),
+            Ok(PexMessage {
+                added: vec![peer(3, 0)],
+                dropped: vec![peer(1, 0).addr]
+            })
+        );
+
+        theirs
+            .recieve(&payload, start + Duration::from_secs(1))
+            .unwrap();
+        assert!(theirs.take_peers().is_empty()); // too soon
+
+        let junk = PexMessage {
+            added: vec![
+                PexPeer {
+                    addr: "0.0.0.0:6881".parse().unwrap(),
+                    flags: 0,
+                },
+                peer(4, 0),
+                peer(4, 0),
+                PexPeer {
+                    addr: "10.0.0.5:0".parse().unwrap(),
+                    flags: 0,
+                },
+            ],
+            dropped: vec![],
+        };
+        theirs.recieve(&junk.encode(), later).unwrap();
+        assert_eq!(theirs.take_peers(), vec![peer(4, 0)]);
+    }
+}
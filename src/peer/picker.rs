@@ -0,0 +1,462 @@
+//! Choosing which piece to download next from a peer
+
+use super::Bitfield;
+use crate::torrent::{Torrent, TorrentFile};
+use crate::utils::Xorshift;
+
+/// How much a file (and so the pieces holding it) is wanted, used by
+/// [PiecePicker::set_file_priorities]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum Priority {
+    /// Don't download at all
+    Skip,
+
+    /// Download after everything else
+    Low,
+
+    /// Download as usual, the default
+    #[default]
+    Normal,
+
+    /// Download before everything else
+    High,
+}
+
+/// State of a single piece inside of a [PiecePicker]
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PieceState {
+    /// Not downloaded at all
+    Missing,
+
+    /// Started, with some blocks still needing to be requested
+    Partial,
+
+    /// Every block has been requested, waiting on them to arrive
+    Requested,
+
+    /// Downloaded and verified
+    Have,
+}
+
+/// Picks which piece to download next from a peer, going rarest-first
+///
+/// Pieces are picked in this order, only ever picking pieces the peer has:
+///
+/// 1. Partly downloaded pieces, so pieces get finished (and can be shared)
+///    sooner
+/// 2. Pieces of a higher [Priority], skipping [Priority::Skip] entirely
+/// 3. Pieces the least amount of peers have, with ties broken randomly so
+///    peers don't all go for the same piece
+///
+/// Once every wanted piece has been started and fully requested, the picker
+/// goes into endgame mode where pieces with requests already outstanding are
+/// given out again so the last blocks can come from whichever peer is fastest.
+/// Duplicate requests should be cancelled once their block arrives from
+/// elsewhere
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::peer::{Bitfield, PiecePicker};
+///
+/// fn main() {
+///     let mut picker = PiecePicker::new(3);
+///     let mut first = Bitfield::new(3);
+///     let mut second = Bitfield::new(3);
+///
+///     first.set(0);
+///     first.set(1);
+///     second.set(1);
+///
+///     picker.add_peer(&first);
+///     picker.add_peer(&second);
+///
+///     assert_eq!(picker.pick(&first), Some(0)); // only one peer has piece 0
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    /// State of each piece
+    states: Vec<PieceState>,
+
+    /// Amount of connected peers which have each piece
+    availability: Vec<u32>,
+
+    /// Priority of each piece
+    priorities: Vec<Priority>,
+
+    /// If endgame mode has been reached
+    endgame: bool,
+
+    /// Random numbers used to break ties
+    rng: Xorshift,
+}
+
+impl PiecePicker {
+    /// Creates a picker for `num_pieces` pieces, none of which we have yet
+    pub fn new(num_pieces: u32) -> Self {
+        Self {
+            states: vec![PieceState::Missing; num_pieces as usize],
+            availability: vec![0; num_pieces as usize],
+            priorities: vec![Priority::Normal; num_pieces as usize],
+            endgame: false,
+            rng: Xorshift::new(),
+        }
+    }
+
+    /// Creates a picker for every piece of the `torrent`
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self::new(torrent.pieces.len() as u32)
+    }
+
+    /// Sets the priority of each piece from the priorities of the `torrent`'s
+    /// files, given in the same order as [Torrent::file_structure]. A piece
+    /// holding parts of several files takes the highest priority of them, so
+    /// it's only skipped if every file it holds is. Files without a priority
+    /// given are [Priority::Normal]
+    pub fn set_file_priorities(&mut self, torrent: &Torrent, file_priorities: &[Priority]) {
+        let lengths: Vec<usize> = match &torrent.file_structure {
+            TorrentFile::Single(length) => vec![*length],
+            TorrentFile::MultiFile(files) => files.iter().map(|(length, _)| *length).collect(),
+        };
+
+        for priority in self.priorities.iter_mut() {
+            *priority = Priority::Skip;
+        }
+
+        let piece_length = torrent.piece_length.max(1) as u64;
+        let num_pieces = self.priorities.len() as u64;
+        let mut offset = 0u64;
+
+        for (ind, length) in lengths.into_iter().enumerate() {
+            if length == 0 {
+                continue;
+            }
+
+            // only edited torrents can overflow, past which there are no pieces
+            let end = match offset.checked_add(length as u64) {
+                Some(end) => end,
+                None => break,
+            };
+            let priority = file_priorities.get(ind).copied().unwrap_or_default();
+            let first = offset / piece_length;
+            let last = (end - 1) / piece_length;
+
+            for piece in (first..=last).take_while(|piece| *piece < num_pieces) {
+                let piece = piece as usize;
+                self.priorities[piece] = self.priorities[piece].max(priority);
+            }
+
+            offset = end;
+        }
+    }
+
+    /// Sets the priority of a single piece
+    pub fn set_priority(&mut self, index: u32, priority: Priority) {
+        if let Some(old) = self.priorities.get_mut(index as usize) {
+            *old = priority;
+        }
+    }
+
+    /// Counts the pieces of a newly connected peer's `bitfield`
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            self.peer_have(index);
+        }
+    }
+
+    /// Counts a piece a peer just told us it has with a `have` message
+    pub fn peer_have(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Stops counting the pieces of a disconnected peer's `bitfield`
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            if let Some(count) = self.availability.get_mut(index as usize) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Amount of connected peers which have the piece at `index`
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    /// If endgame mode has been reached, see [PiecePicker] for what this means
+    pub fn in_endgame(&self) -> bool {
+        self.endgame
+    }
+
+    /// If every piece not set to [Priority::Skip] has been downloaded
+    pub fn is_complete(&self) -> bool {
+        self.states
+            .iter()
+            .zip(self.priorities.iter())
+            .all(|(state, priority)| *state == PieceState::Have || *priority == Priority::Skip)
+    }
+
+    /// Picks the best wanted piece in `state` which the peer has in
+    /// `peer_has` and isn't in `exclude`, by highest priority and then lowest
+    /// availability, breaking ties randomly
+    fn best(&mut self, state: PieceState, peer_has: &Bitfield, exclude: &[u32]) -> Option<u32> {
+        let mut best: Option<(usize, (Priority, u32))> = None;
+        let mut ties = 0u64;
+
+        for ind in 0..self.states.len() {
+            if self.states[ind] != state
+                || self.priorities[ind] == Priority::Skip
+                || !peer_has.get(ind as u32)
+                || exclude.contains(&(ind as u32))
+            {
+                continue;
+            }
+
+            let key = (self.priorities[ind], u32::MAX - self.availability[ind]);
+
+            match best {
+                Some((_, best_key)) if key < best_key => continue,
+                Some((_, best_key)) if key == best_key => {
+                    ties += 1;
+
+                    if self.rng.next_u64().is_multiple_of(ties) {
+                        best = Some((ind, key));
+                    }
+                }
+                _ => {
+                    ties = 1;
+                    best = Some((ind, key));
+                }
+            }
+        }
+
+        best.map(|(ind, _)| ind as u32)
+    }
+
+    /// Picks the next piece to download from a peer which has the pieces in
+    /// `peer_has`, marking it as started. [None] is given if the peer has
+    /// nothing we want
+    pub fn pick(&mut self, peer_has: &Bitfield) -> Option<u32> {
+        self.pick_excluding(peer_has, &[])
+    }
+
+    /// Same as [PiecePicker::pick] but never picks the pieces in `exclude`,
+    /// such as ones in endgame mode which every block has already been
+    /// requested from this peer for
+    pub fn pick_excluding(&mut self, peer_has: &Bitfield, exclude: &[u32]) -> Option<u32> {
+        if let Some(index) = self.best(PieceState::Partial, peer_has, exclude) {
+            return Some(index);
+        } else if let Some(index) = self.best(PieceState::Missing, peer_has, exclude) {
+            self.states[index as usize] = PieceState::Partial;
+            return Some(index);
+        }
+
+        let unrequested =
+            self.states
+                .iter()
+                .zip(self.priorities.iter())
+                .any(|(state, priority)| {
+                    matches!(state, PieceState::Missing | PieceState::Partial)
+                        && *priority != Priority::Skip
+                });
+
+        if unrequested {
+            return None;
+        }
+
+        self.endgame = true;
+        self.best(PieceState::Requested, peer_has, exclude)
+    }
+
+    /// Marks that every block of the piece at `index` has been requested, so
+    /// it's only given out again in endgame mode
+    pub fn fully_requested(&mut self, index: u32) {
+        if let Some(state @ PieceState::Partial) = self.states.get_mut(index as usize) {
+            *state = PieceState::Requested;
+        }
+    }
+
+    /// Marks that some requests for the piece at `index` where dropped (such as
+    /// from a choke or timeout), so it needs picking again. This leaves endgame
+    /// mode until the piece is fully requested again
+    pub fn requests_dropped(&mut self, index: u32) {
+        if let Some(state @ PieceState::Requested) = self.states.get_mut(index as usize) {
+            *state = PieceState::Partial;
+            self.endgame = false;
+        }
+    }
+
+    /// Marks the piece at `index` as downloaded and verified
+    pub fn mark_have(&mut self, index: u32) {
+        if let Some(state) = self.states.get_mut(index as usize) {
+            *state = PieceState::Have;
+        }
+    }
+
+    /// Marks that the piece at `index` failed it's hash check, so it's
+    /// downloaded again from scratch. This leaves endgame mode until the piece
+    /// is fully requested again
+    pub fn mark_failed(&mut self, index: u32) {
+        if let Some(state) = self.states.get_mut(index as usize) {
+            *state = PieceState::Missing;
+            self.endgame = false;
+        }
+    }
+
+    /// If the piece at `index` has been downloaded and verified
+    pub fn has(&self, index: u32) -> bool {
+        self.states.get(index as usize) == Some(&PieceState::Have)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::make_torrent;
+
+    /// Creates a bitfield for `len` pieces with given `indexes` set
+    fn bitfield(len: u32, indexes: &[u32]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+
+        for index in indexes {
+            bitfield.set(*index);
+        }
+
+        bitfield
+    }
+
+    /// Tests that the rarest piece is picked, partly downloaded pieces are
+    /// finished first and ties are broken randomly
+    #[test]
+    fn picker_rarest_first() {
+        let mut picker = PiecePicker::new(4);
+        let everything = bitfield(4, &[0, 1, 2, 3]);
+
+        picker.add_peer(&everything);
+        picker.add_peer(&bitfield(4, &[0, 1, 3]));
+        picker.add_peer(&bitfield(4, &[0, 3]));
+
+        assert_eq!(picker.pick(&everything), Some(2));
+        assert_eq!(picker.pick(&everything), Some(2)); // still partial
+        picker.fully_requested(2);
+        assert_eq!(picker.pick(&everything), Some(1));
+        picker.fully_requested(1);
+
+        let mut seen = [false; 4];
+
+        for _ in 0..50 {
+            let mut tied = PiecePicker::new(4);
+            seen[tied.pick(&everything).unwrap() as usize] = true;
+        }
+
+        assert_eq!(seen, [true; 4]);
+    }
+
+    /// Tests that priorities are derived from files and followed, with skipped
+    /// pieces never picked
+    #[test]
+    fn picker_file_priorities() {
+        let torrent = make_torrent(
+            "test",
+            10,
+            &[0; 80],
+            &[(15, "a"), (0, "b"), (5, "c"), (20, "d")],
+        );
+
+        let mut picker = PiecePicker::from_torrent(&torrent);
+        picker.set_file_priorities(
+            &torrent,
+            &[
+                Priority::Skip,
+                Priority::High,
+                Priority::Low,
+                Priority::High,
+            ],
+        );
+
+        assert_eq!(
+            picker.priorities,
+            vec![
+                Priority::Skip,
+                Priority::Low,
+                Priority::High,
+                Priority::High
+            ]
+        );
+
+        let everything = bitfield(4, &[0, 1, 2, 3]);
+        let mut picked: Vec<u32> = (0..3)
+            .map(|_| {
+                let index = picker.pick(&everything).unwrap();
+                picker.fully_requested(index);
+                index
+            })
+            .collect();
+
+        assert_eq!(picked.pop(), Some(1));
+        picked.sort_unstable();
+        assert_eq!(picked, vec![2, 3]);
+
+        for index in 1..4 {
+            picker.mark_have(index);
+        }
+
+        assert!(picker.is_complete());
+        assert_eq!(picker.pick(&everything), None);
+    }
+
+    /// Tests that endgame mode only starts once every wanted piece has been
+    /// requested, and then gives out requested pieces again
+    #[test]
+    fn picker_endgame() {
+        let mut picker = PiecePicker::new(2);
+        let first = bitfield(2, &[0]);
+        let both = bitfield(2, &[0, 1]);
+
+        assert_eq!(picker.pick(&first), Some(0));
+        picker.fully_requested(0);
+        assert_eq!(picker.pick(&first), None);
+        assert!(!picker.in_endgame());
+
+        assert_eq!(picker.pick(&both), Some(1));
+        picker.fully_requested(1);
+        picker.mark_have(1);
+
+        assert_eq!(picker.pick(&both), Some(0));
+        assert!(picker.in_endgame());
+
+        picker.mark_failed(0);
+        assert!(!picker.in_endgame());
+        assert_eq!(picker.pick(&first), Some(0));
+    }
+
+    /// Tests that partly requested pieces hold off endgame mode, including
+    /// ones whose requests where dropped during it
+    #[test]
+    fn picker_endgame_partial() {
+        let mut picker = PiecePicker::new(2);
+        let first = bitfield(2, &[0]);
+        let second = bitfield(2, &[1]);
+
+        assert_eq!(picker.pick(&first), Some(0));
+        assert_eq!(picker.pick(&second), Some(1));
+        picker.fully_requested(1);
+        assert_eq!(picker.pick(&second), None); // piece 0 still partial
+        assert!(!picker.in_endgame());
+
+        picker.fully_requested(0);
+        assert_eq!(picker.pick(&second), Some(1));
+        assert!(picker.in_endgame());
+
+        assert_eq!(picker.pick_excluding(&bitfield(2, &[0, 1]), &[0]), Some(1));
+        assert_eq!(picker.pick_excluding(&second, &[1]), None);
+
+        picker.requests_dropped(0);
+        assert!(!picker.in_endgame());
+        assert_eq!(picker.pick(&second), None);
+        assert_eq!(picker.pick(&first), Some(0));
+    }
+}
@@ -0,0 +1,789 @@
+//! The handshake and length-prefixed message codec of the peer wire protocol
+
+use crate::error::PeerError;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+/// Protocol string which starts every handshake, prefixed by it's length
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Length of a [Handshake] in bytes
+pub const HANDSHAKE_LEN: usize = 49 + PROTOCOL.len();
+
+/// Largest message length prefix accepted (1 MiB), stopping peers from making
+/// torro allocate huge buffers. This allows `piece` messages far larger than
+/// any sane block and `bitfield` messages for over 8 million pieces
+pub const MAX_MESSAGE_LEN: u32 = 1 << 20;
+
+/// Message id of [Message::Choke]
+const ID_CHOKE: u8 = 0;
+
+/// Message id of [Message::Unchoke]
+const ID_UNCHOKE: u8 = 1;
+
+/// Message id of [Message::Interested]
+const ID_INTERESTED: u8 = 2;
+
+/// Message id of [Message::NotInterested]
+const ID_NOT_INTERESTED: u8 = 3;
+
+/// Message id of [Message::Have]
+const ID_HAVE: u8 = 4;
+
+/// Message id of [Message::Bitfield]
+const ID_BITFIELD: u8 = 5;
+
+/// Message id of [Message::Request]
+const ID_REQUEST: u8 = 6;
+
+/// Message id of [Message::Piece]
+const ID_PIECE: u8 = 7;
+
+/// Message id of [Message::Cancel]
+const ID_CANCEL: u8 = 8;
+
+/// Message id of [Message::Port]
+const ID_PORT: u8 = 9;
+
+/// Message id of [Message::SuggestPiece]
+const ID_SUGGEST_PIECE: u8 = 0x0d;
+
+/// Message id of [Message::HaveAll]
+const ID_HAVE_ALL: u8 = 0x0e;
+
+/// Message id of [Message::HaveNone]
+const ID_HAVE_NONE: u8 = 0x0f;
+
+/// Message id of [Message::RejectRequest]
+const ID_REJECT_REQUEST: u8 = 0x10;
+
+/// Message id of [Message::AllowedFast]
+const ID_ALLOWED_FAST: u8 = 0x11;
+
+/// Message id of [Message::Extended]
+const ID_EXTENDED: u8 = 20;
+
+/// Reserved bit (in the last reserved byte) telling the other side the
+/// [BEP0006](https://www.bittorrent.org/beps/bep_0006.html) fast extension is
+/// supported
+const RESERVED_FAST: u8 = 0x04;
+
+/// Reserved bit (in the sixth reserved byte) telling the other side the
+/// [BEP0010](https://www.bittorrent.org/beps/bep_0010.html) extension protocol
+/// is supported
+const RESERVED_EXTENDED: u8 = 0x10;
+
+/// Maps an IO error into a [PeerError::Io]
+fn io_err(err: std::io::Error) -> PeerError {
+    PeerError::Io(err.kind())
+}
+
+/// Reads a big-endian [u32] at `offset` of `buf`, which must already be known
+/// to be long enough
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// The handshake sent by both sides when a connection to a peer is opened,
+/// before any [Message]
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// The handshake starts with character ninteen (decimal) followed by the string
+/// 'BitTorrent protocol'. The leading character is a length prefix, put there
+/// in the hope that other new protocols may do the same and thus be trivially
+/// distinguishable from each other.
+///
+/// All later integers sent in the protocol are encoded as four bytes
+/// big-endian.
+///
+/// After the fixed headers come eight reserved bytes, which are all zero in
+/// all current implementations. If you wish to extend the protocol using these
+/// bytes, please coordinate with Bram Cohen to make sure all extensions are
+/// done compatibly.
+///
+/// Next comes the 20 byte sha1 hash of the bencoded form of the info value from
+/// the metainfo file. (This is the same value which is announced as info_hash
+/// to the tracker, only here it's raw instead of quoted here). If both sides
+/// don't send the same value, they sever the connection. The one possible
+/// exception is if a downloader wants to do multiple downloads over a single
+/// port, they may wait for incoming connections to give a download hash first,
+/// and respond with the same one if it's in their list.
+///
+/// After the download hash comes the 20-byte peer id which is reported in
+/// tracker requests and contained in peer lists in tracker responses.
+/// ```
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::net::TcpStream;
+/// use torro::peer::Handshake;
+///
+/// fn main() {
+///     let info_hash = [0; 20];
+///     let mut stream = TcpStream::connect("127.0.0.1:6881").unwrap();
+///
+///     Handshake::new(info_hash, *b"-TO0000-000000000000")
+///         .write_to(&mut stream)
+///         .unwrap();
+///
+///     let theirs = Handshake::read_from(&mut stream).unwrap();
+///     theirs.verify(info_hash).unwrap();
+///
+///     println!("Connected to peer {:?}", theirs.peer_id);
+/// }
+/// ```
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Handshake {
+    /// Reserved bytes, used to tell the other side which extensions are
+    /// supported
+    pub reserved: [u8; 8],
+
+    /// Info-hash of the torrent the connection is for
+    pub info_hash: [u8; 20],
+
+    /// Peer id of the side sending the handshake
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// Creates a new handshake for given `info_hash` and our `peer_id` with no
+    /// reserved bits set
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Appends the encoded handshake to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(PROTOCOL.len() as u8);
+        buf.extend_from_slice(PROTOCOL);
+        buf.extend_from_slice(&self.reserved);
+        buf.extend_from_slice(&self.info_hash);
+        buf.extend_from_slice(&self.peer_id);
+    }
+
+    /// Decodes a handshake from the start of `buf`, giving [None] if `buf`
+    /// doesn't hold all [HANDSHAKE_LEN] bytes yet or
+    /// [PeerError::BadHandshake] if it isn't a BitTorrent handshake
+    pub fn decode(buf: &[u8]) -> Result<Option<Self>, PeerError> {
+        let header = std::iter::once(PROTOCOL.len() as u8).chain(PROTOCOL.iter().copied());
+
+        if buf.iter().zip(header).any(|(got, want)| *got != want) {
+            return Err(PeerError::BadHandshake);
+        } else if buf.len() < HANDSHAKE_LEN {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            reserved: buf[20..28].try_into().unwrap(),
+            info_hash: buf[28..48].try_into().unwrap(),
+            peer_id: buf[48..68].try_into().unwrap(),
+        }))
+    }
+
+    /// Reads a whole handshake from `reader`
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, PeerError> {
+        let mut buf = [0; HANDSHAKE_LEN];
+        reader.read_exact(&mut buf).map_err(io_err)?;
+
+        Ok(Self::decode(&buf)?.unwrap())
+    }
+
+    /// Writes the encoded handshake to `writer`
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<(), PeerError> {
+        let mut buf = Vec::with_capacity(HANDSHAKE_LEN);
+        self.encode(&mut buf);
+
+        writer.write_all(&buf).map_err(io_err)
+    }
+
+    /// Sets the reserved bit saying we support the
+    /// [BEP0006](https://www.bittorrent.org/beps/bep_0006.html) fast extension
+    pub fn fast(mut self) -> Self {
+        self.reserved[7] |= RESERVED_FAST;
+        self
+    }
+
+    /// If the side sending this handshake supports the fast extension
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & RESERVED_FAST != 0
+    }
+
+    /// Sets the reserved bit saying we support the
+    /// [BEP0010](https://www.bittorrent.org/beps/bep_0010.html) extension
+    /// protocol
+    pub fn extended(mut self) -> Self {
+        self.reserved[5] |= RESERVED_EXTENDED;
+        self
+    }
+
+    /// If the side sending this handshake supports the extension protocol
+    pub fn supports_extended(&self) -> bool {
+        self.reserved[5] & RESERVED_EXTENDED != 0
+    }
+
+    /// Checks that this handshake is for the torrent with given `info_hash`,
+    /// giving [PeerError::InfoHashMismatch] if not so the connection can be
+    /// severed
+    pub fn verify(&self, info_hash: [u8; 20]) -> Result<(), PeerError> {
+        if self.info_hash == info_hash {
+            Ok(())
+        } else {
+            Err(PeerError::InfoHashMismatch)
+        }
+    }
+}
+
+/// A single message of the peer wire protocol, sent after the [Handshake]
+///
+/// Messages are encoded into (and decoded from) byte buffers given by the
+/// caller so that one buffer can be reused for a whole connection. Decoding
+/// never panics on bad input and refuses length prefixes over
+/// [MAX_MESSAGE_LEN] before allocating anything
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// That's it for handshaking, next comes an alternating stream of length
+/// prefixes and messages. Messages of length zero are keepalives, and ignored.
+/// Keepalives are generally sent once every two minutes, but note that
+/// timeouts can be done much more quickly when data is expected.
+///
+/// All non-keepalive messages start with a single byte which gives their type.
+/// ```
+///
+/// # Examples
+///
+/// ```rust
+/// use torro::peer::Message;
+///
+/// fn main() {
+///     let mut buf = vec![];
+///     Message::Have(3).encode(&mut buf);
+///     Message::Interested.encode(&mut buf);
+///
+///     let (first, used) = Message::decode(&buf).unwrap().unwrap();
+///     let (second, _) = Message::decode(&buf[used..]).unwrap().unwrap();
+///
+///     assert_eq!(first, Message::Have(3));
+///     assert_eq!(second, Message::Interested);
+/// }
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    /// Message of length zero, sent to keep the connection open
+    KeepAlive,
+
+    /// The sender won't answer requests until it sends [Message::Unchoke]
+    Choke,
+
+    /// The sender will answer requests
+    Unchoke,
+
+    /// The sender wants pieces the reciever has
+    Interested,
+
+    /// The sender doesn't want any pieces the reciever has
+    NotInterested,
+
+    /// The sender has just finished and verified the piece at given index
+    Have(u32),
+
+    /// Which pieces the sender has, only sent directly after the handshake.
+    /// The highest bit of the first byte is the first piece and spare bits at
+    /// the end are cleared
+    Bitfield(Vec<u8>),
+
+    /// Asks for a block of a piece
+    Request {
+        /// Index of the piece
+        index: u32,
+
+        /// Offset of the block inside of the piece
+        begin: u32,
+
+        /// Length of the block
+        length: u32,
+    },
+
+    /// A block of a piece, answering a [Message::Request]
+    Piece {
+        /// Index of the piece
+        index: u32,
+
+        /// Offset of the block inside of the piece
+        begin: u32,
+
+        /// Data of the block
+        block: Vec<u8>,
+    },
+
+    /// Cancels an earlier [Message::Request] with the same fields
+    Cancel {
+        /// Index of the piece
+        index: u32,
+
+        /// Offset of the block inside of the piece
+        begin: u32,
+
+        /// Length of the block
+        length: u32,
+    },
+
+    /// Port the sender's DHT node listens on, from
+    /// [BEP0005](https://www.bittorrent.org/beps/bep_0005.html)
+    Port(u16),
+
+    /// Suggests the reciever downloads the piece at given index, from the
+    /// [BEP0006](https://www.bittorrent.org/beps/bep_0006.html) fast extension
+    SuggestPiece(u32),
+
+    /// The sender has every piece, sent in place of a [Message::Bitfield]. From
+    /// the fast extension
+    HaveAll,
+
+    /// The sender has no pieces, sent in place of a [Message::Bitfield]. From
+    /// the fast extension
+    HaveNone,
+
+    /// The sender won't answer an earlier [Message::Request] with the same
+    /// fields. From the fast extension
+    RejectRequest {
+        /// Index of the piece
+        index: u32,
+
+        /// Offset of the block inside of the piece
+        begin: u32,
+
+        /// Length of the block
+        length: u32,
+    },
+
+    /// The reciever may request the piece at given index even while choked.
+    /// From the fast extension
+    AllowedFast(u32),
+
+    /// A message of the
+    /// [BEP0010](https://www.bittorrent.org/beps/bep_0010.html) extension
+    /// protocol, see [ExtensionRegistry](super::ExtensionRegistry)
+    Extended {
+        /// Extended message id, `0` being the extended handshake and the rest
+        /// being ids the reciever gave extensions in it's handshake
+        id: u8,
+
+        /// Payload of the extended message
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Appends the length-prefixed encoding of this message to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; 4]);
+
+        match self {
+            Message::KeepAlive => (),
+            Message::Choke => buf.push(ID_CHOKE),
+            Message::Unchoke => buf.push(ID_UNCHOKE),
+            Message::Interested => buf.push(ID_INTERESTED),
+            Message::NotInterested => buf.push(ID_NOT_INTERESTED),
+            Message::Have(index) => {
+                buf.push(ID_HAVE);
+                buf.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::Bitfield(bitfield) => {
+                buf.push(ID_BITFIELD);
+                buf.extend_from_slice(bitfield);
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                buf.push(match self {
+                    Message::Request { .. } => ID_REQUEST,
+                    Message::Cancel { .. } => ID_CANCEL,
+                    _ => ID_REJECT_REQUEST,
+                });
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(&length.to_be_bytes());
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                buf.push(ID_PIECE);
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(block);
+            }
+            Message::Port(port) => {
+                buf.push(ID_PORT);
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+            Message::SuggestPiece(index) | Message::AllowedFast(index) => {
+                buf.push(match self {
+                    Message::SuggestPiece(_) => ID_SUGGEST_PIECE,
+                    _ => ID_ALLOWED_FAST,
+                });
+                buf.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::HaveAll => buf.push(ID_HAVE_ALL),
+            Message::HaveNone => buf.push(ID_HAVE_NONE),
+            Message::Extended { id, payload } => {
+                buf.push(ID_EXTENDED);
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+            }
+        }
+
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    }
+
+    /// Decodes the message at the start of `buf`, giving it and the amount of
+    /// bytes it used, or [None] if `buf` doesn't hold the whole message yet
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, PeerError> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+
+        let len = u32_at(buf, 0);
+
+        if len > MAX_MESSAGE_LEN {
+            return Err(PeerError::MessageTooLarge(len));
+        } else if buf.len() < 4 + len as usize {
+            return Ok(None);
+        }
+
+        let used = 4 + len as usize;
+
+        Ok(Some((Self::from_payload(&buf[4..used])?, used)))
+    }
+
+    /// Decodes a message from it's `payload`, which is everything after the
+    /// length prefix
+    pub fn from_payload(payload: &[u8]) -> Result<Self, PeerError> {
+        let (id, body) = match payload.split_first() {
+            Some((id, body)) => (*id, body),
+            None => return Ok(Message::KeepAlive),
+        };
+        let expect_len = |len: usize| {
+            if body.len() == len {
+                Ok(())
+            } else {
+                Err(PeerError::BadMessageLength((id, body.len())))
+            }
+        };
+
+        match id {
+            ID_CHOKE => expect_len(0).map(|_| Message::Choke),
+            ID_UNCHOKE => expect_len(0).map(|_| Message::Unchoke),
+            ID_INTERESTED => expect_len(0).map(|_| Message::Interested),
+            ID_NOT_INTERESTED => expect_len(0).map(|_| Message::NotInterested),
+            ID_HAVE => expect_len(4).map(|_| Message::Have(u32_at(body, 0))),
+            ID_BITFIELD => Ok(Message::Bitfield(body.to_vec())),
+            ID_REQUEST | ID_CANCEL | ID_REJECT_REQUEST => {
+                expect_len(12)?;
+
+                let (index, begin, length) = (u32_at(body, 0), u32_at(body, 4), u32_at(body, 8));
+
+                Ok(match id {
+                    ID_REQUEST => Message::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    ID_CANCEL => Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Message::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                })
+            }
+            ID_PIECE if body.len() >= 8 => Ok(Message::Piece {
+                index: u32_at(body, 0),
+                begin: u32_at(body, 4),
+                block: body[8..].to_vec(),
+            }),
+            ID_PIECE => Err(PeerError::BadMessageLength((id, body.len()))),
+            ID_PORT => expect_len(2).map(|_| Message::Port(u16::from_be_bytes([body[0], body[1]]))),
+            ID_SUGGEST_PIECE => expect_len(4).map(|_| Message::SuggestPiece(u32_at(body, 0))),
+            ID_HAVE_ALL => expect_len(0).map(|_| Message::HaveAll),
+            ID_HAVE_NONE => expect_len(0).map(|_| Message::HaveNone),
+            ID_ALLOWED_FAST => expect_len(4).map(|_| Message::AllowedFast(u32_at(body, 0))),
+            ID_EXTENDED => match body.split_first() {
+                Some((ext_id, ext_payload)) => Ok(Message::Extended {
+                    id: *ext_id,
+                    payload: ext_payload.to_vec(),
+                }),
+                None => Err(PeerError::BadMessageLength((id, 0))),
+            },
+            _ => Err(PeerError::UnknownMessage(id)),
+        }
+    }
+
+    /// Reads a whole message from `reader`, using `buf` to hold it's payload so
+    /// the same buffer can be reused for every message
+    pub fn read_from(reader: &mut impl Read, buf: &mut Vec<u8>) -> Result<Self, PeerError> {
+        let mut len_buf = [0; 4];
+        reader.read_exact(&mut len_buf).map_err(io_err)?;
+
+        let len = u32::from_be_bytes(len_buf);
+
+        if len > MAX_MESSAGE_LEN {
+            return Err(PeerError::MessageTooLarge(len));
+        }
+
+        buf.clear();
+        buf.resize(len as usize, 0);
+        reader.read_exact(buf).map_err(io_err)?;
+
+        Self::from_payload(buf)
+    }
+
+    /// Writes this message to `writer`, encoding it into `buf` first so the
+    /// same buffer can be reused for every message
+    pub fn write_to(&self, writer: &mut impl Write, buf: &mut Vec<u8>) -> Result<(), PeerError> {
+        buf.clear();
+        self.encode(buf);
+
+        writer.write_all(buf).map_err(io_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Xorshift;
+    use std::io::Cursor;
+
+    /// Gets one of every kind of [Message]
+    fn all_messages() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0xdeadbeef),
+            Message::Bitfield(vec![0b1010_0000, 0]),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 1,
+                begin: 0,
+                block: vec![7; 100],
+            },
+            Message::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Port(6881),
+            Message::SuggestPiece(2),
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest {
+                index: 1,
+                begin: 0,
+                length: 16384,
+            },
+            Message::AllowedFast(3),
+            Message::Extended {
+                id: 0,
+                payload: b"d1:md6:ut_pexi1eee".to_vec(),
+            },
+        ]
+    }
+
+    /// Tests that a [Handshake] is laid out as BEP0003 gives and decodes back
+    #[test]
+    fn handshake_layout() {
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        let mut buf = vec![];
+        handshake.encode(&mut buf);
+
+        assert_eq!(buf.len(), HANDSHAKE_LEN);
+        assert_eq!(buf[0], 19);
+        assert_eq!(&buf[1..20], b"BitTorrent protocol");
+        assert_eq!(&buf[20..28], &[0; 8]);
+        assert_eq!(&buf[28..48], &[1; 20]);
+        assert_eq!(&buf[48..68], &[2; 20]);
+
+        assert_eq!(Handshake::decode(&buf[..30]), Ok(None));
+        assert_eq!(Handshake::decode(&buf), Ok(Some(handshake)));
+        assert_eq!(Handshake::read_from(&mut Cursor::new(&buf)), Ok(handshake));
+        assert_eq!(handshake.verify([1; 20]), Ok(()));
+        assert!(!handshake.supports_fast());
+        assert_eq!(handshake.fast().reserved, [0, 0, 0, 0, 0, 0, 0, 0x04]);
+        assert!(handshake.fast().supports_fast());
+        assert_eq!(handshake.extended().reserved, [0, 0, 0, 0, 0, 0x10, 0, 0]);
+        assert!(handshake.extended().supports_extended());
+        assert!(!handshake.fast().supports_extended());
+        assert_eq!(handshake.verify([3; 20]), Err(PeerError::InfoHashMismatch));
+
+        buf[5] = b'x';
+        assert_eq!(Handshake::decode(&buf), Err(PeerError::BadHandshake));
+        assert_eq!(Handshake::decode(b"\x13Bit"), Ok(None));
+        assert_eq!(Handshake::decode(b"GET /"), Err(PeerError::BadHandshake));
+    }
+
+    /// Tests that every [Message] encodes and decodes back to itself, including
+    /// when several are read from one stream with a reused buffer
+    #[test]
+    fn message_roundtrip() {
+        let mut buf = vec![];
+
+        for message in all_messages() {
+            message.encode(&mut buf);
+        }
+
+        let mut offset = 0;
+
+        for message in all_messages() {
+            let (decoded, used) = Message::decode(&buf[offset..]).unwrap().unwrap();
+            assert_eq!(decoded, message);
+            offset += used;
+        }
+
+        assert_eq!(offset, buf.len());
+
+        let mut reader = Cursor::new(&buf);
+        let mut read_buf = vec![];
+
+        for message in all_messages() {
+            assert_eq!(Message::read_from(&mut reader, &mut read_buf), Ok(message));
+        }
+
+        assert_eq!(
+            Message::read_from(&mut reader, &mut read_buf),
+            Err(PeerError::Io(std::io::ErrorKind::UnexpectedEof))
+        );
+    }
+
+    /// Tests that messages are encoded with the lengths and ids BEP0003 gives
+    #[test]
+    fn message_layout() {
+        let mut buf = vec![];
+
+        Message::KeepAlive.encode(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 0]);
+
+        buf.clear();
+        Message::Have(5).encode(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 5, 4, 0, 0, 0, 5]);
+
+        buf.clear();
+        Message::Port(0x1ae1).encode(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 3, 9, 0x1a, 0xe1]);
+
+        buf.clear();
+        Message::HaveAll.encode(&mut buf);
+        Message::AllowedFast(1).encode(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 1, 0x0e, 0, 0, 0, 5, 0x11, 0, 0, 0, 1]);
+
+        buf.clear();
+        Message::Extended {
+            id: 3,
+            payload: vec![b'd', b'e'],
+        }
+        .encode(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 4, 20, 3, b'd', b'e']);
+    }
+
+    /// Tests that partial, oversized, wrongly-sized and unknown messages are
+    /// handled without panicking
+    #[test]
+    fn message_decode_errors() {
+        assert_eq!(Message::decode(&[0, 0]), Ok(None));
+        assert_eq!(Message::decode(&[0, 0, 0, 5, 4, 0]), Ok(None));
+        assert_eq!(
+            Message::decode(&[0, 0x10, 0, 1]),
+            Err(PeerError::MessageTooLarge(0x100001))
+        );
+        assert_eq!(
+            Message::read_from(&mut Cursor::new([0xff; 4]), &mut vec![]),
+            Err(PeerError::MessageTooLarge(u32::MAX))
+        );
+        assert_eq!(
+            Message::decode(&[0, 0, 0, 2, 0, 0]),
+            Err(PeerError::BadMessageLength((0, 1)))
+        );
+        assert_eq!(
+            Message::decode(&[0, 0, 0, 5, 7, 0, 0, 0, 0]),
+            Err(PeerError::BadMessageLength((7, 4)))
+        );
+        assert_eq!(
+            Message::decode(&[0, 0, 0, 1, 42]),
+            Err(PeerError::UnknownMessage(42))
+        );
+        assert_eq!(
+            Message::decode(&[0, 0, 0, 1, 20]),
+            Err(PeerError::BadMessageLength((20, 0)))
+        );
+    }
+
+    /// Throws pseudorandom bytes (and pseudorandom mutations of real messages)
+    /// at the decoders to make sure they never panic
+    #[test]
+    fn decode_fuzz() {
+        let mut rng = Xorshift::with_seed(0x2545f4914f6cdd1d);
+        let mut next = || rng.next_u64();
+        let mut valid = vec![];
+
+        for message in all_messages() {
+            message.encode(&mut valid);
+        }
+
+        for _ in 0..2000 {
+            let mut buf = valid.clone();
+            let len = next() as usize % buf.len();
+            buf.truncate(len);
+
+            for _ in 0..(next() % 4) {
+                if !buf.is_empty() {
+                    let ind = next() as usize % buf.len();
+                    buf[ind] = next() as u8;
+                }
+            }
+
+            let _ = Handshake::decode(&buf);
+            let mut offset = 0;
+
+            while let Ok(Some((_, used))) = Message::decode(&buf[offset..]) {
+                offset += used;
+            }
+
+            let mut reader = Cursor::new(&buf);
+            while Message::read_from(&mut reader, &mut vec![]).is_ok() {}
+        }
+    }
+}
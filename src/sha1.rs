@@ -0,0 +1,117 @@
+//! Internal/private [SHA-1](https://tools.ietf.org/html/rfc3174) implementation,
+//! used for info-hashes and piece checking without pulling in a dependency
+//!
+//! SHA-1 is no longer considered secure but is the hash mandated by BEP0003
+
+/// Initial hash state as given in RFC3174
+const INITIAL_STATE: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+/// Processes a single 64-byte `block` into the given hash `state`
+fn process_block(state: &mut [u32; 5], block: &[u8]) {
+    let mut words = [0u32; 80];
+
+    for (ind, chunk) in block.chunks(4).enumerate() {
+        words[ind] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+
+    for ind in 16..80 {
+        words[ind] =
+            (words[ind - 3] ^ words[ind - 8] ^ words[ind - 14] ^ words[ind - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+
+    for (ind, word) in words.iter().enumerate() {
+        let (f, k) = match ind {
+            0..=19 => ((b & c) | (!b & d), 0x5a827999),
+            20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*word);
+
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+    state[4] = state[4].wrapping_add(e);
+}
+
+/// Hashes given `data` into a 20-byte SHA-1 digest
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state = INITIAL_STATE;
+    let mut chunks = data.chunks_exact(64);
+
+    for block in &mut chunks {
+        process_block(&mut state, block);
+    }
+
+    // pad with a single `1` bit, zeros and then the message length in bits
+    let mut tail = chunks.remainder().to_vec();
+    tail.push(0x80);
+
+    while tail.len() % 64 != 56 {
+        tail.push(0x00);
+    }
+
+    tail.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    for block in tail.chunks(64) {
+        process_block(&mut state, block);
+    }
+
+    let mut digest = [0u8; 20];
+
+    for (ind, word) in state.iter().enumerate() {
+        digest[ind * 4..ind * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Converts a hex string into bytes for easier comparisons
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|ind| u8::from_str_radix(&hex[ind..ind + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Tests [sha1] against the test vectors given in RFC3174 and FIPS 180-2
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(
+            sha1(b"").to_vec(),
+            from_hex("da39a3ee5e6b4b0d3255bfef95601890afd80709")
+        );
+        assert_eq!(
+            sha1(b"abc").to_vec(),
+            from_hex("a9993e364706816aba3e25717850c26c9cd0d89d")
+        );
+        assert_eq!(
+            sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").to_vec(),
+            from_hex("84983e441c3bd26ebaae4aa1f95129e5e54670f1")
+        );
+        assert_eq!(
+            sha1(&vec![b'a'; 1_000_000]).to_vec(),
+            from_hex("34aa973cd4c4daa4f61eeb2bdbad27316534016f")
+        );
+    }
+}
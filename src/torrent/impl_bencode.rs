@@ -3,20 +3,36 @@
 
 use crate::bencode::{self, Bencode};
 use crate::error::{TorrentCreationError, TorroError};
-use crate::torrent::{Torrent, TorrentFile};
+use crate::sha1::sha1;
+use crate::torrent::{Torrent, TorrentFile, TorrentString};
 use crate::utils::read_file_bytes;
 use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::io::Read;
 use std::path::PathBuf;
 
 /// Used as an organisation enum for managing [Torrent::new] when pulling from a
 /// bencode dict, see [get_dict_item] for the main usage of this emum
-enum TorrentBencodeKey {
+pub(crate) enum TorrentBencodeKey {
     /// `announce` top-level key
     Announce,
+    /// Optional `announce-list` top-level key
+    AnnounceList,
+    /// Optional `comment` top-level key
+    Comment,
+    /// Optional `comment.utf-8` top-level key, a UTF-8 alternative to
+    /// [TorrentBencodeKey::Comment]
+    CommentUtf8,
+    /// Optional `url-list` top-level key
+    UrlList,
+    /// Optional `nodes` top-level key
+    Nodes,
     /// `info` top-level key
     Info,
     /// `piece length` key inside of the [TorrentBencodeKey::Info] dictionary
     PieceLength,
+    /// Optional `private` key inside of the [TorrentBencodeKey::Info] dictionary
+    Private,
     /// `pieces` key inside of the [TorrentBencodeKey::Info] dictionary
     Pieces,
     /// `name` key inside of the [TorrentBencodeKey::Info] dictionary
@@ -28,19 +44,38 @@ enum TorrentBencodeKey {
     Files,
     /// `path` key inside of a element of the [TorrentBencodeKey::Files] list
     Path,
+    /// Optional `encoding` top-level key
+    Encoding,
+    /// Optional `name.utf-8` key inside of the [TorrentBencodeKey::Info]
+    /// dictionary, a UTF-8 alternative to [TorrentBencodeKey::Name]
+    NameUtf8,
+    /// Optional `path.utf-8` key inside of a element of the
+    /// [TorrentBencodeKey::Files] list, a UTF-8 alternative to
+    /// [TorrentBencodeKey::Path]
+    PathUtf8,
 }
 
 impl TorrentBencodeKey {
-    fn as_vecu8(&self) -> Vec<u8> {
+    /// Gets the bencoded key name as it would be found inside of a dictionary
+    pub(crate) fn as_vecu8(&self) -> Vec<u8> {
         match &self {
             TorrentBencodeKey::Announce => "announce",
+            TorrentBencodeKey::AnnounceList => "announce-list",
+            TorrentBencodeKey::Comment => "comment",
+            TorrentBencodeKey::CommentUtf8 => "comment.utf-8",
+            TorrentBencodeKey::UrlList => "url-list",
+            TorrentBencodeKey::Nodes => "nodes",
             TorrentBencodeKey::Info => "info",
             TorrentBencodeKey::PieceLength => "piece length",
+            TorrentBencodeKey::Private => "private",
             TorrentBencodeKey::Pieces => "pieces",
             TorrentBencodeKey::Name => "name",
             TorrentBencodeKey::Length => "length",
             TorrentBencodeKey::Files => "files",
             TorrentBencodeKey::Path => "path",
+            TorrentBencodeKey::Encoding => "encoding",
+            TorrentBencodeKey::NameUtf8 => "name.utf-8",
+            TorrentBencodeKey::PathUtf8 => "path.utf-8",
         }
         .as_bytes()
         .to_vec()
@@ -59,6 +94,17 @@ impl TorrentBencodeKey {
                 TorrentCreationError::NoLengthFiles
             }
             TorrentBencodeKey::Path => TorrentCreationError::NoPathFound,
+            TorrentBencodeKey::AnnounceList
+            | TorrentBencodeKey::Comment
+            | TorrentBencodeKey::CommentUtf8
+            | TorrentBencodeKey::UrlList
+            | TorrentBencodeKey::Nodes
+            | TorrentBencodeKey::Private
+            | TorrentBencodeKey::Encoding
+            | TorrentBencodeKey::NameUtf8
+            | TorrentBencodeKey::PathUtf8 => {
+                unreachable!("Optional keys are only fetched using get_opt_dict_item")
+            }
         }
     }
 }
@@ -75,6 +121,12 @@ fn get_dict_item(
     }
 }
 
+/// Gets an optional dict value from given key, returning [None] if it isn't
+/// present. Used for keys which have no [TorrentBencodeKey::missing_err]
+fn get_opt_dict_item(dict: &BTreeMap<Vec<u8>, Bencode>, key: TorrentBencodeKey) -> Option<Bencode> {
+    dict.get(&key.as_vecu8()).cloned()
+}
+
 /// Wraps [String::from_utf8] inside a convinient
 /// `Result<String, TorrentCreationError>` for simplified `.into()`/`?`
 /// error processing
@@ -82,35 +134,183 @@ fn vecu8_to_string(input: Vec<u8>) -> Result<String, TorrentCreationError> {
     String::from_utf8(input.clone()).map_err(|_| TorrentCreationError::BadUTF8String(input))
 }
 
+/// Characters for the `0x80` to `0x9f` range of Windows-1252, which is the only
+/// range that differs from ISO-8859-1. Undefined bytes are given as [None]
+const WINDOWS_1252_HIGH: [Option<char>; 32] = [
+    Some('\u{20ac}'),
+    None,
+    Some('\u{201a}'),
+    Some('\u{0192}'),
+    Some('\u{201e}'),
+    Some('\u{2026}'),
+    Some('\u{2020}'),
+    Some('\u{2021}'),
+    Some('\u{02c6}'),
+    Some('\u{2030}'),
+    Some('\u{0160}'),
+    Some('\u{2039}'),
+    Some('\u{0152}'),
+    None,
+    Some('\u{017d}'),
+    None,
+    None,
+    Some('\u{2018}'),
+    Some('\u{2019}'),
+    Some('\u{201c}'),
+    Some('\u{201d}'),
+    Some('\u{2022}'),
+    Some('\u{2013}'),
+    Some('\u{2014}'),
+    Some('\u{02dc}'),
+    Some('\u{2122}'),
+    Some('\u{0161}'),
+    Some('\u{203a}'),
+    Some('\u{0153}'),
+    None,
+    Some('\u{017e}'),
+    Some('\u{0178}'),
+];
+
+/// Decodes `raw` bytes using the given `encoding` label from the top-level
+/// `encoding` key, or as UTF-8 if there is none, returning [None] if it
+/// couldn't be decoded
+///
+/// Only encodings which don't need large lookup tables are known to torro (UTF-8,
+/// ASCII, ISO-8859-1 and Windows-1252). Any other label, such as `Shift_JIS` or
+/// `GBK`, also gives [None] rather than guessing at UTF-8, leaving the raw bytes
+/// for the user to decode
+fn decode_with_encoding(raw: &[u8], encoding: Option<&str>) -> Option<String> {
+    let label: String = encoding
+        .unwrap_or("utf-8")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    match label.as_str() {
+        "ascii" | "usascii" => {
+            if raw.is_ascii() {
+                Some(raw.iter().map(|b| *b as char).collect())
+            } else {
+                None
+            }
+        }
+        "latin1" | "l1" | "iso88591" | "cp819" => Some(raw.iter().map(|b| *b as char).collect()),
+        "windows1252" | "cp1252" => raw
+            .iter()
+            .map(|b| match b {
+                0x80..=0x9f => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+                _ => Some(*b as char),
+            })
+            .collect(),
+        "utf8" => String::from_utf8(raw.to_vec()).ok(),
+        _ => None,
+    }
+}
+
+/// Makes a new [TorrentString] from given `raw` bytes, preferring the
+/// `utf8_alt` from a `name.utf-8`/`path.utf-8` key if it's valid before
+/// decoding with [decode_with_encoding]
+fn make_torrent_string(
+    raw: Vec<u8>,
+    utf8_alt: Option<Bencode>,
+    encoding: Option<&str>,
+) -> TorrentString {
+    let decoded = match utf8_alt
+        .and_then(|alt| alt.bytestring())
+        .and_then(|alt| String::from_utf8(alt).ok())
+    {
+        Some(alt) => Some(alt),
+        None => decode_with_encoding(&raw, encoding),
+    };
+
+    TorrentString { raw, decoded }
+}
+
+/// Gets a list of valid UTF-8 URLs from given optional `urls_raw`, skipping
+/// elements which aren't. A single bytestring is treated as a list of one URL
+fn make_url_list(urls_raw: Option<Bencode>) -> Vec<String> {
+    match urls_raw {
+        Some(Bencode::List(list)) => list
+            .into_iter()
+            .filter_map(|url| url.bytestring())
+            .filter_map(|url| String::from_utf8(url).ok())
+            .collect(),
+        Some(Bencode::ByteString(url)) => String::from_utf8(url).into_iter().collect(),
+        _ => vec![],
+    }
+}
+
+/// Makes the [Torrent::nodes] list from the raw `nodes` key, skipping any
+/// elements which aren't a `[host, port]` pair with a UTF-8 host and valid port
+fn make_nodes(nodes_raw: Option<Bencode>) -> Vec<(String, u16)> {
+    let nodes_raw = match nodes_raw.and_then(|nodes_raw| nodes_raw.list()) {
+        Some(nodes_raw) => nodes_raw,
+        None => return vec![],
+    };
+
+    nodes_raw
+        .into_iter()
+        .filter_map(|node_raw| match node_raw.list().as_deref() {
+            Some([Bencode::ByteString(host), Bencode::Int(port)]) => Some((
+                String::from_utf8(host.clone()).ok()?,
+                (*port).try_into().ok()?,
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Makes a file length from given unparsed, raw `length_raw` [Bencode::Int],
+/// refusing negative lengths rather than wrapping them into huge ones
+fn make_length(length_raw: Bencode) -> Result<usize, TorrentCreationError> {
+    let length = length_raw
+        .int()
+        .ok_or(TorrentCreationError::LengthWrongType)?;
+
+    usize::try_from(length).map_err(|_| TorrentCreationError::BadLength)
+}
+
 /// Makes a new element for [TorrentFile::MultiFile] from given unparsed, raw
 /// `file_raw` [Bencode::Dict]. It is not required to check the `file_raw`
 /// [Bencode] type beforehand, this method will do for you
-fn make_multifile(file_raw: Bencode) -> Result<(usize, Vec<String>), TorrentCreationError> {
+fn make_multifile(
+    file_raw: Bencode,
+    encoding: Option<&str>,
+) -> Result<(usize, Vec<TorrentString>), TorrentCreationError> {
     match file_raw {
         Bencode::Dict(file_dict) => {
-            let length = get_dict_item(&file_dict, TorrentBencodeKey::Length)?
-                .int()
-                .ok_or(TorrentCreationError::LengthWrongType)? as usize;
+            let length = make_length(get_dict_item(&file_dict, TorrentBencodeKey::Length)?)?;
             let path_raw_vec = get_dict_item(&file_dict, TorrentBencodeKey::Path)?
                 .list()
                 .ok_or(TorrentCreationError::PathWrongType)?;
 
-            if path_raw_vec.len() == 0 {
+            if path_raw_vec.is_empty() {
                 return Err(TorrentCreationError::NoPathFound);
             }
 
+            // only used if it matches up with `path`, otherwise it's ignored
+            let mut path_utf8_vec = get_opt_dict_item(&file_dict, TorrentBencodeKey::PathUtf8)
+                .and_then(|path_utf8| path_utf8.list())
+                .filter(|path_utf8| path_utf8.len() == path_raw_vec.len())
+                .map(|path_utf8| path_utf8.into_iter());
+
             let mut path = vec![];
 
             for subdir_raw in path_raw_vec {
+                let subdir_utf8 = path_utf8_vec.as_mut().and_then(|iter| iter.next());
+
                 path.push(match subdir_raw {
-                    Bencode::ByteString(found_subdir) => vecu8_to_string(found_subdir)?,
+                    Bencode::ByteString(found_subdir) => {
+                        make_torrent_string(found_subdir, subdir_utf8, encoding)
+                    }
                     _ => return Err(TorrentCreationError::SubdirWrongType),
                 })
             }
 
             Ok((length, path))
         }
-        _ => return Err(TorrentCreationError::FileWrongType),
+        _ => Err(TorrentCreationError::FileWrongType),
     }
 }
 
@@ -120,19 +320,51 @@ impl Torrent {
     /// If an error is encountered, it will be a [TorrentCreationError] wrapped
     /// inside of [TorroError::TorrentCreationError]
     pub fn new(torrent_data: Vec<u8>) -> Result<Self, TorroError> {
-        let parsed_bencode = bencode::parse(torrent_data)?;
+        let parsed_bencode = bencode::parse_slice(&torrent_data)?;
 
         match parsed_bencode {
             Bencode::Dict(dict_data) => {
                 // top-level dictionary
-                let announce = vecu8_to_string(
-                    get_dict_item(&dict_data, TorrentBencodeKey::Announce)?
-                        .bytestring()
-                        .ok_or(TorrentCreationError::AnnounceWrongType)?,
-                )?;
+                let nodes = make_nodes(get_opt_dict_item(&dict_data, TorrentBencodeKey::Nodes));
+                let announce = match get_dict_item(&dict_data, TorrentBencodeKey::Announce) {
+                    Ok(announce_raw) => vecu8_to_string(
+                        announce_raw
+                            .bytestring()
+                            .ok_or(TorrentCreationError::AnnounceWrongType)?,
+                    )?,
+                    Err(_) if !nodes.is_empty() => String::new(), // trackerless
+                    Err(err) => return Err(err.into()),
+                };
                 let info_dict = get_dict_item(&dict_data, TorrentBencodeKey::Info)?
                     .dict()
                     .ok_or(TorrentCreationError::InfoWrongType)?;
+                let encoding = get_opt_dict_item(&dict_data, TorrentBencodeKey::Encoding)
+                    .and_then(|encoding_raw| encoding_raw.bytestring())
+                    .and_then(|encoding_raw| String::from_utf8(encoding_raw).ok());
+                let announce_list = get_opt_dict_item(&dict_data, TorrentBencodeKey::AnnounceList)
+                    .and_then(|tiers_raw| tiers_raw.list())
+                    .map(|tiers_raw| {
+                        tiers_raw
+                            .into_iter()
+                            .map(|tier_raw| make_url_list(Some(tier_raw)))
+                            .filter(|tier| !tier.is_empty())
+                            .collect()
+                    });
+                let comment = get_opt_dict_item(&dict_data, TorrentBencodeKey::Comment)
+                    .and_then(|comment_raw| comment_raw.bytestring())
+                    .map(|comment_raw| {
+                        make_torrent_string(
+                            comment_raw,
+                            get_opt_dict_item(&dict_data, TorrentBencodeKey::CommentUtf8),
+                            encoding.as_deref(),
+                        )
+                    });
+                let web_seeds =
+                    make_url_list(get_opt_dict_item(&dict_data, TorrentBencodeKey::UrlList));
+                let info_range =
+                    bencode::dict_value_range(&torrent_data, &TorrentBencodeKey::Info.as_vecu8())
+                        .ok_or(TorrentCreationError::NoInfoFound)?;
+                let info_hash = sha1(&torrent_data[info_range.clone()]);
 
                 // inside info_dict
                 let piece_length = get_dict_item(&info_dict, TorrentBencodeKey::PieceLength)?
@@ -142,18 +374,18 @@ impl Torrent {
                 let pieces_raw = get_dict_item(&info_dict, TorrentBencodeKey::Pieces)?
                     .bytestring()
                     .ok_or(TorrentCreationError::PiecesWrongType)?;
-                let name = vecu8_to_string(
+                let name = make_torrent_string(
                     get_dict_item(&info_dict, TorrentBencodeKey::Name)?
                         .bytestring()
                         .ok_or(TorrentCreationError::NameWrongType)?,
-                )?;
+                    get_opt_dict_item(&info_dict, TorrentBencodeKey::NameUtf8),
+                    encoding.as_deref(),
+                );
+                let private = get_opt_dict_item(&info_dict, TorrentBencodeKey::Private)
+                    .and_then(|private_raw| private_raw.int())
+                    == Some(1);
                 let length = match get_dict_item(&info_dict, TorrentBencodeKey::Length) {
-                    Ok(length_raw) => Some(
-                        length_raw
-                            .int()
-                            .ok_or(TorrentCreationError::LengthWrongType)?
-                            as usize,
-                    ),
+                    Ok(length_raw) => Some(make_length(length_raw)?),
                     Err(_) => None,
                 };
                 let files_raw: Option<Vec<Bencode>> =
@@ -172,43 +404,108 @@ impl Torrent {
                     .map(|c| c.to_vec())
                     .collect();
 
-                let file_structure = if files_raw.is_some() {
-                    if length.is_some() {
-                        return Err(TorrentCreationError::BothLengthFiles.into());
-                    }
+                let file_structure = match (files_raw, length) {
+                    (Some(_), Some(_)) => return Err(TorrentCreationError::BothLengthFiles.into()),
+                    (Some(files_raw), None) => {
+                        let mut files = vec![];
 
-                    let mut files = vec![];
+                        for file_raw in files_raw {
+                            files.push(make_multifile(file_raw, encoding.as_deref())?);
+                        }
 
-                    for file_raw in files_raw.unwrap() {
-                        files.push(make_multifile(file_raw)?);
-                    }
+                        let files = TorrentFile::MultiFile(files);
+
+                        if files.total_length().is_none() {
+                            return Err(TorrentCreationError::BadLength.into());
+                        }
 
-                    TorrentFile::MultiFile(files)
-                } else if length.is_some() {
-                    TorrentFile::Single(length.unwrap() as usize)
-                } else {
-                    return Err(TorrentCreationError::NoLengthFiles.into());
+                        files
+                    }
+                    (None, Some(length)) => TorrentFile::Single(length),
+                    (None, None) => return Err(TorrentCreationError::NoLengthFiles.into()),
                 };
 
                 Ok(Self {
                     announce,
+                    announce_list,
+                    comment,
+                    web_seeds,
+                    nodes,
                     name,
+                    encoding,
                     piece_length,
                     pieces,
                     file_structure,
+                    private,
+                    info_hash,
+                    source: torrent_data,
+                    info_range,
                 })
             }
             _ => Err(TorrentCreationError::NoTLDictionary.into()),
         }
     }
 
+    /// Creates a new [Torrent] from given `torrent_data` formatted as a `&[u8]`
+    /// slice, for when the bytes are borrowed from elsewhere (e.g. a HTTP body
+    /// or an archive already in memory)
+    ///
+    /// The bytes are copied once to be kept as [Torrent::as_bytes], see
+    /// [Torrent::new] if you already have an owned `Vec<u8>` to avoid this
+    pub fn from_slice(torrent_data: &[u8]) -> Result<Self, TorroError> {
+        Torrent::new(torrent_data.to_vec())
+    }
+
+    /// Creates a new [Torrent] by reading all bytes from a given `reader` until
+    /// it ends, such as [std::io::stdin] or a file inside of an archive
+    ///
+    /// If reading fails, a [TorroError::BadRead] is returned with the kind of
+    /// error which occured. Asynchronous readers aren't directly supported to
+    /// keep torro dependency-free, but their output may be collected into a
+    /// `Vec<u8>` and given to [Torrent::new] instead
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use torro::Torrent;
+    ///
+    /// fn main() {
+    ///     let my_torrent = Torrent::from_reader(std::io::stdin()).unwrap();
+    ///
+    ///     println!("Torrent name: '{}'", my_torrent.name);
+    /// }
+    /// ```
+    pub fn from_reader(mut reader: impl Read) -> Result<Self, TorroError> {
+        let mut torrent_data = vec![];
+
+        reader
+            .read_to_end(&mut torrent_data)
+            .map_err(|err| TorroError::BadRead(err.kind()))?;
+
+        Torrent::new(torrent_data)
+    }
+
     /// Creates a new [Torrent] from given `.torrent` file path
-    pub fn from_file(file: PathBuf) -> Result<Self, TorroError> {
+    pub fn from_file(file: impl Into<PathBuf>) -> Result<Self, TorroError> {
+        let file = file.into();
+
         match read_file_bytes(&file) {
             Ok(bytes) => Ok(Torrent::new(bytes)?),
             Err(_) => Err(TorroError::BadFileRead(file)),
         }
     }
+
+    /// Gets the original bencoded bytes this torrent was made from (or the
+    /// bytes written by the last [Torrent::edit]) without copying them
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.source
+    }
+
+    /// Gets the raw bencoded `info` dictionary of this torrent without copying
+    /// it, which is exactly what [Torrent::info_hash] is hashed from
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.source[self.info_range.clone()]
+    }
 }
 
 #[cfg(test)]
@@ -294,6 +591,31 @@ mod tests {
         )
     }
 
+    /// Tests that negative file lengths and lengths adding up past a [u64] are
+    /// refused rather than wrapping
+    #[test]
+    fn length_bad() {
+        assert_eq!(
+            Torrent::new(
+                "d8:announce0:4:infod4:name4:test12:piece lengthi16e6:pieces0:6:lengthi-1eee"
+                    .as_bytes()
+                    .to_vec()
+            ),
+            Err(TorrentCreationError::BadLength.into())
+        );
+        assert_eq!(
+            Torrent::new(
+                "d8:announce0:4:infod4:name4:test12:piece lengthi16e6:pieces0:5:filesl\
+                d6:lengthi9223372036854775807e4:pathl1:aee\
+                d6:lengthi9223372036854775807e4:pathl1:bee\
+                d6:lengthi9223372036854775807e4:pathl1:ceeeee"
+                    .as_bytes()
+                    .to_vec()
+            ),
+            Err(TorrentCreationError::BadLength.into())
+        );
+    }
+
     /// Tests that the `path` element of a file inside of [TorrentBencodeKey::Files]
     /// returns the wrong type correctly as an error
     #[test]
@@ -383,4 +705,110 @@ mod tests {
             Err(TorrentCreationError::NoPiecesFound.into())
         );
     }
+
+    /// Tests that a `name.utf-8` key is preferred over a non-UTF-8 `name` and
+    /// that the raw bytes are kept
+    #[test]
+    fn name_utf8_preferred() {
+        let mut data = b"d8:announce0:4:infod4:name4:".to_vec();
+        data.extend_from_slice(&[0x63, 0x61, 0x66, 0xe9]); // "caf\xe9"
+        data.extend_from_slice(
+            b"10:name.utf-85:caf\xc3\xa912:piece lengthi0e6:pieces0:6:lengthi0eee",
+        );
+
+        let torrent = Torrent::new(data).unwrap();
+
+        assert_eq!(torrent.name, "caf\u{e9}");
+        assert_eq!(torrent.name.as_bytes(), &[0x63, 0x61, 0x66, 0xe9]);
+    }
+
+    /// Tests that the top-level `encoding` key is used to decode names and paths
+    /// when no UTF-8 alternative is given
+    #[test]
+    fn encoding_honoured() {
+        let mut data =
+            b"d8:announce0:8:encoding12:windows-12524:infod5:filesld6:lengthi0e4:pathl2:".to_vec();
+        data.extend_from_slice(&[0x80, 0x41]);
+        data.extend_from_slice(b"eee4:name4:test12:piece lengthi0e6:pieces0:ee");
+
+        let torrent = Torrent::new(data).unwrap();
+
+        assert_eq!(torrent.encoding, Some("windows-1252".to_string()));
+        match torrent.file_structure {
+            TorrentFile::MultiFile(files) => assert_eq!(files[0].1[0], "\u{20ac}A"),
+            _ => panic!("Expected a multi-file torrent"),
+        }
+    }
+
+    /// Tests that names in an `encoding` torro doesn't know are left undecoded
+    /// rather than guessed as UTF-8
+    #[test]
+    fn encoding_unsupported() {
+        let mut data = b"d8:announce0:8:encoding9:Shift_JIS4:infod6:lengthi0e4:name4:".to_vec();
+        data.extend_from_slice(&[0x83, 0x65, 0x83, 0x67]); // "テト" in Shift_JIS
+        data.extend_from_slice(b"12:piece lengthi0e6:pieces0:ee");
+
+        let torrent = Torrent::new(data).unwrap();
+
+        assert_eq!(torrent.name.decoded, None);
+        assert_eq!(torrent.name.as_bytes(), &[0x83, 0x65, 0x83, 0x67]);
+        assert_eq!(decode_with_encoding(b"ascii only", Some("GBK")), None);
+        assert_eq!(
+            decode_with_encoding(b"plain", Some("UTF-8")),
+            Some("plain".to_string())
+        );
+    }
+
+    /// Tests that trackerless torrents load with their `nodes`, skipping
+    /// malformed ones
+    #[test]
+    fn trackerless_nodes() {
+        let torrent = Torrent::new(
+            b"d4:infod4:name4:test12:piece lengthi0e6:pieces0:6:lengthi0ee5:nodesll11:dht.example\
+            i6881eel9:127.0.0.1i99999eeli6881eel7:1.2.3.4i1eeee"
+                .to_vec(),
+        )
+        .unwrap();
+
+        assert_eq!(torrent.announce, "");
+        assert_eq!(
+            torrent.nodes,
+            vec![
+                ("dht.example".to_string(), 6881),
+                ("1.2.3.4".to_string(), 1)
+            ]
+        );
+    }
+
+    /// Tests that undecodable names still load with a lossy display form
+    #[test]
+    fn undecodable_name_lossy() {
+        let mut data = b"d8:announce0:4:infod4:name2:".to_vec();
+        data.extend_from_slice(&[0x61, 0xff]);
+        data.extend_from_slice(b"12:piece lengthi0e6:pieces0:6:lengthi0eee");
+
+        let torrent = Torrent::new(data).unwrap();
+
+        assert_eq!(torrent.name.as_str(), None);
+        assert_eq!(torrent.name.to_string_lossy(), "a\u{fffd}");
+        assert_eq!(format!("{}", torrent.name), "a\u{fffd}");
+    }
+
+    /// Tests that [Torrent::from_slice] and [Torrent::from_reader] match
+    /// [Torrent::new] and keep the source bytes
+    #[test]
+    fn slice_reader_constructors() {
+        let data =
+            "d8:announce0:4:infod4:name4:test12:piece lengthi0e6:pieces0:6:lengthi0eee".as_bytes();
+        let torrent = Torrent::new(data.to_vec()).unwrap();
+
+        assert_eq!(Torrent::from_slice(data), Ok(torrent.clone()));
+        assert_eq!(Torrent::from_reader(data), Ok(torrent.clone()));
+        assert_eq!(torrent.as_bytes(), data);
+        assert_eq!(
+            torrent.info_bytes(),
+            "d4:name4:test12:piece lengthi0e6:pieces0:6:lengthi0ee".as_bytes()
+        );
+        assert_eq!(sha1(torrent.info_bytes()), torrent.info_hash);
+    }
 }
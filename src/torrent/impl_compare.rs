@@ -0,0 +1,313 @@
+//! Comparison of the contents of two [Torrent]s, used to find out if data
+//! downloaded for one torrent can be used to seed another (cross-seeding)
+//!
+//! See [Torrent::compare] and [TorrentComparison] for more infomation
+
+use crate::torrent::{Torrent, TorrentFile};
+use std::collections::HashSet;
+
+/// The overall kind of match found between two torrents by [Torrent::compare]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ContentMatch {
+    /// Both torrents have the same file sizes, paths and pieces, so contain
+    /// identical content even if their info-hashes differ (e.g. from a
+    /// different `private` flag or `source` key)
+    Identical,
+
+    /// Both torrents have the same file sizes and pieces but some file or
+    /// directory names differ
+    Renamed,
+
+    /// Both torrents have the same file sizes but where split into pieces of a
+    /// different `piece length`, so their `pieces` can't be compared and the
+    /// content has to be hash checked before it may be used to seed
+    Repieced,
+
+    /// Some files (see [TorrentComparison::file_matches]) or pieces (see
+    /// [TorrentComparison::shared_pieces]) are shared between the torrents but
+    /// their overall file layouts differ, or their layouts and `piece length`
+    /// are the same but only some `pieces` are
+    PartialOverlap,
+
+    /// No files or pieces are shared between the torrents
+    Unrelated,
+}
+
+/// A single file which was found in both torrents by [Torrent::compare]
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileMatch {
+    /// Index of the file inside of the torrent [Torrent::compare] was called on
+    pub ours: usize,
+
+    /// Index of the file inside of the other torrent
+    pub theirs: usize,
+
+    /// Length of the file in bytes, which is the same for both torrents
+    pub length: usize,
+
+    /// If the file path (including [Torrent::name]) is the same for both
+    /// torrents
+    pub same_path: bool,
+}
+
+/// The result of comparing two torrents with [Torrent::compare]
+#[derive(Debug, PartialEq, Clone)]
+pub struct TorrentComparison {
+    /// The overall kind of match between the torrents
+    pub content: ContentMatch,
+
+    /// Files which are found in both torrents, matched by length and preferably
+    /// path. Zero-length files are never matched
+    pub file_matches: Vec<FileMatch>,
+
+    /// Amount of piece hashes from the other torrent which are also found in
+    /// this torrent. Only meaningful if both torrents have the same
+    /// [Torrent::piece_length]
+    pub shared_pieces: usize,
+
+    /// If an existing download of this torrent can seed the other torrent
+    /// directly, without any files needing to be renamed, moved or rewritten.
+    /// This needs the same file layout and `pieces`, so is only ever set for
+    /// [ContentMatch::Identical] content
+    pub can_seed_directly: bool,
+}
+
+/// Flattens the files of given `torrent` into a list of lengths and raw paths,
+/// with the [Torrent::name] as the first path element
+fn flat_files(torrent: &Torrent) -> Vec<(usize, Vec<&[u8]>)> {
+    match &torrent.file_structure {
+        TorrentFile::Single(length) => vec![(*length, vec![torrent.name.as_bytes()])],
+        TorrentFile::MultiFile(files) => files
+            .iter()
+            .map(|(length, path)| {
+                let mut full_path = vec![torrent.name.as_bytes()];
+                full_path.extend(path.iter().map(|subdir| subdir.as_bytes()));
+
+                (*length, full_path)
+            })
+            .collect(),
+    }
+}
+
+/// Matches files between `ours` and `theirs` by length, first matching files
+/// which also have the same path and then any remaining files of the same
+/// length in order
+fn match_files(ours: &[(usize, Vec<&[u8]>)], theirs: &[(usize, Vec<&[u8]>)]) -> Vec<FileMatch> {
+    let mut matches = vec![];
+    let mut used_ours = vec![false; ours.len()];
+    let mut used_theirs = vec![false; theirs.len()];
+
+    for same_path in &[true, false] {
+        for (their_ind, (their_length, their_path)) in theirs.iter().enumerate() {
+            if used_theirs[their_ind] || *their_length == 0 {
+                continue;
+            }
+
+            let found = ours
+                .iter()
+                .enumerate()
+                .position(|(our_ind, (our_length, our_path))| {
+                    !used_ours[our_ind]
+                        && our_length == their_length
+                        && (!same_path || our_path == their_path)
+                });
+
+            if let Some(our_ind) = found {
+                used_ours[our_ind] = true;
+                used_theirs[their_ind] = true;
+
+                matches.push(FileMatch {
+                    ours: our_ind,
+                    theirs: their_ind,
+                    length: *their_length,
+                    same_path: ours[our_ind].1 == *their_path,
+                });
+            }
+        }
+    }
+
+    matches.sort_by_key(|file_match| file_match.theirs);
+
+    matches
+}
+
+impl Torrent {
+    /// Compares the contents of this torrent with an `other` torrent, matching
+    /// their file layouts (sizes and paths) and `pieces`
+    ///
+    /// This is primarily used for cross-seeding, where the same data is held
+    /// under several torrents from different trackers. See
+    /// [TorrentComparison::can_seed_directly] to find out if a download of this
+    /// torrent can be used to seed the `other` torrent as-is
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::path::PathBuf;
+    /// use torro::Torrent;
+    ///
+    /// fn main() {
+    ///     let ours = Torrent::from_file(PathBuf::from("ours.torrent")).unwrap();
+    ///     let theirs = Torrent::from_file(PathBuf::from("theirs.torrent")).unwrap();
+    ///
+    ///     let comparison = ours.compare(&theirs);
+    ///
+    ///     println!(
+    ///         "Content match: {:?}, can seed directly: {}",
+    ///         comparison.content, comparison.can_seed_directly
+    ///     );
+    /// }
+    /// ```
+    pub fn compare(&self, other: &Torrent) -> TorrentComparison {
+        let ours = flat_files(self);
+        let theirs = flat_files(other);
+
+        let same_sizes = ours.len() == theirs.len()
+            && ours
+                .iter()
+                .zip(theirs.iter())
+                .all(|(our_file, their_file)| our_file.0 == their_file.0);
+        let same_paths = same_sizes
+            && ours
+                .iter()
+                .zip(theirs.iter())
+                .all(|(our_file, their_file)| our_file.1 == their_file.1);
+        let same_piece_length = self.piece_length == other.piece_length;
+        let same_pieces = same_piece_length && self.pieces == other.pieces;
+
+        let shared_pieces = if same_piece_length {
+            let our_pieces: HashSet<&Vec<u8>> = self.pieces.iter().collect();
+            other
+                .pieces
+                .iter()
+                .filter(|piece| our_pieces.contains(piece))
+                .count()
+        } else {
+            0
+        };
+        let file_matches = match_files(&ours, &theirs);
+
+        let identical = self.info_hash == other.info_hash || (same_paths && same_pieces);
+
+        // with the same layout and piece length the pieces are authoritative,
+        // so matching file lengths alone don't count as an overlap
+        let content = if identical {
+            ContentMatch::Identical
+        } else if same_sizes && same_pieces {
+            ContentMatch::Renamed
+        } else if same_sizes && !same_piece_length {
+            ContentMatch::Repieced
+        } else if same_sizes {
+            if shared_pieces != 0 {
+                ContentMatch::PartialOverlap
+            } else {
+                ContentMatch::Unrelated
+            }
+        } else if !file_matches.is_empty() || shared_pieces != 0 {
+            ContentMatch::PartialOverlap
+        } else {
+            ContentMatch::Unrelated
+        };
+
+        TorrentComparison {
+            content,
+            file_matches,
+            shared_pieces,
+            can_seed_directly: identical,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::make_torrent;
+
+    /// Tests that identical content under a different info-hash is matched
+    #[test]
+    fn compare_identical() {
+        let ours = make_torrent("data", 16, b"aaaaaaaaaaaaaaaaaaaa", &[(10, "a"), (6, "b")]);
+        let mut theirs = ours.clone();
+        theirs.edit(crate::TorrentEdit::Private(true)).unwrap();
+
+        let comparison = ours.compare(&theirs);
+
+        assert_eq!(comparison.content, ContentMatch::Identical);
+        assert_eq!(comparison.shared_pieces, 1);
+        assert!(comparison.can_seed_directly);
+    }
+
+    /// Tests that renamed and re-pieced content is told apart, and that
+    /// neither can be seeded without changes
+    #[test]
+    fn compare_renamed_repieced() {
+        let ours = make_torrent("data", 16, b"aaaaaaaaaaaaaaaaaaaa", &[(10, "a"), (6, "b")]);
+        let renamed = make_torrent("other", 16, b"aaaaaaaaaaaaaaaaaaaa", &[(10, "a"), (6, "b")]);
+        let repieced = make_torrent(
+            "data",
+            8,
+            b"bbbbbbbbbbbbbbbbbbbbcccccccccccccccccccc",
+            &[(10, "a"), (6, "b")],
+        );
+
+        assert_eq!(ours.compare(&renamed).content, ContentMatch::Renamed);
+        assert!(!ours.compare(&renamed).can_seed_directly);
+        assert_eq!(ours.compare(&repieced).content, ContentMatch::Repieced);
+        assert!(!ours.compare(&repieced).can_seed_directly);
+    }
+
+    /// Tests that the same layout and piece length with different `pieces` is
+    /// not taken as the same content
+    #[test]
+    fn compare_same_layout_different_data() {
+        let ours = make_torrent(
+            "data",
+            16,
+            b"aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb",
+            &[(20, "a"), (12, "b")],
+        );
+        let changed = make_torrent(
+            "data",
+            16,
+            b"aaaaaaaaaaaaaaaaaaaacccccccccccccccccccc",
+            &[(20, "a"), (12, "b")],
+        );
+        let different = make_torrent(
+            "data",
+            16,
+            b"ddddddddddddddddddddcccccccccccccccccccc",
+            &[(20, "a"), (12, "b")],
+        );
+
+        let comparison = ours.compare(&changed);
+        assert_eq!(comparison.content, ContentMatch::PartialOverlap);
+        assert_eq!(comparison.shared_pieces, 1);
+        assert!(!comparison.can_seed_directly);
+
+        let comparison = ours.compare(&different);
+        assert_eq!(comparison.content, ContentMatch::Unrelated);
+        assert!(!comparison.can_seed_directly);
+    }
+
+    /// Tests that partially overlapping and unrelated content is found
+    #[test]
+    fn compare_partial_unrelated() {
+        let ours = make_torrent("data", 16, b"aaaaaaaaaaaaaaaaaaaa", &[(10, "a"), (6, "b")]);
+        let partial = make_torrent("pack", 16, b"cccccccccccccccccccc", &[(4, "c"), (6, "b")]);
+        let unrelated = make_torrent("pack", 16, b"cccccccccccccccccccc", &[(4, "c")]);
+
+        let comparison = ours.compare(&partial);
+
+        assert_eq!(comparison.content, ContentMatch::PartialOverlap);
+        assert_eq!(
+            comparison.file_matches,
+            vec![FileMatch {
+                ours: 1,
+                theirs: 1,
+                length: 6,
+                same_path: false
+            }]
+        );
+        assert_eq!(ours.compare(&unrelated).content, ContentMatch::Unrelated);
+    }
+}
@@ -2,25 +2,25 @@
 //!
 //! NOTE: Currently used as a placeholder module with many `unimplemented!()`
 
-use crate::error::{TorroError, TrackerError};
+use crate::error::TorroError;
 use crate::torrent::Torrent;
-// use crate::tracker_udp; // TODO: import
+use crate::tracker::{AnnounceManager, DEFAULT_PEER_PORT};
 
 impl Torrent {
     /// Downloads given torrent to the defined file/directory ([Torrent::name])
     ///
     /// If an error is encountered, it will be a
-    /// [TrackerError] wrapped inside of
+    /// [TrackerError](crate::error::TrackerError) wrapped inside of
     /// [TorroError::TrackerError](TorroError::TrackerError)
     pub fn download(&self) -> Result<(), TorroError> {
-        let tracker_info = self.get_tracker_info();
+        let _peers = self.announce_manager(DEFAULT_PEER_PORT).get_peers()?;
 
-        unimplemented!(); // TODO: finish
+        Err(TorroError::Unimplemented) // TODO: finish
     }
 
-    /// Gets tracker infomation from [torro::tracker_udp]
-    fn get_tracker_info(&self) -> ! {
-        // TODO: find return type and tracker udp module name
-        unimplemented!();
+    /// Creates an [AnnounceManager] for all of this torrent's trackers, used to
+    /// get peers whilst listening for them on `port`
+    pub fn announce_manager(&self, port: u16) -> AnnounceManager {
+        AnnounceManager::new(self, port)
     }
 }
@@ -0,0 +1,350 @@
+//! Editing of existing [Torrent]s, keeping track of if an edit changes the
+//! [Torrent::info_hash] or not
+//!
+//! See [TorrentEdit] and [Torrent::edit] for more infomation
+
+use super::impl_bencode::TorrentBencodeKey;
+use crate::bencode::{self, Bencode};
+use crate::error::{TorrentCreationError, TorroError};
+use crate::torrent::Torrent;
+use crate::utils::write_file_bytes;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The impact a [TorrentEdit] has upon the [Torrent::info_hash] of a [Torrent]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EditImpact {
+    /// The edit only changed keys outside of the `info` dictionary, so the
+    /// edited torrent keeps the same info-hash and stays in the same swarm
+    InfoHashKept,
+
+    /// The edit changed the `info` dictionary, giving the edited torrent a new
+    /// info-hash. Trackers and peers will see this as an entirely new torrent
+    InfoHashChanged,
+}
+
+/// A single edit to be made to a [Torrent] using [Torrent::edit]
+///
+/// Edits are split between edits to outer keys, which keep the
+/// [Torrent::info_hash], and edits to the `info` dictionary, which change it.
+/// Use [TorrentEdit::impact] to find out which kind an edit is before making it
+#[derive(Debug, PartialEq, Clone)]
+pub enum TorrentEdit {
+    /// Sets the main `announce` tracker URL
+    Announce(String),
+
+    /// Sets the `announce-list` tiers of tracker URLs, or removes the key if
+    /// [None] is given
+    AnnounceList(Option<Vec<Vec<String>>>),
+
+    /// Replaces every use of the first tracker URL with the second inside of
+    /// both the `announce` and `announce-list` keys
+    ReplaceTracker(String, String),
+
+    /// Sets the `comment` or removes it if [None] is given. Any `comment.utf-8`
+    /// alternative is always removed
+    Comment(Option<String>),
+
+    /// Sets the `url-list` web seeds, removing the key if an empty list is given
+    WebSeeds(Vec<String>),
+
+    /// Sets any other top-level key to a [Bencode] value, or removes the key if
+    /// [None] is given. Setting the `info` key with this replaces the whole
+    /// `info` dictionary and so changes the info-hash
+    Outer(Vec<u8>, Option<Bencode>),
+
+    /// Sets the `name` inside of the `info` dictionary. Any `name.utf-8`
+    /// alternative is always removed
+    Name(String),
+
+    /// Sets the `private` flag inside of the `info` dictionary, removing the
+    /// key if `false` is given
+    Private(bool),
+
+    /// Sets any key inside of the `info` dictionary to a [Bencode] value, or
+    /// removes the key if [None] is given
+    Info(Vec<u8>, Option<Bencode>),
+}
+
+/// Sets or removes (if [None]) given `key` of a bencoded `dict`
+fn set_key(dict: &mut BTreeMap<Vec<u8>, Bencode>, key: Vec<u8>, value: Option<Bencode>) {
+    match value {
+        Some(value) => dict.insert(key, value),
+        None => dict.remove(&key),
+    };
+}
+
+/// Converts a [String] into a [Bencode::ByteString]
+fn string_bencode(string: String) -> Bencode {
+    Bencode::ByteString(string.into_bytes())
+}
+
+/// Converts `announce-list` tiers into a [Bencode::List] of lists
+fn tiers_bencode(tiers: Vec<Vec<String>>) -> Bencode {
+    Bencode::List(
+        tiers
+            .into_iter()
+            .map(|tier| Bencode::List(tier.into_iter().map(string_bencode).collect()))
+            .collect(),
+    )
+}
+
+impl TorrentEdit {
+    /// Finds the [EditImpact] this edit will have on the [Torrent::info_hash]
+    /// of a [Torrent] once made
+    pub fn impact(&self) -> EditImpact {
+        match self {
+            TorrentEdit::Outer(key, _) if key == &TorrentBencodeKey::Info.as_vecu8() => {
+                EditImpact::InfoHashChanged
+            }
+            TorrentEdit::Announce(_)
+            | TorrentEdit::AnnounceList(_)
+            | TorrentEdit::ReplaceTracker(_, _)
+            | TorrentEdit::Comment(_)
+            | TorrentEdit::WebSeeds(_)
+            | TorrentEdit::Outer(_, _) => EditImpact::InfoHashKept,
+            TorrentEdit::Name(_) | TorrentEdit::Private(_) | TorrentEdit::Info(_, _) => {
+                EditImpact::InfoHashChanged
+            }
+        }
+    }
+
+    /// Applies this edit to the top-level `dict` of a parsed torrent
+    fn apply(self, dict: &mut BTreeMap<Vec<u8>, Bencode>) -> Result<(), TorrentCreationError> {
+        match self {
+            TorrentEdit::Announce(url) => set_key(
+                dict,
+                TorrentBencodeKey::Announce.as_vecu8(),
+                Some(string_bencode(url)),
+            ),
+            TorrentEdit::AnnounceList(tiers) => set_key(
+                dict,
+                TorrentBencodeKey::AnnounceList.as_vecu8(),
+                tiers.map(tiers_bencode),
+            ),
+            TorrentEdit::ReplaceTracker(old, new) => {
+                let old = string_bencode(old);
+                let new = string_bencode(new);
+
+                for (key, value) in dict.iter_mut() {
+                    if key == &TorrentBencodeKey::Announce.as_vecu8() && value == &old {
+                        *value = new.clone();
+                    } else if key == &TorrentBencodeKey::AnnounceList.as_vecu8() {
+                        if let Bencode::List(tiers) = value {
+                            for tier in tiers.iter_mut() {
+                                if let Bencode::List(urls) = tier {
+                                    for url in urls.iter_mut().filter(|url| **url == old) {
+                                        *url = new.clone();
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            TorrentEdit::Comment(comment) => {
+                set_key(dict, TorrentBencodeKey::CommentUtf8.as_vecu8(), None);
+                set_key(
+                    dict,
+                    TorrentBencodeKey::Comment.as_vecu8(),
+                    comment.map(string_bencode),
+                )
+            }
+            TorrentEdit::WebSeeds(urls) => set_key(
+                dict,
+                TorrentBencodeKey::UrlList.as_vecu8(),
+                if urls.is_empty() {
+                    None
+                } else {
+                    Some(Bencode::List(
+                        urls.into_iter().map(string_bencode).collect(),
+                    ))
+                },
+            ),
+            TorrentEdit::Outer(key, value) => set_key(dict, key, value),
+            info_edit => {
+                let info_dict = match dict.get_mut(&TorrentBencodeKey::Info.as_vecu8()) {
+                    Some(Bencode::Dict(info_dict)) => info_dict,
+                    _ => return Err(TorrentCreationError::InfoWrongType),
+                };
+
+                match info_edit {
+                    TorrentEdit::Name(name) => {
+                        set_key(info_dict, TorrentBencodeKey::NameUtf8.as_vecu8(), None);
+                        set_key(
+                            info_dict,
+                            TorrentBencodeKey::Name.as_vecu8(),
+                            Some(string_bencode(name)),
+                        )
+                    }
+                    TorrentEdit::Private(private) => set_key(
+                        info_dict,
+                        TorrentBencodeKey::Private.as_vecu8(),
+                        if private { Some(Bencode::Int(1)) } else { None },
+                    ),
+                    TorrentEdit::Info(key, value) => set_key(info_dict, key, value),
+                    _ => unreachable!("Outer edits are matched above"),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Encodes a top-level torrent `dict` whilst keeping the given `info_raw` bytes
+/// exactly as they are, so that the info-hash is kept even if the original
+/// `info` dictionary was not canonically encoded
+fn encode_keeping_info(dict: &BTreeMap<Vec<u8>, Bencode>, info_raw: &[u8]) -> Vec<u8> {
+    let info_key = TorrentBencodeKey::Info.as_vecu8();
+    let mut buf = vec![b'd'];
+
+    for (key, value) in dict {
+        buf.extend(bencode::encode(&Bencode::ByteString(key.clone())));
+
+        if key == &info_key {
+            buf.extend_from_slice(info_raw);
+        } else {
+            buf.extend(bencode::encode(value));
+        }
+    }
+
+    buf.push(b'e');
+
+    buf
+}
+
+impl Torrent {
+    /// Makes a single [TorrentEdit] to this torrent, returning the
+    /// [EditImpact] the edit had on [Torrent::info_hash]
+    ///
+    /// All fields of this torrent are updated to reflect the edit and any keys
+    /// which torro does not know about are kept. If the edit would leave the
+    /// torrent invalid, a [TorrentCreationError] wrapped inside of
+    /// [TorroError::TorrentCreationError] is returned and this torrent is left
+    /// unchanged
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::path::PathBuf;
+    /// use torro::{EditImpact, Torrent, TorrentEdit};
+    ///
+    /// fn main() {
+    ///     let mut my_torrent = Torrent::from_file(PathBuf::from("example.torrent")).unwrap();
+    ///
+    ///     let impact = my_torrent
+    ///         .edit(TorrentEdit::Comment(Some("Edited by torro".to_string())))
+    ///         .unwrap();
+    ///     assert_eq!(impact, EditImpact::InfoHashKept);
+    ///
+    ///     my_torrent.to_file(PathBuf::from("edited.torrent")).unwrap();
+    /// }
+    /// ```
+    pub fn edit(&mut self, edit: TorrentEdit) -> Result<EditImpact, TorroError> {
+        let impact = edit.impact();
+        let mut dict = bencode::parse_slice(&self.source)?
+            .dict()
+            .ok_or(TorrentCreationError::NoTLDictionary)?;
+
+        edit.apply(&mut dict)?;
+
+        let new_source = match impact {
+            EditImpact::InfoHashKept => encode_keeping_info(&dict, self.info_bytes()),
+            EditImpact::InfoHashChanged => bencode::encode(&Bencode::Dict(dict)),
+        };
+
+        *self = Torrent::new(new_source)?;
+
+        Ok(impact)
+    }
+
+    /// Gets the bencoded `.torrent` bytes for this torrent, including any
+    /// edits made with [Torrent::edit]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.source.clone()
+    }
+
+    /// Writes this torrent to a `.torrent` file at the given `file` path,
+    /// including any edits made with [Torrent::edit]
+    ///
+    /// If the file couldn't be written, a [TorroError::BadFileWrite] is returned
+    pub fn to_file(&self, file: impl Into<PathBuf>) -> Result<(), TorroError> {
+        let file = file.into();
+
+        write_file_bytes(&file, &self.source).map_err(|_| TorroError::BadFileWrite(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small multi-tracker torrent with an unknown `created by` key
+    const TEST_TORRENT: &str = "d8:announce9:http://a/13:announce-listll9:http://a/el9:http://b/ee10:created by5:torro4:infod4:name4:test12:piece lengthi16384e6:pieces0:6:lengthi0eee";
+
+    /// Tests that edits outside of the `info` dictionary keep the info-hash and
+    /// any unknown keys
+    #[test]
+    fn outer_edits_keep_info_hash() {
+        let mut torrent = Torrent::new(TEST_TORRENT.as_bytes().to_vec()).unwrap();
+        let info_hash = torrent.info_hash;
+
+        assert_eq!(
+            torrent.edit(TorrentEdit::ReplaceTracker(
+                "http://a/".to_string(),
+                "http://c/".to_string()
+            )),
+            Ok(EditImpact::InfoHashKept)
+        );
+        assert_eq!(
+            torrent.edit(TorrentEdit::Comment(Some("hi".to_string()))),
+            Ok(EditImpact::InfoHashKept)
+        );
+
+        assert_eq!(torrent.info_hash, info_hash);
+        assert_eq!(torrent.announce, "http://c/");
+        assert_eq!(
+            torrent.announce_list,
+            Some(vec![
+                vec!["http://c/".to_string()],
+                vec!["http://b/".to_string()]
+            ])
+        );
+        assert_eq!(torrent.comment.unwrap(), "hi");
+        assert!(String::from_utf8_lossy(&torrent.source).contains("10:created by5:torro"));
+    }
+
+    /// Tests that edits inside of the `info` dictionary give a new info-hash
+    #[test]
+    fn info_edits_change_info_hash() {
+        let mut torrent = Torrent::new(TEST_TORRENT.as_bytes().to_vec()).unwrap();
+        let info_hash = torrent.info_hash;
+
+        assert_eq!(
+            TorrentEdit::Private(true).impact(),
+            EditImpact::InfoHashChanged
+        );
+        assert_eq!(
+            torrent.edit(TorrentEdit::Private(true)),
+            Ok(EditImpact::InfoHashChanged)
+        );
+
+        assert!(torrent.private);
+        assert_ne!(torrent.info_hash, info_hash);
+    }
+
+    /// Tests that an edit which would make an invalid torrent is rejected and
+    /// leaves the torrent unchanged
+    #[test]
+    fn bad_edit_unchanged() {
+        let mut torrent = Torrent::new(TEST_TORRENT.as_bytes().to_vec()).unwrap();
+        let original = torrent.clone();
+
+        assert_eq!(
+            torrent.edit(TorrentEdit::Info(b"piece length".to_vec(), None)),
+            Err(TorrentCreationError::NoPieceLengthFound.into())
+        );
+        assert_eq!(torrent, original);
+    }
+}
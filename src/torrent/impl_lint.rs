@@ -0,0 +1,549 @@
+//! Linting of bencoded `.torrent` files, reporting every problem found instead
+//! of stopping at the first like [Torrent::new] does
+//!
+//! See [Torrent::lint] and [LintIssue] for more infomation
+
+use super::impl_bencode::TorrentBencodeKey;
+use crate::bencode::{self, Bencode};
+use crate::error::{BencodeError, TorrentCreationError};
+use crate::torrent::Torrent;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Smallest `piece length` which isn't warned about, 16KiB is the size of a
+/// single block request between peers
+const MIN_PIECE_LENGTH: i64 = 16 * 1024;
+
+/// How serious a [LintIssue] is
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LintSeverity {
+    /// The torrent will load but is unusual or may cause problems for some
+    /// clients
+    Warning,
+
+    /// The torrent is broken and will either fail to load or fail to download
+    Error,
+}
+
+/// The kind of problem found with [Torrent::lint]. Paths are given as lossy
+/// strings (see [TorrentString::to_string_lossy](crate::TorrentString::to_string_lossy))
+/// including the torrent's name as the first element
+#[derive(Debug, PartialEq, Clone)]
+pub enum LintKind {
+    /// The data could not be parsed as bencode at all
+    Bencode(BencodeError),
+
+    /// One of the checks also made by [Torrent::new] failed
+    Creation(TorrentCreationError),
+
+    /// The bencode is valid but not in it's canonical form (e.g. unsorted or
+    /// duplicate dictionary keys), which means other clients may re-encode the
+    /// `info` dictionary differently and get a different info-hash
+    NonCanonicalBencode,
+
+    /// The `announce` key is an empty bytestring
+    EmptyAnnounce,
+
+    /// The `piece length` is not a power of two
+    PieceLengthNotPowerOfTwo(i64),
+
+    /// The `piece length` is smaller than a 16KiB block (or zero/negative, which
+    /// is an [LintSeverity::Error])
+    PieceLengthTooSmall(i64),
+
+    /// The length of the `pieces` bytestring isn't a multiple of 20
+    PiecesNotMultipleOf20(usize),
+
+    /// The amount of piece hashes given in `pieces` doesn't match the amount
+    /// needed to cover the total length of all files
+    PieceCountMismatch {
+        /// Amount of pieces needed for the total length of all files
+        expected: usize,
+        /// Amount of pieces given in `pieces`
+        found: usize,
+    },
+
+    /// A file has a negative length
+    NegativeLength(Vec<String>),
+
+    /// The lengths of all files add up to more than can be represented, which
+    /// can only come from a broken or malicious torrent
+    LengthOverflow,
+
+    /// A file has a length of zero, which some clients mishandle
+    ZeroLengthFile(Vec<String>),
+
+    /// Two or more files share the same path
+    DuplicatePath(Vec<String>),
+
+    /// A name or path element is suspicious, such as `..`, containing a path
+    /// seperator or control characters, or being empty. Elements which could
+    /// escape the download directory are an [LintSeverity::Error]
+    SuspiciousPath(Vec<String>),
+}
+
+/// A single problem found with [Torrent::lint]
+///
+/// The [fmt::Display] implementation gives a human-readable message, suitable
+/// for showing to whoever provided the torrent
+#[derive(Debug, PartialEq, Clone)]
+pub struct LintIssue {
+    /// How serious this issue is
+    pub severity: LintSeverity,
+
+    /// The kind of issue found
+    pub kind: LintKind,
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            LintSeverity::Warning => write!(f, "warning: ")?,
+            LintSeverity::Error => write!(f, "error: ")?,
+        }
+
+        match &self.kind {
+            LintKind::Bencode(err) => write!(f, "invalid bencode ({:?})", err),
+            LintKind::Creation(err) => write!(f, "invalid torrent ({:?})", err),
+            LintKind::NonCanonicalBencode => write!(f, "bencode is not in canonical form"),
+            LintKind::EmptyAnnounce => write!(f, "`announce` tracker URL is empty"),
+            LintKind::PieceLengthNotPowerOfTwo(piece_length) => write!(
+                f,
+                "`piece length` of {} is not a power of two",
+                piece_length
+            ),
+            LintKind::PieceLengthTooSmall(piece_length) => write!(
+                f,
+                "`piece length` of {} is smaller than {}",
+                piece_length, MIN_PIECE_LENGTH
+            ),
+            LintKind::PiecesNotMultipleOf20(length) => {
+                write!(f, "`pieces` length of {} is not a multiple of 20", length)
+            }
+            LintKind::PieceCountMismatch { expected, found } => write!(
+                f,
+                "{} piece hashes given but the total length needs {}",
+                found, expected
+            ),
+            LintKind::NegativeLength(path) => {
+                write!(f, "`{}` has a negative length", path.join("/"))
+            }
+            LintKind::LengthOverflow => write!(f, "total length of all files overflows"),
+            LintKind::ZeroLengthFile(path) => {
+                write!(f, "`{}` has a length of zero", path.join("/"))
+            }
+            LintKind::DuplicatePath(path) => {
+                write!(f, "`{}` is given more than once", path.join("/"))
+            }
+            LintKind::SuspiciousPath(path) => {
+                write!(f, "`{}` is a suspicious path", path.join("/"))
+            }
+        }
+    }
+}
+
+/// Collects [LintIssue]s whilst linting
+#[derive(Default)]
+struct Lints(Vec<LintIssue>);
+
+impl Lints {
+    /// Adds a new [LintSeverity::Warning]
+    fn warn(&mut self, kind: LintKind) {
+        self.0.push(LintIssue {
+            severity: LintSeverity::Warning,
+            kind,
+        })
+    }
+
+    /// Adds a new [LintSeverity::Error]
+    fn error(&mut self, kind: LintKind) {
+        self.0.push(LintIssue {
+            severity: LintSeverity::Error,
+            kind,
+        })
+    }
+
+    /// Adds a new [LintSeverity::Error] for a [TorrentCreationError]
+    fn creation(&mut self, err: TorrentCreationError) {
+        self.error(LintKind::Creation(err))
+    }
+}
+
+/// Finds the [LintSeverity] of a name or path `element` if it's suspicious
+fn suspicious_severity(element: &[u8]) -> Option<LintSeverity> {
+    if element == b".." || element.contains(&b'/') || element.contains(&b'\\') {
+        Some(LintSeverity::Error)
+    } else if element.is_empty()
+        || element == b"."
+        || element.iter().any(|byte| byte.is_ascii_control())
+    {
+        Some(LintSeverity::Warning)
+    } else {
+        None
+    }
+}
+
+/// Lints a single name or `path` from the `checked_from` element onwards, adding
+/// a [LintKind::SuspiciousPath] using the most serious [suspicious_severity] of
+/// any checked element
+fn lint_path(lints: &mut Lints, path: &[Vec<u8>], checked_from: usize) {
+    let severities: Vec<LintSeverity> = path[checked_from..]
+        .iter()
+        .filter_map(|element| suspicious_severity(element))
+        .collect();
+
+    if severities.is_empty() {
+        return;
+    }
+
+    let kind = LintKind::SuspiciousPath(lossy_path(path));
+
+    if severities.contains(&LintSeverity::Error) {
+        lints.error(kind)
+    } else {
+        lints.warn(kind)
+    }
+}
+
+/// Converts a raw path into lossy strings for a [LintKind]
+fn lossy_path(path: &[Vec<u8>]) -> Vec<String> {
+    path.iter()
+        .map(|element| String::from_utf8_lossy(element).to_string())
+        .collect()
+}
+
+/// Lints the `files` list of an `info` dictionary, returning the total length
+/// of all valid files or [None] if it overflows
+fn lint_files(lints: &mut Lints, name: &[u8], files_raw: Bencode) -> Option<i64> {
+    let files = match files_raw.list() {
+        Some(files) => files,
+        None => {
+            lints.creation(TorrentCreationError::FilesWrongType);
+            return None;
+        }
+    };
+
+    let mut total_length = Some(0i64);
+    let mut seen_paths = HashSet::new();
+
+    for file_raw in files {
+        let file_dict = match file_raw.dict() {
+            Some(file_dict) => file_dict,
+            None => {
+                lints.creation(TorrentCreationError::FileWrongType);
+                continue;
+            }
+        };
+
+        let mut path = vec![name.to_vec()];
+
+        match file_dict.get(&TorrentBencodeKey::Path.as_vecu8()) {
+            Some(Bencode::List(path_raw)) if path_raw.is_empty() => {
+                lints.creation(TorrentCreationError::NoPathFound)
+            }
+            Some(Bencode::List(path_raw)) => {
+                for element in path_raw {
+                    match element {
+                        Bencode::ByteString(element) => path.push(element.clone()),
+                        _ => lints.creation(TorrentCreationError::SubdirWrongType),
+                    }
+                }
+
+                lint_path(lints, &path, 1);
+
+                if !seen_paths.insert(path.clone()) {
+                    lints.error(LintKind::DuplicatePath(lossy_path(&path)))
+                }
+            }
+            Some(_) => lints.creation(TorrentCreationError::PathWrongType),
+            None => lints.creation(TorrentCreationError::NoPathFound),
+        }
+
+        match file_dict.get(&TorrentBencodeKey::Length.as_vecu8()) {
+            Some(Bencode::Int(length)) if *length < 0 => {
+                lints.error(LintKind::NegativeLength(lossy_path(&path)))
+            }
+            Some(Bencode::Int(length)) => {
+                if *length == 0 {
+                    lints.warn(LintKind::ZeroLengthFile(lossy_path(&path)))
+                }
+
+                if let Some(total) = total_length {
+                    total_length = total.checked_add(*length);
+
+                    if total_length.is_none() {
+                        lints.error(LintKind::LengthOverflow)
+                    }
+                }
+            }
+            Some(_) => lints.creation(TorrentCreationError::LengthWrongType),
+            None => lints.creation(TorrentCreationError::NoLengthFiles),
+        }
+    }
+
+    total_length
+}
+
+/// Lints an `info` dictionary
+fn lint_info(lints: &mut Lints, info: BTreeMap<Vec<u8>, Bencode>) {
+    let name = match info.get(&TorrentBencodeKey::Name.as_vecu8()) {
+        Some(Bencode::ByteString(name)) => {
+            lint_path(lints, std::slice::from_ref(name), 0);
+            name.clone()
+        }
+        Some(_) => {
+            lints.creation(TorrentCreationError::NameWrongType);
+            vec![]
+        }
+        None => {
+            lints.creation(TorrentCreationError::NoNameFound);
+            vec![]
+        }
+    };
+
+    let piece_length = match info.get(&TorrentBencodeKey::PieceLength.as_vecu8()) {
+        Some(Bencode::Int(piece_length)) if *piece_length <= 0 => {
+            lints.error(LintKind::PieceLengthTooSmall(*piece_length));
+            None
+        }
+        Some(Bencode::Int(piece_length)) => {
+            if !(*piece_length as u64).is_power_of_two() {
+                lints.warn(LintKind::PieceLengthNotPowerOfTwo(*piece_length))
+            }
+
+            if *piece_length < MIN_PIECE_LENGTH {
+                lints.warn(LintKind::PieceLengthTooSmall(*piece_length))
+            }
+
+            Some(*piece_length)
+        }
+        Some(_) => {
+            lints.creation(TorrentCreationError::PieceLengthWrongType);
+            None
+        }
+        None => {
+            lints.creation(TorrentCreationError::NoPieceLengthFound);
+            None
+        }
+    };
+
+    let piece_count = match info.get(&TorrentBencodeKey::Pieces.as_vecu8()) {
+        Some(Bencode::ByteString(pieces)) if pieces.len() % 20 != 0 => {
+            lints.error(LintKind::PiecesNotMultipleOf20(pieces.len()));
+            None
+        }
+        Some(Bencode::ByteString(pieces)) => Some(pieces.len() / 20),
+        Some(_) => {
+            lints.creation(TorrentCreationError::PiecesWrongType);
+            None
+        }
+        None => {
+            lints.creation(TorrentCreationError::NoPiecesFound);
+            None
+        }
+    };
+
+    let total_length = match (
+        info.get(&TorrentBencodeKey::Length.as_vecu8()),
+        info.get(&TorrentBencodeKey::Files.as_vecu8()),
+    ) {
+        (Some(_), Some(_)) => {
+            lints.creation(TorrentCreationError::BothLengthFiles);
+            None
+        }
+        (None, None) => {
+            lints.creation(TorrentCreationError::NoLengthFiles);
+            None
+        }
+        (Some(Bencode::Int(length)), None) if *length < 0 => {
+            lints.error(LintKind::NegativeLength(lossy_path(&[name])));
+            None
+        }
+        (Some(Bencode::Int(length)), None) => Some(*length),
+        (Some(_), None) => {
+            lints.creation(TorrentCreationError::LengthWrongType);
+            None
+        }
+        (None, Some(files_raw)) => lint_files(lints, &name, files_raw.clone()),
+    };
+
+    if let (Some(piece_length), Some(found), Some(total_length)) =
+        (piece_length, piece_count, total_length)
+    {
+        // both are positive here, so this can't overflow like `a + b - 1`
+        let expected = total_length / piece_length + (total_length % piece_length != 0) as i64;
+        let expected = usize::try_from(expected).unwrap_or(usize::MAX);
+
+        if expected != found {
+            lints.error(LintKind::PieceCountMismatch { expected, found })
+        }
+    }
+}
+
+impl Torrent {
+    /// Lints given bencoded `torrent_data`, returning a list of every
+    /// [LintIssue] found. An empty list means no problems where found
+    ///
+    /// Unlike [Torrent::new], this doesn't stop at the first problem so it may
+    /// be used to give useful feedback about broken torrents. Any
+    /// [LintSeverity::Error] means that the torrent will either fail to load
+    /// with [Torrent::new] or fail to download
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use torro::{LintSeverity, Torrent};
+    ///
+    /// fn main() {
+    ///     let data = "d8:announce0:4:infod4:name4:test12:piece lengthi3e6:pieces0:6:lengthi5eee";
+    ///
+    ///     for issue in Torrent::lint(data.as_bytes()) {
+    ///         println!("{}", issue);
+    ///     }
+    /// }
+    /// ```
+    pub fn lint(torrent_data: &[u8]) -> Vec<LintIssue> {
+        let mut lints = Lints::default();
+
+        let parsed = match bencode::parse_slice(torrent_data) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                lints.error(LintKind::Bencode(err));
+                return lints.0;
+            }
+        };
+
+        if bencode::encode(&parsed) != torrent_data {
+            lints.warn(LintKind::NonCanonicalBencode)
+        }
+
+        let dict = match parsed.dict() {
+            Some(dict) => dict,
+            None => {
+                lints.creation(TorrentCreationError::NoTLDictionary);
+                return lints.0;
+            }
+        };
+
+        match dict.get(&TorrentBencodeKey::Announce.as_vecu8()) {
+            Some(Bencode::ByteString(announce)) if announce.is_empty() => {
+                lints.warn(LintKind::EmptyAnnounce)
+            }
+            Some(Bencode::ByteString(_)) => (),
+            Some(_) => lints.creation(TorrentCreationError::AnnounceWrongType),
+            None => lints.creation(TorrentCreationError::NoAnnounceFound),
+        }
+
+        match dict.get(&TorrentBencodeKey::Info.as_vecu8()) {
+            Some(Bencode::Dict(info)) => lint_info(&mut lints, info.clone()),
+            Some(_) => lints.creation(TorrentCreationError::InfoWrongType),
+            None => lints.creation(TorrentCreationError::NoInfoFound),
+        }
+
+        lints.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a well-formed torrent has no lints
+    #[test]
+    fn lint_clean() {
+        assert_eq!(
+            Torrent::lint(
+                "d8:announce9:http://a/4:infod6:lengthi16384e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+                    .as_bytes()
+            ),
+            vec![]
+        );
+    }
+
+    /// Tests that multiple problems are all reported instead of stopping at the
+    /// first one
+    #[test]
+    fn lint_many() {
+        let issues = Torrent::lint(
+            "d8:announce0:4:infod5:filesld6:lengthi0e4:pathl2:..eed6:lengthi5e4:pathl2:..eee4:name4:test12:piece lengthi3e6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+                .as_bytes(),
+        );
+        let kinds: Vec<LintKind> = issues.iter().map(|issue| issue.kind.clone()).collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                LintKind::EmptyAnnounce,
+                LintKind::PieceLengthNotPowerOfTwo(3),
+                LintKind::PieceLengthTooSmall(3),
+                LintKind::SuspiciousPath(vec!["test".to_string(), "..".to_string()]),
+                LintKind::ZeroLengthFile(vec!["test".to_string(), "..".to_string()]),
+                LintKind::SuspiciousPath(vec!["test".to_string(), "..".to_string()]),
+                LintKind::DuplicatePath(vec!["test".to_string(), "..".to_string()]),
+                LintKind::PieceCountMismatch {
+                    expected: 2,
+                    found: 1
+                },
+            ]
+        );
+        assert_eq!(issues[3].severity, LintSeverity::Error);
+    }
+
+    /// Tests that huge lengths from hostile torrents are reported rather than
+    /// overflowing
+    #[test]
+    fn lint_overflow() {
+        let kinds = |data: &str| -> Vec<LintKind> {
+            Torrent::lint(data.as_bytes())
+                .into_iter()
+                .map(|issue| issue.kind)
+                .collect()
+        };
+
+        assert_eq!(
+            kinds(
+                "d8:announce3:url4:infod5:filesld6:lengthi9223372036854775807e4:pathl1:aee\
+                d6:lengthi9223372036854775807e4:pathl1:beee4:name4:test\
+                12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+            ),
+            vec![LintKind::LengthOverflow]
+        );
+        assert_eq!(
+            kinds(
+                "d8:announce3:url4:infod6:lengthi9223372036854775807e4:name4:test\
+                12:piece lengthi4611686018427387904e6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+            ),
+            vec![LintKind::PieceCountMismatch {
+                expected: 2,
+                found: 1
+            }]
+        );
+    }
+
+    /// Tests that missing keys and non-canonical bencode are reported
+    #[test]
+    fn lint_missing_noncanonical() {
+        assert_eq!(
+            Torrent::lint("d4:infod4:name4:test6:pieces1:a12:piece lengthi16384eee".as_bytes()),
+            vec![
+                LintIssue {
+                    severity: LintSeverity::Warning,
+                    kind: LintKind::NonCanonicalBencode
+                },
+                LintIssue {
+                    severity: LintSeverity::Error,
+                    kind: LintKind::Creation(TorrentCreationError::NoAnnounceFound)
+                },
+                LintIssue {
+                    severity: LintSeverity::Error,
+                    kind: LintKind::PiecesNotMultipleOf20(1)
+                },
+                LintIssue {
+                    severity: LintSeverity::Error,
+                    kind: LintKind::Creation(TorrentCreationError::NoLengthFiles)
+                },
+            ]
+        );
+    }
+}
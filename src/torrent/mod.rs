@@ -4,10 +4,84 @@
 //! See [Torrent] and [TorrentFile] for more infomation
 
 mod impl_bencode;
+mod impl_compare;
 mod impl_download;
+mod impl_edit;
+mod impl_lint;
 
-pub use impl_bencode::*;
-pub use impl_download::*;
+pub use impl_compare::*;
+pub use impl_edit::*;
+pub use impl_lint::*;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+/// A bytestring from a `.torrent` file which is meant to be read as text, such
+/// as [Torrent::name] or a subdirectory of [TorrentFile::MultiFile]
+///
+/// Older torrents are commonly written in legacy encodings instead of UTF-8, so
+/// torro keeps the [TorrentString::raw] bytes exactly as given and decodes them
+/// into [TorrentString::decoded] where possible. Decoding is attempted in the
+/// following order:
+///
+/// 1. The `name.utf-8`/`path.utf-8` alternative keys, if present
+/// 2. The top-level `encoding` key (see [Torrent::encoding]), if present. Only
+///    UTF-8, ASCII, ISO-8859-1 and Windows-1252 are known to torro, so strings
+///    in any other encoding (such as Shift_JIS or GBK) are left undecoded
+/// 3. Plain UTF-8, if there is no `encoding` key
+///
+/// If none of these succeed, [TorrentString::to_string_lossy] may be used to get
+/// a displayable form (which is also what the [fmt::Display] implementation uses)
+#[derive(Debug, PartialEq, Clone)]
+pub struct TorrentString {
+    /// Raw bytes exactly as they where given inside of the `.torrent` file
+    pub raw: Vec<u8>,
+
+    /// Decoded text if a working decoding was found, see the documentation of
+    /// [TorrentString] for the order this is found in
+    pub decoded: Option<String>,
+}
+
+impl TorrentString {
+    /// Gets the raw bytes of this string, exactly as given in the `.torrent` file
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Gets the decoded text of this string if it could be decoded
+    pub fn as_str(&self) -> Option<&str> {
+        self.decoded.as_deref()
+    }
+
+    /// Gets the decoded text of this string or, if it couldn't be decoded, a
+    /// lossy UTF-8 version with invalid bytes replaced by `U+FFFD`. This is
+    /// suitable for displaying or for use as a file name when downloading
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        match &self.decoded {
+            Some(decoded) => Cow::Borrowed(decoded),
+            None => String::from_utf8_lossy(&self.raw),
+        }
+    }
+}
+
+impl fmt::Display for TorrentString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_lossy())
+    }
+}
+
+impl PartialEq<str> for TorrentString {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == Some(other)
+    }
+}
+
+impl PartialEq<&str> for TorrentString {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == Some(*other)
+    }
+}
 
 /// Represents the overall torrent directory structure for a given [Torrent]
 ///
@@ -34,7 +108,7 @@ pub enum TorrentFile {
     Single(usize),
 
     /// Multiple files with a similar [usize] to [TorrentFile::Single] as the first
-    /// element and a [Vec] of [String] subdirectories
+    /// element and a [Vec] of [TorrentString] subdirectories
     ///
     /// # BitTorrent Description
     ///
@@ -45,7 +119,23 @@ pub enum TorrentFile {
     /// the last of which is the actual file name (a zero length list is an error
     /// case).
     /// ```
-    MultiFile(Vec<(usize, Vec<String>)>),
+    MultiFile(Vec<(usize, Vec<TorrentString>)>),
+}
+
+impl TorrentFile {
+    /// Gets the total length in bytes of all files, which is the length of the
+    /// data that [Torrent::pieces] covers
+    ///
+    /// [None] is given if the lengths add up to more than a [u64] can hold,
+    /// which [Torrent::new] refuses but an edited torrent may still have
+    pub fn total_length(&self) -> Option<u64> {
+        match self {
+            TorrentFile::Single(length) => Some(*length as u64),
+            TorrentFile::MultiFile(files) => files
+                .iter()
+                .try_fold(0u64, |total, (length, _)| total.checked_add(*length as u64)),
+        }
+    }
 }
 
 /// The primary representation of a torrent, created from a parsing function
@@ -70,6 +160,18 @@ pub enum TorrentFile {
 /// }
 /// ```
 ///
+/// From anything implementing [Read](std::io::Read), such as stdin:
+///
+/// ```no_run
+/// use torro::Torrent;
+///
+/// fn main() {
+///     let my_torrent = Torrent::from_reader(std::io::stdin()).unwrap();
+///
+///     println!("my_torrent's values: {:#?}", my_torrent);
+/// }
+/// ```
+///
 /// From raw torrent bytes:
 ///
 /// ```rust
@@ -97,7 +199,8 @@ pub enum TorrentFile {
 /// `0e08ddf84d8d3bf101cdf897fc312f2774588c9e`
 #[derive(Debug, PartialEq, Clone)]
 pub struct Torrent {
-    /// URL for tracker
+    /// URL for tracker, empty for trackerless torrents which only give
+    /// [Torrent::nodes]
     ///
     /// # BitTorrent Description
     ///
@@ -106,16 +209,75 @@ pub struct Torrent {
     /// ```
     pub announce: String,
 
+    /// Tiers of backup tracker URLs from the optional `announce-list` key, if
+    /// given. Elements which aren't valid UTF-8 bytestrings are skipped
+    ///
+    /// # BitTorrent Description
+    ///
+    /// *Taken from [BEP0012](https://www.bittorrent.org/beps/bep_0012.html)*
+    ///
+    /// ```none
+    /// In addition to the standard "announce" key, in the main area of the
+    /// metadata file and not part of the "info" section, will be a new key,
+    /// "announce-list". This key will refer to a list of lists of URLs, and will
+    /// contain a list of tiers of announces.
+    /// ```
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    /// Free-form comment from the optional `comment` key (or it's `comment.utf-8`
+    /// alternative), if given
+    pub comment: Option<TorrentString>,
+
+    /// HTTP/FTP web seed URLs from the optional `url-list` key, which may be
+    /// given as either a single URL or a list of them
+    ///
+    /// # BitTorrent Description
+    ///
+    /// *Taken from [BEP0019](https://www.bittorrent.org/beps/bep_0019.html)*
+    ///
+    /// ```none
+    /// In the main area of the metadata file and not part of the "info" section,
+    /// will be a new key, "url-list". This key will refer to a one or more URLs,
+    /// and will contain a list of web addresses where torrent data can be
+    /// retrieved.
+    /// ```
+    pub web_seeds: Vec<String>,
+
+    /// DHT nodes to bootstrap from as `(host, port)` pairs, from the optional
+    /// `nodes` key typically given by trackerless torrents. Elements which
+    /// aren't a valid pair are skipped
+    ///
+    /// # BitTorrent Description
+    ///
+    /// *Taken from [BEP0005](https://www.bittorrent.org/beps/bep_0005.html)*
+    ///
+    /// ```none
+    /// A trackerless torrent dictionary does not have an "announce" key.
+    /// Instead, a trackerless torrent has a "nodes" key. This key should be set
+    /// to the K closest nodes in the torrent generating client's routing table.
+    /// Alternatively, the key could be set to a known good node such as one
+    /// operated by the person generating the torrent.
+    /// ```
+    pub nodes: Vec<(String, u16)>,
+
     /// Advised save name for torrent once leeched, is use by torro by default
     /// but may be changed
     ///
+    /// This is a [TorrentString] as older torrents may not have a UTF-8 name,
+    /// see it's documentation for how the name is decoded
+    ///
     /// # BitTorrent Description
     ///
     /// ```none
     /// The `name` key maps to a UTF-8 encoded string which is the suggested name
     /// to save the file (or directory) as. It is purely advisory.
     /// ```
-    pub name: String, // TODO: allow changing once implemented
+    pub name: TorrentString, // TODO: allow changing once implemented
+
+    /// The character encoding used for strings such as [Torrent::name], if
+    /// given by the optional top-level `encoding` key. This is not part of
+    /// BEP0003 but is commonly written by older clients
+    pub encoding: Option<String>,
 
     /// File buffer (aka piece) length, commonly a power of 2 (e.g. `2`, `4`,
     /// `8`, `16`)
@@ -177,4 +339,74 @@ pub struct Torrent {
     /// muliple file case, it's the name of a directory.
     /// ```
     pub file_structure: TorrentFile,
+
+    /// If this torrent is private, from the optional `private` key inside of
+    /// the `info` dictionary. Private torrents should only get peers from their
+    /// trackers
+    ///
+    /// # BitTorrent Description
+    ///
+    /// *Taken from [BEP0027](https://www.bittorrent.org/beps/bep_0027.html)*
+    ///
+    /// ```none
+    /// When generating a metainfo file, users denote a torrent as private by
+    /// including the key-value pair "private=1" in the "info" dict of the
+    /// torrent's metainfo file.
+    /// ```
+    pub private: bool,
+
+    /// The SHA-1 hash of the raw, bencoded `info` dictionary which is used to
+    /// identify this torrent with trackers and peers
+    ///
+    /// # BitTorrent Description
+    ///
+    /// ```none
+    /// info_hash - The 20 byte sha1 hash of the bencoded form of the info value
+    /// from the metainfo file.
+    /// ```
+    pub info_hash: [u8; 20],
+
+    /// The bencoded bytes this torrent was made from, kept so that any keys torro
+    /// doesn't know about are kept when writing an edited torrent
+    pub(crate) source: Vec<u8>,
+
+    /// Byte range of the raw `info` dictionary inside of [Torrent::source], see
+    /// [Torrent::info_bytes]
+    pub(crate) info_range: Range<usize>,
+}
+
+/// Makes a new multi-file [Torrent] from given `name`, `piece_length`, raw
+/// concatenated `pieces` and list of `(length, filename)` files for testing
+#[cfg(test)]
+pub(crate) fn make_torrent(
+    name: &str,
+    piece_length: usize,
+    pieces: &[u8],
+    files: &[(usize, &str)],
+) -> Torrent {
+    let files: String = files
+        .iter()
+        .map(|(length, filename)| {
+            format!(
+                "d6:lengthi{}e4:pathl{}:{}ee",
+                length,
+                filename.len(),
+                filename
+            )
+        })
+        .collect();
+    let mut raw = format!(
+        "d8:announce0:4:infod5:filesl{}e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+        files,
+        name.len(),
+        name,
+        piece_length,
+        pieces.len()
+    )
+    .into_bytes();
+
+    raw.extend_from_slice(pieces);
+    raw.extend_from_slice(b"ee");
+
+    Torrent::new(raw).unwrap()
 }
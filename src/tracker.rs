@@ -0,0 +1,658 @@
+//! Protocol-independant tracker access, choosing between [crate::tracker_udp]
+//! and [crate::tracker_http] by announce URL scheme
+//!
+//! Most users will only need an [AnnounceManager] (made with
+//! [Torrent::announce_manager](crate::Torrent::announce_manager)), which goes
+//! through all of a torrent's trackers as
+//! [BEP0012](https://www.bittorrent.org/beps/bep_0012.html) describes to get
+//! peers
+
+use crate::error::TrackerError;
+use crate::tracker_http::{HttpAnnounceResp, HttpTracker};
+use crate::tracker_udp::{
+    AnnounceEvent, AnnounceReq, AnnounceResp, ScrapeStats, TrackerSocket, UdpTracker,
+};
+use crate::utils::Xorshift;
+use crate::Torrent;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The port typically listened on for peers
+pub const DEFAULT_PEER_PORT: u16 = 6881;
+
+/// Time to wait before retrying a tracker after it's first failure, doubled for
+/// each failure after
+const BACKOFF_BASE: Duration = Duration::from_secs(15);
+
+/// Longest time to wait before retrying a failing tracker
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Timeouts an [AnnounceManager] gives UDP trackers by default, being the first
+/// two BEP0015 timeouts so a dead tracker is given up on after 45 seconds and
+/// backed off from, rather than blocking for the full BEP0015 schedule
+const MANAGER_UDP_TIMEOUTS: [Duration; 2] = [Duration::from_secs(15), Duration::from_secs(30)];
+
+/// A tracker's response to an announce, regardless of the protocol used
+#[derive(Debug, PartialEq, Clone)]
+pub struct TrackerResp {
+    /// Amount of seconds to wait before announcing again
+    pub interval: u32,
+
+    /// Minimum amount of seconds to wait before announcing again, if given
+    pub min_interval: Option<u32>,
+
+    /// Amount of peers which are still downloading, if given
+    pub leechers: Option<u32>,
+
+    /// Amount of peers which have completed their download, if given
+    pub seeders: Option<u32>,
+
+    /// Addresses of peers to connect to
+    pub peers: Vec<SocketAddr>,
+
+    /// A warning from the tracker which didn't stop the announce, if given
+    pub warning: Option<String>,
+}
+
+impl From<AnnounceResp> for TrackerResp {
+    fn from(resp: AnnounceResp) -> Self {
+        Self {
+            interval: resp.interval,
+            min_interval: None,
+            leechers: Some(resp.leechers),
+            seeders: Some(resp.seeders),
+            peers: resp.peers,
+            warning: None,
+        }
+    }
+}
+
+impl From<HttpAnnounceResp> for TrackerResp {
+    fn from(resp: HttpAnnounceResp) -> Self {
+        Self {
+            interval: resp.interval,
+            min_interval: resp.min_interval,
+            leechers: resp.leechers,
+            seeders: resp.seeders,
+            peers: resp.peers,
+            warning: resp.warning,
+        }
+    }
+}
+
+/// A connection to a single tracker of any protocol, typically made with
+/// [connect]
+pub trait Tracker {
+    /// Sends the announce request `req` to the tracker, returning the tracker's
+    /// [TrackerResp] or a [TrackerError]
+    fn announce(&mut self, req: &AnnounceReq) -> Result<TrackerResp, TrackerError>;
+
+    /// Scrapes the tracker for statistics on any amount of `info_hashes`,
+    /// returning a [ScrapeStats] for each in the same order or a [TrackerError]
+    fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError>;
+}
+
+impl Tracker for UdpTracker {
+    fn announce(&mut self, req: &AnnounceReq) -> Result<TrackerResp, TrackerError> {
+        UdpTracker::announce(self, req).map(TrackerResp::from)
+    }
+
+    fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        UdpTracker::scrape(self, info_hashes)
+    }
+}
+
+impl Tracker for HttpTracker {
+    fn announce(&mut self, req: &AnnounceReq) -> Result<TrackerResp, TrackerError> {
+        HttpTracker::announce(self, req).map(TrackerResp::from)
+    }
+
+    fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        HttpTracker::scrape(self, info_hashes)
+    }
+}
+
+/// Creates a [Tracker] for given `announce` URL, picking the protocol from it's
+/// scheme (`udp` or `http`). UDP trackers use the shared `socket`
+///
+/// # Examples
+///
+/// ```no_run
+/// use torro::tracker::connect;
+/// use torro::tracker_udp::{AnnounceReq, TrackerSocket};
+///
+/// fn main() {
+///     let socket = TrackerSocket::dual_stack(0).unwrap();
+///     let mut tracker = connect(&socket, "udp://tracker-url-here.co.biz:6969").unwrap();
+///     let resp = tracker.announce(&AnnounceReq::new([0; 20], 6881)).unwrap();
+///
+///     println!("Got {} peers: {:?}", resp.peers.len(), resp.peers);
+/// }
+/// ```
+pub fn connect(socket: &TrackerSocket, announce: &str) -> Result<Box<dyn Tracker>, TrackerError> {
+    connect_timeouts(socket, announce, None)
+}
+
+/// Same as [connect] but gives UDP trackers the `udp_timeouts`, if any, see
+/// [UdpTracker::timeouts]
+fn connect_timeouts(
+    socket: &TrackerSocket,
+    announce: &str,
+    udp_timeouts: Option<&[Duration]>,
+) -> Result<Box<dyn Tracker>, TrackerError> {
+    let scheme_end = announce
+        .find("://")
+        .ok_or_else(|| TrackerError::InvalidUrl(announce.to_string()))?;
+
+    match announce[..scheme_end].to_ascii_lowercase().as_str() {
+        "udp" => {
+            let tracker = UdpTracker::with_socket(socket, announce)?;
+
+            Ok(Box::new(match udp_timeouts {
+                Some(timeouts) => tracker.timeouts(timeouts.iter().copied()),
+                None => tracker,
+            }))
+        }
+        "http" | "https" => Ok(Box::new(HttpTracker::new(announce)?)),
+        scheme => Err(TrackerError::UnsupportedScheme(scheme.to_string())),
+    }
+}
+
+/// Shuffles given `tier` in place, as BEP0012 asks for each tier
+pub(crate) fn shuffle<T>(tier: &mut [T]) {
+    let mut rng = Xorshift::new();
+
+    for ind in (1..tier.len()).rev() {
+        tier.swap(ind, (rng.next_u64() % (ind as u64 + 1)) as usize);
+    }
+}
+
+/// A single tracker inside of an [AnnounceManager]'s tiers
+struct TierEntry {
+    /// Announce URL of the tracker
+    url: String,
+
+    /// Connection to the tracker, made when it is first announced to
+    tracker: Option<Box<dyn Tracker>>,
+
+    /// When the tracker's `interval` is next up
+    next_regular: Instant,
+
+    /// Earliest the tracker may be announced to without an event, from it's
+    /// `min interval`
+    min_next: Instant,
+
+    /// Amount of failures in a row for this tracker
+    failures: u32,
+
+    /// When a failing tracker may be retried
+    retry_at: Instant,
+}
+
+impl TierEntry {
+    /// Creates a new entry for `url` which can be announced to straight away
+    fn new(url: String) -> Self {
+        let now = Instant::now();
+
+        Self {
+            url,
+            tracker: None,
+            next_regular: now,
+            min_next: now,
+            failures: 0,
+            retry_at: now,
+        }
+    }
+
+    /// Announces `req` to this tracker, connecting to it first if needed with
+    /// given `udp_timeouts` and binding the shared `socket` if that hasn't
+    /// happened yet
+    fn announce(
+        &mut self,
+        socket: &mut Option<TrackerSocket>,
+        udp_timeouts: &[Duration],
+        req: &AnnounceReq,
+    ) -> Result<TrackerResp, TrackerError> {
+        if self.tracker.is_none() {
+            if socket.is_none() {
+                *socket = Some(TrackerSocket::dual_stack(0)?);
+            }
+
+            self.tracker = Some(connect_timeouts(
+                socket.as_ref().unwrap(),
+                &self.url,
+                Some(udp_timeouts),
+            )?);
+        }
+
+        self.tracker.as_mut().unwrap().announce(req)
+    }
+}
+
+/// Announces a single torrent to all of it's trackers, giving a single "get me
+/// peers" method in [AnnounceManager::get_peers]
+///
+/// Trackers are gone through in the tiers given by the torrent's
+/// `announce-list` (or just `announce` if there isn't one) as BEP0012
+/// describes: each tier is shuffled, the first tracker to respond is moved to
+/// the front of it's tier and later tiers are only used if every tracker in the
+/// tiers before fails. Failing trackers are backed off from exponentially
+///
+/// The `started` event is sent on the first announce, `completed` after
+/// [AnnounceManager::complete] and `stopped` with [AnnounceManager::stop]. Other
+/// announces honour the working tracker's `interval` (see
+/// [AnnounceManager::next_announce] for when to next announce), or only it's
+/// `min interval` when more peers are wanted sooner with
+/// [AnnounceManager::get_more_peers]
+///
+/// UDP trackers are only given a couple of short timeouts rather than the full
+/// BEP0015 schedule, so a dead tracker doesn't hold up the rest of it's tier
+/// for hours. See [AnnounceManager::udp_timeouts] to change these
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// The tiers of announces will be processed sequentially; all URLs in each
+/// tier must be checked before the client goes on to the next tier. URLs
+/// within each tier will be processed in a randomly chosen order; in other
+/// words, the list will be shuffled when first read, and then parsed in
+/// order. In addition, if a connection with a tracker is successful, it will
+/// be moved to the front of the tier.
+/// ```
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::PathBuf;
+/// use torro::Torrent;
+///
+/// fn main() {
+///     let torrent = Torrent::from_file(PathBuf::from("my.torrent")).unwrap();
+///     let mut manager = torrent.announce_manager(6881);
+///
+///     loop {
+///         let peers = manager.get_peers().unwrap();
+///         println!("Got {} new peers: {:?}", peers.len(), peers);
+///
+///         std::thread::sleep(manager.next_announce() - std::time::Instant::now());
+///     }
+/// }
+/// ```
+pub struct AnnounceManager {
+    /// Announce request used as the base of every announce, keeping the same
+    /// peer id and key throughout
+    req: AnnounceReq,
+
+    /// Tiers of trackers, in the order they are tried
+    tiers: Vec<Vec<TierEntry>>,
+
+    /// Socket shared by the UDP trackers, bound on first use if not given with
+    /// [AnnounceManager::socket]
+    socket: Option<TrackerSocket>,
+
+    /// Event to send on the next announce, if any
+    pending: Option<AnnounceEvent>,
+
+    /// If the `started` event has been sent successfully, so there is a tracker
+    /// which may need a `stopped` event
+    started: bool,
+
+    /// Tier of the tracker which last responded
+    working_tier: Option<usize>,
+
+    /// Timeouts given to UDP trackers, see [UdpTracker::timeouts]
+    udp_timeouts: Vec<Duration>,
+}
+
+impl AnnounceManager {
+    /// Creates a new manager for the `torrent`'s trackers, listening for peers
+    /// on `port`. Nothing is sent until [AnnounceManager::get_peers] is called
+    pub fn new(torrent: &Torrent, port: u16) -> Self {
+        let mut tiers = match &torrent.announce_list {
+            Some(announce_list) if announce_list.iter().any(|tier| !tier.is_empty()) => {
+                announce_list.clone()
+            }
+            _ if torrent.announce.is_empty() => vec![], // trackerless
+            _ => vec![vec![torrent.announce.clone()]],
+        };
+
+        for tier in tiers.iter_mut() {
+            shuffle(tier);
+        }
+
+        let req = AnnounceReq::new(torrent.info_hash, port)
+            .left(torrent.file_structure.total_length().unwrap_or(u64::MAX));
+
+        Self::from_tiers(req, tiers)
+    }
+
+    /// Creates a new manager from already-shuffled `tiers` of tracker URLs,
+    /// using `req` as the base of every announce
+    fn from_tiers(req: AnnounceReq, tiers: Vec<Vec<String>>) -> Self {
+        Self {
+            req,
+            tiers: tiers
+                .into_iter()
+                .filter(|tier| !tier.is_empty())
+                .map(|tier| tier.into_iter().map(TierEntry::new).collect())
+                .collect(),
+            socket: None,
+            pending: Some(AnnounceEvent::Started),
+            started: false,
+            working_tier: None,
+            udp_timeouts: MANAGER_UDP_TIMEOUTS.to_vec(),
+        }
+    }
+
+    /// Sets the socket used for UDP trackers, which may be shared with the
+    /// managers of other torrents. By default a new dual-stack socket is bound
+    /// on an ephemeral port with [TrackerSocket::dual_stack] once needed
+    pub fn socket(mut self, socket: TrackerSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Sets the timeouts given to UDP trackers for each exchange, which is 15
+    /// and then 30 seconds by default. See [UdpTracker::timeouts]
+    pub fn udp_timeouts(mut self, timeouts: impl IntoIterator<Item = Duration>) -> Self {
+        self.udp_timeouts = timeouts.into_iter().collect();
+        self
+    }
+
+    /// Sets the amount of bytes downloaded, uploaded and left to download, sent
+    /// on every announce after
+    pub fn set_progress(&mut self, downloaded: u64, uploaded: u64, left: u64) {
+        self.req.downloaded = downloaded;
+        self.req.uploaded = uploaded;
+        self.req.left = left;
+    }
+
+    /// Marks the download as complete, sending the `completed` event on the
+    /// next [AnnounceManager::get_peers] regardless of intervals
+    pub fn complete(&mut self) {
+        self.req.left = 0;
+
+        if self.started {
+            self.pending = Some(AnnounceEvent::Completed);
+        }
+    }
+
+    /// Gets when the next regular announce is due, which is now if an event is
+    /// waiting to be sent or nothing has been announced yet
+    pub fn next_announce(&self) -> Instant {
+        match self.working_tier {
+            Some(tier) if self.pending.is_none() => self.tiers[tier][0].next_regular,
+            _ => Instant::now(),
+        }
+    }
+
+    /// Announces to the first tracker that responds, returning it's peers or
+    /// the last [TrackerError] if every tracker failed
+    ///
+    /// If the working tracker's `interval` hasn't passed yet and there is no
+    /// event to send, nothing is sent and no peers are given. If every tracker
+    /// is being backed off from, [TrackerError::NoTrackersAvailable] is given
+    pub fn get_peers(&mut self) -> Result<Vec<SocketAddr>, TrackerError> {
+        self.announce(false)
+    }
+
+    /// Same as [AnnounceManager::get_peers] but announces before the working
+    /// tracker's `interval` is up if more peers are needed, as long as it's
+    /// `min interval` has passed
+    pub fn get_more_peers(&mut self) -> Result<Vec<SocketAddr>, TrackerError> {
+        self.announce(true)
+    }
+
+    /// Announces to the first tracker that responds, waiting for the working
+    /// tracker's `min interval` if `early` or it's `interval` otherwise
+    fn announce(&mut self, early: bool) -> Result<Vec<SocketAddr>, TrackerError> {
+        let now = Instant::now();
+        let req = self
+            .req
+            .clone()
+            .event(self.pending.unwrap_or(AnnounceEvent::None));
+        let mut last_err = TrackerError::NoTrackersAvailable;
+
+        for tier_ind in 0..self.tiers.len() {
+            for entry_ind in 0..self.tiers[tier_ind].len() {
+                let entry = &mut self.tiers[tier_ind][entry_ind];
+
+                if entry.failures != 0 && entry.retry_at > now {
+                    continue;
+                }
+
+                let next = if early {
+                    entry.min_next
+                } else {
+                    entry.next_regular
+                };
+
+                if entry.failures == 0 && next > now && self.pending.is_none() {
+                    return Ok(vec![]);
+                }
+
+                match entry.announce(&mut self.socket, &self.udp_timeouts, &req) {
+                    Ok(resp) => {
+                        let min_interval = resp.min_interval.unwrap_or(resp.interval);
+
+                        entry.failures = 0;
+                        entry.next_regular = now + Duration::from_secs(resp.interval as u64);
+                        entry.min_next = now + Duration::from_secs(min_interval as u64);
+
+                        let entry = self.tiers[tier_ind].remove(entry_ind);
+                        self.tiers[tier_ind].insert(0, entry);
+                        self.working_tier = Some(tier_ind);
+
+                        match self.pending.take() {
+                            Some(AnnounceEvent::Started) => self.started = true,
+                            Some(AnnounceEvent::Stopped) => self.started = false,
+                            _ => (),
+                        }
+
+                        return Ok(resp.peers);
+                    }
+                    Err(err) => {
+                        entry.failures += 1;
+                        entry.retry_at = now
+                            + (BACKOFF_BASE * 2u32.pow(entry.failures.min(8) - 1)).min(BACKOFF_MAX);
+                        last_err = err;
+                    }
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Sends the `stopped` event to the first tracker that responds, if the
+    /// `started` event was sent before. Announcing again after this sends
+    /// `started` again
+    pub fn stop(&mut self) -> Result<(), TrackerError> {
+        if !self.started {
+            return Ok(());
+        }
+
+        self.pending = Some(AnnounceEvent::Stopped);
+        let result = self.get_peers().map(|_| ());
+
+        if result.is_ok() {
+            self.pending = Some(AnnounceEvent::Started);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Log of `(url, event)` announces recieved by [MockTracker]s
+    type AnnounceLog = Rc<RefCell<Vec<(String, AnnounceEvent)>>>;
+
+    /// A stand-in [Tracker] which logs announces and fails if `fails`
+    struct MockTracker {
+        url: String,
+        fails: bool,
+        log: AnnounceLog,
+    }
+
+    impl Tracker for MockTracker {
+        fn announce(&mut self, req: &AnnounceReq) -> Result<TrackerResp, TrackerError> {
+            self.log.borrow_mut().push((self.url.clone(), req.event));
+
+            if self.fails {
+                return Err(TrackerError::Timeout);
+            }
+
+            Ok(TrackerResp {
+                interval: 1800,
+                min_interval: Some(60),
+                leechers: None,
+                seeders: None,
+                peers: vec!["10.0.0.1:6881".parse().unwrap()],
+                warning: None,
+            })
+        }
+
+        fn scrape(&mut self, _: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+            Err(TrackerError::ScrapeUnsupported)
+        }
+    }
+
+    /// Makes a manager with a [MockTracker] for each `(url, fails)` in `tiers`
+    fn mock_manager(tiers: &[&[(&str, bool)]]) -> (AnnounceManager, AnnounceLog) {
+        let log = AnnounceLog::default();
+        let urls = tiers
+            .iter()
+            .map(|tier| tier.iter().map(|(url, _)| url.to_string()).collect())
+            .collect();
+        let mut manager = AnnounceManager::from_tiers(AnnounceReq::new([0; 20], 6881), urls);
+
+        for (tier, mock_tier) in manager.tiers.iter_mut().zip(tiers) {
+            for (entry, (url, fails)) in tier.iter_mut().zip(mock_tier.iter()) {
+                entry.tracker = Some(Box::new(MockTracker {
+                    url: url.to_string(),
+                    fails: *fails,
+                    log: log.clone(),
+                }));
+            }
+        }
+
+        (manager, log)
+    }
+
+    /// Gets the URLs of the first tier of `manager` in order
+    fn first_tier(manager: &AnnounceManager) -> Vec<&str> {
+        manager.tiers[0]
+            .iter()
+            .map(|entry| entry.url.as_str())
+            .collect()
+    }
+
+    /// Tests that failing trackers are skipped within a tier and the working
+    /// tracker is moved to the front of it's tier
+    #[test]
+    fn tiers_fallback_reorder() {
+        let (mut manager, log) = mock_manager(&[&[("a", true), ("b", false)], &[("c", false)]]);
+
+        assert_eq!(manager.get_peers().unwrap().len(), 1);
+        assert_eq!(first_tier(&manager), vec!["b", "a"]);
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                ("a".to_string(), AnnounceEvent::Started),
+                ("b".to_string(), AnnounceEvent::Started)
+            ]
+        );
+    }
+
+    /// Tests that later tiers are used once every tracker in a tier fails, and
+    /// that failing trackers are backed off from
+    #[test]
+    fn tiers_next_tier_backoff() {
+        let (mut manager, log) = mock_manager(&[&[("a", true)], &[("b", true)]]);
+
+        assert_eq!(manager.get_peers(), Err(TrackerError::Timeout));
+        assert_eq!(log.borrow().len(), 2);
+        assert_eq!(manager.get_peers(), Err(TrackerError::NoTrackersAvailable));
+        assert_eq!(log.borrow().len(), 2);
+    }
+
+    /// Tests that events are sent in order and that announces without an event
+    /// honour the interval, or the minimum interval when more peers are wanted
+    #[test]
+    fn events_and_intervals() {
+        let (mut manager, log) = mock_manager(&[&[("a", false)]]);
+
+        assert_eq!(manager.get_peers().unwrap().len(), 1);
+        assert_eq!(manager.get_peers().unwrap(), vec![]);
+        assert_eq!(manager.get_more_peers().unwrap(), vec![]);
+        assert!(manager.next_announce() > Instant::now() + Duration::from_secs(1700));
+
+        manager.tiers[0][0].min_next = Instant::now();
+        assert_eq!(manager.get_peers().unwrap(), vec![]);
+        assert_eq!(manager.get_more_peers().unwrap().len(), 1);
+
+        manager.tiers[0][0].next_regular = Instant::now();
+        assert_eq!(manager.get_peers().unwrap().len(), 1);
+
+        manager.complete();
+        assert_eq!(manager.get_peers().unwrap().len(), 1);
+        manager.stop().unwrap();
+
+        assert_eq!(
+            log.borrow()
+                .iter()
+                .map(|(_, event)| *event)
+                .collect::<Vec<AnnounceEvent>>(),
+            vec![
+                AnnounceEvent::Started,
+                AnnounceEvent::None,
+                AnnounceEvent::None,
+                AnnounceEvent::Completed,
+                AnnounceEvent::Stopped
+            ]
+        );
+    }
+
+    /// Tests that a dead UDP tracker is given up on after the manager's short
+    /// timeouts, falling back to the next tier
+    #[test]
+    fn dead_udp_tracker_fallback() {
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dead_url = format!("udp://{}", silent.local_addr().unwrap());
+        let (manager, log) = mock_manager(&[&[("a", true)], &[("b", false)]]);
+        let mut manager = manager
+            .socket(TrackerSocket::bind("127.0.0.1:0").unwrap())
+            .udp_timeouts(vec![Duration::from_millis(50); 2]);
+        manager.tiers[0][0] = TierEntry::new(dead_url);
+
+        let start = Instant::now();
+        assert_eq!(manager.get_peers().unwrap().len(), 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            *log.borrow(),
+            vec![("b".to_string(), AnnounceEvent::Started)]
+        );
+    }
+
+    /// Tests that [connect] picks the protocol by scheme
+    #[test]
+    fn connect_schemes() {
+        let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+
+        assert!(connect(&socket, "udp://127.0.0.1:6969/announce").is_ok());
+        assert!(connect(&socket, "http://127.0.0.1:6969/announce").is_ok());
+        assert_eq!(
+            connect(&socket, "https://127.0.0.1/announce").err(),
+            Some(TrackerError::UnsupportedScheme("https".to_string()))
+        );
+        assert_eq!(
+            connect(&socket, "wss://127.0.0.1/announce").err(),
+            Some(TrackerError::UnsupportedScheme("wss".to_string()))
+        );
+    }
+}
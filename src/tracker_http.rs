@@ -0,0 +1,866 @@
+//! A [BEP0003](https://www.bittorrent.org/beps/bep_0003.html)-conforming HTTP
+//! tracker connection module, supporting compact
+//! ([BEP0023](https://www.bittorrent.org/beps/bep_0023.html)), IPv6
+//! ([BEP0007](https://www.bittorrent.org/beps/bep_0007.html)) and dictionary
+//! peer lists, along with the
+//! [BEP0048](https://www.bittorrent.org/beps/bep_0048.html) scrape convention
+//!
+//! Only plain `http://` trackers are supported as torro has no TLS
+//! implementation (and doesn't take on a dependency for one), so `https://`
+//! trackers give [TrackerError::UnsupportedScheme]
+
+use crate::bencode::{self, Bencode};
+use crate::error::TrackerError;
+use crate::tracker_udp::{
+    family_addrs, parse_compact_peers, AnnounceEvent, AnnounceReq, ScrapeStats, MAX_SCRAPE_HASHES,
+};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Default timeout for connecting to, sending to and recieving from an HTTP
+/// tracker
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest HTTP response read from a tracker, headers included, so a broken or
+/// malicious tracker can't make us read forever. Even a scrape of
+/// [MAX_SCRAPE_HASHES] torrents is well under this
+const MAX_HTTP_RESPONSE: u64 = 4 * 1024 * 1024;
+
+/// A parsed `http://` tracker URL
+#[derive(Debug, PartialEq, Clone)]
+struct HttpUrl {
+    /// Host of the tracker, with brackets kept for IPv6 addresses
+    host: String,
+
+    /// Port of the tracker, `80` if not given
+    port: u16,
+
+    /// Path of the tracker including any existing query, always starting with
+    /// a `/`
+    path: String,
+}
+
+impl HttpUrl {
+    /// Parses given `url`, only accepting the `http` scheme
+    fn parse(url: &str) -> Result<Self, TrackerError> {
+        let invalid = || TrackerError::InvalidUrl(url.to_string());
+
+        let scheme_end = url.find("://").ok_or_else(invalid)?;
+        let scheme = url[..scheme_end].to_ascii_lowercase();
+
+        if scheme != "http" {
+            return Err(TrackerError::UnsupportedScheme(scheme));
+        }
+
+        let rest = &url[scheme_end + 3..];
+        let rest = rest.split('#').next().unwrap();
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(ind) if rest[ind..].starts_with('/') => (&rest[..ind], rest[ind..].to_string()),
+            Some(ind) => (&rest[..ind], format!("/{}", &rest[ind..])),
+            None => (rest, "/".to_string()),
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(ind) if !authority[ind..].contains(']') => (
+                &authority[..ind],
+                authority[ind + 1..].parse().map_err(|_| invalid())?,
+            ),
+            _ => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+
+    /// Gets the full request target for this URL with given `query` pairs
+    /// appended, which should already be percent-encoded
+    fn target(&self, query: &str) -> String {
+        let seperator = if self.path.contains('?') { '&' } else { '?' };
+
+        format!("{}{}{}", self.path, seperator, query)
+    }
+
+    /// Derives the scrape URL from this announce URL, or [None] if this URL
+    /// doesn't follow the scrape convention
+    ///
+    /// # BitTorrent Description
+    ///
+    /// ```none
+    /// Take the tracker's announce URL. Find the last '/' in it. If the text
+    /// immediately following that '/' isn't 'announce' it will be taken as a
+    /// sign that that tracker doesn't support the scrape convention. If it
+    /// does, substitute 'scrape' for 'announce' to find the scrape page.
+    /// ```
+    fn to_scrape(&self) -> Option<Self> {
+        let query_start = self.path.find('?').unwrap_or(self.path.len());
+        let last_slash = self.path[..query_start].rfind('/')?;
+        let announce_end = last_slash + 1 + "announce".len();
+
+        if !self.path[last_slash + 1..query_start].starts_with("announce") {
+            return None;
+        }
+
+        Some(Self {
+            host: self.host.clone(),
+            port: self.port,
+            path: format!(
+                "{}scrape{}",
+                &self.path[..last_slash + 1],
+                &self.path[announce_end..]
+            ),
+        })
+    }
+}
+
+/// Percent-encodes given `bytes` for use in a URL query, leaving only the
+/// unreserved characters from RFC3986 as-is
+pub(crate) fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+
+    for byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(*byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// Decodes a percent-encoded URL query value from given `text`, also decoding
+/// `+` as a space. Gives [None] if a percent-encoding is invalid
+pub(crate) fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut ind = 0;
+
+    while ind < bytes.len() {
+        match bytes[ind] {
+            b'%' => {
+                let hex = text.get(ind + 1..ind + 3)?;
+
+                if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                    return None;
+                }
+
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                ind += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                ind += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                ind += 1;
+            }
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Resolves the host of `url` into up to one IPv4 and one IPv6 address
+fn resolve(url: &HttpUrl) -> Result<Vec<SocketAddr>, TrackerError> {
+    let addrs = (url.host.trim_matches(['[', ']']), url.port)
+        .to_socket_addrs()
+        .map(|addrs| family_addrs(addrs, true, true))
+        .unwrap_or_default();
+
+    if addrs.is_empty() {
+        return Err(TrackerError::AddressResolution(url.host.clone()));
+    }
+
+    Ok(addrs)
+}
+
+/// Sends an HTTP `GET` request for given `target` to the tracker at `url`,
+/// which has been resolved to `addr`, returning the status code and body of
+/// the response. Responses larger than [MAX_HTTP_RESPONSE] give
+/// [TrackerError::MalformedHttp]
+fn http_get(
+    url: &HttpUrl,
+    addr: SocketAddr,
+    target: &str,
+    timeout: Duration,
+) -> Result<(u16, Vec<u8>), TrackerError> {
+    let io_err = |err: std::io::Error| match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => TrackerError::Timeout,
+        kind => TrackerError::SocketIo(kind),
+    };
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(io_err)?;
+    stream.set_read_timeout(Some(timeout)).map_err(io_err)?;
+    stream.set_write_timeout(Some(timeout)).map_err(io_err)?;
+
+    let host = if url.port == 80 {
+        url.host.clone()
+    } else {
+        format!("{}:{}", url.host, url.port)
+    };
+
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: torro\r\nConnection: close\r\n\r\n",
+        target, host
+    )
+    .map_err(io_err)?;
+
+    let mut resp = vec![];
+    (&mut stream)
+        .take(MAX_HTTP_RESPONSE + 1)
+        .read_to_end(&mut resp)
+        .map_err(io_err)?;
+
+    if resp.len() as u64 > MAX_HTTP_RESPONSE {
+        return Err(TrackerError::MalformedHttp);
+    }
+
+    let header_end = resp
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or(TrackerError::MalformedHttp)?;
+    let status_line = resp[..header_end]
+        .split(|byte| *byte == b'\n')
+        .next()
+        .unwrap();
+    let status = std::str::from_utf8(status_line)
+        .ok()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or(TrackerError::MalformedHttp)?;
+
+    Ok((status, resp[header_end + 4..].to_vec()))
+}
+
+/// Parses the body of an HTTP tracker response with given `status` into it's
+/// bencoded dictionary, giving the tracker's `failure reason` as a
+/// [TrackerError::TrackerMessage] if there is one
+fn parse_tracker_body(
+    status: u16,
+    body: &[u8],
+) -> Result<BTreeMap<Vec<u8>, Bencode>, TrackerError> {
+    let dict = match bencode::parse_slice(body).map(|bencode| bencode.dict()) {
+        Ok(Some(dict)) => dict,
+        Ok(None) if status == 200 => return Err(TrackerError::MalformedHttp),
+        Err(err) if status == 200 => return Err(TrackerError::BadBencode(err)),
+        _ => return Err(TrackerError::HttpStatus(status)),
+    };
+
+    if let Some(Bencode::ByteString(reason)) = dict.get(&b"failure reason"[..]) {
+        return Err(TrackerError::TrackerMessage(
+            String::from_utf8_lossy(reason).into_owned(),
+        ));
+    }
+
+    if status != 200 {
+        return Err(TrackerError::HttpStatus(status));
+    }
+
+    Ok(dict)
+}
+
+/// Gets a non-negative integer from given `dict` at `key`, giving [None] if it
+/// doesn't exist and a [TrackerError::MissingKey] if it has the wrong type
+fn get_u32(
+    dict: &BTreeMap<Vec<u8>, Bencode>,
+    key: &'static str,
+) -> Result<Option<u32>, TrackerError> {
+    match dict.get(key.as_bytes()) {
+        Some(Bencode::Int(num)) => u32::try_from(*num)
+            .map(Some)
+            .map_err(|_| TrackerError::MissingKey(key)),
+        Some(_) => Err(TrackerError::MissingKey(key)),
+        None => Ok(None),
+    }
+}
+
+/// Gets a lossily-decoded string from given `dict` at `key` if it exists as a
+/// bytestring
+fn get_string(dict: &BTreeMap<Vec<u8>, Bencode>, key: &str) -> Option<String> {
+    match dict.get(key.as_bytes()) {
+        Some(Bencode::ByteString(bytes)) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
+    }
+}
+
+/// Parses a dictionary peer list, ignoring any peers with a missing or
+/// unresolvable `ip` or `port`
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// peers maps to a list of dictionaries corresponding to peers, each of which
+/// contains the keys peer id, ip, and port, which map to the peer's
+/// self-selected ID, IP address or dns name as a string, and port number,
+/// respectively.
+/// ```
+fn parse_dict_peers(peers: &[Bencode]) -> Vec<SocketAddr> {
+    peers
+        .iter()
+        .filter_map(|peer| match peer {
+            Bencode::Dict(peer) => {
+                let ip = get_string(peer, "ip")?;
+                let port = u16::try_from(get_u32(peer, "port").ok()??).ok()?;
+
+                (ip.trim_matches(['[', ']']), port)
+                    .to_socket_addrs()
+                    .ok()?
+                    .next()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// A tracker's response to an announce sent with [HttpTracker::announce]
+#[derive(Debug, PartialEq, Clone)]
+pub struct HttpAnnounceResp {
+    /// Amount of seconds to wait before announcing again
+    pub interval: u32,
+
+    /// Minimum amount of seconds to wait before announcing again, if given
+    pub min_interval: Option<u32>,
+
+    /// Amount of peers which are still downloading, if given
+    pub leechers: Option<u32>,
+
+    /// Amount of peers which have completed their download, if given
+    pub seeders: Option<u32>,
+
+    /// Addresses of peers to connect to, from any of the compact, dictionary
+    /// or IPv6 (`peers6`) peer lists
+    pub peers: Vec<SocketAddr>,
+
+    /// A warning from the tracker which didn't stop the announce, if given
+    pub warning: Option<String>,
+
+    /// Tracker id to send back on later announces, if given
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+impl HttpAnnounceResp {
+    /// Parses an announce response from the `dict` found with
+    /// [parse_tracker_body]
+    ///
+    /// # BitTorrent Description
+    ///
+    /// ```none
+    /// Tracker responses are bencoded dictionaries. If a tracker response has a
+    /// key failure reason, then that maps to a human readable string which
+    /// explains why the query failed, and no other keys are required.
+    /// Otherwise, it must have two keys: interval, which maps to the number of
+    /// seconds the downloader should wait between regular rerequests, and
+    /// peers. [...]
+    ///
+    /// peers6 [...] This key is a string of multiple of 18 bytes in the same
+    /// format as in the "peers" key, except for the IPv4 address being
+    /// replaced with an IPv6 address.
+    /// ```
+    fn from_dict(dict: &BTreeMap<Vec<u8>, Bencode>) -> Result<Self, TrackerError> {
+        let mut peers = match dict.get(&b"peers"[..]) {
+            Some(Bencode::ByteString(compact)) => parse_compact_peers(compact, false),
+            Some(Bencode::List(peers)) => parse_dict_peers(peers),
+            Some(_) => return Err(TrackerError::MissingKey("peers")),
+            None => vec![],
+        };
+
+        if let Some(Bencode::ByteString(compact)) = dict.get(&b"peers6"[..]) {
+            peers.extend(parse_compact_peers(compact, true));
+        }
+
+        Ok(Self {
+            interval: get_u32(dict, "interval")?.ok_or(TrackerError::MissingKey("interval"))?,
+            min_interval: get_u32(dict, "min interval")?,
+            leechers: get_u32(dict, "incomplete")?,
+            seeders: get_u32(dict, "complete")?,
+            peers,
+            warning: get_string(dict, "warning message"),
+            tracker_id: match dict.get(&b"tracker id"[..]) {
+                Some(Bencode::ByteString(tracker_id)) => Some(tracker_id.clone()),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// A connection to a single HTTP tracker, which keeps the tracker id given by
+/// the tracker to send back on later announces
+///
+/// If the tracker's host has both IPv4 and IPv6 addresses, announces are sent
+/// over both so that the tracker knows us by each, with the peers given back
+/// merged
+///
+/// # Examples
+///
+/// ```no_run
+/// use torro::tracker_http::HttpTracker;
+/// use torro::tracker_udp::{AnnounceEvent, AnnounceReq};
+///
+/// fn main() {
+///     let mut tracker = HttpTracker::new("http://tracker-url-here.co.biz/announce").unwrap();
+///     let req = AnnounceReq::new([0; 20], 6881).event(AnnounceEvent::Started);
+///
+///     let resp = tracker.announce(&req).unwrap();
+///
+///     println!("Got {} peers: {:?}", resp.peers.len(), resp.peers);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct HttpTracker {
+    /// Parsed announce URL of the tracker
+    url: HttpUrl,
+
+    /// Tracker id given on the last announce which gave one
+    tracker_id: Option<Vec<u8>>,
+
+    /// Timeout used for connecting, sending and recieving
+    timeout: Duration,
+}
+
+impl HttpTracker {
+    /// Creates a new tracker from given `announce` URL, without contacting the
+    /// tracker yet
+    pub fn new(announce: &str) -> Result<Self, TrackerError> {
+        Ok(Self {
+            url: HttpUrl::parse(announce)?,
+            tracker_id: None,
+            timeout: HTTP_TIMEOUT,
+        })
+    }
+
+    /// Sets the timeout used for connecting to, sending to and recieving from
+    /// the tracker, which is 30 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Builds the announce query for given `req`, always asking for a compact
+    /// peer list
+    ///
+    /// # BitTorrent Description
+    ///
+    /// ```none
+    /// Tracker GET requests have the following keys:
+    ///
+    /// info_hash
+    ///     The 20 byte sha1 hash of the bencoded form of the info value from
+    ///     the metainfo file. This value will almost certainly have to be
+    ///     escaped.
+    /// peer_id
+    ///     A string of length 20 which this downloader uses as its id. [...]
+    /// port, uploaded, downloaded, left
+    /// event
+    ///     This is an optional key which maps to started, completed, or
+    ///     stopped (or empty, which is the same as not being present).
+    /// ```
+    fn announce_query(&self, req: &AnnounceReq) -> String {
+        let mut query = format!(
+            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&key={:08x}",
+            percent_encode(&req.info_hash),
+            percent_encode(&req.peer_id),
+            req.port,
+            req.uploaded,
+            req.downloaded,
+            req.left,
+            req.key
+        );
+
+        let event = match req.event {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        };
+
+        if let Some(event) = event {
+            query.push_str(&format!("&event={}", event));
+        }
+
+        if req.num_want >= 0 {
+            query.push_str(&format!("&numwant={}", req.num_want));
+        }
+
+        if let Some(tracker_id) = &self.tracker_id {
+            query.push_str(&format!("&trackerid={}", percent_encode(tracker_id)));
+        }
+
+        query
+    }
+
+    /// Scrapes the tracker for statistics on any amount of `info_hashes`,
+    /// returning a [ScrapeStats] for each in the same order or a [TrackerError]
+    ///
+    /// The scrape URL is found from the announce URL using the scrape
+    /// convention, giving [TrackerError::ScrapeUnsupported] if the announce URL
+    /// doesn't follow it. Info-hashes are split into requests of at most
+    /// [MAX_SCRAPE_HASHES] to keep URLs short and any torrents the tracker
+    /// doesn't know of are given all-zero statistics, as with UDP trackers
+    ///
+    /// # BitTorrent Description
+    ///
+    /// ```none
+    /// The response of this HTTP GET method is a "text/plain" or sometimes gzip
+    /// compressed document consisting of a bencoded dictionary, containing the
+    /// following keys:
+    ///
+    /// files: a dictionary containing one key/value pair for each torrent for
+    /// which there are stats. [...]
+    ///     key: the 20-byte binary value of the info hash
+    ///     value: a dictionary containing the following:
+    ///         complete: number of peers with the entire file, i.e. seeders
+    ///         downloaded: total number of times the tracker has registered a
+    ///                     completion ("event=complete")
+    ///         incomplete: number of non-seeder peers, aka "leechers"
+    /// ```
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let scrape_url = self
+            .url
+            .to_scrape()
+            .ok_or(TrackerError::ScrapeUnsupported)?;
+        let mut stats = Vec::with_capacity(info_hashes.len());
+
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let query: Vec<String> = chunk
+                .iter()
+                .map(|info_hash| format!("info_hash={}", percent_encode(info_hash)))
+                .collect();
+            let target = scrape_url.target(&query.join("&"));
+            let (status, body) = first_answer(&scrape_url, |addr| {
+                http_get(&scrape_url, addr, &target, self.timeout)
+            })?;
+            let files = match parse_tracker_body(status, &body)?.remove(&b"files"[..]) {
+                Some(Bencode::Dict(files)) => files,
+                _ => return Err(TrackerError::MissingKey("files")),
+            };
+
+            for info_hash in chunk {
+                let file = match files.get(&info_hash[..]) {
+                    Some(Bencode::Dict(file)) => Some(file),
+                    Some(_) => return Err(TrackerError::MissingKey("files")),
+                    None => None,
+                };
+                let get = |key| match file {
+                    Some(file) => get_u32(file, key).map(|num| num.unwrap_or(0)),
+                    None => Ok(0),
+                };
+
+                stats.push(ScrapeStats {
+                    info_hash: *info_hash,
+                    complete: get("complete")?,
+                    downloaded: get("downloaded")?,
+                    incomplete: get("incomplete")?,
+                });
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Sends the announce request `req` to the tracker, returning the tracker's
+    /// [HttpAnnounceResp] or a [TrackerError]
+    ///
+    /// When the tracker has both an IPv4 and IPv6 address, both are announced
+    /// to and their peers merged, only failing if neither address answers
+    pub fn announce(&mut self, req: &AnnounceReq) -> Result<HttpAnnounceResp, TrackerError> {
+        let target = self.url.target(&self.announce_query(req));
+        let mut merged: Option<HttpAnnounceResp> = None;
+        let mut last_err = TrackerError::Timeout;
+
+        for addr in resolve(&self.url)? {
+            let resp = match http_get(&self.url, addr, &target, self.timeout)
+                .and_then(|(status, body)| parse_tracker_body(status, &body))
+                .and_then(|dict| HttpAnnounceResp::from_dict(&dict))
+            {
+                Ok(resp) => resp,
+                Err(err) => {
+                    last_err = err;
+                    continue;
+                }
+            };
+
+            if resp.tracker_id.is_some() {
+                self.tracker_id = resp.tracker_id.clone();
+            }
+
+            merged = Some(match merged {
+                None => resp,
+                Some(mut merged) => {
+                    merged.interval = merged.interval.min(resp.interval);
+                    merged.leechers = merged.leechers.max(resp.leechers);
+                    merged.seeders = merged.seeders.max(resp.seeders);
+
+                    for peer in resp.peers {
+                        if !merged.peers.contains(&peer) {
+                            merged.peers.push(peer);
+                        }
+                    }
+
+                    merged
+                }
+            });
+        }
+
+        merged.ok_or(last_err)
+    }
+}
+
+/// Runs `get` for each address `url` resolves to, giving the first successful
+/// result or the last error if none succeed
+fn first_answer<T>(
+    url: &HttpUrl,
+    mut get: impl FnMut(SocketAddr) -> Result<T, TrackerError>,
+) -> Result<T, TrackerError> {
+    let mut last_err = TrackerError::Timeout;
+
+    for addr in resolve(url)? {
+        match get(addr) {
+            Ok(got) => return Ok(got),
+            Err(err) => last_err = err,
+        }
+    }
+
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Starts a local HTTP tracker stand-in which answers the next `resps.len()`
+    /// requests in order with a `200` status and the matching body, returning
+    /// it's URL and a reciever for the requests' targets
+    fn http_stand_in(resps: Vec<Vec<u8>>) -> (String, std::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (sender, reciever) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            for resp in resps {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut req = vec![];
+                let mut buf = [0; 1024];
+
+                while !req.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).unwrap();
+                    req.extend_from_slice(&buf[..read]);
+                }
+
+                let req = String::from_utf8(req).unwrap();
+                sender
+                    .send(req.split_whitespace().nth(1).unwrap().to_string())
+                    .unwrap();
+
+                stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+                stream.write_all(&resp).ok(); // may be cut off if too large
+            }
+        });
+
+        (url, reciever)
+    }
+
+    /// Tests that [percent_encode] only leaves unreserved characters as-is and
+    /// that [percent_decode] reverses it
+    #[test]
+    fn percent_encode_binary() {
+        assert_eq!(percent_encode(b"aZ0-._~"), "aZ0-._~");
+        assert_eq!(percent_encode(&[0x00, 0x12, 0xff, b' ']), "%00%12%FF%20");
+        assert_eq!(
+            percent_decode("%00%12%ff+a"),
+            Some(vec![0x00, 0x12, 0xff, b' ', b'a'])
+        );
+        assert_eq!(percent_decode("%1"), None);
+    }
+
+    /// Tests that [HttpUrl::parse] finds hosts, ports and paths and refuses
+    /// anything other than `http`
+    #[test]
+    fn http_url_parse() {
+        assert_eq!(
+            HttpUrl::parse("http://[::1]:8080/announce?passkey=abc").unwrap(),
+            HttpUrl {
+                host: "[::1]".to_string(),
+                port: 8080,
+                path: "/announce?passkey=abc".to_string()
+            }
+        );
+        assert_eq!(
+            HttpUrl::parse("http://tracker.example")
+                .unwrap()
+                .target("a=1"),
+            "/?a=1"
+        );
+        assert_eq!(
+            HttpUrl::parse("https://tracker.example/announce"),
+            Err(TrackerError::UnsupportedScheme("https".to_string()))
+        );
+        assert_eq!(
+            HttpUrl::parse("tracker.example"),
+            Err(TrackerError::InvalidUrl("tracker.example".to_string()))
+        );
+    }
+
+    /// Tests that [HttpTracker::announce] sends a BEP0003 query to a local
+    /// stand-in and parses compact, IPv6 and dictionary peer lists, sending the
+    /// tracker id back on the next announce
+    #[test]
+    fn announce_stand_in_tracker() {
+        let mut first = b"d8:completei5e10:incompletei3e8:intervali1800e5:peers6:".to_vec();
+        first.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        first.extend_from_slice(b"6:peers618:");
+        first.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        first.extend_from_slice(&[0x1a, 0xe2]);
+        first.extend_from_slice(b"10:tracker id3:abc15:warning message4:slowe");
+        let second =
+            b"d8:intervali60e5:peersld2:ip8:10.0.0.27:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti80eeee"
+                .to_vec();
+
+        let (url, targets) = http_stand_in(vec![first, second]);
+        let mut tracker = HttpTracker::new(&url).unwrap();
+        let req = AnnounceReq::new([0xab; 20], 6881)
+            .peer_id([b'-'; 20])
+            .left(1024)
+            .key(0xdeadbeef)
+            .event(AnnounceEvent::Started);
+
+        let resp = tracker.announce(&req).unwrap();
+
+        assert_eq!(
+            resp,
+            HttpAnnounceResp {
+                interval: 1800,
+                min_interval: None,
+                leechers: Some(3),
+                seeders: Some(5),
+                peers: vec![
+                    "10.0.0.1:6881".parse().unwrap(),
+                    "[::1]:6882".parse().unwrap()
+                ],
+                warning: Some("slow".to_string()),
+                tracker_id: Some(b"abc".to_vec())
+            }
+        );
+        assert_eq!(
+            targets.recv().unwrap(),
+            format!(
+                "/announce?info_hash={}&peer_id={}&port=6881&uploaded=0&downloaded=0&left=1024&compact=1&key=deadbeef&event=started",
+                "%AB".repeat(20),
+                "-".repeat(20)
+            )
+        );
+
+        let resp = tracker.announce(&req.event(AnnounceEvent::None)).unwrap();
+
+        assert_eq!(resp.peers, vec!["10.0.0.2:80".parse().unwrap()]);
+        assert!(targets
+            .recv()
+            .unwrap()
+            .ends_with("&key=deadbeef&trackerid=abc"));
+    }
+
+    /// Tests that scrape URLs are only derived from announce URLs following the
+    /// scrape convention
+    #[test]
+    fn scrape_url_convention() {
+        let scrape_path = |url| {
+            HttpUrl::parse(url)
+                .unwrap()
+                .to_scrape()
+                .map(|scrape_url| scrape_url.path)
+        };
+
+        assert_eq!(
+            scrape_path("http://a/announce"),
+            Some("/scrape".to_string())
+        );
+        assert_eq!(
+            scrape_path("http://a/x/announce.php?passkey=announce"),
+            Some("/x/scrape.php?passkey=announce".to_string())
+        );
+        assert_eq!(scrape_path("http://a/a"), None);
+        assert_eq!(scrape_path("http://a/announce/x"), None);
+        assert_eq!(scrape_path("http://a/?announce"), None);
+    }
+
+    /// Tests that [HttpTracker::scrape] requests several info-hashes from a
+    /// local stand-in and parses the `files` dictionary in order
+    #[test]
+    fn scrape_stand_in_tracker() {
+        let mut resp = b"d5:filesd20:".to_vec();
+        resp.extend_from_slice(&[2; 20]);
+        resp.extend_from_slice(b"d8:completei5e10:downloadedi10e10:incompletei2eeee");
+
+        let (url, targets) = http_stand_in(vec![resp]);
+        let stats = HttpTracker::new(&url)
+            .unwrap()
+            .scrape(&[[1; 20], [2; 20]])
+            .unwrap();
+
+        assert_eq!(
+            targets.recv().unwrap(),
+            format!(
+                "/scrape?info_hash={}&info_hash={}",
+                "%01".repeat(20),
+                "%02".repeat(20)
+            )
+        );
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    info_hash: [1; 20],
+                    complete: 0,
+                    downloaded: 0,
+                    incomplete: 0
+                },
+                ScrapeStats {
+                    info_hash: [2; 20],
+                    complete: 5,
+                    downloaded: 10,
+                    incomplete: 2
+                }
+            ]
+        );
+    }
+
+    /// Tests that a `failure reason` is given as a [TrackerError::TrackerMessage]
+    #[test]
+    fn announce_failure_reason() {
+        let (url, _targets) = http_stand_in(vec![b"d14:failure reason12:unregisterede".to_vec()]);
+
+        assert_eq!(
+            HttpTracker::new(&url)
+                .unwrap()
+                .announce(&AnnounceReq::new([0; 20], 6881)),
+            Err(TrackerError::TrackerMessage("unregistered".to_string()))
+        );
+    }
+
+    /// Tests that responses over [MAX_HTTP_RESPONSE] are refused rather than
+    /// read into memory forever
+    #[test]
+    fn announce_response_too_large() {
+        let (url, _targets) = http_stand_in(vec![vec![b'a'; MAX_HTTP_RESPONSE as usize + 1]]);
+
+        assert_eq!(
+            HttpTracker::new(&url)
+                .unwrap()
+                .announce(&AnnounceReq::new([0; 20], 6881)),
+            Err(TrackerError::MalformedHttp)
+        );
+    }
+}
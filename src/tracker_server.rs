@@ -0,0 +1,867 @@
+//! An embedded BitTorrent tracker, serving
+//! [BEP0003](https://www.bittorrent.org/beps/bep_0003.html) HTTP and
+//! [BEP0015](https://www.bittorrent.org/beps/bep_0015.html) UDP announces and
+//! scrapes from a single in-memory set of swarms
+//!
+//! This is intended for small closed networks (e.g. LAN distribution) and
+//! testing rather than large public trackers, see [TrackerServer] to start one
+
+use crate::bencode::{self, Bencode};
+use crate::error::TrackerError;
+use crate::sha1::sha1;
+use crate::tracker_http::percent_decode;
+use crate::tracker_udp::{
+    read_u32, read_u64, AnnounceEvent, ScrapeStats, ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR,
+    ACTION_SCRAPE, CONNECTION_ID_LIFETIME, MAX_SCRAPE_HASHES, PROTOCOL_ID,
+};
+use crate::utils::random_secret;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Default amount of seconds clients are asked to wait between announces
+pub const DEFAULT_INTERVAL: u32 = 1800;
+
+/// Amount of peers given if a client doesn't ask for an amount
+const DEFAULT_NUM_WANT: usize = 50;
+
+/// Most peers given to a client in a single announce
+const MAX_NUM_WANT: usize = 200;
+
+/// Timeout for reading a request from an HTTP client
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest HTTP request accepted, in bytes
+const MAX_HTTP_REQUEST: usize = 8192;
+
+/// A single peer inside of a [Swarm]
+#[derive(Debug, Clone)]
+struct PeerEntry {
+    /// Address the peer is listening on
+    addr: SocketAddr,
+
+    /// If the peer has nothing left to download
+    seeding: bool,
+
+    /// When the peer last announced
+    last_seen: Instant,
+}
+
+/// All peers announcing for a single info-hash
+#[derive(Debug, Default)]
+struct Swarm {
+    /// Peers by peer id
+    peers: HashMap<[u8; 20], PeerEntry>,
+
+    /// Amount of `completed` events recieved
+    downloaded: u32,
+}
+
+impl Swarm {
+    /// Removes any peers which haven't announced within `peer_timeout`
+    fn expire(&mut self, peer_timeout: Duration) {
+        self.peers
+            .retain(|_, peer| peer.last_seen.elapsed() < peer_timeout);
+    }
+
+    /// Gets the amount of seeders and leechers in this swarm
+    fn counts(&self) -> (u32, u32) {
+        let seeders = self.peers.values().filter(|peer| peer.seeding).count();
+
+        (seeders as u32, (self.peers.len() - seeders) as u32)
+    }
+}
+
+/// An announce recieved by the server over either protocol
+struct ServerAnnounce {
+    /// Info-hash of the torrent being announced
+    info_hash: [u8; 20],
+
+    /// Peer id of the announcing client
+    peer_id: [u8; 20],
+
+    /// Address the client is listening for peers on
+    addr: SocketAddr,
+
+    /// Amount of bytes the client has left to download
+    left: u64,
+
+    /// Event the client announced with
+    event: AnnounceEvent,
+
+    /// Amount of peers wanted, if given
+    num_want: Option<usize>,
+}
+
+/// The server's response to a [ServerAnnounce]
+struct ServerAnnounceResp {
+    /// Amount of seeders in the swarm
+    seeders: u32,
+
+    /// Amount of leechers in the swarm
+    leechers: u32,
+
+    /// Peer ids and addresses of other peers in the swarm
+    peers: Vec<([u8; 20], SocketAddr)>,
+}
+
+/// Mutable state shared between all of a server's threads
+#[derive(Debug, Default)]
+struct ServerState {
+    /// Swarms by info-hash
+    swarms: HashMap<[u8; 20], Swarm>,
+}
+
+/// An embedded tracker which keeps it's swarms in memory, served over HTTP
+/// with [TrackerServer::serve_http] and/or UDP with [TrackerServer::serve_udp]
+///
+/// Peers which haven't announced within the peer timeout (double the announce
+/// interval by default) are forgotten, and an info-hash whitelist may be set
+/// with [TrackerServer::whitelist] to only track certain torrents
+///
+/// # Examples
+///
+/// ```no_run
+/// use torro::tracker_server::TrackerServer;
+///
+/// fn main() {
+///     let server = TrackerServer::new().interval(60);
+///     let http = server.serve_http("0.0.0.0:6969").unwrap();
+///     let udp = server.serve_udp("0.0.0.0:6969").unwrap();
+///
+///     println!("Serving on {} and {}", http.announce_url(), udp.announce_url());
+///     std::thread::park(); // both stop when their handles are dropped
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TrackerServer {
+    /// Swarms, shared between every served socket
+    state: Arc<Mutex<ServerState>>,
+
+    /// Secret UDP connection ids are made from, so clients can't guess the
+    /// connection id of another address
+    connection_secret: u128,
+
+    /// When the server was created, which connection id lifetimes are counted
+    /// from
+    started: Instant,
+
+    /// Info-hashes allowed to be tracked, or [None] to allow any
+    whitelist: Option<HashSet<[u8; 20]>>,
+
+    /// Amount of seconds clients are asked to wait between announces
+    interval: u32,
+
+    /// How long a peer is kept after it's last announce, or [None] for double
+    /// the `interval`
+    peer_timeout: Option<Duration>,
+}
+
+impl TrackerServer {
+    /// Creates a new tracker with no swarms, no whitelist and an interval of
+    /// [DEFAULT_INTERVAL]
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ServerState::default())),
+            connection_secret: random_secret(),
+            started: Instant::now(),
+            whitelist: None,
+            interval: DEFAULT_INTERVAL,
+            peer_timeout: None,
+        }
+    }
+
+    /// Sets the amount of seconds clients are asked to wait between announces
+    pub fn interval(mut self, interval: u32) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how long a peer is kept after it's last announce, which is double
+    /// the interval by default
+    pub fn peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.peer_timeout = Some(peer_timeout);
+        self
+    }
+
+    /// Only allows the given `info_hashes` to be announced, refusing any others
+    pub fn whitelist(mut self, info_hashes: impl IntoIterator<Item = [u8; 20]>) -> Self {
+        self.whitelist = Some(info_hashes.into_iter().collect());
+        self
+    }
+
+    /// Serves HTTP announces (at `/announce`) and scrapes (at `/scrape`) on a
+    /// new listener bound to `bind_addr`, until the returned [ServerHandle] is
+    /// dropped
+    pub fn serve_http(&self, bind_addr: &str) -> Result<ServerHandle, TrackerError> {
+        let listener = TcpListener::bind(bind_addr)
+            .map_err(|_| TrackerError::SocketBind(bind_addr.to_string()))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|err| TrackerError::SocketIo(err.kind()))?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let server = self.clone();
+        let thread_stopping = stopping.clone();
+
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                if let Ok(stream) = stream {
+                    let server = server.clone();
+                    thread::spawn(move || server.handle_http(stream));
+                }
+            }
+        });
+
+        Ok(ServerHandle {
+            local_addr,
+            udp: false,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+
+    /// Serves UDP connects, announces and scrapes on a new socket bound to
+    /// `bind_addr`, until the returned [ServerHandle] is dropped
+    pub fn serve_udp(&self, bind_addr: &str) -> Result<ServerHandle, TrackerError> {
+        let socket = UdpSocket::bind(bind_addr)
+            .map_err(|_| TrackerError::SocketBind(bind_addr.to_string()))?;
+        let local_addr = socket
+            .local_addr()
+            .map_err(|err| TrackerError::SocketIo(err.kind()))?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let server = self.clone();
+        let thread_stopping = stopping.clone();
+
+        let thread = thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+
+            loop {
+                let recieved = socket.recv_from(&mut buf);
+
+                if thread_stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                if let Ok((len, from)) = recieved {
+                    if let Some(resp) = server.handle_udp(&buf[..len], from) {
+                        socket.send_to(&resp, from).ok();
+                    }
+                }
+            }
+        });
+
+        Ok(ServerHandle {
+            local_addr,
+            udp: true,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+
+    /// Checks if `info_hash` is allowed by the whitelist, if there is one
+    fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        match &self.whitelist {
+            Some(whitelist) => whitelist.contains(info_hash),
+            None => true,
+        }
+    }
+
+    /// Records an `announce` in it's swarm, giving the other peers in the swarm
+    /// or a failure message
+    fn announce(&self, announce: ServerAnnounce) -> Result<ServerAnnounceResp, &'static str> {
+        if !self.is_allowed(&announce.info_hash) {
+            return Err("info-hash not whitelisted");
+        }
+
+        let peer_timeout = self
+            .peer_timeout
+            .unwrap_or_else(|| Duration::from_secs(self.interval as u64 * 2));
+        let mut state = self.state.lock().unwrap();
+        let swarm = state.swarms.entry(announce.info_hash).or_default();
+        swarm.expire(peer_timeout);
+
+        if announce.event == AnnounceEvent::Stopped {
+            swarm.peers.remove(&announce.peer_id);
+        } else {
+            if announce.event == AnnounceEvent::Completed {
+                swarm.downloaded = swarm.downloaded.saturating_add(1);
+            }
+
+            swarm.peers.insert(
+                announce.peer_id,
+                PeerEntry {
+                    addr: announce.addr,
+                    seeding: announce.left == 0,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+
+        let (seeders, leechers) = swarm.counts();
+        let peers = if announce.event == AnnounceEvent::Stopped {
+            vec![]
+        } else {
+            swarm
+                .peers
+                .iter()
+                .filter(|(peer_id, _)| **peer_id != announce.peer_id)
+                .map(|(peer_id, peer)| (*peer_id, peer.addr))
+                .take(
+                    announce
+                        .num_want
+                        .unwrap_or(DEFAULT_NUM_WANT)
+                        .min(MAX_NUM_WANT),
+                )
+                .collect()
+        };
+
+        Ok(ServerAnnounceResp {
+            seeders,
+            leechers,
+            peers,
+        })
+    }
+
+    /// Gets scrape statistics for each of `info_hashes`, with unknown torrents
+    /// given all-zero statistics
+    fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<ScrapeStats> {
+        let state = self.state.lock().unwrap();
+
+        info_hashes
+            .iter()
+            .map(|info_hash| {
+                let (complete, incomplete, downloaded) = match state.swarms.get(info_hash) {
+                    Some(swarm) => {
+                        let (seeders, leechers) = swarm.counts();
+                        (seeders, leechers, swarm.downloaded)
+                    }
+                    None => (0, 0, 0),
+                };
+
+                ScrapeStats {
+                    info_hash: *info_hash,
+                    complete,
+                    downloaded,
+                    incomplete,
+                }
+            })
+            .collect()
+    }
+
+    /// Reads a single HTTP request from `stream` and responds to it
+    fn handle_http(&self, mut stream: TcpStream) {
+        let peer_ip = match stream.peer_addr() {
+            Ok(peer_addr) => peer_addr.ip(),
+            Err(_) => return,
+        };
+
+        stream.set_read_timeout(Some(HTTP_READ_TIMEOUT)).ok();
+
+        let mut req = vec![];
+        let mut buf = [0u8; 1024];
+
+        while !req.windows(4).any(|window| window == b"\r\n\r\n") {
+            match stream.read(&mut buf) {
+                Ok(read) if read != 0 && req.len() + read <= MAX_HTTP_REQUEST => {
+                    req.extend_from_slice(&buf[..read])
+                }
+                _ => return,
+            }
+        }
+
+        let target = String::from_utf8_lossy(&req)
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or("/")
+            .to_string();
+        let (path, query) = match target.find('?') {
+            Some(ind) => (&target[..ind], &target[ind + 1..]),
+            None => (target.as_str(), ""),
+        };
+        let params: Vec<(&str, Vec<u8>)> = query
+            .split('&')
+            .filter_map(|pair| {
+                let mut split = pair.splitn(2, '=');
+                let key = split.next()?;
+
+                Some((key, percent_decode(split.next().unwrap_or(""))?))
+            })
+            .collect();
+
+        let (status, body) = if path.ends_with("/announce") {
+            ("200 OK", self.http_announce(peer_ip, &params))
+        } else if path.ends_with("/scrape") {
+            ("200 OK", self.http_scrape(&params))
+        } else {
+            ("404 Not Found", failure_body("not found"))
+        };
+
+        let header = format!(
+            "HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+
+        stream.write_all(header.as_bytes()).ok();
+        stream.write_all(&body).ok();
+    }
+
+    /// Responds to an HTTP announce from `peer_ip` with given query `params`
+    fn http_announce(&self, peer_ip: IpAddr, params: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let param = |key| {
+            params
+                .iter()
+                .find(|(param_key, _)| *param_key == key)
+                .map(|(_, value)| value.as_slice())
+        };
+        let num_param = |key| {
+            param(key)
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        let (info_hash, peer_id, port) = match (
+            param("info_hash").and_then(to_hash),
+            param("peer_id").and_then(to_hash),
+            num_param("port").filter(|port| *port <= u16::MAX as u64),
+        ) {
+            (Some(info_hash), Some(peer_id), Some(port)) => (info_hash, peer_id, port as u16),
+            _ => return failure_body("missing or invalid info_hash, peer_id or port"),
+        };
+        let event = match param("event") {
+            Some(b"started") => AnnounceEvent::Started,
+            Some(b"completed") => AnnounceEvent::Completed,
+            Some(b"stopped") => AnnounceEvent::Stopped,
+            _ => AnnounceEvent::None,
+        };
+
+        let resp = match self.announce(ServerAnnounce {
+            info_hash,
+            peer_id,
+            addr: SocketAddr::new(peer_ip, port),
+            left: num_param("left").unwrap_or(0),
+            event,
+            num_want: num_param("numwant").map(|num_want| num_want as usize),
+        }) {
+            Ok(resp) => resp,
+            Err(reason) => return failure_body(reason),
+        };
+
+        let mut dict = BTreeMap::new();
+        dict.insert(b"interval".to_vec(), Bencode::Int(self.interval as i64));
+        dict.insert(b"complete".to_vec(), Bencode::Int(resp.seeders as i64));
+        dict.insert(b"incomplete".to_vec(), Bencode::Int(resp.leechers as i64));
+
+        if param("compact") == Some(b"0") {
+            let peers = resp
+                .peers
+                .iter()
+                .map(|(peer_id, addr)| {
+                    let mut peer = BTreeMap::new();
+                    peer.insert(b"peer id".to_vec(), Bencode::ByteString(peer_id.to_vec()));
+                    peer.insert(
+                        b"ip".to_vec(),
+                        Bencode::ByteString(addr.ip().to_string().into_bytes()),
+                    );
+                    peer.insert(b"port".to_vec(), Bencode::Int(addr.port() as i64));
+
+                    Bencode::Dict(peer)
+                })
+                .collect();
+
+            dict.insert(b"peers".to_vec(), Bencode::List(peers));
+        } else {
+            let addrs: Vec<SocketAddr> = resp.peers.iter().map(|(_, addr)| *addr).collect();
+            let peers6 = encode_compact_peers(&addrs, true);
+
+            dict.insert(
+                b"peers".to_vec(),
+                Bencode::ByteString(encode_compact_peers(&addrs, false)),
+            );
+
+            if !peers6.is_empty() {
+                dict.insert(b"peers6".to_vec(), Bencode::ByteString(peers6));
+            }
+        }
+
+        bencode::encode(&Bencode::Dict(dict))
+    }
+
+    /// Responds to an HTTP scrape with given query `params`, leaving out any
+    /// info-hashes which aren't whitelisted
+    fn http_scrape(&self, params: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let info_hashes: Vec<[u8; 20]> = params
+            .iter()
+            .filter(|(key, _)| *key == "info_hash")
+            .filter_map(|(_, value)| to_hash(value))
+            .filter(|info_hash| self.is_allowed(info_hash))
+            .collect();
+
+        let files = self
+            .scrape(&info_hashes)
+            .into_iter()
+            .map(|stats| {
+                let mut file = BTreeMap::new();
+                file.insert(b"complete".to_vec(), Bencode::Int(stats.complete as i64));
+                file.insert(
+                    b"downloaded".to_vec(),
+                    Bencode::Int(stats.downloaded as i64),
+                );
+                file.insert(
+                    b"incomplete".to_vec(),
+                    Bencode::Int(stats.incomplete as i64),
+                );
+
+                (stats.info_hash.to_vec(), Bencode::Dict(file))
+            })
+            .collect();
+
+        let mut dict = BTreeMap::new();
+        dict.insert(b"files".to_vec(), Bencode::Dict(files));
+
+        bencode::encode(&Bencode::Dict(dict))
+    }
+
+    /// Amount of whole connection id lifetimes since the server was created
+    fn connection_bucket(&self) -> u64 {
+        self.started.elapsed().as_secs() / CONNECTION_ID_LIFETIME.as_secs()
+    }
+
+    /// Makes the UDP connection id of the client at `from` for given
+    /// `bucket`, being a truncated SHA-1 hash of them and the server's secret
+    /// so nothing has to be stored per client
+    fn connection_id(&self, from: SocketAddr, bucket: u64) -> u64 {
+        let mut data = self.connection_secret.to_be_bytes().to_vec();
+
+        match from.ip() {
+            IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+        }
+
+        data.extend_from_slice(&from.port().to_be_bytes());
+        data.extend_from_slice(&bucket.to_be_bytes());
+
+        u64::from_be_bytes(sha1(&data)[..8].try_into().unwrap())
+    }
+
+    /// Gives out a UDP connection id to the client at `from`
+    fn new_connection(&self, from: SocketAddr) -> u64 {
+        self.connection_id(from, self.connection_bucket())
+    }
+
+    /// Checks if `connection_id` was given out to the client at `from` within
+    /// the last one to two minutes, as BEP0015 asks servers to accept
+    fn valid_connection(&self, connection_id: u64, from: SocketAddr) -> bool {
+        let bucket = self.connection_bucket();
+
+        connection_id == self.connection_id(from, bucket)
+            || (bucket != 0 && connection_id == self.connection_id(from, bucket - 1))
+    }
+
+    /// Responds to a single UDP packet `buf` recieved from `from`, giving
+    /// [None] if it should be ignored
+    fn handle_udp(&self, buf: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        let connection_id = read_u64(buf, 0)?;
+        let action = read_u32(buf, 8)?;
+        let transaction_id = read_u32(buf, 12)?;
+        let mut resp = vec![];
+
+        if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID as u64 {
+                return None;
+            }
+
+            resp.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            resp.extend_from_slice(&transaction_id.to_be_bytes());
+            resp.extend_from_slice(&self.new_connection(from).to_be_bytes());
+
+            return Some(resp);
+        }
+
+        let result = if !self.valid_connection(connection_id, from) {
+            Err("invalid connection id")
+        } else if action == ACTION_ANNOUNCE {
+            self.udp_announce(buf, from, transaction_id)
+        } else if action == ACTION_SCRAPE {
+            Ok(self.udp_scrape(buf, transaction_id))
+        } else {
+            Err("unknown action")
+        };
+
+        Some(result.unwrap_or_else(|message| {
+            resp.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+            resp.extend_from_slice(&transaction_id.to_be_bytes());
+            resp.extend_from_slice(message.as_bytes());
+            resp
+        }))
+    }
+
+    /// Responds to a UDP announce packet `buf` from `from`, see
+    /// [AnnounceReq](crate::tracker_udp::AnnounceReq) for the layout of `buf`
+    fn udp_announce(
+        &self,
+        buf: &[u8],
+        from: SocketAddr,
+        transaction_id: u32,
+    ) -> Result<Vec<u8>, &'static str> {
+        if buf.len() < 98 {
+            return Err("announce too short");
+        }
+
+        let num_want = read_u32(buf, 92).unwrap() as i32;
+        let announce = self.announce(ServerAnnounce {
+            info_hash: to_hash(&buf[16..36]).unwrap(),
+            peer_id: to_hash(&buf[36..56]).unwrap(),
+            addr: SocketAddr::new(from.ip(), u16::from_be_bytes([buf[96], buf[97]])),
+            left: read_u64(buf, 64).unwrap(),
+            event: AnnounceEvent::from_u32(read_u32(buf, 80).unwrap()).ok_or("unknown event")?,
+            num_want: if num_want < 0 {
+                None
+            } else {
+                Some(num_want as usize)
+            },
+        })?;
+        let addrs: Vec<SocketAddr> = announce.peers.iter().map(|(_, addr)| *addr).collect();
+
+        let mut resp = vec![];
+        resp.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        resp.extend_from_slice(&transaction_id.to_be_bytes());
+        resp.extend_from_slice(&self.interval.to_be_bytes());
+        resp.extend_from_slice(&announce.leechers.to_be_bytes());
+        resp.extend_from_slice(&announce.seeders.to_be_bytes());
+        resp.extend(encode_compact_peers(&addrs, from.is_ipv6()));
+
+        Ok(resp)
+    }
+
+    /// Responds to a UDP scrape packet `buf` of up to [MAX_SCRAPE_HASHES]
+    /// info-hashes
+    fn udp_scrape(&self, buf: &[u8], transaction_id: u32) -> Vec<u8> {
+        let info_hashes: Vec<[u8; 20]> = buf[16..]
+            .chunks_exact(20)
+            .take(MAX_SCRAPE_HASHES)
+            .filter_map(to_hash)
+            .collect();
+
+        let mut resp = vec![];
+        resp.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+        resp.extend_from_slice(&transaction_id.to_be_bytes());
+
+        for stats in self.scrape(&info_hashes) {
+            resp.extend_from_slice(&stats.complete.to_be_bytes());
+            resp.extend_from_slice(&stats.downloaded.to_be_bytes());
+            resp.extend_from_slice(&stats.incomplete.to_be_bytes());
+        }
+
+        resp
+    }
+}
+
+impl Default for TrackerServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts `bytes` into an info-hash or peer id if it is exactly 20 bytes long
+fn to_hash(bytes: &[u8]) -> Option<[u8; 20]> {
+    if bytes.len() != 20 {
+        return None;
+    }
+
+    let mut hash = [0; 20];
+    hash.copy_from_slice(bytes);
+
+    Some(hash)
+}
+
+/// Encodes the IPv6 (if `ipv6`) or IPv4 addresses from `addrs` into compact
+/// peers, leaving out addresses of the other family
+pub(crate) fn encode_compact_peers(addrs: &[SocketAddr], ipv6: bool) -> Vec<u8> {
+    let mut buf = vec![];
+
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) if !ipv6 => buf.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) if ipv6 => buf.extend_from_slice(&ip.octets()),
+            _ => continue,
+        }
+
+        buf.extend_from_slice(&addr.port().to_be_bytes());
+    }
+
+    buf
+}
+
+/// Makes a bencoded HTTP tracker response with given failure `reason`
+fn failure_body(reason: &str) -> Vec<u8> {
+    let mut dict = BTreeMap::new();
+    dict.insert(
+        b"failure reason".to_vec(),
+        Bencode::ByteString(reason.as_bytes().to_vec()),
+    );
+
+    bencode::encode(&Bencode::Dict(dict))
+}
+
+/// A running HTTP or UDP socket of a [TrackerServer], which stops serving when
+/// dropped
+#[derive(Debug)]
+pub struct ServerHandle {
+    /// Address the socket is bound to
+    local_addr: SocketAddr,
+
+    /// If this is serving UDP rather than HTTP
+    udp: bool,
+
+    /// Set when the handle is dropped to stop the serving thread
+    stopping: Arc<AtomicBool>,
+
+    /// The serving thread
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Gets the address the server is bound to, useful when bound to port `0`
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Gets the announce URL clients should use for this server, such as
+    /// `http://127.0.0.1:6969/announce` or `udp://127.0.0.1:6969`
+    pub fn announce_url(&self) -> String {
+        if self.udp {
+            format!("udp://{}", self.local_addr)
+        } else {
+            format!("http://{}/announce", self.local_addr)
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        // wake the serving thread up so it sees it is stopping
+        let mut wake_addr = self.local_addr;
+
+        if wake_addr.ip().is_unspecified() {
+            wake_addr.set_ip(match wake_addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+
+        let woken = if self.udp {
+            let any: SocketAddr = match wake_addr {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+
+            UdpSocket::bind(any)
+                .and_then(|socket| socket.send_to(&[], wake_addr))
+                .is_ok()
+        } else {
+            TcpStream::connect(wake_addr).is_ok()
+        };
+
+        if woken {
+            if let Some(thread) = self.thread.take() {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes a UDP announce packet for `info_hash` from `peer_id` on
+    /// `connection_id`
+    fn udp_announce_buf(connection_id: u64, info_hash: [u8; 20], peer_id: [u8; 20]) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&connection_id.to_be_bytes());
+        buf.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        buf.extend_from_slice(&7u32.to_be_bytes());
+        buf.extend_from_slice(&info_hash);
+        buf.extend_from_slice(&peer_id);
+        buf.extend_from_slice(&[0; 24]); // downloaded, left, uploaded
+        buf.extend_from_slice(&2u32.to_be_bytes());
+        buf.extend_from_slice(&[0; 8]); // ip, key
+        buf.extend_from_slice(&(-1i32).to_be_bytes());
+        buf.extend_from_slice(&6881u16.to_be_bytes());
+        buf
+    }
+
+    /// Tests that UDP announces need a connection id given out by the server
+    /// and that the whitelist is honoured
+    #[test]
+    fn udp_connection_and_whitelist() {
+        let server = TrackerServer::new().whitelist(vec![[1; 20]]);
+        let from = "10.0.0.1:1234".parse().unwrap();
+
+        let mut connect = PROTOCOL_ID.to_be_bytes().to_vec();
+        connect.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 9]);
+        let connected = server.handle_udp(&connect, from).unwrap();
+        let connection_id = read_u64(&connected, 8).unwrap();
+
+        let refused = server
+            .handle_udp(&udp_announce_buf(connection_id ^ 1, [1; 20], [2; 20]), from)
+            .unwrap();
+        assert_eq!(read_u32(&refused, 0), Some(ACTION_ERROR));
+        assert_eq!(&refused[8..], b"invalid connection id");
+
+        let spoofed = server
+            .handle_udp(
+                &udp_announce_buf(connection_id, [1; 20], [2; 20]),
+                "10.0.0.2:1234".parse().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(&spoofed[8..], b"invalid connection id");
+
+        let unlisted = server
+            .handle_udp(&udp_announce_buf(connection_id, [3; 20], [2; 20]), from)
+            .unwrap();
+        assert_eq!(&unlisted[8..], b"info-hash not whitelisted");
+
+        let announced = server
+            .handle_udp(&udp_announce_buf(connection_id, [1; 20], [2; 20]), from)
+            .unwrap();
+        assert_eq!(read_u32(&announced, 0), Some(ACTION_ANNOUNCE));
+        assert_eq!(read_u32(&announced, 16), Some(1)); // seeders
+    }
+
+    /// Tests that peers which haven't announced within the peer timeout are
+    /// forgotten
+    #[test]
+    fn peers_expire() {
+        let server = TrackerServer::new().peer_timeout(Duration::from_millis(50));
+        let announce = |peer_id| ServerAnnounce {
+            info_hash: [1; 20],
+            peer_id,
+            addr: "10.0.0.1:6881".parse().unwrap(),
+            left: 10,
+            event: AnnounceEvent::Started,
+            num_want: None,
+        };
+
+        server.announce(announce([2; 20])).unwrap();
+        assert_eq!(server.announce(announce([3; 20])).unwrap().peers.len(), 1);
+
+        thread::sleep(Duration::from_millis(100));
+        let resp = server.announce(announce([3; 20])).unwrap();
+
+        assert_eq!(resp.peers.len(), 0);
+        assert_eq!(resp.leechers, 1);
+    }
+}
@@ -3,16 +3,66 @@
 //! [Torrent::download](crate::Torrent::download)
 
 use crate::error::TrackerError;
-use crate::utils::randish_128;
+use crate::utils::{generate_torro_id, random_secret};
+use std::collections::HashMap;
 use std::mem::size_of;
-use std::net::UdpSocket;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
-/// The address typically used to bind a [UdpSocket] to for tracker connections
-pub const TORRO_BIND_ADDR: &str = "127.0.0.1:7667";
+/// The address typically used to bind an IPv4 [TrackerSocket] to, letting the
+/// OS pick an ephemeral port
+pub const TORRO_BIND_ADDR: &str = "0.0.0.0:0";
+
+/// The address typically used to bind an IPv6 [TrackerSocket] to, letting the
+/// OS pick an ephemeral port
+pub const TORRO_BIND_ADDR_V6: &str = "[::]:0";
+
+/// How often the reader threads of a [TrackerSocket] check if the socket has
+/// been dropped
+const READER_POLL: Duration = Duration::from_millis(500);
+
+/// Largest possible UDP payload, used as the size of the reader threads' buffer
+/// so large responses (e.g. 200 IPv6 peers taking 3620 bytes) aren't truncated
+const MAX_PACKET: usize = 65536;
 
 /// The "magic constant" for connecting to the tracker. This is assumed to be the
 /// bittorrent protocol id designation
-const PROTOCOL_ID: i64 = 0x41727101980;
+pub(crate) const PROTOCOL_ID: i64 = 0x41727101980;
+
+/// Action number for connection requests and responses
+pub(crate) const ACTION_CONNECT: u32 = 0;
+
+/// Action number for announce requests and responses
+pub(crate) const ACTION_ANNOUNCE: u32 = 1;
+
+/// Action number for scrape requests and responses
+pub(crate) const ACTION_SCRAPE: u32 = 2;
+
+/// Action number for error responses, which may be sent instead of any other
+/// response
+pub(crate) const ACTION_ERROR: u32 = 3;
+
+/// How long a connection id may be used for after it was recieved, as given in
+/// BEP0015
+pub(crate) const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Maximum amount of info-hashes which may be scraped in a single packet, as
+/// given in BEP0015
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Option type for the end of options in an announce request, as given in
+/// BEP0041
+const OPTION_END: u8 = 0x0;
+
+/// Option type for a chunk of URL data in an announce request, as given in
+/// BEP0041
+const OPTION_URL_DATA: u8 = 0x2;
+
+/// Maximum `n` to use for [timeout_calc] before giving up, as given in BEP0015
+const MAX_TRIES: u8 = 8;
 
 /// Calculates seconds for timeout according to the `15 * 2 ^ n` formula where
 /// `n` is formatted as `tries` in this function signature, with an allowed range
@@ -22,7 +72,7 @@ const PROTOCOL_ID: i64 = 0x41727101980;
 /// [Time outs](https://www.bittorrent.org/beps/bep_0015.html#time-outs)" section
 /// of BEP0015 with examples of what `tries` to use
 fn timeout_calc(tries: u8) -> u16 {
-    assert!(!(tries > 8), "Timeouts can only be set as 0-8");
+    assert!(tries <= 8, "Timeouts can only be set as 0-8");
 
     15 * 2u16.pow(tries as u32) // TODO: make a rustc RFC for new `**` operator
 }
@@ -51,16 +101,510 @@ fn build_connect_req_buf(transaction_id: u32) -> [u8; 16] {
     let mut buf = [0x00; 16];
 
     buf[..size_of::<u64>()].copy_from_slice(&PROTOCOL_ID.to_be_bytes());
-    buf[..size_of::<u32>()].copy_from_slice(&0u32.to_be_bytes());
-    buf[..size_of::<u32>()].copy_from_slice(&transaction_id.to_be_bytes());
+    buf[8..12].copy_from_slice(&ACTION_CONNECT.to_be_bytes());
+    buf[12..16].copy_from_slice(&transaction_id.to_be_bytes());
 
     buf
 }
 
-// TODO: tell user not to use this and make an automated higher-level func for all tracker info needs
-// TODO: test
+/// Reads a big-endian [u32] from `buf` at given `offset`, or [None] if `buf` is
+/// too short
+pub(crate) fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(buf.get(offset..offset + 4)?);
+
+    Some(u32::from_be_bytes(bytes))
+}
+
+/// Reads a big-endian [u64] from `buf` at given `offset`, or [None] if `buf` is
+/// too short
+pub(crate) fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(buf.get(offset..offset + 8)?);
+
+    Some(u64::from_be_bytes(bytes))
+}
+
+/// Parses a connect response into a connection id if it is a connect response
+/// for the given `transaction_id`
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// connect response:
+///
+/// Offset  Size            Name            Value
+/// 0       32-bit integer  action          0 // connect
+/// 4       32-bit integer  transaction_id
+/// 8       64-bit integer  connection_id
+/// 16
+/// ```
+fn parse_connect_resp(transaction_id: u32, buf: &[u8]) -> Option<u64> {
+    if buf.len() < 16 || read_u32(buf, 0)? != ACTION_CONNECT || read_u32(buf, 4)? != transaction_id
+    {
+        return None;
+    }
+
+    read_u64(buf, 8)
+}
+
+/// Picks the first IPv4 and first IPv6 address from `addrs` (in the order
+/// given), leaving out a family if it isn't wanted with `v4` or `v6`
+pub(crate) fn family_addrs(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    v4: bool,
+    v6: bool,
+) -> Vec<SocketAddr> {
+    let mut picked: Vec<SocketAddr> = vec![];
+
+    for addr in addrs {
+        let wanted = if addr.is_ipv4() { v4 } else { v6 };
+
+        if wanted && !picked.iter().any(|other| other.is_ipv4() == addr.is_ipv4()) {
+            picked.push(addr);
+        }
+    }
+
+    picked
+}
+
+/// Resolves a tracker's `authority` (`host:port`) into up to one address per
+/// family, only including families given by `v4` and `v6`
+fn resolve(authority: &str, v4: bool, v6: bool) -> Result<Vec<SocketAddr>, TrackerError> {
+    let addrs = authority
+        .to_socket_addrs()
+        .map(|addrs| family_addrs(addrs, v4, v6))
+        .unwrap_or_default();
+
+    if addrs.is_empty() {
+        return Err(TrackerError::AddressResolution(authority.to_string()));
+    }
+
+    Ok(addrs)
+}
+
+/// A packet (or sign of one) routed by a [TrackerSocket] to a waiting exchange
+#[derive(Debug, Clone)]
+enum Routed {
+    /// A packet with the transaction id of the exchange
+    Packet(Vec<u8>),
+
+    /// A packet from the same tracker which couldn't be matched to any
+    /// exchange, given as the error it would cause
+    Stray(TrackerError),
+}
+
+/// A waiting exchange registered with a [TrackerSocket]
+#[derive(Debug)]
+struct Route {
+    /// Address of the tracker the exchange is with
+    addr: SocketAddr,
+
+    /// Where to send packets for the exchange
+    sender: Sender<Routed>,
+}
+
+/// Waiting exchanges by transaction id
+type Routes = Mutex<HashMap<u32, Route>>;
+
+/// A UDP socket (or pair of IPv4 and IPv6 sockets) shared between any amount of
+/// trackers and torrents, with responses sorted to their requests by
+/// transaction id
+///
+/// Each socket has a background thread reading responses, which stops shortly
+/// after the last clone of the [TrackerSocket] is dropped. Cloning a
+/// [TrackerSocket] is cheap and gives another handle to the same sockets
+///
+/// # Examples
+///
+/// ```no_run
+/// use torro::tracker_udp::{AnnounceReq, TrackerSocket, UdpTracker};
+///
+/// fn main() {
+///     let socket = TrackerSocket::dual_stack(0).unwrap();
+///     let mut first = UdpTracker::with_socket(&socket, "udp://tracker-one.co.biz:6969").unwrap();
+///     let mut second = UdpTracker::with_socket(&socket, "udp://tracker-two.co.biz:6969").unwrap();
+///
+///     let req = AnnounceReq::new([0; 20], 6881);
+///     println!("{:?}", first.announce(&req).unwrap());
+///     println!("{:?}", second.announce(&req).unwrap());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TrackerSocket {
+    /// IPv4 socket, if bound
+    v4: Option<Arc<UdpSocket>>,
+
+    /// IPv6 socket, if bound
+    v6: Option<Arc<UdpSocket>>,
+
+    /// Waiting exchanges, shared with the reader threads
+    routes: Arc<Routes>,
+}
+
+impl TrackerSocket {
+    /// Binds a single socket to `bind_addr`, which may be IPv4 or IPv6 and use
+    /// port `0` for an ephemeral port, such as [TORRO_BIND_ADDR]
+    pub fn bind(bind_addr: &str) -> Result<Self, TrackerError> {
+        let socket = UdpSocket::bind(bind_addr)
+            .map_err(|_| TrackerError::SocketBind(bind_addr.to_string()))?;
+        let ipv6 = socket
+            .local_addr()
+            .map_err(|err| TrackerError::SocketIo(err.kind()))?
+            .is_ipv6();
+
+        if ipv6 {
+            Self::from_sockets(None, Some(socket))
+        } else {
+            Self::from_sockets(Some(socket), None)
+        }
+    }
+
+    /// Binds an IPv4 and an IPv6 socket on all interfaces with given `port`
+    /// (`0` for ephemeral ports), so trackers of either family can be reached
+    ///
+    /// Only one family is required to bind, as IPv6 may be unavailable. Some
+    /// operating systems make IPv6 sockets also take the IPv4 port, in which
+    /// case only one family may bind with a non-zero `port`
+    pub fn dual_stack(port: u16) -> Result<Self, TrackerError> {
+        let v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).ok();
+        let v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).ok();
+
+        if v4.is_none() && v6.is_none() {
+            return Err(TrackerError::SocketBind(format!("[::]:{}", port)));
+        }
+
+        Self::from_sockets(v4, v6)
+    }
+
+    /// Creates from already-bound sockets, starting their reader threads
+    fn from_sockets(v4: Option<UdpSocket>, v6: Option<UdpSocket>) -> Result<Self, TrackerError> {
+        let routes = Arc::new(Routes::default());
+
+        for socket in v4.iter().chain(v6.iter()) {
+            spawn_reader(socket, Arc::downgrade(&routes))
+                .map_err(|err| TrackerError::SocketIo(err.kind()))?;
+        }
+
+        Ok(Self {
+            v4: v4.map(Arc::new),
+            v6: v6.map(Arc::new),
+            routes,
+        })
+    }
+
+    /// Gets the local addresses of the bound sockets
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.v4
+            .iter()
+            .chain(self.v6.iter())
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect()
+    }
+
+    /// If an IPv4 socket is bound
+    pub fn has_v4(&self) -> bool {
+        self.v4.is_some()
+    }
+
+    /// If an IPv6 socket is bound
+    pub fn has_v6(&self) -> bool {
+        self.v6.is_some()
+    }
+
+    /// Gets a random transaction id which isn't used by any other exchange on
+    /// this socket, as BEP0015 asks for. These can't be guessed so responses
+    /// can't be forged for exchanges of other torrents or trackers
+    fn transaction_id(&self) -> u32 {
+        self.transaction_ids(1)[0]
+    }
+
+    /// Gets `amount` different random transaction ids, see
+    /// [TrackerSocket::transaction_id]
+    fn transaction_ids(&self, amount: usize) -> Vec<u32> {
+        let routes = self.routes.lock().unwrap();
+        let mut transaction_ids = Vec::with_capacity(amount);
+
+        while transaction_ids.len() < amount {
+            let transaction_id = random_secret() as u32;
+
+            if !routes.contains_key(&transaction_id) && !transaction_ids.contains(&transaction_id) {
+                transaction_ids.push(transaction_id);
+            }
+        }
+
+        transaction_ids
+    }
+
+    /// Sends `buf` to `addr` from the socket of the same family
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<(), TrackerError> {
+        let socket = if addr.is_ipv4() { &self.v4 } else { &self.v6 };
+
+        socket
+            .as_ref()
+            .ok_or_else(|| TrackerError::AddressResolution(addr.to_string()))?
+            .send_to(buf, addr)
+            .map(|_| ())
+            .map_err(|err| TrackerError::SocketIo(err.kind()))
+    }
+
+    /// Registers an exchange with `addr` for given `transaction_ids`, sending
+    /// it's packets to `sender` until the returned [Registration] is dropped
+    fn register(
+        &self,
+        addr: SocketAddr,
+        transaction_ids: &[u32],
+        sender: Sender<Routed>,
+    ) -> Registration<'_> {
+        let mut routes = self.routes.lock().unwrap();
+
+        for transaction_id in transaction_ids {
+            routes.insert(
+                *transaction_id,
+                Route {
+                    addr,
+                    sender: sender.clone(),
+                },
+            );
+        }
+
+        Registration {
+            routes: &self.routes,
+            transaction_ids: transaction_ids.to_vec(),
+        }
+    }
+}
+
+/// Registered transaction ids of an exchange on a [TrackerSocket], which are
+/// unregistered once dropped
+struct Registration<'a> {
+    /// Routes the transaction ids are registered in
+    routes: &'a Routes,
+
+    /// Registered transaction ids
+    transaction_ids: Vec<u32>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut routes = self.routes.lock().unwrap();
+
+        for transaction_id in self.transaction_ids.iter() {
+            routes.remove(transaction_id);
+        }
+    }
+}
+
+/// Starts a thread reading packets from `socket` and sorting them to the
+/// exchanges waiting in `routes`, stopping once `routes` is dropped
+fn spawn_reader(socket: &UdpSocket, routes: Weak<Routes>) -> std::io::Result<()> {
+    let socket = socket.try_clone()?;
+    socket.set_read_timeout(Some(READER_POLL))?;
+
+    thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_PACKET];
+
+        loop {
+            let recieved = socket.recv_from(&mut buf);
+            let routes = match routes.upgrade() {
+                Some(routes) => routes,
+                None => break,
+            };
+
+            if let Ok((len, from)) = recieved {
+                route_packet(&routes, &buf[..len], from);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Sends a `packet` recieved `from` a tracker to the exchange waiting for it's
+/// transaction id. If there isn't one, every exchange with the same tracker is
+/// told about the stray packet instead
+fn route_packet(routes: &Routes, packet: &[u8], from: SocketAddr) {
+    let routes = routes.lock().unwrap();
+    let stray = match read_u32(packet, 4) {
+        Some(transaction_id) => match routes.get(&transaction_id) {
+            Some(route) if route.addr == from => {
+                route.sender.send(Routed::Packet(packet.to_vec())).ok();
+                return;
+            }
+            _ => TrackerError::WrongTransactionId,
+        },
+        None => TrackerError::ShortPacket(packet.len()),
+    };
+
+    for route in routes.values().filter(|route| route.addr == from) {
+        route.sender.send(Routed::Stray(stray.clone())).ok();
+    }
+}
+
+/// A parsed UDP tracker announce URL
+#[derive(Debug, PartialEq, Clone)]
+struct UdpUrl {
+    /// Host and port of the tracker
+    authority: String,
+
+    /// Path and query of the URL, sent along with announces as BEP0041 URL
+    /// data
+    url_data: Vec<u8>,
+}
+
+impl UdpUrl {
+    /// Parses a `udp://host:port/path?query` announce URL, also accepting a
+    /// plain `host:port` without a scheme
+    fn parse(announce: &str) -> Result<Self, TrackerError> {
+        let rest = match announce.find("://") {
+            Some(ind) if announce[..ind].eq_ignore_ascii_case("udp") => &announce[ind + 3..],
+            Some(ind) => {
+                return Err(TrackerError::UnsupportedScheme(
+                    announce[..ind].to_ascii_lowercase(),
+                ))
+            }
+            None => announce,
+        };
+        let rest = rest.split('#').next().unwrap();
+        let (authority, url_data) = match rest.find(['/', '?']) {
+            Some(ind) => (&rest[..ind], &rest[ind..]),
+            None => (rest, ""),
+        };
+
+        if authority.is_empty() {
+            return Err(TrackerError::InvalidUrl(announce.to_string()));
+        }
+
+        Ok(Self {
+            authority: authority.to_string(),
+            url_data: url_data.as_bytes().to_vec(),
+        })
+    }
+}
+
+/// Sends `req_buf` with the given `transaction_id` to `addr` and waits for a
+/// response which `parse_resp` accepts, resending each time one of the given
+/// `timeouts` passes
+///
+/// See [exchange_many] for how recieved packets are matched and which errors
+/// may be given
+fn exchange<T>(
+    socket: &TrackerSocket,
+    addr: SocketAddr,
+    req_buf: &[u8],
+    transaction_id: u32,
+    timeouts: impl Iterator<Item = Duration>,
+    parse_resp: impl Fn(&[u8]) -> Option<T>,
+) -> Result<T, TrackerError> {
+    let mut resps = exchange_many(
+        socket,
+        addr,
+        &[(transaction_id, req_buf)],
+        timeouts,
+        |_, buf| parse_resp(buf),
+    )?;
+
+    Ok(resps.remove(0))
+}
+
+/// Sends every `(transaction_id, req_buf)` request in `reqs` to `addr` and waits
+/// for a response to each which `parse_resp` accepts, resending unanswered
+/// requests each time one of the given `timeouts` passes. Responses are given
+/// in the same order as `reqs`
+///
+/// Recieved packets are sorted to their request by the [TrackerSocket] using
+/// their transaction id and then given to `parse_resp` along with the index of
+/// that request. An error response (action `3`) fails the whole exchange with
+/// [TrackerError::TrackerMessage], whilst packets from the tracker which are too
+/// short to parse or are for an unknown transaction id are ignored. If the
+/// `timeouts` run out, the last ignored packet decides the error given,
+/// defaulting to [TrackerError::Timeout] if nothing was recieved
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// error response:
+///
+/// Offset  Size            Name            Value
+/// 0       32-bit integer  action          3 // error
+/// 4       32-bit integer  transaction_id
+/// 8       string  message
+/// ```
+fn exchange_many<T>(
+    socket: &TrackerSocket,
+    addr: SocketAddr,
+    reqs: &[(u32, &[u8])],
+    timeouts: impl Iterator<Item = Duration>,
+    parse_resp: impl Fn(usize, &[u8]) -> Option<T>,
+) -> Result<Vec<T>, TrackerError> {
+    let (sender, reciever) = mpsc::channel();
+    let transaction_ids: Vec<u32> = reqs
+        .iter()
+        .map(|(transaction_id, _)| *transaction_id)
+        .collect();
+    let _registration = socket.register(addr, &transaction_ids, sender);
+    let mut resps: Vec<Option<T>> = reqs.iter().map(|_| None).collect();
+    let mut last_err = TrackerError::Timeout;
+
+    for timeout in timeouts {
+        for ((_, req_buf), resp) in reqs.iter().zip(resps.iter()) {
+            if resp.is_none() {
+                socket.send_to(req_buf, addr)?;
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let resp = match reciever.recv_timeout(remaining) {
+                Ok(Routed::Packet(resp)) => resp,
+                Ok(Routed::Stray(err)) => {
+                    last_err = err;
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            let transaction_id = read_u32(&resp, 4).unwrap();
+            let req_ind = match reqs
+                .iter()
+                .zip(resps.iter())
+                .position(|((req_id, _), resp)| resp.is_none() && *req_id == transaction_id)
+            {
+                Some(req_ind) => req_ind,
+                None => continue, // duplicate response to a resent request
+            };
+
+            if read_u32(&resp, 0) == Some(ACTION_ERROR) {
+                return Err(TrackerError::TrackerMessage(
+                    String::from_utf8_lossy(&resp[8..]).into_owned(),
+                ));
+            }
+
+            match parse_resp(req_ind, &resp) {
+                Some(parsed) => resps[req_ind] = Some(parsed),
+                None => last_err = TrackerError::ShortPacket(resp.len()),
+            }
+
+            if resps.iter().all(|resp| resp.is_some()) {
+                return Ok(resps.into_iter().flatten().collect());
+            }
+        }
+    }
+
+    Err(last_err)
+}
+
+/// Gets the BEP0015 timeouts to use for [exchange], as found with [timeout_calc]
+fn bep15_timeouts() -> impl Iterator<Item = Duration> {
+    (0..=MAX_TRIES).map(|tries| Duration::from_secs(timeout_calc(tries) as u64))
+}
+
 /// A connection request to a tracker, the first low-level exchange to and from
 /// the client with the tracker
+///
+/// This is low-level and most users will want an
+/// [AnnounceManager](crate::tracker::AnnounceManager) instead
 pub struct ConnectReq {
     /// Randomly-generated id that torro provides the tracker
     pub transaction_id: u32,
@@ -73,7 +617,12 @@ impl ConnectReq {
     /// a new [ConnectReq] from it or returns a [TrackerError]
     ///
     /// `bind_addr` is typically just passed as the [TORRO_BIND_ADDR] constant,
-    /// like so: `ConnectReq::send(TORRO_BIND_ADDR, something)`
+    /// like so: `ConnectReq::send(TORRO_BIND_ADDR, something)`, or
+    /// [TORRO_BIND_ADDR_V6] for IPv6 trackers
+    ///
+    /// The request is resent using the `15 * 2 ^ n` timeouts given in BEP0015
+    /// until a response for this request's transaction id is recieved, giving
+    /// up after `n` reaches 8
     ///
     /// # Examples
     ///
@@ -93,26 +642,729 @@ impl ConnectReq {
     ///     );
     /// }
     /// ```
-    pub fn send(bind_addr: &'static str, announce: String) -> Result<Self, TrackerError> {
-        let transaction_id = randish_128() as u32;
-        let mut connection_buf = &build_connect_req_buf(transaction_id);
+    pub fn send(bind_addr: &str, announce: String) -> Result<Self, TrackerError> {
+        let socket = TrackerSocket::bind(bind_addr)?;
+        let authority = UdpUrl::parse(&announce)?.authority;
+        let addr = resolve(&authority, socket.has_v4(), socket.has_v6())?[0];
 
-        let mut socket =
-            UdpSocket::bind(bind_addr).map_err(|_| TrackerError::SocketBind(bind_addr))?;
+        ConnectReq::send_socket(&socket, addr, bep15_timeouts())
+    }
 
-        socket
-            .send_to(connection_buf, &announce)
-            .map_err(|_| TrackerError::SocketBind(bind_addr))?;
+    /// Sends a connection request on an already bound `socket`, resending after
+    /// each of the given `timeouts`
+    fn send_socket(
+        socket: &TrackerSocket,
+        addr: SocketAddr,
+        timeouts: impl Iterator<Item = Duration>,
+    ) -> Result<Self, TrackerError> {
+        let transaction_id = socket.transaction_id();
+        let connection_buf = build_connect_req_buf(transaction_id);
+
+        let connection_id = exchange(
+            socket,
+            addr,
+            &connection_buf,
+            transaction_id,
+            timeouts,
+            |buf| parse_connect_resp(transaction_id, buf),
+        )?;
+
+        Ok(Self {
+            transaction_id,
+            connection_id,
+        })
+    }
+}
+
+/// The event given with an [AnnounceReq], telling the tracker what state the
+/// download is in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AnnounceEvent {
+    /// A regular announce at the interval the tracker asked for
+    None,
+
+    /// The download has completed, only sent once when it does
+    Completed,
+
+    /// The download has just started, sent as the first announce
+    Started,
+
+    /// The download has stopped, sent when gracefully shutting down
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// Gets the number representing this event inside of a BEP0015 announce
+    fn as_u32(&self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+
+    /// Gets the event represented by `num` inside of a BEP0015 announce, or
+    /// [None] if it isn't a known event
+    pub(crate) fn from_u32(num: u32) -> Option<Self> {
+        match num {
+            0 => Some(AnnounceEvent::None),
+            1 => Some(AnnounceEvent::Completed),
+            2 => Some(AnnounceEvent::Started),
+            3 => Some(AnnounceEvent::Stopped),
+            _ => None,
+        }
+    }
+}
+
+/// An announce request to a tracker, made once a connection id has been found
+/// with [ConnectReq] to get a list of peers for a torrent
+///
+/// This is made using [AnnounceReq::new] and then changed with the builder
+/// methods like [AnnounceReq::left], with any values not given being left as
+/// the defaults described on each method
+#[derive(Debug, PartialEq, Clone)]
+pub struct AnnounceReq {
+    /// Info-hash of the torrent to get peers for, see
+    /// [Torrent::info_hash](crate::Torrent::info_hash)
+    pub info_hash: [u8; 20],
+
+    /// Peer id of this client
+    pub peer_id: [u8; 20],
+
+    /// Amount of bytes downloaded so far
+    pub downloaded: u64,
+
+    /// Amount of bytes left to download
+    pub left: u64,
+
+    /// Amount of bytes uploaded so far
+    pub uploaded: u64,
+
+    /// The current [AnnounceEvent]
+    pub event: AnnounceEvent,
+
+    /// Random key used by the tracker to recognise this client if it's IP
+    /// address changes
+    pub key: u32,
+
+    /// Amount of peers wanted or `-1` for the tracker's default amount
+    pub num_want: i32,
+
+    /// Port this client is listening for peers on
+    pub port: u16,
+}
+
+impl AnnounceReq {
+    /// Creates a new announce request for given `info_hash` when listening for
+    /// peers on `port`
+    ///
+    /// A new torro peer id and random key are generated, no bytes are counted as
+    /// downloaded, left or uploaded, the event is [AnnounceEvent::None] and the
+    /// tracker's default amount of peers are wanted
+    pub fn new(info_hash: [u8; 20], port: u16) -> Self {
+        let mut peer_id = [0; 20];
+        peer_id.copy_from_slice(generate_torro_id().as_bytes());
+
+        Self {
+            info_hash,
+            peer_id,
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: AnnounceEvent::None,
+            key: random_secret() as u32,
+            num_want: -1,
+            port,
+        }
+    }
+
+    /// Sets the peer id to announce as
+    pub fn peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    /// Sets the amount of bytes downloaded so far
+    pub fn downloaded(mut self, downloaded: u64) -> Self {
+        self.downloaded = downloaded;
+        self
+    }
+
+    /// Sets the amount of bytes left to download
+    pub fn left(mut self, left: u64) -> Self {
+        self.left = left;
+        self
+    }
+
+    /// Sets the amount of bytes uploaded so far
+    pub fn uploaded(mut self, uploaded: u64) -> Self {
+        self.uploaded = uploaded;
+        self
+    }
+
+    /// Sets the [AnnounceEvent] to announce with
+    pub fn event(mut self, event: AnnounceEvent) -> Self {
+        self.event = event;
+        self
+    }
+
+    /// Sets the key used to recognise this client
+    pub fn key(mut self, key: u32) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Sets the amount of peers wanted, `-1` being the tracker's default
+    pub fn num_want(mut self, num_want: i32) -> Self {
+        self.num_want = num_want;
+        self
+    }
+
+    /// Builds the announce request packet for given `connection_id` and
+    /// `transaction_id`, with `url_data` from the announce URL appended as
+    /// BEP0041 URL data options if there is any
+    ///
+    /// # BitTorrent Description
+    ///
+    /// ```none
+    /// IPv4 announce request:
+    ///
+    /// Offset  Size    Name    Value
+    /// 0       64-bit integer  connection_id
+    /// 8       32-bit integer  action          1 // announce
+    /// 12      32-bit integer  transaction_id
+    /// 16      20-byte string  info_hash
+    /// 36      20-byte string  peer_id
+    /// 56      64-bit integer  downloaded
+    /// 64      64-bit integer  left
+    /// 72      64-bit integer  uploaded
+    /// 80      32-bit integer  event           0 // 0: none; 1: completed; 2: started; 3: stopped
+    /// 84      32-bit integer  IP address      0 // default
+    /// 88      32-bit integer  key
+    /// 92      32-bit integer  num_want        -1 // default
+    /// 96      16-bit integer  port
+    /// 98
+    ///
+    /// [...] The URLData option [...] contains a string of bytes. The request
+    /// string is carried as a sequence of URLData options, each carrying up to
+    /// 255 bytes of the request string, that are concatenated
+    ///
+    /// Offset  Size    Name            Value
+    /// 0       8-bit   option-type     0x2 // URLData
+    /// 1       8-bit   length          n
+    /// 2       n bytes data
+    /// ```
+    fn build_buf(&self, connection_id: u64, transaction_id: u32, url_data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(98 + url_data.len() + url_data.len() / 255 * 2 + 3);
+
+        buf.extend_from_slice(&connection_id.to_be_bytes());
+        buf.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        buf.extend_from_slice(&transaction_id.to_be_bytes());
+        buf.extend_from_slice(&self.info_hash);
+        buf.extend_from_slice(&self.peer_id);
+        buf.extend_from_slice(&self.downloaded.to_be_bytes());
+        buf.extend_from_slice(&self.left.to_be_bytes());
+        buf.extend_from_slice(&self.uploaded.to_be_bytes());
+        buf.extend_from_slice(&self.event.as_u32().to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(&self.key.to_be_bytes());
+        buf.extend_from_slice(&self.num_want.to_be_bytes());
+        buf.extend_from_slice(&self.port.to_be_bytes());
+
+        if !url_data.is_empty() {
+            for chunk in url_data.chunks(255) {
+                buf.push(OPTION_URL_DATA);
+                buf.push(chunk.len() as u8);
+                buf.extend_from_slice(chunk);
+            }
+
+            buf.push(OPTION_END);
+        }
+
+        buf
+    }
+
+    /// Connects to a tracker from given `announce` address with a [ConnectReq]
+    /// and then sends this announce request, returning the tracker's
+    /// [AnnounceResp] or a [TrackerError]
+    ///
+    /// A new connection is made each time this is called, see
+    /// [UdpTracker::announce] for repeat announces to the same tracker
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use torro::tracker_udp::{AnnounceEvent, AnnounceReq, TORRO_BIND_ADDR};
+    ///
+    /// fn main() {
+    ///     let resp = AnnounceReq::new([0; 20], 6881)
+    ///         .left(1024)
+    ///         .event(AnnounceEvent::Started)
+    ///         .send(TORRO_BIND_ADDR, "tracker-url-here.co.biz:6969".to_string())
+    ///         .unwrap();
+    ///
+    ///     println!("Got {} peers: {:?}", resp.peers.len(), resp.peers);
+    /// }
+    /// ```
+    pub fn send(&self, bind_addr: &str, announce: String) -> Result<AnnounceResp, TrackerError> {
+        UdpTracker::new(bind_addr, &announce)?.announce(self)
+    }
+}
+
+/// A tracker's response to an [AnnounceReq]
+#[derive(Debug, PartialEq, Clone)]
+pub struct AnnounceResp {
+    /// Amount of seconds to wait before announcing again
+    pub interval: u32,
+
+    /// Amount of peers which are still downloading
+    pub leechers: u32,
+
+    /// Amount of peers which have completed their download
+    pub seeders: u32,
+
+    /// Addresses of peers to connect to
+    pub peers: Vec<SocketAddr>,
+}
+
+impl AnnounceResp {
+    /// Parses an announce response for given `transaction_id`, with peers given
+    /// as IPv6 addresses if `ipv6` (if the tracker was reached over IPv6)
+    ///
+    /// # BitTorrent Description
+    ///
+    /// ```none
+    /// IPv4 announce response:
+    ///
+    /// Offset      Size            Name            Value
+    /// 0           32-bit integer  action          1 // announce
+    /// 4           32-bit integer  transaction_id
+    /// 8           32-bit integer  interval
+    /// 12          32-bit integer  leechers
+    /// 16          32-bit integer  seeders
+    /// 20 + 6 * n  32-bit integer  IP address
+    /// 24 + 6 * n  16-bit integer  TCP port
+    /// 20 + 6 * N
+    ///
+    /// [...] Which format is used is determined by the address family of the
+    /// underlying UDP packet. I.e. packets from a v4 address use the v4 format,
+    /// those from a v6 address use the v6 format.
+    /// ```
+    fn from_bytes(transaction_id: u32, buf: &[u8], ipv6: bool) -> Option<Self> {
+        if read_u32(buf, 0)? != ACTION_ANNOUNCE || read_u32(buf, 4)? != transaction_id {
+            return None;
+        }
+
+        Some(Self {
+            interval: read_u32(buf, 8)?,
+            leechers: read_u32(buf, 12)?,
+            seeders: read_u32(buf, 16)?,
+            peers: parse_compact_peers(&buf[20..], ipv6),
+        })
+    }
+}
+
+/// Parses compact peer addresses (an IP address followed by a 16-bit port) from
+/// given `buf`, as IPv6 addresses if `ipv6`. Any trailing bytes which don't
+/// make up a whole address are ignored
+pub(crate) fn parse_compact_peers(buf: &[u8], ipv6: bool) -> Vec<SocketAddr> {
+    let ip_len = if ipv6 { 16 } else { 4 };
+
+    buf.chunks_exact(ip_len + 2)
+        .map(|peer| {
+            let ip = if ipv6 {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&peer[..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            } else {
+                IpAddr::V4(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]))
+            };
+
+            SocketAddr::new(ip, u16::from_be_bytes([peer[ip_len], peer[ip_len + 1]]))
+        })
+        .collect()
+}
+
+/// Scrape statistics for a single torrent, as given by a tracker from
+/// [scrape] or [HttpTracker::scrape](crate::tracker_http::HttpTracker::scrape)
+#[derive(Debug, PartialEq, Clone)]
+pub struct ScrapeStats {
+    /// Info-hash of the torrent these statistics are for
+    pub info_hash: [u8; 20],
+
+    /// Amount of peers which have completed their download (seeders)
+    pub complete: u32,
+
+    /// Amount of times the torrent has been downloaded in total
+    pub downloaded: u32,
+
+    /// Amount of peers which are still downloading (leechers)
+    pub incomplete: u32,
+}
+
+/// Builds a scrape request packet for given `connection_id`, `transaction_id`
+/// and up to [MAX_SCRAPE_HASHES] `info_hashes`
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// scrape request:
+///
+/// Offset          Size            Name            Value
+/// 0               64-bit integer  connection_id
+/// 8               32-bit integer  action          2 // scrape
+/// 12              32-bit integer  transaction_id
+/// 16 + 20 * n     20-byte string  info_hash
+/// 16 + 20 * N
+/// ```
+fn build_scrape_req_buf(
+    connection_id: u64,
+    transaction_id: u32,
+    info_hashes: &[[u8; 20]],
+) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16 + 20 * info_hashes.len());
+
+    buf.extend_from_slice(&connection_id.to_be_bytes());
+    buf.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+    buf.extend_from_slice(&transaction_id.to_be_bytes());
+
+    for info_hash in info_hashes {
+        buf.extend_from_slice(info_hash);
+    }
+
+    buf
+}
+
+/// Parses a scrape response for given `transaction_id` and the `info_hashes`
+/// which where requested in the same order
+///
+/// # BitTorrent Description
+///
+/// ```none
+/// scrape response:
+///
+/// Offset      Size            Name            Value
+/// 0           32-bit integer  action          2 // scrape
+/// 4           32-bit integer  transaction_id
+/// 8 + 12 * n  32-bit integer  seeders
+/// 12 + 12 * n 32-bit integer  completed
+/// 16 + 12 * n 32-bit integer  leechers
+/// 8 + 12 * N
+/// ```
+fn parse_scrape_resp(
+    transaction_id: u32,
+    info_hashes: &[[u8; 20]],
+    buf: &[u8],
+) -> Option<Vec<ScrapeStats>> {
+    if read_u32(buf, 0)? != ACTION_SCRAPE || read_u32(buf, 4)? != transaction_id {
+        return None;
+    }
+
+    info_hashes
+        .iter()
+        .enumerate()
+        .map(|(ind, info_hash)| {
+            Some(ScrapeStats {
+                info_hash: *info_hash,
+                complete: read_u32(buf, 8 + 12 * ind)?,
+                downloaded: read_u32(buf, 12 + 12 * ind)?,
+                incomplete: read_u32(buf, 16 + 12 * ind)?,
+            })
+        })
+        .collect()
+}
+
+/// Scrapes a tracker from given `announce` address for statistics on any
+/// amount of `info_hashes`, returning a [ScrapeStats] for each in the same order
+/// or a [TrackerError]
+///
+/// Info-hashes are split into packets of at most [MAX_SCRAPE_HASHES] which are
+/// all sent at once over a single connection, with responses matched up to
+/// their packets by transaction id. A new connection is made each time this is
+/// called, see [UdpTracker::scrape] for repeat scrapes of the same tracker
+///
+/// # Examples
+///
+/// ```no_run
+/// use torro::tracker_udp::{scrape, TORRO_BIND_ADDR};
+///
+/// fn main() {
+///     let info_hashes = vec![[0; 20], [1; 20]];
+///     let stats = scrape(
+///         TORRO_BIND_ADDR,
+///         "tracker-url-here.co.biz:6969".to_string(),
+///         &info_hashes,
+///     )
+///     .unwrap();
+///
+///     for torrent_stats in stats {
+///         println!(
+///             "{} seeders, {} leechers",
+///             torrent_stats.complete, torrent_stats.incomplete
+///         );
+///     }
+/// }
+/// ```
+pub fn scrape(
+    bind_addr: &str,
+    announce: String,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeStats>, TrackerError> {
+    UdpTracker::new(bind_addr, &announce)?.scrape(info_hashes)
+}
+
+/// A connection to a single UDP tracker which keeps it's connection ids for
+/// the one minute they are valid for, so repeat announces and scrapes skip the
+/// [ConnectReq] round trip
+///
+/// If the tracker resolves to both IPv4 and IPv6 addresses and the
+/// [TrackerSocket] has both families bound, announces are sent to both so that
+/// peers of either family can find us, with the peers given back merged
+///
+/// # Examples
+///
+/// ```no_run
+/// use torro::tracker_udp::{AnnounceEvent, AnnounceReq, UdpTracker, TORRO_BIND_ADDR};
+///
+/// fn main() {
+///     let mut tracker = UdpTracker::new(TORRO_BIND_ADDR, "tracker-url-here.co.biz:6969").unwrap();
+///     let req = AnnounceReq::new([0; 20], 6881).event(AnnounceEvent::Started);
+///
+///     let resp = tracker.announce(&req).unwrap();
+///     let stats = tracker.scrape(&[[0; 20]]).unwrap(); // reuses the connection id
+///
+///     println!("Got {} peers, {:?}", resp.peers.len(), stats);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct UdpTracker {
+    /// Socket used for all exchanges with the tracker, possibly shared with
+    /// other trackers
+    socket: TrackerSocket,
+
+    /// Resolved addresses of the tracker, at most one per family
+    addrs: Vec<SocketAddr>,
+
+    /// Path and query of the announce URL, sent with announces as BEP0041 URL
+    /// data
+    url_data: Vec<u8>,
+
+    /// Last connection id given by each address of the tracker and when it was
+    /// recieved
+    connections: HashMap<SocketAddr, (u64, Instant)>,
+
+    /// Timeouts to use for each exchange, typically from [bep15_timeouts]
+    timeouts: Vec<Duration>,
+}
+
+impl UdpTracker {
+    /// Binds a new [TrackerSocket] to `bind_addr` and resolves the tracker's
+    /// `announce` URL, without contacting the tracker yet
+    ///
+    /// The `announce` URL may either be a full `udp://host:port/path?query` URL,
+    /// with any path and query sent along with announces as
+    /// [BEP0041](https://www.bittorrent.org/beps/bep_0041.html) URL data, or a
+    /// plain `host:port`
+    pub fn new(bind_addr: &str, announce: &str) -> Result<Self, TrackerError> {
+        Self::with_socket(&TrackerSocket::bind(bind_addr)?, announce)
+    }
+
+    /// Same as [UdpTracker::new] but uses an existing `socket`, which may be
+    /// shared between many trackers and torrents at once
+    pub fn with_socket(socket: &TrackerSocket, announce: &str) -> Result<Self, TrackerError> {
+        let url = UdpUrl::parse(announce)?;
+
+        Ok(Self {
+            socket: socket.clone(),
+            addrs: resolve(&url.authority, socket.has_v4(), socket.has_v6())?,
+            url_data: url.url_data,
+            connections: HashMap::new(),
+            timeouts: bep15_timeouts().collect(),
+        })
+    }
+
+    /// Sets the timeouts waited for each exchange with the tracker, resending
+    /// the request after each one. By default these are the BEP0015 timeouts
+    /// of `15 * 2 ^ n` seconds for `n` of 0 to 8, which take over two hours to
+    /// give up on a dead tracker
+    pub fn timeouts(mut self, timeouts: impl IntoIterator<Item = Duration>) -> Self {
+        self.timeouts = timeouts.into_iter().collect();
+        self
+    }
+
+    /// Gets the first resolved address of the tracker
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// Gets all resolved addresses of the tracker, at most one per family
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Gets a connection id for the tracker at `addr`, only sending a
+    /// [ConnectReq] if there isn't one from the last minute already
+    fn connection_id(&mut self, addr: SocketAddr) -> Result<u64, TrackerError> {
+        if let Some((connection_id, recieved)) = self.connections.get(&addr) {
+            if recieved.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(*connection_id);
+            }
+        }
+
+        let recieved = Instant::now();
+        let connection =
+            ConnectReq::send_socket(&self.socket, addr, self.timeouts.iter().copied())?;
+        self.connections
+            .insert(addr, (connection.connection_id, recieved));
+
+        Ok(connection.connection_id)
+    }
+
+    /// Forgets the cached connection id for `addr` if `result` is an error from
+    /// the tracker, as it may have rejected the connection id, so the next
+    /// request connects again
+    fn check_result<T>(
+        &mut self,
+        addr: SocketAddr,
+        result: Result<T, TrackerError>,
+    ) -> Result<T, TrackerError> {
+        if let Err(TrackerError::TrackerMessage(_)) = result {
+            self.connections.remove(&addr);
+        }
+
+        result
+    }
+
+    /// Sends the announce request `req` to a single address of the tracker
+    fn announce_addr(
+        &mut self,
+        addr: SocketAddr,
+        req: &AnnounceReq,
+    ) -> Result<AnnounceResp, TrackerError> {
+        let connection_id = self.connection_id(addr)?;
+        let transaction_id = self.socket.transaction_id();
+        let announce_buf = req.build_buf(connection_id, transaction_id, &self.url_data);
+        let ipv6 = addr.is_ipv6();
+
+        let result = exchange(
+            &self.socket,
+            addr,
+            &announce_buf,
+            transaction_id,
+            self.timeouts.iter().copied(),
+            |buf| AnnounceResp::from_bytes(transaction_id, buf, ipv6),
+        );
+
+        self.check_result(addr, result)
+    }
+
+    /// Sends the announce request `req` to the tracker, returning the tracker's
+    /// [AnnounceResp] or a [TrackerError]
+    ///
+    /// When the tracker has both an IPv4 and IPv6 address, both are announced
+    /// to and their peers merged, only failing if neither address answers
+    pub fn announce(&mut self, req: &AnnounceReq) -> Result<AnnounceResp, TrackerError> {
+        let mut merged: Option<AnnounceResp> = None;
+        let mut last_err = TrackerError::Timeout;
+
+        for addr in self.addrs.clone() {
+            let resp = match self.announce_addr(addr, req) {
+                Ok(resp) => resp,
+                Err(err) => {
+                    last_err = err;
+                    continue;
+                }
+            };
+
+            merged = Some(match merged {
+                None => resp,
+                Some(mut merged) => {
+                    merged.interval = merged.interval.min(resp.interval);
+                    merged.leechers = merged.leechers.max(resp.leechers);
+                    merged.seeders = merged.seeders.max(resp.seeders);
+
+                    for peer in resp.peers {
+                        if !merged.peers.contains(&peer) {
+                            merged.peers.push(peer);
+                        }
+                    }
+
+                    merged
+                }
+            });
+        }
+
+        merged.ok_or(last_err)
+    }
+
+    /// Scrapes a single address of the tracker, see [UdpTracker::scrape]
+    fn scrape_addr(
+        &mut self,
+        addr: SocketAddr,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let connection_id = self.connection_id(addr)?;
+        let chunks: Vec<(u32, &[[u8; 20]])> = self
+            .socket
+            .transaction_ids(info_hashes.chunks(MAX_SCRAPE_HASHES).len())
+            .into_iter()
+            .zip(info_hashes.chunks(MAX_SCRAPE_HASHES))
+            .collect();
+        let req_bufs: Vec<Vec<u8>> = chunks
+            .iter()
+            .map(|(transaction_id, chunk)| {
+                build_scrape_req_buf(connection_id, *transaction_id, chunk)
+            })
+            .collect();
+        let reqs: Vec<(u32, &[u8])> = chunks
+            .iter()
+            .zip(req_bufs.iter())
+            .map(|((transaction_id, _), buf)| (*transaction_id, buf.as_slice()))
+            .collect();
+
+        let result = exchange_many(
+            &self.socket,
+            addr,
+            &reqs,
+            self.timeouts.iter().copied(),
+            |ind, buf| parse_scrape_resp(chunks[ind].0, chunks[ind].1, buf),
+        );
+
+        Ok(self
+            .check_result(addr, result)?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
 
-        // TODO: recieve inbound req and restructure to loop for timeouts with [timeout_calc]
+    /// Scrapes the tracker for statistics on any amount of `info_hashes`, see
+    /// [scrape] for more infomation
+    ///
+    /// As scrape statistics are the same for every address of a tracker, only
+    /// the first address which answers is used
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        if info_hashes.is_empty() {
+            return Ok(vec![]);
+        }
 
-        unimplemented!();
+        let mut last_err = TrackerError::Timeout;
+
+        for addr in self.addrs.clone() {
+            match self.scrape_addr(addr, info_hashes) {
+                Ok(stats) => return Ok(stats),
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(last_err)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::randish_128;
 
     /// Tests that [timeout_calc] works correctly
     #[test]
@@ -137,4 +1389,441 @@ mod tests {
             build_connect_req_buf(randish_128() as u32);
         }
     }
+
+    /// Tests that [build_connect_req_buf] puts each field at the offsets given
+    /// in BEP0015
+    #[test]
+    fn build_connect_req_buf_layout() {
+        assert_eq!(
+            build_connect_req_buf(0xdeadbeef),
+            [0, 0, 0x04, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef]
+        );
+    }
+
+    /// Starts a local tracker stand-in which ignores the first `ignore` connect
+    /// requests and then responds to the next with `connection_id`, returning
+    /// it's address
+    fn connect_stand_in(ignore: usize, connection_id: u64) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 16];
+
+            for _ in 0..ignore {
+                socket.recv_from(&mut buf).unwrap();
+            }
+
+            let (_, client) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..8], &PROTOCOL_ID.to_be_bytes());
+            assert_eq!(&buf[8..12], &ACTION_CONNECT.to_be_bytes());
+
+            // stray packet with the wrong transaction id which should be ignored
+            let mut resp = vec![];
+            resp.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            resp.extend_from_slice(&(read_u32(&buf, 12).unwrap() ^ 1).to_be_bytes());
+            resp.extend_from_slice(&0u64.to_be_bytes());
+            socket.send_to(&resp, client).unwrap();
+
+            resp[4..8].copy_from_slice(&buf[12..16]);
+            resp[8..16].copy_from_slice(&connection_id.to_be_bytes());
+            socket.send_to(&resp, client).unwrap();
+        });
+
+        addr
+    }
+
+    /// Tests that [ConnectReq::send] gets a connection id from a local tracker
+    /// stand-in
+    #[test]
+    fn connect_stand_in_tracker() {
+        let addr = connect_stand_in(0, 0x1234);
+        let connection = ConnectReq::send("127.0.0.1:0", addr).unwrap();
+
+        assert_eq!(connection.connection_id, 0x1234);
+    }
+
+    /// Tests that connection requests are resent after a timeout if the tracker
+    /// doesn't respond
+    #[test]
+    fn connect_retries() {
+        let addr = connect_stand_in(2, 0x5678);
+        let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+        let timeouts = (0..4).map(|_| Duration::from_millis(100));
+
+        let connection = ConnectReq::send_socket(&socket, addr.parse().unwrap(), timeouts).unwrap();
+
+        assert_eq!(connection.connection_id, 0x5678);
+    }
+
+    /// Tests that giving up after all timeouts gives [TrackerError::Timeout]
+    #[test]
+    fn connect_gives_up() {
+        let addr = connect_stand_in(5, 0);
+        let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+        let timeouts = (0..2).map(|_| Duration::from_millis(50));
+
+        assert_eq!(
+            ConnectReq::send_socket(&socket, addr.parse().unwrap(), timeouts).err(),
+            Some(TrackerError::Timeout)
+        );
+    }
+
+    /// Tests that one [TrackerSocket] shared between threads sorts responses
+    /// from several trackers to the right exchange
+    #[test]
+    fn shared_socket_routes() {
+        let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+        let threads: Vec<_> = (1..=4u64)
+            .map(|connection_id| {
+                let addr = connect_stand_in(0, connection_id);
+                let socket = socket.clone();
+
+                std::thread::spawn(move || {
+                    let timeouts = (0..4).map(|_| Duration::from_millis(500));
+                    ConnectReq::send_socket(&socket, addr.parse().unwrap(), timeouts)
+                        .unwrap()
+                        .connection_id
+                })
+            })
+            .collect();
+
+        for (ind, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), ind as u64 + 1);
+        }
+    }
+
+    /// Tests that [family_addrs] keeps the first address of each wanted family
+    #[test]
+    fn family_addrs_picks() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:1".parse().unwrap(),
+            "10.0.0.1:2".parse().unwrap(),
+            "[::2]:3".parse().unwrap(),
+            "10.0.0.2:4".parse().unwrap(),
+        ];
+
+        assert_eq!(
+            family_addrs(addrs.clone(), true, true),
+            vec![addrs[0], addrs[1]]
+        );
+        assert_eq!(family_addrs(addrs.clone(), true, false), vec![addrs[1]]);
+        assert_eq!(
+            family_addrs(addrs, false, true),
+            vec!["[::1]:1".parse().unwrap()]
+        );
+    }
+
+    /// Starts a local tracker stand-in which responds to the first packet it
+    /// recieves with the result of `make_resp` given that packet, returning
+    /// it's address
+    fn reply_stand_in(make_resp: impl Fn(&[u8]) -> Vec<u8> + Send + 'static) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 16];
+            let (_, client) = socket.recv_from(&mut buf).unwrap();
+
+            socket.send_to(&make_resp(&buf), client).unwrap();
+        });
+
+        addr
+    }
+
+    /// Tests that error responses, short packets and packets for the wrong
+    /// transaction id each give their own [TrackerError]
+    #[test]
+    fn exchange_errors() {
+        let timeouts = || (0..2).map(|_| Duration::from_millis(100));
+        let connect = |addr: String| {
+            let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+            ConnectReq::send_socket(&socket, addr.parse().unwrap(), timeouts()).err()
+        };
+
+        let addr = reply_stand_in(|req| {
+            let mut resp = vec![0, 0, 0, 3];
+            resp.extend_from_slice(&req[12..16]);
+            resp.extend_from_slice(b"not today");
+            resp
+        });
+        assert_eq!(
+            connect(addr),
+            Some(TrackerError::TrackerMessage("not today".to_string()))
+        );
+
+        let addr = reply_stand_in(|_| vec![0, 0, 0]);
+        assert_eq!(connect(addr), Some(TrackerError::ShortPacket(3)));
+
+        let addr = reply_stand_in(|req| {
+            let mut resp = vec![0, 0, 0, 0];
+            resp.extend_from_slice(&(read_u32(req, 12).unwrap() ^ 1).to_be_bytes());
+            resp.extend_from_slice(&0u64.to_be_bytes());
+            resp
+        });
+        assert_eq!(connect(addr), Some(TrackerError::WrongTransactionId));
+
+        assert_eq!(
+            UdpTracker::new("127.0.0.1:0", "not an address").err(),
+            Some(TrackerError::AddressResolution(
+                "not an address".to_string()
+            ))
+        );
+    }
+
+    /// Tests that [UdpTracker] reuses it's connection id for repeat announces
+    /// instead of connecting again
+    #[test]
+    fn udp_tracker_caches_connection() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 98];
+
+            let (_, client) = socket.recv_from(&mut buf).unwrap();
+            let mut resp = vec![];
+            resp.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            resp.extend_from_slice(&buf[12..16]);
+            resp.extend_from_slice(&0x99u64.to_be_bytes());
+            socket.send_to(&resp, client).unwrap();
+
+            for _ in 0..2 {
+                let (_, client) = socket.recv_from(&mut buf).unwrap();
+                assert_eq!(read_u64(&buf, 0), Some(0x99));
+                assert_eq!(read_u32(&buf, 8), Some(ACTION_ANNOUNCE));
+
+                let mut resp = vec![];
+                resp.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                resp.extend_from_slice(&buf[12..16]);
+                resp.extend_from_slice(&[0; 12]);
+                socket.send_to(&resp, client).unwrap();
+            }
+        });
+
+        let mut tracker = UdpTracker::new("127.0.0.1:0", &addr).unwrap();
+        tracker.timeouts = vec![Duration::from_millis(500); 2];
+        let req = AnnounceReq::new([1; 20], 6881);
+
+        assert!(tracker.announce(&req).is_ok());
+        assert!(tracker.announce(&req).is_ok());
+    }
+
+    /// Tests that [UdpUrl::parse] splits the path and query off as URL data
+    #[test]
+    fn udp_url_parse() {
+        assert_eq!(
+            UdpUrl::parse("udp://tracker.example:80/announce?passkey=abc#frag").unwrap(),
+            UdpUrl {
+                authority: "tracker.example:80".to_string(),
+                url_data: b"/announce?passkey=abc".to_vec()
+            }
+        );
+        assert_eq!(
+            UdpUrl::parse("[::1]:6969").unwrap(),
+            UdpUrl {
+                authority: "[::1]:6969".to_string(),
+                url_data: vec![]
+            }
+        );
+        assert_eq!(
+            UdpUrl::parse("http://tracker.example/announce"),
+            Err(TrackerError::UnsupportedScheme("http".to_string()))
+        );
+    }
+
+    /// Tests that URL data is split into options of up to 255 bytes after the
+    /// announce request, as BEP0041 describes
+    #[test]
+    fn announce_url_data_options() {
+        let req = AnnounceReq::new([0; 20], 6881);
+        let url_data = vec![b'a'; 300];
+
+        assert_eq!(req.build_buf(0, 0, &[]).len(), 98);
+
+        let buf = req.build_buf(0, 0, &url_data);
+
+        assert_eq!(&buf[98..100], &[OPTION_URL_DATA, 255]);
+        assert_eq!(&buf[355..357], &[OPTION_URL_DATA, 45]);
+        assert_eq!(buf.len(), 357 + 45 + 1);
+        assert_eq!(buf[buf.len() - 1], OPTION_END);
+    }
+
+    /// Tests that [AnnounceReq::send] announces to a local tracker stand-in and
+    /// gets it's peers back
+    #[test]
+    fn announce_stand_in_tracker() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 98];
+
+            let (_, client) = socket.recv_from(&mut buf).unwrap();
+            let mut resp = vec![];
+            resp.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            resp.extend_from_slice(&buf[12..16]);
+            resp.extend_from_slice(&0xabcdu64.to_be_bytes());
+            socket.send_to(&resp, client).unwrap();
+
+            let (req_len, client) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(req_len, 98);
+            assert_eq!(read_u64(&buf, 0), Some(0xabcd));
+            assert_eq!(read_u32(&buf, 8), Some(ACTION_ANNOUNCE));
+            assert_eq!(&buf[16..36], &[7; 20]);
+            assert_eq!(read_u64(&buf, 64), Some(1024));
+            assert_eq!(read_u32(&buf, 80), Some(2));
+            assert_eq!(&buf[96..98], &6881u16.to_be_bytes());
+
+            let mut resp = vec![];
+            resp.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            resp.extend_from_slice(&buf[12..16]);
+            resp.extend_from_slice(&1800u32.to_be_bytes());
+            resp.extend_from_slice(&3u32.to_be_bytes());
+            resp.extend_from_slice(&5u32.to_be_bytes());
+            resp.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+            socket.send_to(&resp, client).unwrap();
+        });
+
+        let resp = AnnounceReq::new([7; 20], 6881)
+            .left(1024)
+            .event(AnnounceEvent::Started)
+            .send("127.0.0.1:0", addr)
+            .unwrap();
+
+        assert_eq!(
+            resp,
+            AnnounceResp {
+                interval: 1800,
+                leechers: 3,
+                seeders: 5,
+                peers: vec!["10.0.0.1:6881".parse().unwrap()]
+            }
+        );
+    }
+
+    /// Tests that transaction ids are random rather than counting up, and never
+    /// given out twice at once
+    #[test]
+    fn transaction_ids_random() {
+        let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+        let mut ids = socket.transaction_ids(100);
+
+        assert!(ids
+            .windows(2)
+            .any(|pair| pair[1] != pair[0].wrapping_add(1)));
+
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 100);
+    }
+
+    /// Tests that announce responses larger than a typical MTU are read whole
+    #[test]
+    fn announce_large_response() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 98];
+
+            let (_, client) = socket.recv_from(&mut buf).unwrap();
+            let mut resp = vec![];
+            resp.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            resp.extend_from_slice(&buf[12..16]);
+            resp.extend_from_slice(&1u64.to_be_bytes());
+            socket.send_to(&resp, client).unwrap();
+
+            let (_, client) = socket.recv_from(&mut buf).unwrap();
+            let mut resp = vec![];
+            resp.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            resp.extend_from_slice(&buf[12..16]);
+            resp.extend_from_slice(&[0; 12]);
+
+            for peer in 0..600u16 {
+                resp.extend_from_slice(&[10, 0]);
+                resp.extend_from_slice(&peer.to_be_bytes());
+                resp.extend_from_slice(&6881u16.to_be_bytes());
+            }
+
+            socket.send_to(&resp, client).unwrap();
+        });
+
+        let resp = AnnounceReq::new([7; 20], 6881)
+            .send("127.0.0.1:0", addr)
+            .unwrap();
+
+        assert_eq!(resp.peers.len(), 600);
+        assert_eq!(resp.peers[599], "10.0.2.87:6881".parse().unwrap());
+    }
+
+    /// Tests that IPv6 announce responses are parsed with 18-byte peers
+    #[test]
+    fn announce_resp_ipv6() {
+        let mut buf = vec![0, 0, 0, 1, 0, 0, 0, 9, 0, 0, 0, 60, 0, 0, 0, 0, 0, 0, 0, 1];
+        buf.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        buf.extend_from_slice(&[0x1a, 0xe1, 0xff]); // trailing byte ignored
+
+        assert_eq!(
+            AnnounceResp::from_bytes(9, &buf, true).unwrap().peers,
+            vec!["[::1]:6881".parse().unwrap()]
+        );
+        assert_eq!(AnnounceResp::from_bytes(8, &buf, true), None);
+    }
+
+    /// Tests that [scrape] splits many info-hashes into several packets and
+    /// matches their responses by transaction id, even when answered out of
+    /// order
+    #[test]
+    fn scrape_stand_in_tracker() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+
+            let (_, client) = socket.recv_from(&mut buf).unwrap();
+            let mut resp = vec![];
+            resp.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            resp.extend_from_slice(&buf[12..16]);
+            resp.extend_from_slice(&1u64.to_be_bytes());
+            socket.send_to(&resp, client).unwrap();
+
+            let mut reqs = vec![];
+
+            for _ in 0..2 {
+                let (req_len, client) = socket.recv_from(&mut buf).unwrap();
+                assert_eq!(read_u32(&buf, 8), Some(ACTION_SCRAPE));
+                reqs.push((buf[..req_len].to_vec(), client));
+            }
+
+            assert_eq!(reqs[0].0.len(), 16 + 20 * MAX_SCRAPE_HASHES);
+            assert_eq!(reqs[1].0.len(), 16 + 20 * 6);
+
+            for (req, client) in reqs.iter().rev() {
+                let mut resp = vec![];
+                resp.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                resp.extend_from_slice(&req[12..16]);
+
+                for info_hash in req[16..].chunks(20) {
+                    resp.extend_from_slice(&(info_hash[0] as u32).to_be_bytes());
+                    resp.extend_from_slice(&0u32.to_be_bytes());
+                    resp.extend_from_slice(&1u32.to_be_bytes());
+                }
+
+                socket.send_to(&resp, client).unwrap();
+            }
+        });
+
+        let info_hashes: Vec<[u8; 20]> = (0..80).map(|ind| [ind as u8; 20]).collect();
+        let stats = scrape("127.0.0.1:0", addr, &info_hashes).unwrap();
+
+        assert_eq!(stats.len(), 80);
+
+        for (ind, torrent_stats) in stats.iter().enumerate() {
+            assert_eq!(torrent_stats.info_hash, [ind as u8; 20]);
+            assert_eq!(torrent_stats.complete, ind as u32);
+            assert_eq!(torrent_stats.incomplete, 1);
+        }
+    }
 }
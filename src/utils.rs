@@ -1,7 +1,9 @@
 //! Internal/private utilities like (insecure) RNG for use globally inside torro
 
 use crate::CLIENT_PREFIX;
+use std::collections::hash_map::RandomState;
 use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
 use std::io::prelude::*;
 use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -21,12 +23,59 @@ pub fn randish_128() -> u128 {
         .expect("Time went backwards")
         .as_nanos();
 
-    seed = seed << 13;
-    seed = seed >> 4;
+    seed <<= 13;
+    seed >>= 4;
 
     seed << 5
 }
 
+/// Random 128-bit number which can't be guessed from the outside, unlike
+/// [randish_128], for secrets and ids which others mustn't predict
+///
+/// This uses the random keys the OS gives std for it's [RandomState] hashers,
+/// as torro has no other source of secure randomness
+pub fn random_secret() -> u128 {
+    let half = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(randish_128());
+        hasher.finish() as u128
+    };
+
+    (half() << 64) | half()
+}
+
+/// Small xorshift pseudorandom number generator, used anywhere torro needs
+/// cheap randomness such as breaking ties or shuffling
+///
+/// # Usage notice
+///
+/// Like [randish_128] which it's seeded from by default, this isn't secure and
+/// must not be used for anything a peer shouldn't be able to guess
+#[derive(Debug, Clone)]
+pub struct Xorshift(u64);
+
+impl Xorshift {
+    /// Creates a new generator seeded from [randish_128]
+    pub fn new() -> Self {
+        Self::with_seed((randish_128() >> 14) as u64)
+    }
+
+    /// Creates a new generator from a fixed `seed`, giving the same numbers
+    /// every time. The lowest bit is always set as an all-zero state would
+    /// only ever give zeros
+    pub fn with_seed(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+
+    /// Gets the next pseudorandom number
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
 /// Generates torro id using [randish_128]
 ///
 /// **WARNING: THIS CAN LEAK CREATION TIME AND IS NOT SECURE, SEE [randish_128] FOR
@@ -56,6 +105,14 @@ pub fn read_file_bytes(file: &PathBuf) -> Result<Vec<u8>, std::io::Error> {
     Ok(contents)
 }
 
+/// Writes given `contents` to a `file` &[PathBuf], creating or overwriting it,
+/// or returns a [std::io::Error]
+///
+/// The opposite of [read_file_bytes], used for [Torrent::to_file]
+pub fn write_file_bytes(file: &PathBuf, contents: &[u8]) -> Result<(), std::io::Error> {
+    File::create(file)?.write_all(contents)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,6 +131,29 @@ mod tests {
         }
     }
 
+    /// Checks that [random_secret] doesn't repeat itself
+    #[test]
+    fn random_secret_nodupe() {
+        for _ in 0..100 {
+            assert_ne!(random_secret(), random_secret());
+        }
+    }
+
+    /// Checks that [Xorshift] gives the same numbers for the same seed and
+    /// never gets stuck on zero
+    #[test]
+    fn xorshift_seeded() {
+        let mut first = Xorshift::with_seed(0);
+        let mut second = Xorshift::with_seed(1);
+
+        for _ in 0..100 {
+            let num = first.next_u64();
+
+            assert_ne!(num, 0);
+            assert_eq!(num, second.next_u64());
+        }
+    }
+
     #[test]
     fn check_torro_id() {
         for _ in 0..1000 {
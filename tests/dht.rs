@@ -0,0 +1,104 @@
+//! Ensures that a small swarm of local [Dht] nodes can find each other and
+//! each other's peers
+
+use std::collections::HashSet;
+use std::time::Duration;
+use torro::dht::{distance, Crawler, Dht, RoutingTable};
+use torro::error::DhtError;
+
+/// Starts `count` nodes on localhost, each bootstrapping from the first
+fn swarm(count: usize) -> Vec<Dht> {
+    let nodes: Vec<Dht> = (0..count)
+        .map(|_| {
+            Dht::bind("127.0.0.1:0")
+                .unwrap()
+                .timeout(Duration::from_millis(500))
+        })
+        .collect();
+    let router = nodes[0].local_addr();
+
+    for node in &nodes[1..] {
+        assert!(node.bootstrap(&[router]).unwrap() >= 1);
+    }
+
+    nodes
+}
+
+/// Tests that a peer announced by one node is found by another, and that
+/// `find_node` gives nodes closest to the target
+#[test]
+fn dht_announce_get_peers() {
+    let nodes = swarm(6);
+    let info_hash = [7; 20];
+
+    nodes[2].announce(info_hash, Some(6881)).unwrap();
+    nodes[3].announce(info_hash, None).unwrap();
+
+    let peers = nodes[5].get_peers(info_hash).unwrap();
+    assert!(peers.contains(&"127.0.0.1:6881".parse().unwrap()));
+    assert!(peers.contains(&nodes[3].local_addr()));
+
+    let target = nodes[4].id();
+    let found = nodes[1].find_node(target).unwrap();
+    assert_eq!(found[0].id, target);
+    assert!(found
+        .windows(2)
+        .all(|pair| distance(&pair[0].id, &target) <= distance(&pair[1].id, &target)));
+}
+
+/// Tests that a saved routing table can be used to start a node again without
+/// bootstrapping, and that lone nodes can't look anything up
+#[test]
+fn dht_save_load() {
+    let nodes = swarm(3);
+    let path = std::env::temp_dir().join(format!("torro_dht_{}.dat", nodes[1].local_addr().port()));
+
+    nodes[1].save(&path).unwrap();
+    let table = RoutingTable::load(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+
+    assert_eq!(table.id(), nodes[1].id());
+    assert_eq!(table.len(), nodes[1].routing_table().len());
+
+    let restarted = Dht::with_table("127.0.0.1:0", table).unwrap();
+    assert_eq!(restarted.id(), nodes[1].id());
+    assert_eq!(restarted.ping(nodes[0].local_addr()), Ok(nodes[0].id()));
+    assert!(!restarted.find_node([0; 20]).unwrap().is_empty());
+
+    let lonely = Dht::bind("127.0.0.1:0").unwrap();
+    assert_eq!(lonely.get_peers([0; 20]), Err(DhtError::NoNodes));
+}
+
+/// Tests that a crawler samples every info-hash announced in the swarm, and
+/// that nodes learn their external address from the `ip` key
+#[test]
+fn dht_crawl_samples() {
+    let nodes = swarm(5);
+    let info_hashes: HashSet<[u8; 20]> = (1..=4).map(|ind| [ind; 20]).collect();
+
+    for info_hash in info_hashes.iter() {
+        nodes[1].announce(*info_hash, Some(6881)).unwrap();
+    }
+
+    let response = nodes[2]
+        .sample_infohashes(nodes[0].local_addr(), [0; 20])
+        .unwrap();
+    assert_eq!(response.num, Some(4));
+    assert_eq!(response.samples.len(), 4);
+    assert!(response.interval.is_some());
+
+    let crawler_node = Dht::bind("127.0.0.1:0")
+        .unwrap()
+        .timeout(Duration::from_millis(500));
+    assert!(Crawler::new(&crawler_node).crawl(10).is_err());
+
+    crawler_node.bootstrap(&[nodes[0].local_addr()]).unwrap();
+    let mut crawler = Crawler::new(&crawler_node);
+
+    assert_eq!(crawler.crawl(100).unwrap(), 4);
+    assert_eq!(crawler.info_hashes(), &info_hashes);
+    assert_eq!(crawler.nodes_found(), 5);
+    assert_eq!(crawler.crawl(100).unwrap(), 0);
+
+    assert_eq!(nodes[4].external_ip(), Some("127.0.0.1".parse().unwrap()));
+}
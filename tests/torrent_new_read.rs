@@ -1,5 +1,6 @@
 //! Ensures that [Torrent::from_file] can properly read a `.torrent` file
 
+use std::fs::File;
 use std::path::PathBuf;
 use torro::Torrent;
 
@@ -33,3 +34,61 @@ fn torrent_from_tails() {
 
     Torrent::from_file(file).unwrap();
 }
+
+/// Tests that [Torrent::info_hash] matches the known info-hashes of the
+/// `tails-amd64-4.10.img.torrent` and
+/// `ubuntu-20.04.1-live-server-amd64.iso.torrent` files
+#[test]
+fn torrent_info_hashes() {
+    let tails = Torrent::from_file(PathBuf::from(format!(
+        "{}tails-amd64-4.10.img.torrent",
+        DATA_PATH_PREFIX
+    )))
+    .unwrap();
+    let ubuntu = Torrent::from_file(PathBuf::from(format!(
+        "{}ubuntu-20.04.1-live-server-amd64.iso.torrent",
+        DATA_PATH_PREFIX
+    )))
+    .unwrap();
+
+    assert_eq!(
+        to_hex(&tails.info_hash),
+        "6eef56ce24447220dba7da10c2b5674390043803"
+    );
+    assert_eq!(
+        to_hex(&ubuntu.info_hash),
+        "36c67464c37a83478ceff54932b5a9bddea636f3"
+    );
+}
+
+/// Converts bytes into a lowercase hex string for easier comparisons
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Tests that [Torrent::from_reader] reading straight from an open file gives
+/// the same [Torrent] as [Torrent::from_file]
+#[test]
+fn torrent_from_reader_tails() {
+    let file = PathBuf::from(format!("{}tails-amd64-4.10.img.torrent", DATA_PATH_PREFIX));
+
+    assert_eq!(
+        Torrent::from_reader(File::open(&file).unwrap()).unwrap(),
+        Torrent::from_file(file).unwrap()
+    );
+}
+
+/// Tests that [Torrent::lint] finds no errors with the real-world
+/// `tails-amd64-4.10.img.torrent` and
+/// `ubuntu-20.04.1-live-server-amd64.iso.torrent` files
+#[test]
+fn torrent_lint_real_world() {
+    for file in &[
+        "tails-amd64-4.10.img.torrent",
+        "ubuntu-20.04.1-live-server-amd64.iso.torrent",
+    ] {
+        let torrent = Torrent::from_file(format!("{}{}", DATA_PATH_PREFIX, file)).unwrap();
+
+        assert_eq!(Torrent::lint(torrent.as_bytes()), vec![]);
+    }
+}
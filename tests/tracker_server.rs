@@ -0,0 +1,109 @@
+//! Ensures that torro's own tracker clients work against an embedded
+//! [TrackerServer] over both HTTP and UDP
+
+use torro::error::TrackerError;
+use torro::tracker::connect;
+use torro::tracker_server::TrackerServer;
+use torro::tracker_udp::{AnnounceEvent, AnnounceReq, TrackerSocket, UdpTracker};
+
+/// Tests that peers announcing over HTTP and UDP share a swarm and can be
+/// scraped over either protocol
+#[test]
+fn tracker_server_http_udp() {
+    let server = TrackerServer::new().interval(60);
+    let http = server.serve_http("127.0.0.1:0").unwrap();
+    let udp = server.serve_udp("127.0.0.1:0").unwrap();
+
+    let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+    let mut http_tracker = connect(&socket, &http.announce_url()).unwrap();
+    let mut udp_tracker = connect(&socket, &udp.announce_url()).unwrap();
+
+    let leecher = AnnounceReq::new([1; 20], 6881)
+        .peer_id([b'a'; 20])
+        .left(100)
+        .event(AnnounceEvent::Started);
+    let seeder = AnnounceReq::new([1; 20], 6882)
+        .peer_id([b'b'; 20])
+        .event(AnnounceEvent::Started);
+
+    let resp = http_tracker.announce(&leecher).unwrap();
+    assert_eq!(resp.interval, 60);
+    assert!(resp.peers.is_empty());
+
+    let resp = udp_tracker.announce(&seeder).unwrap();
+    assert_eq!(resp.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    assert_eq!((resp.seeders, resp.leechers), (Some(1), Some(1)));
+
+    let resp = http_tracker
+        .announce(&leecher.clone().event(AnnounceEvent::None))
+        .unwrap();
+    assert_eq!(resp.peers, vec!["127.0.0.1:6882".parse().unwrap()]);
+
+    for tracker in [&mut http_tracker, &mut udp_tracker].iter_mut() {
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).unwrap();
+
+        assert_eq!((stats[0].complete, stats[0].incomplete), (1, 1));
+        assert_eq!((stats[1].complete, stats[1].incomplete), (0, 0));
+    }
+
+    udp_tracker
+        .announce(&seeder.event(AnnounceEvent::Stopped))
+        .unwrap();
+    assert_eq!(udp_tracker.scrape(&[[1; 20]]).unwrap()[0].complete, 0);
+}
+
+/// Tests that a whitelisted server refuses other torrents over HTTP
+#[test]
+fn tracker_server_whitelist() {
+    let server = TrackerServer::new().whitelist(vec![[1; 20]]);
+    let http = server.serve_http("127.0.0.1:0").unwrap();
+    let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+    let mut tracker = connect(&socket, &http.announce_url()).unwrap();
+
+    assert!(tracker.announce(&AnnounceReq::new([1; 20], 6881)).is_ok());
+    assert_eq!(
+        tracker.announce(&AnnounceReq::new([2; 20], 6881)).err(),
+        Some(TrackerError::TrackerMessage(
+            "info-hash not whitelisted".to_string()
+        ))
+    );
+}
+
+/// Tests that a single [TrackerSocket] can announce several torrents to several
+/// UDP trackers at once, with each response reaching the right announce
+#[test]
+fn tracker_server_shared_socket() {
+    let servers: Vec<_> = (0..2).map(|_| TrackerServer::new()).collect();
+    let handles: Vec<_> = servers
+        .iter()
+        .map(|server| server.serve_udp("127.0.0.1:0").unwrap())
+        .collect();
+    let socket = TrackerSocket::bind("127.0.0.1:0").unwrap();
+
+    let threads: Vec<_> = (0..8u8)
+        .map(|ind| {
+            let socket = socket.clone();
+            let url = handles[ind as usize % 2].announce_url();
+
+            std::thread::spawn(move || {
+                let mut tracker = UdpTracker::with_socket(&socket, &url).unwrap();
+                let port = 7000 + ind as u16;
+
+                tracker
+                    .announce(&AnnounceReq::new([ind; 20], port))
+                    .unwrap();
+                tracker
+                    .announce(&AnnounceReq::new([ind; 20], port + 100))
+                    .unwrap()
+                    .peers
+            })
+        })
+        .collect();
+
+    for (ind, thread) in threads.into_iter().enumerate() {
+        let peers = thread.join().unwrap();
+
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].port(), 7000 + ind as u16);
+    }
+}